@@ -0,0 +1,51 @@
+//! Piece hashing throughput, inline and through the blocking thread pool the download path
+//! uses, at the piece sizes large torrents pick.
+//!
+//! Run with `cargo +nightly bench --bench hashing` from the repository root.
+
+#![feature(test)]
+
+extern crate test;
+
+use test::Bencher;
+
+// the client is a binary crate, so take the module straight from its source
+#[path = "../src/hash.rs"]
+#[allow(dead_code)]
+mod hash;
+
+use hash::TorrentVersion;
+
+const PIECE: usize = 4 << 20;
+
+fn piece() -> Vec<u8> {
+    (0..PIECE).map(|i| (i % 251) as u8).collect()
+}
+
+#[bench]
+fn sha1_inline(b: &mut Bencher) {
+    let data = piece();
+    b.bytes = PIECE as u64;
+    b.iter(|| TorrentVersion::V1.digest(&data));
+}
+
+#[bench]
+fn sha256_inline(b: &mut Bencher) {
+    let data = piece();
+    b.bytes = PIECE as u64;
+    b.iter(|| TorrentVersion::V2.digest(&data));
+}
+
+#[bench]
+fn sha1_blocking_pool(b: &mut Bencher) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let expected = TorrentVersion::V1.digest(&piece());
+    let mut data = piece();
+    b.bytes = PIECE as u64;
+    b.iter(|| {
+        let (back, ok) =
+            runtime.block_on(TorrentVersion::V1.verify_blocking(data.split_off(0), &expected));
+        assert!(ok);
+        data = back;
+    });
+}
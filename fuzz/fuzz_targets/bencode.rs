@@ -0,0 +1,25 @@
+//! Feeds arbitrary bytes to the bencode decoder: it must fail cleanly rather than panic or
+//! overflow the stack, agree with `value_len` on where a value ends, and re-encode whatever
+//! it accepts to bytes that decode to the same value.
+//!
+//! Run with `cargo fuzz run bencode` from the repository root.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// the client is a binary crate, so take the module straight from its source
+#[path = "../../src/bencode.rs"]
+#[allow(dead_code)]
+mod bencode;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok((value, rest)) = bencode::decode_bytes(data) else {
+        return;
+    };
+    assert_eq!(bencode::value_len(data), Some(data.len() - rest.len()));
+    let encoded = bencode::encode_bencoded(&value).expect("decoded values can be encoded");
+    let (again, rest) = bencode::decode_bytes(&encoded).expect("encoded values can be decoded");
+    assert!(rest.is_empty());
+    assert_eq!(again, value);
+});
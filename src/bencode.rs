@@ -0,0 +1,334 @@
+use std::fmt::Write;
+
+use serde_json::Value;
+
+#[derive(Debug, thiserror::Error)]
+pub enum EncodeError {
+    #[error("bencode has no representation for {0}")]
+    Unrepresentable(&'static str),
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid bencode at byte {at}: {reason}")]
+pub struct DecodeError {
+    pub at: usize,
+    pub reason: &'static str,
+}
+
+impl DecodeError {
+    /// The same error for input that started `offset` bytes further into a larger buffer.
+    pub fn shifted(self, offset: usize) -> Self {
+        Self {
+            at: self.at + offset,
+            ..self
+        }
+    }
+}
+
+/// Deepest nesting of lists and dictionaries we accept. Real metainfo and extension messages
+/// nest a few levels; the limit keeps hostile input from exhausting the stack.
+const MAX_DEPTH: usize = 64;
+
+/// Encodes a JSON value as bencode, the inverse of [`decode_bytes`]: for every value that
+/// `decode_bytes` produces from UTF-8 input, decoding the output of this function yields the
+/// same value.
+///
+/// Dictionary keys are emitted in sorted order as the spec requires. Booleans, nulls and
+/// non-integer numbers have no bencode form and are rejected.
+pub fn encode_bencoded(value: &Value) -> Result<Vec<u8>, EncodeError> {
+    let mut out = Vec::new();
+    encode_into(value, &mut out)?;
+    Ok(out)
+}
+
+fn encode_into(value: &Value, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+    match value {
+        Value::String(s) => encode_bytes(s.as_bytes(), out),
+        Value::Number(n) => {
+            let n = if let Some(n) = n.as_i64() {
+                n.to_string()
+            } else if let Some(n) = n.as_u64() {
+                n.to_string()
+            } else {
+                return Err(EncodeError::Unrepresentable("floating point numbers"));
+            };
+            out.push(b'i');
+            out.extend_from_slice(n.as_bytes());
+            out.push(b'e');
+        }
+        Value::Array(items) => {
+            out.push(b'l');
+            for item in items {
+                encode_into(item, out)?;
+            }
+            out.push(b'e');
+        }
+        Value::Object(entries) => {
+            let mut entries: Vec<_> = entries.iter().collect();
+            entries.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+            out.push(b'd');
+            for (key, value) in entries {
+                encode_bytes(key.as_bytes(), out);
+                encode_into(value, out)?;
+            }
+            out.push(b'e');
+        }
+        Value::Bool(_) => return Err(EncodeError::Unrepresentable("booleans")),
+        Value::Null => return Err(EncodeError::Unrepresentable("null")),
+    }
+    Ok(())
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend_from_slice(bytes);
+}
+
+/// Length of the single bencoded value at the start of `bytes`, or `None` if it is truncated
+/// or malformed. Used to split a bencoded header from raw data appended after it.
+pub fn value_len(bytes: &[u8]) -> Option<usize> {
+    value_len_within(bytes, MAX_DEPTH)
+}
+
+fn value_len_within(bytes: &[u8], depth: usize) -> Option<usize> {
+    match *bytes.first()? {
+        b'i' => Some(bytes.iter().position(|&b| b == b'e')? + 1),
+        b'l' | b'd' if depth > 0 => {
+            let mut at = 1;
+            while *bytes.get(at)? != b'e' {
+                at += value_len_within(&bytes[at..], depth - 1)?;
+            }
+            Some(at + 1)
+        }
+        b'0'..=b'9' => {
+            let colon = bytes.iter().position(|&b| b == b':')?;
+            let len: usize = std::str::from_utf8(&bytes[..colon]).ok()?.parse().ok()?;
+            let end = (colon + 1).checked_add(len)?;
+            (end <= bytes.len()).then_some(end)
+        }
+        _ => None,
+    }
+}
+
+/// Decodes the bencoded value at the start of `bytes` into JSON, returning it with the
+/// remaining input. Malformed input is an error pointing at the offending byte, never a
+/// panic. A byte string that isn't valid UTF-8, such as a `pieces` blob, keeps its valid
+/// parts and has every other byte escaped as `\xNN`.
+pub fn decode_bytes(bytes: &[u8]) -> Result<(Value, &[u8]), DecodeError> {
+    let (value, len) = decode_at(bytes, 0, MAX_DEPTH)?;
+    Ok((value, &bytes[len..]))
+}
+
+/// Decodes the value starting at `at`, returning it and the offset just past it.
+fn decode_at(bytes: &[u8], at: usize, depth: usize) -> Result<(Value, usize), DecodeError> {
+    let error = |at, reason| DecodeError { at, reason };
+    match bytes.get(at) {
+        None => Err(error(at, "unexpected end of input")),
+        Some(b'i') => {
+            let end = find(bytes, at + 1, b'e').ok_or(error(at, "unterminated integer"))?;
+            let digits = &bytes[at + 1..end];
+            // the spec forbids leading zeros and negative zero
+            let canonical = match digits {
+                [b'0'] => true,
+                [b'-', b'1'..=b'9', rest @ ..] | [b'1'..=b'9', rest @ ..] => {
+                    rest.iter().all(u8::is_ascii_digit)
+                }
+                _ => false,
+            };
+            if !canonical {
+                return Err(error(at + 1, "malformed integer"));
+            }
+            let n: i64 = std::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| digits.parse().ok())
+                .ok_or(error(at + 1, "integer out of range"))?;
+            Ok((Value::from(n), end + 1))
+        }
+        Some(b'l' | b'd') if depth == 0 => Err(error(at, "nested too deeply")),
+        Some(b'l') => {
+            let mut items = Vec::new();
+            let mut at = at + 1;
+            while bytes.get(at) != Some(&b'e') {
+                let (item, next) = decode_at(bytes, at, depth - 1)?;
+                items.push(item);
+                at = next;
+            }
+            Ok((Value::Array(items), at + 1))
+        }
+        Some(b'd') => {
+            let mut entries = serde_json::Map::new();
+            let mut at = at + 1;
+            while bytes.get(at) != Some(&b'e') {
+                if !bytes.get(at).is_some_and(u8::is_ascii_digit) {
+                    return Err(error(at, "expected a string dictionary key"));
+                }
+                let (key, next) = decode_string(bytes, at)?;
+                let (value, next) = decode_at(bytes, next, depth - 1)?;
+                entries.insert(escape_bytes(key), value);
+                at = next;
+            }
+            Ok((Value::Object(entries), at + 1))
+        }
+        Some(b'0'..=b'9') => {
+            let (string, next) = decode_string(bytes, at)?;
+            Ok((Value::String(escape_bytes(string)), next))
+        }
+        Some(_) => Err(error(at, "expected a bencoded value")),
+    }
+}
+
+fn decode_string(bytes: &[u8], at: usize) -> Result<(&[u8], usize), DecodeError> {
+    let error = |at, reason| DecodeError { at, reason };
+    let colon = find(bytes, at, b':').ok_or(error(at, "unterminated string length"))?;
+    let len: usize = std::str::from_utf8(&bytes[at..colon])
+        .ok()
+        .and_then(|len| len.parse().ok())
+        .ok_or(error(at, "malformed string length"))?;
+    let end = (colon + 1)
+        .checked_add(len)
+        .filter(|&end| end <= bytes.len())
+        .ok_or(error(at, "string runs past end of input"))?;
+    Ok((&bytes[colon + 1..end], end))
+}
+
+fn find(bytes: &[u8], from: usize, byte: u8) -> Option<usize> {
+    bytes[from..]
+        .iter()
+        .position(|&b| b == byte)
+        .map(|i| from + i)
+}
+
+fn escape_bytes(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        escaped.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            write!(escaped, "\\x{byte:02x}").expect("writing to a String cannot fail");
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> Value {
+        let (value, rest) = decode_bytes(bytes).unwrap();
+        assert!(rest.is_empty(), "{} bytes left over", rest.len());
+        value
+    }
+
+    #[test]
+    fn encodes_every_kind_of_value() {
+        let value = json!({"b": 1, "a": ["spam", -3, u64::MAX], "": {}});
+        assert_eq!(
+            encode_bencoded(&value).unwrap(),
+            b"d0:de1:al4:spami-3ei18446744073709551615ee1:bi1ee"
+        );
+    }
+
+    #[test]
+    fn sorts_keys_by_their_bytes() {
+        let value = json!({"b": 0, "B": 0, "ab": 0, "a": 0});
+        assert_eq!(
+            encode_bencoded(&value).unwrap(),
+            b"d1:Bi0e1:ai0e2:abi0e1:bi0ee"
+        );
+    }
+
+    #[test]
+    fn encode_undoes_decode() {
+        let inputs: &[&[u8]] = &[
+            b"i0e",
+            b"i-42e",
+            b"0:",
+            b"5:hello",
+            b"le",
+            b"de",
+            b"l4:spami7ee",
+            b"d3:bar4:spam3:fooi42ee",
+            b"d4:infod6:lengthi12e4:name4:testee",
+            b"lld1:aleee1:be",
+        ];
+        for &input in inputs {
+            let encoded = encode_bencoded(&decode(input)).unwrap();
+            assert_eq!(encoded, input, "{}", String::from_utf8_lossy(input));
+        }
+    }
+
+    #[test]
+    fn decode_undoes_encode() {
+        let value = json!({"announce": "http://tracker", "info": {"files": [
+            {"length": 5, "path": ["a", "b"]},
+            {"length": 0, "path": ["ü"]},
+        ]}});
+        assert_eq!(decode(&encode_bencoded(&value).unwrap()), value);
+    }
+
+    #[test]
+    fn refuses_values_bencode_lacks() {
+        for value in [json!(true), json!(null), json!(1.5), json!([{"a": null}])] {
+            assert!(encode_bencoded(&value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn refuses_truncated_input() {
+        let whole = b"d3:bari-7e3:fool4:spami42eee";
+        assert_eq!(value_len(whole), Some(whole.len()));
+        for end in 0..whole.len() {
+            let truncated = &whole[..end];
+            assert!(decode_bytes(truncated).is_err(), "decoded {end} bytes");
+            assert_eq!(value_len(truncated), None, "measured {end} bytes");
+        }
+    }
+
+    #[test]
+    fn points_at_the_offending_byte() {
+        let cases: &[(&[u8], usize, &str)] = &[
+            (b"l4:spam", 7, "unexpected end of input"),
+            (b"i03e", 1, "malformed integer"),
+            (b"i-0e", 1, "malformed integer"),
+            (b"i99999999999999999999e", 1, "integer out of range"),
+            (b"di1ei2ee", 1, "expected a string dictionary key"),
+            (b"l6:spame", 1, "string runs past end of input"),
+            (b"x", 0, "expected a bencoded value"),
+        ];
+        for &(input, at, reason) in cases {
+            let error = decode_bytes(input).unwrap_err();
+            assert_eq!((error.at, error.reason), (at, reason));
+        }
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let nested = |depth| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+        let deepest = nested(MAX_DEPTH);
+        assert_eq!(decode_bytes(&deepest).unwrap().1, b"");
+        assert_eq!(value_len(&deepest), Some(deepest.len()));
+
+        let too_deep = nested(MAX_DEPTH + 1);
+        let error = decode_bytes(&too_deep).unwrap_err();
+        assert_eq!((error.at, error.reason), (MAX_DEPTH, "nested too deeply"));
+        assert_eq!(value_len(&too_deep), None);
+    }
+
+    #[test]
+    fn escapes_bytes_that_are_not_utf8() {
+        assert_eq!(
+            decode(b"d4:a\xffbci1e3:\xc3\xa9\xfe4:\x00\x01\x02\x03e"),
+            json!({"a\\xffbc": 1, "é\\xfe": "\u{0}\u{1}\u{2}\u{3}"})
+        );
+    }
+
+    #[test]
+    fn leaves_what_follows_the_value() {
+        let (value, rest) = decode_bytes(b"i1eraw data").unwrap();
+        assert_eq!((value, rest), (json!(1), &b"raw data"[..]));
+        assert_eq!(value_len(b"i1eraw data"), Some(3));
+    }
+}
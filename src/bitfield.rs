@@ -0,0 +1,107 @@
+#[derive(Debug, thiserror::Error)]
+pub enum BitfieldError {
+    #[error("bitfield for {npieces} pieces needs {expected} bytes, got {actual}")]
+    Length {
+        npieces: usize,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("bitfield has spare bits set past the last piece")]
+    SpareBits,
+}
+
+/// Which pieces a peer (or we) have, stored high bit first as on the wire.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    npieces: usize,
+}
+
+impl Bitfield {
+    pub fn new(npieces: usize) -> Self {
+        Self {
+            bytes: vec![0; npieces.div_ceil(8)],
+            npieces,
+        }
+    }
+
+    pub fn full(npieces: usize) -> Self {
+        let mut bitfield = Self::new(npieces);
+        for index in 0..npieces {
+            bitfield.set_piece(index);
+        }
+        bitfield
+    }
+
+    pub fn from_payload(payload: Vec<u8>, npieces: usize) -> Result<Self, BitfieldError> {
+        let expected = npieces.div_ceil(8);
+        if payload.len() != expected {
+            return Err(BitfieldError::Length {
+                npieces,
+                expected,
+                actual: payload.len(),
+            });
+        }
+        let spare = expected * 8 - npieces;
+        if spare > 0 && payload[expected - 1] & ((1 << spare) - 1) != 0 {
+            return Err(BitfieldError::SpareBits);
+        }
+        Ok(Self {
+            bytes: payload,
+            npieces,
+        })
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        index < self.npieces && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set_piece(&mut self, index: usize) {
+        assert!(index < self.npieces, "piece {index} out of range");
+        self.bytes[index / 8] |= 0x80 >> (index % 8);
+    }
+
+    /// Indices of the pieces that are present, in ascending order.
+    pub fn pieces(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.npieces).filter(|&i| self.has_piece(i))
+    }
+
+    pub fn count(&self) -> usize {
+        self.bytes.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.npieces
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_wire_layout() {
+        let bitfield = Bitfield::from_payload(vec![0b1010_0000, 0b1000_0000], 9).unwrap();
+        assert_eq!(bitfield.pieces().collect::<Vec<_>>(), [0, 2, 8]);
+        assert!(!bitfield.has_piece(9));
+    }
+
+    #[test]
+    fn rejects_spare_bits_past_the_last_piece() {
+        let error = Bitfield::from_payload(vec![0xff, 0b1100_0000], 9).unwrap_err();
+        assert!(matches!(error, BitfieldError::SpareBits));
+        assert!(Bitfield::from_payload(vec![0xff, 0b1000_0000], 9).is_ok());
+        // a whole number of bytes leaves no spare bits
+        assert!(Bitfield::from_payload(vec![0xff], 8).is_ok());
+    }
+
+    #[test]
+    fn rejects_the_wrong_length() {
+        let error = Bitfield::from_payload(vec![0xff], 9).unwrap_err();
+        assert!(matches!(error, BitfieldError::Length { expected: 2, .. }));
+    }
+}
@@ -0,0 +1,509 @@
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand, ValueEnum};
+
+use crate::create::MetaVersion;
+use crate::daemon::ControlAddr;
+use crate::maintenance::Job;
+use crate::mse::Encryption;
+use crate::peer_id::PeerId;
+use crate::sink::Fsync;
+use crate::speed::AltWindow;
+use crate::stats::{self, ExportFormat};
+use crate::tracker::Event;
+use crate::transport::{PeerPath, Transport};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    #[command(subcommand)]
+    pub commands: Commands,
+    /// Read flag defaults from this TOML file instead of
+    /// ~/.config/bittorrent-starter-rust/config.toml.
+    #[arg(long = "config", global = true, value_name = "FILE")]
+    pub config_file: Option<PathBuf>,
+    /// Increase log verbosity (-v info, -vv debug, -vvv trace).
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    pub verbose: u8,
+    /// Emit logs as JSON lines instead of human-readable text.
+    #[arg(long, global = true)]
+    pub log_json: bool,
+    /// Use a fixed peer id instead of a random one, e.g. for reproducible tests.
+    #[arg(long, global = true)]
+    pub peer_id: Option<PeerId>,
+    /// Local address to bind tracker and peer connections to.
+    #[arg(long, global = true)]
+    pub bind: Option<IpAddr>,
+    /// Network interface to bind tracker and peer connections to (Linux only), e.g. a VPN
+    /// tunnel.
+    #[arg(long, global = true)]
+    pub interface: Option<String>,
+    /// Route tracker and peer connections through a proxy, e.g. socks5://127.0.0.1:1080 or
+    /// http://proxy:3128.
+    #[arg(long, global = true)]
+    pub proxy: Option<reqwest::Url>,
+    /// Leave TCP_NODELAY off on peer connections, letting the kernel coalesce small writes.
+    #[arg(long, global = true)]
+    pub no_nodelay: bool,
+    /// Socket send buffer size for peer connections; the OS default otherwise.
+    #[arg(long, global = true, value_name = "BYTES")]
+    pub send_buffer: Option<u32>,
+    /// Socket receive buffer size for peer connections; the OS default otherwise.
+    #[arg(long, global = true, value_name = "BYTES")]
+    pub recv_buffer: Option<u32>,
+    /// Most outbound peer connections that may be connecting at once.
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 20,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub max_half_open: u32,
+    /// Times a piece may fail hash verification before giving up on the torrent.
+    #[arg(long, global = true, default_value_t = 3)]
+    pub max_hash_failures: u32,
+    /// How many peers to ask trackers for.
+    #[arg(long, global = true, default_value_t = 50)]
+    pub numwant: u32,
+    /// Most peer connections to have open at once, across every torrent.
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 200,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub max_peers: u32,
+    /// Most peers a single download fetches pieces from at once.
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 8,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub max_connections_per_torrent: u32,
+    /// Most peers we upload to at once, across every torrent; 0 never uploads.
+    #[arg(long, global = true, default_value_t = 8)]
+    pub max_upload_slots: u32,
+    /// Key to identify this client to trackers; random by default.
+    #[arg(long, global = true)]
+    pub tracker_key: Option<String>,
+    /// Largest tracker response body to accept, in bytes.
+    #[arg(long, global = true, default_value_t = 1 << 20)]
+    pub max_tracker_response: usize,
+    /// Stop advertising a protocol extension in our handshake.
+    #[arg(long = "disable", value_enum, global = true)]
+    pub disabled: Vec<Capability>,
+    /// Disconnect peers that send messages for extensions that weren't negotiated.
+    #[arg(long, global = true)]
+    pub strict: bool,
+    /// Whether to obfuscate peer connections with Message Stream Encryption.
+    #[arg(long, value_enum, global = true, default_value_t = Encryption::Disable)]
+    pub encryption: Encryption,
+    /// Transport to reach peers over.
+    #[arg(long, value_enum, global = true, default_value_t = Transport::Tcp)]
+    pub transport: Transport,
+    /// How to connect to the peers of private torrents, e.g. `secure` to only ever reach them
+    /// encrypted and through --proxy.
+    #[arg(long, value_enum, global = true, default_value_t = PeerPath::Configured)]
+    pub private_peers: PeerPath,
+    /// How to connect to the peers of public torrents, e.g. `fastest` to skip encryption and
+    /// the proxy.
+    #[arg(long, value_enum, global = true, default_value_t = PeerPath::Configured)]
+    pub public_peers: PeerPath,
+    /// When to force downloaded data out to disk.
+    #[arg(long, value_enum, global = true, default_value_t = Fsync::Never)]
+    pub fsync: Fsync,
+    /// Write downloads through a memory map; needs a build with the `mmap` feature.
+    #[arg(long, global = true)]
+    pub mmap: bool,
+    /// Download pieces in order, so the output can be played while it downloads.
+    #[arg(long, global = true)]
+    pub sequential: bool,
+    /// Directory to cache torrent metadata in, by info hash. Defaults to
+    /// ~/.cache/bittorrent-starter-rust/metadata, except in builds for the codecrafters stage
+    /// tests, which only cache if given one.
+    #[arg(long, global = true)]
+    pub metadata_cache: Option<PathBuf>,
+    /// Don't read or write the metadata cache.
+    #[arg(long, global = true, conflicts_with = "metadata_cache")]
+    pub no_metadata_cache: bool,
+    /// Accept connections from peers that learn about us from the tracker, over TCP and uTP.
+    /// Also lets connected peers introduce us to ones neither side can dial (holepunching).
+    #[arg(long, global = true)]
+    pub listen: bool,
+    /// Port to listen on; by default the first free one of 6881-6889.
+    #[arg(long, global = true, requires = "listen")]
+    pub port: Option<u16>,
+    /// Ask the router to forward the listen port to us, over NAT-PMP or else UPnP, so peers
+    /// behind it can reach us too.
+    #[arg(long, global = true, requires = "listen")]
+    pub map_port: bool,
+    /// Most inbound connections to accept a second, over TCP and uTP together.
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 20,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub max_accepts_per_sec: u32,
+    /// Most inbound peers that may be handshaking at once.
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 32,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub max_inbound_handshakes: u32,
+    /// Most inbound connections one IP address may have open.
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 4,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub max_connections_per_ip: u32,
+    /// Print transfer statistics and piece availability to stderr every this many seconds
+    /// while downloading.
+    #[arg(long, global = true, value_name = "SECONDS")]
+    pub stats_interval: Option<u64>,
+    /// Cap on the download rate, across every torrent; unlimited by default.
+    #[arg(long, global = true, value_name = "KIB_PER_SEC")]
+    pub download_limit: Option<u32>,
+    /// Download rate cap while alternative speed limits ("turtle mode") are on; unlimited by
+    /// default.
+    #[arg(long, global = true, value_name = "KIB_PER_SEC")]
+    pub alt_download_limit: Option<u32>,
+    /// Turn alternative speed limits on during this daily window, in UTC, and off outside it,
+    /// e.g. 08:00-20:00.
+    #[arg(long, global = true, value_name = "HH:MM-HH:MM")]
+    pub alt_speed_window: Option<AltWindow>,
+    /// Start with alternative speed limits on.
+    #[arg(long, global = true)]
+    pub alt_speed: bool,
+    /// Bytes to ask for in each block request, at most 16 KiB.
+    #[arg(
+        long,
+        global = true,
+        value_name = "BYTES",
+        default_value_t = 1 << 14,
+        value_parser = clap::value_parser!(u32).range(1..=1 << 14)
+    )]
+    pub block_size: u32,
+    /// Write every message sent to or received from a peer to this file, as JSON lines.
+    #[arg(long, global = true, value_name = "FILE")]
+    pub trace_wire: Option<PathBuf>,
+    /// Give up connecting to a peer after this many seconds.
+    #[arg(long, global = true, value_name = "SECONDS", default_value_t = 5)]
+    pub connect_timeout: u64,
+    /// Give up on a peer that hasn't finished its handshake after this many seconds.
+    #[arg(long, global = true, value_name = "SECONDS", default_value_t = 10)]
+    pub handshake_timeout: u64,
+    /// Give up on a tracker announce after this many seconds.
+    #[arg(long, global = true, value_name = "SECONDS", default_value_t = 15)]
+    pub announce_timeout: u64,
+    /// Drop a peer that sends nothing at all for this many seconds.
+    #[arg(long, global = true, value_name = "SECONDS", default_value_t = 30)]
+    pub block_timeout: u64,
+    /// Cancel and send again a block request unanswered for this many seconds.
+    #[arg(long, global = true, value_name = "SECONDS", default_value_t = 20)]
+    pub request_timeout: u64,
+    /// Also hand a piece to another peer after this many seconds on it.
+    #[arg(long, global = true, value_name = "SECONDS", default_value_t = 60)]
+    pub piece_timeout: u64,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+    Dht,
+    Fast,
+    Extensions,
+}
+
+/// How commands that report on a torrent print their results.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human-readable text.
+    Plain,
+    /// A single JSON document, for scripts.
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+#[clap(rename_all = "snake_case")]
+pub enum Commands {
+    /// Decode bencode to JSON: the argument, or every value in `--file` or stdin.
+    Decode {
+        #[arg(conflicts_with = "file")]
+        value: Option<String>,
+        #[arg(long)]
+        file: Option<PathBuf>,
+    },
+    /// Encode a JSON value as bencode and write it to stdout.
+    Encode {
+        value: String,
+    },
+    Info {
+        torrent: PathBuf,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Plain)]
+        format: OutputFormat,
+    },
+    Peers {
+        torrent: PathBuf,
+        /// Handshake with every peer and report which are reachable and what client they run.
+        #[arg(long)]
+        probe: bool,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Plain)]
+        format: OutputFormat,
+    },
+    Handshake {
+        torrent: PathBuf,
+        peer: String,
+    },
+    /// Send a single announce with the given parameters and print the decoded response.
+    Announce {
+        torrent: PathBuf,
+        /// Announce to this URL instead of the torrent's tracker.
+        #[arg(long)]
+        tracker: Option<String>,
+        #[arg(long, value_enum)]
+        event: Option<Event>,
+        #[arg(long, default_value_t = 0)]
+        uploaded: usize,
+        #[arg(long, default_value_t = 0)]
+        downloaded: usize,
+        /// Bytes left to download; the torrent's length by default.
+        #[arg(long)]
+        left: Option<usize>,
+    },
+    /// Run a scripted exchange against a peer and print a compliance report.
+    Conformance {
+        torrent: PathBuf,
+        peer: String,
+    },
+    /// Check downloaded data against the torrent's piece hashes.
+    Verify {
+        torrent: PathBuf,
+        /// The downloaded file, or the directory holding a multi-file torrent's files.
+        path: PathBuf,
+        /// Check the BEP 52 merkle trees instead of the v1 piece hashes of a hybrid torrent.
+        #[arg(long)]
+        v2: bool,
+    },
+    /// Hash data already on disk, e.g. fetched by another client, and write a resume file
+    /// recording the pieces it has, which a download into the same file then starts from.
+    Recheck {
+        torrent: PathBuf,
+        /// The downloaded file, or the directory holding a multi-file torrent's files.
+        path: PathBuf,
+        /// Directory to write the resume file to, instead of the default data directory.
+        #[arg(long, value_name = "DIR")]
+        resume_dir: Option<PathBuf>,
+    },
+    /// Build a .torrent file from a local file or directory.
+    CreateTorrent {
+        path: PathBuf,
+        #[arg(short)]
+        output: PathBuf,
+        #[arg(long)]
+        announce: String,
+        /// A tier of comma-separated tracker URLs; repeat for more tiers.
+        #[arg(long = "tier")]
+        tiers: Vec<String>,
+        #[arg(long)]
+        comment: Option<String>,
+        #[arg(long)]
+        private: bool,
+        #[arg(long, default_value_t = 1 << 18)]
+        piece_length: usize,
+        /// Write v1, v2 (BEP 52) or hybrid metainfo.
+        #[arg(long, value_enum, default_value_t = MetaVersion::V1)]
+        meta_version: MetaVersion,
+    },
+    DownloadPiece {
+        #[arg(short)]
+        output: PathBuf,
+        torrent: PathBuf,
+        piece: usize,
+        /// Download from this peer instead of asking the tracker; repeat for more peers.
+        #[arg(long = "peer")]
+        peers: Vec<SocketAddr>,
+    },
+    Download {
+        #[arg(short)]
+        output: PathBuf,
+        torrent: PathBuf,
+        /// Download from this peer instead of asking the tracker; repeat for more peers.
+        #[arg(long = "peer")]
+        peers: Vec<SocketAddr>,
+        /// Only download these comma-separated files, by their path within the torrent.
+        #[arg(long, value_delimiter = ',')]
+        files: Vec<String>,
+    },
+    /// Download a torrent in order while serving it over HTTP at `--addr`; range requests
+    /// wait for the pieces they cover, so a player can start before the download finishes.
+    Stream {
+        #[arg(short)]
+        output: PathBuf,
+        torrent: PathBuf,
+        /// Local address to serve the file on.
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        addr: SocketAddr,
+        /// Download from this peer instead of asking the tracker; repeat for more peers.
+        #[arg(long = "peer")]
+        peers: Vec<SocketAddr>,
+    },
+    MagnetParse {
+        link: String,
+    },
+    MagnetHandshake {
+        link: String,
+    },
+    MagnetInfo {
+        link: String,
+    },
+    MagnetDownloadPiece {
+        #[arg(short)]
+        output: PathBuf,
+        link: String,
+        piece: usize,
+    },
+    MagnetDownload {
+        #[arg(short)]
+        output: PathBuf,
+        link: String,
+    },
+    /// Fetch the metadata of every magnet link in a file, one per line, without downloading
+    /// their contents: writes a `.torrent` file for each and prints a JSON summary line of its
+    /// name, size and files.
+    ResolveMagnets {
+        /// Directory to write the `.torrent` files to, named by info hash.
+        #[arg(short, default_value = ".")]
+        output: PathBuf,
+        links: PathBuf,
+        /// Links resolved at once.
+        #[arg(long, default_value_t = 8)]
+        jobs: usize,
+    },
+    /// Work with the transfer statistics kept across runs.
+    Stats {
+        #[command(subcommand)]
+        command: StatsCommand,
+    },
+    /// Inspect the settings layered from flags, `BITTORRENT_*` variables and the config file.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Run, or control, a daemon downloading several torrents at once.
+    Daemon {
+        /// Control socket: the path of a Unix socket, or an `address:port`. Defaults to
+        /// $XDG_RUNTIME_DIR/bittorrent-starter-rust.sock.
+        #[arg(long)]
+        control: Option<ControlAddr>,
+        /// Require this token of every control request, or send it with ours. Needed to take
+        /// control connections on a TCP port.
+        #[arg(long, value_name = "TOKEN")]
+        control_token: Option<String>,
+        #[command(subcommand)]
+        command: DaemonCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum StatsCommand {
+    /// Dump per-torrent transfer totals, ratios and uptime.
+    Export {
+        #[arg(long, value_enum, default_value_t = ExportFormat::Csv)]
+        format: ExportFormat,
+        /// Only count transfers finished since this Unix time, or within this age (e.g. 30d).
+        #[arg(long, value_parser = stats::parse_since, default_value = "0")]
+        since: u64,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DaemonCommand {
+    /// Run the daemon until interrupted.
+    Run {
+        /// Also serve an HTTP management API on this address; needs a build with the
+        /// `http-api` feature.
+        #[arg(long, value_name = "ADDR")]
+        http: Option<SocketAddr>,
+        /// Require this bearer token of every HTTP API request. Needed to serve the API on
+        /// anything but a loopback address.
+        #[arg(long, value_name = "TOKEN", requires = "http")]
+        http_token: Option<String>,
+        /// Only read `.torrent` files and write downloads added over the control socket or
+        /// HTTP below this directory; defaults to the working directory.
+        #[arg(long, value_name = "DIR")]
+        root: Option<PathBuf>,
+        /// Keep the torrents here across restarts instead of in
+        /// ~/.local/share/bittorrent-starter-rust/session.
+        #[arg(long, value_name = "DIR")]
+        session_dir: Option<PathBuf>,
+        /// Don't keep the torrents across restarts.
+        #[arg(long, conflicts_with = "session_dir")]
+        ephemeral: bool,
+    },
+    /// Start downloading a `.torrent` file, URL or magnet link into `output`.
+    Add {
+        #[arg(short)]
+        output: PathBuf,
+        source: String,
+        /// A label to list the torrent under.
+        #[arg(long)]
+        category: Option<String>,
+        /// Add it without starting the download.
+        #[arg(long)]
+        paused: bool,
+        /// Also announce to this tracker; repeat for more.
+        #[arg(long = "tracker", value_name = "URL")]
+        trackers: Vec<String>,
+        /// Take the pieces already at `output` to be intact without checking them, and only
+        /// download the rest.
+        #[arg(long)]
+        skip_verify: bool,
+    },
+    /// Stop driving a torrent's download, keeping its connections.
+    Pause { id: String },
+    /// Carry on with a paused torrent, or start a failed one over.
+    Resume { id: String },
+    /// Stop a torrent and forget it. Whatever it wrote stays on disk.
+    Remove { id: String },
+    /// List every torrent with its state and progress, as JSON.
+    Status,
+    /// Show how the daemon's maintenance jobs have been doing, as JSON.
+    Maintenance {
+        /// Run this job now instead of when it next comes due.
+        #[arg(long, value_enum)]
+        run: Option<Job>,
+    },
+    /// Show the torrents and peers moving the most data, refreshed in place.
+    Top {
+        /// Seconds between refreshes.
+        #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u64).range(1..))]
+        interval: u64,
+        /// Print one snapshot and exit, e.g. from cron.
+        #[arg(long)]
+        once: bool,
+        /// Torrents and peers to show.
+        #[arg(long, default_value_t = 10)]
+        limit: usize,
+    },
+    /// Show whether the alternative speed limits are in force, or switch them on or off until
+    /// `--alt-speed-window` next does.
+    AltSpeed { set: Option<Switch> },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Switch {
+    On,
+    Off,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Print the effective value of every global flag and where it came from, as TOML.
+    Show,
+}
@@ -0,0 +1,114 @@
+use crate::download::BLOCK_MAX;
+use crate::listener::AcceptLimits;
+use crate::metadata_cache::MetadataCache;
+use crate::mse::Encryption;
+use crate::net::NetConfig;
+use crate::peer::ConnectionLimit;
+use crate::peer_id::PeerId;
+use crate::resume::ResumeStore;
+use crate::retry::RetryPolicy;
+use crate::sink::Fsync;
+use crate::speed::SpeedLimits;
+use crate::stats::StatsStore;
+use crate::timeout::Timeouts;
+use crate::transport::{Transport, TransportPolicy};
+use crate::utp::Endpoint;
+use crate::wire::Capabilities;
+use crate::wire_trace::TraceFile;
+
+/// Settings shared by every tracker and peer connection of a single torrent.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub peer_id: PeerId,
+    pub timeouts: Timeouts,
+    pub retry: RetryPolicy,
+    pub net: NetConfig,
+    /// Port announced to trackers for peers to connect to us on.
+    pub port: u16,
+    /// Our uTP socket on that port, while listening; holepunched connections go through it.
+    pub utp: Option<Endpoint>,
+    /// How many times a piece may fail hash verification before the download is abandoned.
+    pub max_hash_failures: u32,
+    /// Largest tracker response body, in bytes, we are willing to buffer.
+    pub max_tracker_response: usize,
+    /// How many peers to ask trackers for.
+    pub numwant: u32,
+    /// Shared by every peer connection in the process, whichever torrent it is for.
+    pub connections: ConnectionLimit,
+    /// Shared by every outbound connection attempt until it connects or fails.
+    pub half_open: ConnectionLimit,
+    /// Shared by the TCP and uTP listeners.
+    pub accept: AcceptLimits,
+    /// Most peers a single download fetches pieces from at once.
+    pub max_connections_per_torrent: usize,
+    /// Shared by every peer we are uploading to, inbound or outbound, whichever torrent it is
+    /// for; the rest are kept choked.
+    pub upload_slots: ConnectionLimit,
+    /// Identifies us to trackers across IP address changes; random per process by default.
+    pub tracker_key: String,
+    /// Extensions we advertise in our handshake.
+    pub capabilities: Capabilities,
+    /// Drop peers that send messages for extensions that weren't negotiated.
+    pub strict: bool,
+    /// Bytes asked for in each block request, at most 16 KiB.
+    pub block_size: usize,
+    pub encryption: Encryption,
+    pub transport: Transport,
+    /// Overrides `encryption`, `transport` and the proxy for private or public torrents.
+    pub transport_policy: TransportPolicy,
+    pub fsync: Fsync,
+    /// Write downloads through a memory map instead of file writes.
+    pub mmap: bool,
+    /// Shared by every peer connection in the process, whichever torrent it is for.
+    pub speed: SpeedLimits,
+    /// Download pieces in order and write them out as a growing prefix, for streaming.
+    pub sequential: bool,
+    /// Take the pieces the output already holds to be intact, without hashing them.
+    pub skip_verify: bool,
+    /// Where fetched torrent metadata is kept, if anywhere.
+    pub metadata_cache: Option<MetadataCache>,
+    /// Where completed transfers are recorded, if anywhere.
+    pub stats: Option<StatsStore>,
+    /// Where `recheck` records the pieces found on disk, if anywhere, so a download into the
+    /// same place needn't hash them again.
+    pub resume: Option<ResumeStore>,
+    /// Where every peer message is recorded, if anywhere.
+    pub wire_trace: Option<TraceFile>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            peer_id: PeerId::generate(),
+            timeouts: Timeouts::default(),
+            retry: RetryPolicy::default(),
+            net: NetConfig::default(),
+            port: 6881,
+            utp: None,
+            max_hash_failures: 3,
+            max_tracker_response: 1 << 20,
+            numwant: 50,
+            connections: ConnectionLimit::new(200),
+            half_open: ConnectionLimit::new(20),
+            accept: AcceptLimits::default(),
+            max_connections_per_torrent: 8,
+            upload_slots: ConnectionLimit::new(8),
+            tracker_key: format!("{:08x}", rand::random::<u32>()),
+            capabilities: Capabilities::default(),
+            strict: false,
+            block_size: BLOCK_MAX,
+            encryption: Encryption::default(),
+            transport: Transport::default(),
+            transport_policy: TransportPolicy::default(),
+            fsync: Fsync::default(),
+            mmap: false,
+            speed: SpeedLimits::default(),
+            sequential: false,
+            skip_verify: false,
+            metadata_cache: MetadataCache::default_dir().map(MetadataCache::new),
+            stats: StatsStore::default_path().map(StatsStore::new),
+            resume: ResumeStore::default_dir().map(ResumeStore::new),
+            wire_trace: None,
+        }
+    }
+}
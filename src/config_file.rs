@@ -0,0 +1,221 @@
+//! Defaults for the global flags, layered the way clap's own `env` support would: a flag on
+//! the command line wins, then a `BITTORRENT_<FLAG>` environment variable, then the config
+//! file, then the built-in default.
+//!
+//! Config file keys are flag names, e.g. `numwant = 30` or `disable = ["dht"]`. Values from
+//! the environment and the file are handed to clap as extra flags, so they go through the
+//! same parsers and checks as the command line.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, CommandFactory, FromArgMatches};
+
+use crate::cli::Args;
+
+const ENV_PREFIX: &str = "BITTORRENT_";
+
+/// Where a global flag's effective value came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    CommandLine,
+    Env(String),
+    File,
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::CommandLine => f.write_str("command line"),
+            Source::Env(name) => write!(f, "${name}"),
+            Source::File => f.write_str("config file"),
+            Source::Default => f.write_str("default"),
+        }
+    }
+}
+
+/// How the parsed arguments were put together, for `config show`.
+#[derive(Debug)]
+pub struct Layers {
+    matches: ArgMatches,
+    /// Keyed by flag name.
+    sources: BTreeMap<String, Source>,
+    /// The config file read, if any.
+    path: Option<PathBuf>,
+}
+
+/// `$XDG_CONFIG_HOME/bittorrent-starter-rust/config.toml`, falling back to `~/.config`.
+pub fn default_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("bittorrent-starter-rust").join("config.toml"))
+}
+
+/// Parses the process's arguments, filling in the global flags that weren't given from the
+/// environment and then from the config file. Exits on usage errors, like `Args::parse`.
+pub fn parse() -> anyhow::Result<(Args, Layers)> {
+    let mut argv: Vec<OsString> = std::env::args_os().collect();
+    let command = Args::command();
+    let matches = command
+        .clone()
+        .try_get_matches_from(&argv)
+        .unwrap_or_else(|e| e.exit());
+
+    let explicit = matches
+        .get_one::<PathBuf>("config_file")
+        .cloned()
+        .or_else(|| std::env::var_os(format!("{ENV_PREFIX}CONFIG")).map(PathBuf::from));
+    let path = explicit.clone().or_else(default_path);
+    let file = match &path {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(text) => Some(
+                toml::from_str::<toml::Table>(&text)
+                    .with_context(|| format!("parse {}", path.display()))?,
+            ),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && explicit.is_none() => None,
+            Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+        },
+        None => None,
+    };
+    let mut file = file.unwrap_or_default();
+
+    let mut extra = Vec::new();
+    let mut sources = BTreeMap::new();
+    for arg in layered_args(&command) {
+        let long = arg.get_long().expect("layered args have long names");
+        let env = format!("{ENV_PREFIX}{}", long.replace('-', "_").to_uppercase());
+        let from_file = file.remove(long);
+        let given = matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine);
+        let source = if given {
+            Source::CommandLine
+        } else if let Ok(value) = std::env::var(&env) {
+            let value = env_value(arg, value);
+            extra.extend(flag_args(arg, long, &value).with_context(|| format!("read ${env}"))?);
+            Source::Env(env)
+        } else if let Some(value) = from_file {
+            extra.extend(flag_args(arg, long, &value).context("read config file")?);
+            Source::File
+        } else {
+            Source::Default
+        };
+        sources.insert(long.to_owned(), source);
+    }
+    if let Some(key) = file.keys().next() {
+        anyhow::bail!("unknown setting `{key}` in config file");
+    }
+
+    let matches = if extra.is_empty() {
+        matches
+    } else {
+        // global flags may come before the subcommand, where no `--` can have ended them
+        argv.splice(1..1, extra);
+        command
+            .try_get_matches_from(&argv)
+            .unwrap_or_else(|e| e.exit())
+    };
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let path = path.filter(|path| path.exists());
+    Ok((
+        args,
+        Layers {
+            matches,
+            sources,
+            path,
+        },
+    ))
+}
+
+/// The effective value of every global flag, as a config file would spell it and with where
+/// it came from.
+pub fn show(layers: &Layers) -> String {
+    let mut out = match &layers.path {
+        Some(path) => format!("# read from {}\n", path.display()),
+        None => "# no config file\n".to_owned(),
+    };
+    for arg in layered_args(&Args::command()) {
+        let long = arg.get_long().expect("layered args have long names");
+        let id = arg.get_id().as_str();
+        let value = match arg.get_action() {
+            ArgAction::SetTrue => Some(toml::Value::Boolean(layers.matches.get_flag(id))),
+            ArgAction::Count => Some(toml::Value::Integer(layers.matches.get_count(id).into())),
+            action => layers.matches.get_raw(id).map(|values| {
+                let mut values = values.map(|value| scalar_value(&value.to_string_lossy()));
+                if matches!(action, ArgAction::Append) {
+                    toml::Value::Array(values.collect())
+                } else {
+                    values.next().expect("flags that are set have a value")
+                }
+            }),
+        };
+        let source = &layers.sources[long];
+        match value {
+            Some(value) => out += &format!("{long} = {value}  # {source}\n"),
+            None => out += &format!("# {long} is unset\n"),
+        }
+    }
+    out
+}
+
+/// Global flags that can be given defaults; `--config` itself can't.
+fn layered_args(command: &clap::Command) -> impl Iterator<Item = &Arg> {
+    command.get_arguments().filter(|arg| {
+        arg.is_global_set() && arg.get_long().is_some() && arg.get_id().as_str() != "config_file"
+    })
+}
+
+/// An environment variable's value as the config file would hold it.
+fn env_value(arg: &Arg, value: String) -> toml::Value {
+    match arg.get_action() {
+        ArgAction::SetTrue => toml::Value::Boolean(matches!(value.as_str(), "1" | "true")),
+        ArgAction::Count => value
+            .parse()
+            .map_or(toml::Value::String(value), toml::Value::Integer),
+        _ => toml::Value::String(value),
+    }
+}
+
+/// Reads numbers back as numbers, so `show` prints `numwant = 50` rather than `"50"`.
+fn scalar_value(value: &str) -> toml::Value {
+    value.parse().map_or_else(
+        |_| toml::Value::String(value.to_owned()),
+        toml::Value::Integer,
+    )
+}
+
+/// The command line flags that set `arg` to `value`.
+fn flag_args(arg: &Arg, long: &str, value: &toml::Value) -> anyhow::Result<Vec<OsString>> {
+    let flag = format!("--{long}");
+    Ok(match (arg.get_action(), value) {
+        (ArgAction::SetTrue, toml::Value::Boolean(set)) => {
+            set.then(|| flag.into()).into_iter().collect()
+        }
+        (ArgAction::Count, toml::Value::Integer(count)) => {
+            let count = usize::try_from(*count).context("count can't be negative")?;
+            vec![flag.into(); count]
+        }
+        (ArgAction::Append, toml::Value::Array(values)) => values
+            .iter()
+            .map(|value| Ok(format!("{flag}={}", scalar(long, value)?).into()))
+            .collect::<anyhow::Result<_>>()?,
+        (ArgAction::Set | ArgAction::Append, value) => {
+            vec![format!("{flag}={}", scalar(long, value)?).into()]
+        }
+        (_, value) => anyhow::bail!("`{long}` can't be set to {value}"),
+    })
+}
+
+fn scalar(long: &str, value: &toml::Value) -> anyhow::Result<String> {
+    Ok(match value {
+        toml::Value::String(s) => s.clone(),
+        toml::Value::Integer(n) => n.to_string(),
+        toml::Value::Float(x) => x.to_string(),
+        toml::Value::Boolean(b) => b.to_string(),
+        _ => anyhow::bail!("`{long}` can't be set to {value}"),
+    })
+}
@@ -0,0 +1,251 @@
+use std::fmt;
+use std::net::SocketAddr;
+
+use anyhow::Context;
+use futures_util::SinkExt;
+use tokio_util::codec::Framed;
+
+use bittorrent_starter_rust::Torrent;
+
+use crate::bitfield::Bitfield;
+use crate::config::ClientConfig;
+use crate::download::{self, PeerState};
+use crate::geometry::PieceGeometry;
+use crate::message::{Message, MessageFramer, MessageTag};
+use crate::mse::PeerStream;
+use crate::peer;
+use crate::wire::{Piece, Request};
+
+/// Extended message id of the extension handshake (BEP 10).
+const EXTENDED_HANDSHAKE: u8 = 0;
+
+pub enum Outcome {
+    Pass(String),
+    Fail(String),
+    Skip(String),
+}
+
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (status, detail) = match &self.outcome {
+            Outcome::Pass(detail) => ("PASS", detail),
+            Outcome::Fail(detail) => ("FAIL", detail),
+            Outcome::Skip(detail) => ("SKIP", detail),
+        };
+        write!(f, "{status} {:<20} {detail}", self.name)
+    }
+}
+
+fn outcome<T>(result: anyhow::Result<T>, pass: impl FnOnce(&T) -> String) -> Outcome {
+    match &result {
+        Ok(v) => Outcome::Pass(pass(v)),
+        Err(e) => Outcome::Fail(format!("{e:#}")),
+    }
+}
+
+/// Runs a scripted exchange against a single peer and reports how each step went. Steps that
+/// depend on a failed step are skipped rather than failed.
+pub async fn run(t: &Torrent, addr: SocketAddr, config: &ClientConfig) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let mut config = config.clone();
+    config.capabilities.extensions = true;
+    config.capabilities.fast = true;
+
+    let info_hash = t.info_hash();
+    let connected =
+        peer::connect(addr, info_hash, &config)
+            .await
+            .and_then(|(stream, handshake)| {
+                anyhow::ensure!(
+                    handshake.info_hash == info_hash,
+                    "peer answered with info hash {}",
+                    hex::encode(handshake.info_hash)
+                );
+                Ok((stream, handshake))
+            });
+    checks.push(Check {
+        name: "handshake",
+        outcome: outcome(connected.as_ref().map(|_| ()).map_err(clone_err), |_| {
+            String::new()
+        }),
+    });
+    let Ok((stream, handshake)) = connected else {
+        return checks;
+    };
+    let capabilities = handshake.capabilities();
+    checks.push(Check {
+        name: "capabilities",
+        outcome: Outcome::Pass(format!("{capabilities:?}")),
+    });
+
+    let negotiated = config.capabilities.intersect(capabilities);
+    let mut state = PeerState::new(negotiated, true);
+    let mut peer = Framed::new(stream, MessageFramer::new());
+    let npieces = t.info.pieces.0.len();
+    let bitfield = availability(&mut peer, &mut state, npieces, &config).await;
+    checks.push(Check {
+        name: "bitfield",
+        outcome: outcome(bitfield.as_ref().map_err(clone_err), |(b, how)| {
+            format!("{}/{} pieces ({how})", b.count(), npieces)
+        }),
+    });
+
+    if negotiated.extensions {
+        let result = extension_handshake(&mut peer, &state, &config).await;
+        checks.push(Check {
+            name: "extension handshake",
+            outcome: outcome(result, |keys| format!("keys: {keys}")),
+        });
+    } else {
+        checks.push(Check {
+            name: "extension handshake",
+            outcome: Outcome::Skip("peer does not support extensions".to_string()),
+        });
+    }
+
+    let Some(index) = bitfield.ok().and_then(|(b, _)| b.pieces().next()) else {
+        checks.push(Check {
+            name: "request/piece",
+            outcome: Outcome::Skip("peer has no pieces".to_string()),
+        });
+        return checks;
+    };
+    let block_len = PieceGeometry::of(t).block_len(index, 0);
+    let request = Request::new(index as u32, 0, block_len as u32);
+    let result = request_block(&mut peer, state, request, &config).await;
+    checks.push(Check {
+        name: "request/piece",
+        outcome: outcome(result, |_| {
+            format!("piece {index}, {} bytes", request.length)
+        }),
+    });
+
+    checks
+}
+
+fn clone_err(e: &anyhow::Error) -> anyhow::Error {
+    anyhow::anyhow!("{e:#}")
+}
+
+type PeerFrames = Framed<PeerStream, MessageFramer>;
+
+/// Reads the peer's pieces from its first message, and how it announced them. A peer with
+/// nothing to offer may skip the announcement, so any other first message counts as an
+/// empty bitfield.
+async fn availability(
+    peer: &mut PeerFrames,
+    state: &mut PeerState,
+    npieces: usize,
+    config: &ClientConfig,
+) -> anyhow::Result<(Bitfield, &'static str)> {
+    let msg = download::recv(peer, state, &config.timeouts).await?;
+    if download::is_announcement(&msg, state) {
+        let how = match msg.tag {
+            MessageTag::HaveAll => "have all",
+            MessageTag::HaveNone => "have none",
+            _ => "bitfield",
+        };
+        return Ok((download::parse_availability(msg, state, npieces)?, how));
+    }
+    download::apply(&msg, state)?;
+    let mut bitfield = Bitfield::new(npieces);
+    for piece in state.announced.drain(..) {
+        let piece = piece as usize;
+        anyhow::ensure!(
+            piece < npieces,
+            "peer announced piece {piece} of a torrent with {npieces}"
+        );
+        bitfield.set_piece(piece);
+    }
+    Ok((bitfield, "no bitfield"))
+}
+
+async fn extension_handshake(
+    peer: &mut PeerFrames,
+    state: &PeerState,
+    config: &ClientConfig,
+) -> anyhow::Result<String> {
+    let mut payload = vec![EXTENDED_HANDSHAKE];
+    payload.extend_from_slice(b"d1:md11:ut_metadatai1eee");
+    peer.send(Message {
+        tag: MessageTag::Extended,
+        payload: payload.into(),
+    })
+    .await
+    .context("send extension handshake")?;
+
+    loop {
+        let msg = download::recv(peer, state, &config.timeouts).await?;
+        if msg.tag != MessageTag::Extended {
+            continue;
+        }
+        let (&id, dict) = msg
+            .payload
+            .split_first()
+            .context("empty extended message")?;
+        anyhow::ensure!(
+            id == EXTENDED_HANDSHAKE,
+            "expected extension handshake, got extended message {id}"
+        );
+        let dict: serde_bencode::value::Value =
+            serde_bencode::from_bytes(dict).context("extension handshake is not bencode")?;
+        let serde_bencode::value::Value::Dict(dict) = dict else {
+            anyhow::bail!("extension handshake is not a dictionary");
+        };
+        let keys: Vec<_> = dict.keys().map(|k| String::from_utf8_lossy(k)).collect();
+        return Ok(keys.join(","));
+    }
+}
+
+async fn request_block(
+    peer: &mut PeerFrames,
+    mut state: PeerState,
+    request: Request,
+    config: &ClientConfig,
+) -> anyhow::Result<()> {
+    peer.send(Message::empty(MessageTag::Interested))
+        .await
+        .context("send interested message")?;
+    while state.choked {
+        let msg = download::recv(peer, &state, &config.timeouts).await?;
+        if msg.tag == MessageTag::Unchoke {
+            state.choked = false;
+        }
+    }
+
+    peer.send(Message {
+        tag: MessageTag::Request,
+        payload: request.to_bytes().to_vec().into(),
+    })
+    .await
+    .context("send request message")?;
+    loop {
+        let msg = download::recv(peer, &state, &config.timeouts).await?;
+        match msg.tag {
+            MessageTag::Piece => {
+                let piece = Piece::from_bytes(&msg.payload).context("parse piece message")?;
+                anyhow::ensure!(
+                    piece.index == request.index && piece.begin == request.begin,
+                    "peer answered with piece {} offset {}",
+                    piece.index,
+                    piece.begin
+                );
+                anyhow::ensure!(
+                    piece.block.len() == request.length as usize,
+                    "peer sent {} bytes, requested {}",
+                    piece.block.len(),
+                    request.length
+                );
+                return Ok(());
+            }
+            MessageTag::RejectRequest => anyhow::bail!("peer rejected the request"),
+            MessageTag::Choke => anyhow::bail!("peer choked us mid-request"),
+            _ => {}
+        }
+    }
+}
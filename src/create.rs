@@ -0,0 +1,468 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::Serialize;
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+
+use crate::hash::{PieceHasher, TorrentVersion};
+use crate::v2::{self, FileHashes};
+
+/// Which metainfo formats a created torrent carries.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MetaVersion {
+    #[default]
+    V1,
+    V2,
+    /// Both, so v1-only and v2 clients can share the swarm.
+    Hybrid,
+}
+
+impl MetaVersion {
+    fn has_v1(self) -> bool {
+        self != MetaVersion::V2
+    }
+
+    fn has_v2(self) -> bool {
+        self != MetaVersion::V1
+    }
+}
+
+/// Assembles a torrent from local files, e.g.
+/// `TorrentBuilder::new().announce(url).add_file(path, ["dir", "a.txt"]).build()`.
+#[derive(Debug, Clone)]
+pub struct TorrentBuilder {
+    name: Option<String>,
+    announce: String,
+    /// Tiers of tracker URLs, written as `announce-list` when non-empty.
+    announce_list: Vec<Vec<String>>,
+    comment: Option<String>,
+    private: bool,
+    piece_length: usize,
+    meta_version: MetaVersion,
+    /// Each local file with its path inside the torrent.
+    files: Vec<(PathBuf, Vec<String>)>,
+    /// Whether the torrent is the one file added, rather than a directory of files.
+    single: bool,
+}
+
+impl Default for TorrentBuilder {
+    fn default() -> Self {
+        Self {
+            name: None,
+            announce: String::new(),
+            announce_list: Vec::new(),
+            comment: None,
+            private: false,
+            piece_length: 1 << 18,
+            meta_version: MetaVersion::default(),
+            files: Vec::new(),
+            single: false,
+        }
+    }
+}
+
+impl TorrentBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A builder for the file or directory at `path`, named after it. Directories become
+    /// multi-file torrents with files in sorted path order.
+    pub fn from_path(path: &Path) -> anyhow::Result<Self> {
+        let name = path
+            .file_name()
+            .context("path has no file name")?
+            .to_string_lossy()
+            .into_owned();
+        if path.is_file() {
+            return Ok(Self {
+                files: vec![(path.to_path_buf(), Vec::new())],
+                single: true,
+                ..Self::new().name(name)
+            });
+        }
+        let mut files = Vec::new();
+        collect_files(path, &mut Vec::new(), &mut files)
+            .with_context(|| format!("list files in {}", path.display()))?;
+        anyhow::ensure!(!files.is_empty(), "{} contains no files", path.display());
+        Ok(files
+            .into_iter()
+            .fold(Self::new().name(name), |builder, (local, path)| {
+                builder.add_file(local, path)
+            }))
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn announce(mut self, url: impl Into<String>) -> Self {
+        self.announce = url.into();
+        self
+    }
+
+    /// Adds a tier of tracker URLs to `announce-list`.
+    pub fn tier(mut self, urls: Vec<String>) -> Self {
+        self.announce_list.push(urls);
+        self
+    }
+
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    pub fn piece_length(mut self, piece_length: usize) -> Self {
+        self.piece_length = piece_length;
+        self
+    }
+
+    pub fn meta_version(mut self, meta_version: MetaVersion) -> Self {
+        self.meta_version = meta_version;
+        self
+    }
+
+    /// Adds the local file at `local` to a multi-file torrent, under `path`.
+    pub fn add_file<S: Into<String>>(
+        mut self,
+        local: impl Into<PathBuf>,
+        path: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.files
+            .push((local.into(), path.into_iter().map(Into::into).collect()));
+        self.single = false;
+        self
+    }
+
+    /// Hashes the files and assembles the metainfo. In a multi-file hybrid, each file is
+    /// followed by padding to a piece boundary so v1 and v2 pieces line up.
+    pub fn build(&self) -> anyhow::Result<Created> {
+        anyhow::ensure!(
+            self.piece_length.is_power_of_two() && self.piece_length >= v2::BLOCK_LEN,
+            "piece length must be a power of two of at least 16 KiB"
+        );
+        let name = self.name.clone().context("torrent has no name")?;
+        anyhow::ensure!(!self.files.is_empty(), "torrent has no files");
+        let files = &self.files;
+        let single = self.single;
+
+        let version = self.meta_version;
+        let mut hasher = version
+            .has_v1()
+            .then(|| PieceHashes::new(TorrentVersion::V1, self.piece_length));
+        let mut entries = Vec::with_capacity(files.len());
+        let mut file_tree = HashMap::new();
+        let mut piece_layers = BTreeMap::new();
+        for (i, (file, components)) in files.iter().enumerate() {
+            let mut blocks = version.has_v2().then(BlockHashes::default);
+            let length = hash_file(file, hasher.as_mut(), blocks.as_mut())
+                .with_context(|| format!("hash {}", file.display()))?;
+            entries.push(FileEntry {
+                attr: None,
+                length,
+                path: components.clone(),
+            });
+            if let Some(blocks) = blocks {
+                let mut fields = HashMap::from([(b"length".to_vec(), Value::Int(length as i64))]);
+                if length > 0 {
+                    let hashes = FileHashes::from_blocks(&blocks.finish(), self.piece_length);
+                    fields.insert(b"pieces root".to_vec(), Value::Bytes(hashes.root.to_vec()));
+                    if !hashes.piece_layer.is_empty() {
+                        piece_layers.insert(
+                            ByteBuf::from(hashes.root.to_vec()),
+                            ByteBuf::from(hashes.piece_layer.concat()),
+                        );
+                    }
+                }
+                let tree_path = if single {
+                    std::slice::from_ref(&name)
+                } else {
+                    components.as_slice()
+                };
+                insert_file(&mut file_tree, tree_path, fields);
+            }
+            let last = i + 1 == files.len();
+            if let Some(hasher) = hasher
+                .as_mut()
+                .filter(|_| version == MetaVersion::Hybrid && !last)
+            {
+                let pad = hasher.pad_to_piece();
+                if pad > 0 {
+                    entries.push(FileEntry {
+                        attr: Some("p".to_string()),
+                        length: pad,
+                        path: vec![".pad".to_string(), pad.to_string()],
+                    });
+                }
+            }
+        }
+
+        let info = Info {
+            file_tree: version.has_v2().then_some(Value::Dict(file_tree)),
+            length: (version.has_v1() && single).then(|| entries[0].length),
+            files: (version.has_v1() && !single).then_some(entries),
+            meta_version: version.has_v2().then_some(2),
+            name,
+            piece_length: self.piece_length,
+            pieces: hasher.map(PieceHashes::finish),
+            private: self.private.then_some(1),
+        };
+        let creation_date = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Ok(Created {
+            metainfo: MetaInfo {
+                announce: self.announce.clone(),
+                announce_list: self.announce_list.clone(),
+                comment: self.comment.clone(),
+                created_by: concat!("bittorrent-starter-rust/", env!("CARGO_PKG_VERSION"))
+                    .to_string(),
+                creation_date,
+                info,
+                piece_layers,
+            },
+        })
+    }
+}
+
+/// A created torrent. Its info hashes are worked out from the info dictionary when asked
+/// for, so they always match what [`Created::to_bytes`] writes.
+pub struct Created {
+    metainfo: MetaInfo,
+}
+
+impl Created {
+    /// The bencoded metainfo, ready to be written out as a .torrent file.
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        serde_bencode::to_bytes(&self.metainfo).context("encode torrent")
+    }
+
+    /// The v1 info hash, if the torrent carries v1 metainfo.
+    pub fn info_hash(&self) -> anyhow::Result<Option<[u8; 20]>> {
+        let info = self.info_bytes()?;
+        Ok(self
+            .metainfo
+            .info
+            .pieces
+            .is_some()
+            .then(|| Sha1::digest(&info).into()))
+    }
+
+    /// The v2 info hash, if the torrent carries v2 metainfo.
+    pub fn info_hash_v2(&self) -> anyhow::Result<Option<v2::Hash>> {
+        let info = self.info_bytes()?;
+        Ok(self
+            .metainfo
+            .info
+            .file_tree
+            .is_some()
+            .then(|| v2::sha256(&info)))
+    }
+
+    fn info_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        serde_bencode::to_bytes(&self.metainfo.info).context("encode info dictionary")
+    }
+}
+
+// Fields are declared in key order; bencode dictionaries must be sorted.
+#[derive(Serialize)]
+struct MetaInfo {
+    announce: String,
+    #[serde(rename = "announce-list", skip_serializing_if = "Vec::is_empty")]
+    announce_list: Vec<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+    #[serde(rename = "created by")]
+    created_by: String,
+    #[serde(rename = "creation date")]
+    creation_date: u64,
+    info: Info,
+    #[serde(rename = "piece layers", skip_serializing_if = "BTreeMap::is_empty")]
+    piece_layers: BTreeMap<ByteBuf, ByteBuf>,
+}
+
+#[derive(Serialize)]
+struct Info {
+    #[serde(rename = "file tree", skip_serializing_if = "Option::is_none")]
+    file_tree: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<FileEntry>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    length: Option<u64>,
+    #[serde(rename = "meta version", skip_serializing_if = "Option::is_none")]
+    meta_version: Option<u8>,
+    name: String,
+    #[serde(rename = "piece length")]
+    piece_length: usize,
+    #[serde(with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+    pieces: Option<Vec<u8>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    private: Option<u8>,
+}
+
+#[derive(Serialize)]
+struct FileEntry {
+    /// `p` marks a BEP 47 padding file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attr: Option<String>,
+    length: u64,
+    path: Vec<String>,
+}
+
+/// Adds a file's `""` entry to a v2 file tree under its path components.
+fn insert_file(
+    tree: &mut HashMap<Vec<u8>, Value>,
+    path: &[String],
+    fields: HashMap<Vec<u8>, Value>,
+) {
+    let Some((dir, rest)) = path.split_first() else {
+        tree.insert(Vec::new(), Value::Dict(fields));
+        return;
+    };
+    let node = tree
+        .entry(dir.as_bytes().to_vec())
+        .or_insert_with(|| Value::Dict(HashMap::new()));
+    if let Value::Dict(children) = node {
+        insert_file(children, rest, fields);
+    }
+}
+
+/// Reads `path` once, feeding its bytes to whichever hashers are in use.
+fn hash_file(
+    path: &Path,
+    mut v1: Option<&mut PieceHashes>,
+    mut v2: Option<&mut BlockHashes>,
+) -> std::io::Result<u64> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0; 1 << 16];
+    let mut length = 0;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(length);
+        }
+        length += n as u64;
+        if let Some(v1) = v1.as_deref_mut() {
+            v1.update(&buf[..n]);
+        }
+        if let Some(v2) = v2.as_deref_mut() {
+            v2.update(&buf[..n]);
+        }
+    }
+}
+
+fn collect_files(
+    dir: &Path,
+    prefix: &mut Vec<String>,
+    out: &mut Vec<(PathBuf, Vec<String>)>,
+) -> std::io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        prefix.push(entry.file_name().to_string_lossy().into_owned());
+        if entry.file_type()?.is_dir() {
+            collect_files(&entry.path(), prefix, out)?;
+        } else {
+            out.push((entry.path(), prefix.clone()));
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+/// Hashes a stream of files as if they were concatenated, one digest per piece.
+struct PieceHashes {
+    version: TorrentVersion,
+    piece_length: usize,
+    hasher: Box<dyn PieceHasher>,
+    /// Bytes fed to `hasher` for the current piece.
+    filled: usize,
+    pieces: Vec<u8>,
+}
+
+impl PieceHashes {
+    fn new(version: TorrentVersion, piece_length: usize) -> Self {
+        Self {
+            version,
+            piece_length,
+            hasher: version.hasher(),
+            filled: 0,
+            pieces: Vec::new(),
+        }
+    }
+
+    fn update(&mut self, mut chunk: &[u8]) {
+        while !chunk.is_empty() {
+            let take = (self.piece_length - self.filled).min(chunk.len());
+            self.hasher.update(&chunk[..take]);
+            self.filled += take;
+            chunk = &chunk[take..];
+            if self.filled == self.piece_length {
+                self.finish_piece();
+            }
+        }
+    }
+
+    /// Feeds zeros up to the next piece boundary and returns how many.
+    fn pad_to_piece(&mut self) -> u64 {
+        if self.filled == 0 {
+            return 0;
+        }
+        let pad = self.piece_length - self.filled;
+        self.update(&vec![0; pad]);
+        pad as u64
+    }
+
+    fn finish_piece(&mut self) {
+        let hasher = std::mem::replace(&mut self.hasher, self.version.hasher());
+        self.pieces.extend_from_slice(&hasher.finish());
+        self.filled = 0;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.filled > 0 {
+            self.finish_piece();
+        }
+        self.pieces
+    }
+}
+
+/// SHA-256 of each 16 KiB block of one file, the leaves of its v2 merkle tree.
+#[derive(Default)]
+struct BlockHashes {
+    block: Vec<u8>,
+    hashes: Vec<v2::Hash>,
+}
+
+impl BlockHashes {
+    fn update(&mut self, mut chunk: &[u8]) {
+        while !chunk.is_empty() {
+            let take = (v2::BLOCK_LEN - self.block.len()).min(chunk.len());
+            self.block.extend_from_slice(&chunk[..take]);
+            chunk = &chunk[take..];
+            if self.block.len() == v2::BLOCK_LEN {
+                self.hashes.push(v2::sha256(&self.block));
+                self.block.clear();
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<v2::Hash> {
+        if !self.block.is_empty() {
+            self.hashes.push(v2::sha256(&self.block));
+        }
+        self.hashes
+    }
+}
@@ -0,0 +1,864 @@
+//! A long-running [`Session`] downloading several torrents at once, driven over a control
+//! socket: a Unix socket by default, or a TCP port. Clients send one JSON-RPC 2.0 request per
+//! line and get one response line back for each request carrying an id.
+//!
+//! Methods: `add {source, output, ...}` with a `.torrent` path, URL or magnet link and the
+//! rest of [`AddTorrentParams`], returning the torrent's id (its hex info hash); `pause`,
+//! `resume`, `remove`, `torrent` and `peers`, each taking `{id}`; `status`, listing every
+//! torrent with its transfer rates; `alt_speed`, optionally taking `{enabled}`, which
+//! switches the alternative speed limits and reports whether they are in force; and
+//! `maintenance`, optionally taking `{run}` with a [`Job`] to run now, which reports how every
+//! maintenance job has been doing. With the `http-api` feature the same operations can also
+//! be served over HTTP, see `http_api`.
+//!
+//! Whoever can send requests can make the daemon read and write files, so the `.torrent` files
+//! and outputs of added torrents must lie below its root directory. With a token set, every
+//! request must carry it as `"token"` beside `method`; a control port on TCP needs one even on
+//! loopback, since any web page the user opens can have their browser post to it. The Unix
+//! socket is only open to the user running the daemon.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::config::ClientConfig;
+use crate::listener::Torrents;
+use crate::maintenance::{Job, Maintenance};
+use crate::session::{AddTorrentParams, Session, TorrentHandle, TorrentSource, TorrentState};
+use crate::session_store::{SavedTorrent, SessionStore};
+use crate::stats;
+use crate::tracker::Announcer;
+
+/// Longest request line we read before hanging up on the client.
+const MAX_REQUEST: u64 = 64 << 10;
+/// Requests waiting for the daemon's main loop.
+const CALL_QUEUE: usize = 16;
+/// Cached metadata not used for this long is trimmed.
+const CACHE_UNUSED: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The request was understood but couldn't be carried out.
+pub const FAILED: i64 = -32000;
+pub const NO_SUCH_TORRENT: i64 = -32001;
+const UNAUTHORIZED: i64 = -32002;
+
+/// Where the daemon takes control connections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ControlAddr {
+    /// `$XDG_RUNTIME_DIR/bittorrent-starter-rust.sock`, falling back to `daemon.sock` beside
+    /// the saved session.
+    pub fn default_socket() -> Option<Self> {
+        let path = match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(dir) => PathBuf::from(dir).join("bittorrent-starter-rust.sock"),
+            None => SessionStore::default_dir()?.with_file_name("daemon.sock"),
+        };
+        Some(ControlAddr::Unix(path))
+    }
+}
+
+impl FromStr for ControlAddr {
+    type Err = Infallible;
+
+    /// An `address:port`, or else the path of a Unix socket.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(s.parse()
+            .map_or_else(|_| ControlAddr::Unix(PathBuf::from(s)), ControlAddr::Tcp))
+    }
+}
+
+impl fmt::Display for ControlAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlAddr::Tcp(addr) => write!(f, "{addr}"),
+            ControlAddr::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn failed(error: anyhow::Error) -> Self {
+        Self::new(FAILED, format!("{error:#}"))
+    }
+
+    pub fn invalid(message: impl Into<String>) -> Self {
+        Self::new(INVALID_PARAMS, message)
+    }
+
+    fn unknown(id: &str) -> Self {
+        Self::new(NO_SUCH_TORRENT, format!("no torrent {id}"))
+    }
+}
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    id: Option<Value>,
+    /// Checked against the daemon's token, if it has one.
+    token: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AddParams {
+    pub source: String,
+    #[serde(flatten)]
+    pub params: AddTorrentParams,
+}
+
+#[derive(Deserialize)]
+struct IdParams {
+    id: String,
+}
+
+#[derive(Deserialize, Default)]
+struct MaintenanceParams {
+    /// Runs this job now; left out, the jobs are only reported.
+    run: Option<Job>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct AltSpeedParams {
+    /// Switches the alternative limits on or off; left out, they are only reported.
+    pub enabled: Option<bool>,
+}
+
+/// What a client asks of the main loop.
+pub enum Request {
+    Add {
+        source: Box<TorrentSource>,
+        params: AddTorrentParams,
+    },
+    Pause(String),
+    Resume(String),
+    Remove(String),
+    /// One torrent's state and progress.
+    Torrent(String),
+    /// The peers one torrent is connected to.
+    Peers(String),
+    /// Every torrent's state and progress.
+    Status,
+    /// How the maintenance jobs are doing, after making this one due now.
+    Maintenance(Option<Job>),
+}
+
+impl Request {
+    /// Whether it may change what the store should hold.
+    fn changes_torrents(&self) -> bool {
+        !matches!(
+            self,
+            Request::Torrent(_) | Request::Peers(_) | Request::Status | Request::Maintenance(_)
+        )
+    }
+}
+
+/// Who may use the control socket and what they may touch.
+#[derive(Debug, Clone)]
+pub struct ControlOptions {
+    /// Required of every request. Needed unless the socket is a Unix socket.
+    pub token: Option<String>,
+    /// Torrents added over the control socket or HTTP may only be read from and written to
+    /// below here.
+    pub root: PathBuf,
+}
+
+/// Where and how to serve the HTTP API.
+#[derive(Debug, Clone)]
+pub struct HttpOptions {
+    pub addr: SocketAddr,
+    /// Required of every request as `Authorization: Bearer {token}`. Needed unless `addr` is
+    /// a loopback address.
+    pub token: Option<String>,
+}
+
+pub struct Call {
+    request: Request,
+    reply: oneshot::Sender<Result<Value, RpcError>>,
+}
+
+/// What the store knows about a torrent that the session doesn't.
+#[derive(Debug, Clone, Copy)]
+struct Carried {
+    added: u64,
+    /// Bytes downloaded in earlier runs.
+    downloaded: u64,
+    /// Bytes uploaded in earlier runs.
+    uploaded: u64,
+}
+
+struct Daemon<'a> {
+    session: Session<'a>,
+    store: Option<SessionStore>,
+    /// Keyed by id.
+    carried: HashMap<String, Carried>,
+    maintenance: Maintenance,
+}
+
+/// Runs the daemon until interrupted, taking requests on `control` and, if `http` is given,
+/// on an HTTP API there too, which needs the `http-api` feature. Torrents are kept in `store`,
+/// if any, and the ones saved there are picked back up first.
+pub async fn run(
+    control: &ControlAddr,
+    options: ControlOptions,
+    http: Option<HttpOptions>,
+    store: Option<SessionStore>,
+    config: &ClientConfig,
+    announcer: &Announcer,
+    inbound: Option<&Torrents>,
+) -> anyhow::Result<()> {
+    // anyone who can reach the port, a web page in the user's browser included, could make us
+    // read and write files
+    anyhow::ensure!(
+        options.token.is_some() || matches!(control, ControlAddr::Unix(_)),
+        "taking control connections on TCP port {control} needs --control-token"
+    );
+    let root = options
+        .root
+        .canonicalize()
+        .with_context(|| format!("resolve root {}", options.root.display()))?;
+    let options = Arc::new(ControlOptions { root, ..options });
+    let listener = ControlListener::bind(control)
+        .await
+        .with_context(|| format!("listen for control connections on {control}"))?;
+    tracing::info!(%control, root = %options.root.display(), "daemon taking control connections");
+    let (calls_tx, mut calls) = mpsc::channel(CALL_QUEUE);
+    let mut daemon = Daemon {
+        session: Session::new(config, announcer, inbound),
+        store,
+        carried: HashMap::new(),
+        maintenance: Maintenance::default(),
+    };
+    daemon.restore()?;
+    if let Some(http) = http {
+        // anyone who can reach the API can make us read and write files
+        anyhow::ensure!(
+            http.token.is_some() || http.addr.ip().is_loopback(),
+            "serving the HTTP API on {}, which isn't a loopback address, needs --http-token",
+            http.addr
+        );
+        #[cfg(feature = "http-api")]
+        {
+            let addr = http.addr;
+            let listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("serve HTTP API on {addr}"))?;
+            tracing::info!(%addr, "serving HTTP API");
+            let events = daemon.session.events().clone();
+            let root = options.root.clone();
+            crate::http_api::spawn(
+                listener,
+                http,
+                root,
+                calls_tx.clone(),
+                events,
+                config.clone(),
+            );
+        }
+        #[cfg(not(feature = "http-api"))]
+        {
+            anyhow::bail!(
+                "serving an HTTP API on {} needs a build with the http-api feature",
+                http.addr
+            );
+        }
+    }
+    loop {
+        tokio::select! {
+            accepted = listener.serve_next(&calls_tx, &options, config) => {
+                if let Err(e) = accepted {
+                    tracing::warn!(error = %e, "accepting control connection failed");
+                }
+            }
+            Some(call) = calls.recv() => {
+                let changes = call.request.changes_torrents();
+                let _ = call.reply.send(daemon.handle(call.request));
+                if changes {
+                    daemon.save_or_warn();
+                }
+            }
+            () = daemon.session.run() => {}
+            job = daemon.maintenance.next() => daemon.maintain(job, config),
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+    daemon.save_or_warn();
+    tracing::warn!("interrupted, announcing stop to trackers");
+    daemon.session.stop().await;
+    Ok(())
+}
+
+impl Daemon<'_> {
+    /// Adds back the torrents the store holds as they were added, paused if they were.
+    fn restore(&mut self) -> anyhow::Result<()> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        for (saved, source) in store.load()? {
+            if let Err(e) = self.session.add(source, saved.params) {
+                tracing::warn!(id = %saved.id, error = %e, "can't restore saved torrent");
+                continue;
+            }
+            let carried = Carried {
+                added: saved.added,
+                downloaded: saved.downloaded,
+                uploaded: saved.uploaded,
+            };
+            self.carried.insert(saved.id, carried);
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, request: Request) -> Result<Value, RpcError> {
+        let session = &mut self.session;
+        match request {
+            Request::Add { source, params } => {
+                let id = hex::encode(source.info_hash());
+                if let (Some(store), TorrentSource::Torrent { metainfo, .. }) =
+                    (&self.store, &*source)
+                {
+                    // without it the torrent couldn't be restored
+                    store
+                        .save_metainfo(&id, metainfo)
+                        .map_err(RpcError::failed)?;
+                }
+                let handle = session.add(*source, params).map_err(RpcError::failed)?;
+                let carried = Carried {
+                    added: stats::unix_time(SystemTime::now()),
+                    downloaded: 0,
+                    uploaded: 0,
+                };
+                self.carried.insert(id, carried);
+                Ok(json!({ "id": handle.id() }))
+            }
+            Request::Pause(id) => {
+                torrent(session, &id)?.pause();
+                Ok(Value::Null)
+            }
+            Request::Resume(id) => {
+                let handle = torrent(session, &id)?;
+                if let TorrentState::Failed(_) = handle.state() {
+                    // the restart starts its statistics over
+                    let snapshot = handle.stats().snapshot();
+                    if let Some(carried) = self.carried.get_mut(&id) {
+                        carried.downloaded += snapshot.downloaded;
+                        carried.uploaded += snapshot.uploaded;
+                    }
+                    session.restart(&id);
+                } else {
+                    torrent(session, &id)?.resume();
+                }
+                Ok(Value::Null)
+            }
+            Request::Remove(id) => {
+                session.remove(&id).ok_or_else(|| RpcError::unknown(&id))?;
+                self.carried.remove(&id);
+                Ok(Value::Null)
+            }
+            Request::Torrent(id) => Ok(torrent_status(torrent(session, &id)?)),
+            Request::Peers(id) => {
+                let peers = torrent(session, &id)?.stats().snapshot().peers;
+                let peers = peers.iter().map(|(addr, peer)| {
+                    json!({
+                        "addr": addr.to_string(),
+                        "downloaded": peer.downloaded,
+                        "down_rate": peer.down_rate,
+                        "up_rate": peer.up_rate,
+                        "idle": peer.last_active.elapsed().as_secs_f64(),
+                    })
+                });
+                Ok(Value::Array(peers.collect()))
+            }
+            Request::Status => Ok(Value::Array(
+                session.torrents().map(torrent_status).collect(),
+            )),
+            Request::Maintenance(run) => {
+                if let Some(job) = run {
+                    self.maintenance.trigger(job);
+                }
+                Ok(self.maintenance.report())
+            }
+        }
+    }
+
+    /// Runs `job`, in the background unless it is quick. Skipped while its last run is still
+    /// going.
+    fn maintain(&mut self, job: Job, config: &ClientConfig) {
+        let Some(run) = self.maintenance.start(job) else {
+            tracing::debug!(job = job.name(), "maintenance job still running, skipped");
+            return;
+        };
+        match job {
+            Job::Save => run.finish(&self.save()),
+            Job::TrimCache => {
+                let cache = config.metadata_cache.clone();
+                tokio::task::spawn_blocking(move || {
+                    let result = match cache {
+                        Some(cache) => cache.trim(CACHE_UNUSED).map(|removed| {
+                            tracing::debug!(removed, "metadata cache trimmed");
+                        }),
+                        None => Ok(()),
+                    };
+                    run.finish(&result.map_err(anyhow::Error::from));
+                });
+            }
+            Job::Scrub => self.session.scrub(run),
+        }
+    }
+
+    fn save_or_warn(&self) {
+        if let Err(e) = self.save() {
+            tracing::warn!(error = %format!("{e:#}"), "saving torrents failed");
+        }
+    }
+
+    /// Writes the unfinished torrents to the store; finished ones are dropped from it.
+    fn save(&self) -> anyhow::Result<()> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        let saved: Vec<_> = self
+            .session
+            .torrents()
+            .filter_map(|handle| {
+                let state = handle.state();
+                if state == TorrentState::Done {
+                    return None;
+                }
+                let carried = self.carried.get(handle.id()).copied();
+                let snapshot = handle.stats().snapshot();
+                let magnet = match handle.source() {
+                    TorrentSource::Magnet(magnet) => Some(magnet.to_string()),
+                    TorrentSource::Torrent { .. } => None,
+                };
+                Some(SavedTorrent {
+                    id: handle.id().to_owned(),
+                    magnet,
+                    params: AddTorrentParams {
+                        paused: state == TorrentState::Paused,
+                        ..handle.params().clone()
+                    },
+                    added: carried.map_or(0, |carried| carried.added),
+                    downloaded: carried.map_or(0, |carried| carried.downloaded)
+                        + snapshot.downloaded,
+                    uploaded: carried.map_or(0, |carried| carried.uploaded) + snapshot.uploaded,
+                })
+            })
+            .collect();
+        store.save(&saved)
+    }
+}
+
+fn torrent<'s>(session: &'s Session<'_>, id: &str) -> Result<&'s TorrentHandle, RpcError> {
+    session.get(id).ok_or_else(|| RpcError::unknown(id))
+}
+
+fn torrent_status(handle: &TorrentHandle) -> Value {
+    let snapshot = handle.stats().snapshot();
+    let (state, error) = match handle.state() {
+        TorrentState::Downloading => ("downloading", None),
+        TorrentState::Paused => ("paused", None),
+        TorrentState::Done => ("done", None),
+        TorrentState::Failed(error) => ("failed", Some(error)),
+    };
+    json!({
+        "id": handle.id(),
+        "name": handle.source().name(),
+        "output": handle.output().display().to_string(),
+        "category": handle.params().category,
+        "state": state,
+        "error": error,
+        "downloaded": snapshot.downloaded,
+        "down_rate": snapshot.down_rate,
+        "uploaded": snapshot.uploaded,
+        "up_rate": snapshot.up_rate,
+        "pieces_completed": snapshot.pieces_completed,
+        "peers": snapshot.peers.len(),
+    })
+}
+
+enum ControlListener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl ControlListener {
+    async fn bind(control: &ControlAddr) -> anyhow::Result<Self> {
+        match control {
+            ControlAddr::Tcp(addr) => Ok(Self::Tcp(TcpListener::bind(addr).await?)),
+            ControlAddr::Unix(path) => {
+                if path.exists() {
+                    // left behind by a daemon that didn't shut down cleanly, unless one answers
+                    if UnixStream::connect(path).await.is_ok() {
+                        anyhow::bail!("a daemon is already running there");
+                    }
+                    std::fs::remove_file(path).context("remove stale socket")?;
+                }
+                let listener = UnixListener::bind(path)?;
+                // only the user running the daemon may control it
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+                    .context("restrict socket permissions")?;
+                Ok(Self::Unix(listener, path.clone()))
+            }
+        }
+    }
+
+    /// Waits for a client, then serves it in the background.
+    async fn serve_next(
+        &self,
+        calls: &mpsc::Sender<Call>,
+        options: &Arc<ControlOptions>,
+        config: &ClientConfig,
+    ) -> std::io::Result<()> {
+        let (calls, options, config) = (calls.clone(), options.clone(), config.clone());
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, _) = listener.accept().await?;
+                tokio::spawn(serve(stream, calls, options, config));
+            }
+            Self::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                tokio::spawn(serve(stream, calls, options, config));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ControlListener {
+    fn drop(&mut self) {
+        if let Self::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Answers a client's requests, one line each, until it hangs up or sends one that isn't
+/// JSON-RPC or lacks the token.
+async fn serve(
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+    calls: mpsc::Sender<Call>,
+    options: Arc<ControlOptions>,
+    config: ClientConfig,
+) {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match (&mut reader).take(MAX_REQUEST).read_line(&mut line).await {
+            Ok(0) => return,
+            Ok(n) if n as u64 == MAX_REQUEST && !line.ends_with('\n') => {
+                tracing::debug!("control request too long");
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                tracing::debug!(error = %e, "reading control request failed");
+                return;
+            }
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (response, refused) = match respond(&line, &calls, &options, &config).await {
+            Ok(Some(response)) => (response, false),
+            Ok(None) => continue,
+            // something else talking to us, such as a browser's HTTP request, gets no further
+            Err(response) => (response, true),
+        };
+        let mut response = response.to_string();
+        response.push('\n');
+        if let Err(e) = writer.write_all(response.as_bytes()).await {
+            tracing::debug!(error = %e, "writing control response failed");
+            return;
+        }
+        if refused {
+            tracing::debug!("hanging up on control client");
+            return;
+        }
+    }
+}
+
+/// The response to one request line, or none for a notification; an error response if the
+/// client should be hung up on.
+async fn respond(
+    line: &str,
+    calls: &mpsc::Sender<Call>,
+    options: &ControlOptions,
+    config: &ClientConfig,
+) -> Result<Option<Value>, Value> {
+    let request = match serde_json::from_str::<Value>(line) {
+        Ok(request) => request,
+        Err(e) => {
+            let error = RpcError::new(PARSE_ERROR, e.to_string());
+            return Err(response(Value::Null, Err(error)));
+        }
+    };
+    let request = match serde_json::from_value::<RpcRequest>(request) {
+        Ok(request) if request.jsonrpc == "2.0" => request,
+        _ => {
+            let error = RpcError::new(INVALID_REQUEST, "not a JSON-RPC 2.0 request");
+            return Err(response(Value::Null, Err(error)));
+        }
+    };
+    if let Some(token) = &options.token {
+        let given = request.token.as_deref().unwrap_or_default();
+        if !same_token(given.as_bytes(), token.as_bytes()) {
+            let error = RpcError::new(UNAUTHORIZED, "missing or wrong token");
+            return Err(response(request.id.unwrap_or_default(), Err(error)));
+        }
+    }
+    let result = dispatch(&request.method, request.params, calls, options, config).await;
+    Ok(request.id.map(|id| response(id, result)))
+}
+
+/// Compares tokens in time that doesn't depend on where they differ.
+pub fn same_token(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// `path` resolved against `root`, refused if it would lead outside it.
+pub fn confine(root: &Path, path: &Path) -> Result<PathBuf, RpcError> {
+    let outside = || RpcError::invalid(format!("{} is outside {}", path.display(), root.display()));
+    if path.components().any(|c| c == Component::ParentDir) {
+        return Err(outside());
+    }
+    let path = root.join(path);
+    if !path.starts_with(root) {
+        return Err(outside());
+    }
+    // a symlink below the root could still lead out of it
+    let existing = path.ancestors().find_map(|dir| dir.canonicalize().ok());
+    if existing.is_some_and(|dir| !dir.starts_with(root)) {
+        return Err(outside());
+    }
+    Ok(path)
+}
+
+/// The request adding `source` with `params`, once its `.torrent` file, if it is a local one,
+/// and its output are confined to `root`, which must be canonical.
+pub async fn add_request(
+    root: &Path,
+    source: String,
+    mut params: AddTorrentParams,
+    config: &ClientConfig,
+) -> Result<Request, RpcError> {
+    params.output = confine(root, &params.output)?;
+    let remote = ["magnet:", "http://", "https://"]
+        .iter()
+        .any(|scheme| source.starts_with(scheme));
+    let source = if remote {
+        source
+    } else {
+        confine(root, Path::new(&source))?.display().to_string()
+    };
+    let source = TorrentSource::load(&source, config)
+        .await
+        .map_err(RpcError::failed)?;
+    Ok(Request::Add {
+        source: Box::new(source),
+        params,
+    })
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(error) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": error.code, "message": error.message },
+        }),
+    }
+}
+
+async fn dispatch(
+    method: &str,
+    params: Value,
+    calls: &mpsc::Sender<Call>,
+    options: &ControlOptions,
+    config: &ClientConfig,
+) -> Result<Value, RpcError> {
+    let request = match method {
+        "add" => {
+            let AddParams { source, params } = params_of(params)?;
+            add_request(&options.root, source, params, config).await?
+        }
+        "pause" => Request::Pause(params_of::<IdParams>(params)?.id),
+        "resume" => Request::Resume(params_of::<IdParams>(params)?.id),
+        "remove" => Request::Remove(params_of::<IdParams>(params)?.id),
+        "torrent" => Request::Torrent(params_of::<IdParams>(params)?.id),
+        "peers" => Request::Peers(params_of::<IdParams>(params)?.id),
+        "status" => Request::Status,
+        "maintenance" => {
+            let params = match params {
+                Value::Null => MaintenanceParams::default(),
+                params => params_of::<MaintenanceParams>(params)?,
+            };
+            Request::Maintenance(params.run)
+        }
+        // the limits are shared with every connection, so there's no need for the main loop
+        "alt_speed" => {
+            let params = match params {
+                Value::Null => AltSpeedParams::default(),
+                params => params_of(params)?,
+            };
+            return Ok(alt_speed(config, params));
+        }
+        _ => {
+            return Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("no method `{method}`"),
+            ));
+        }
+    };
+    submit(calls, request).await
+}
+
+/// Applies `params` to the alternative speed limits and reports the limits now in force.
+pub fn alt_speed(config: &ClientConfig, AltSpeedParams { enabled }: AltSpeedParams) -> Value {
+    if let Some(enabled) = enabled {
+        config.speed.set_alt_enabled(enabled);
+        tracing::info!(enabled, "alternative speed limits switched");
+    }
+    let (enabled, limit) = config.speed.current();
+    json!({ "enabled": enabled, "download_limit": limit })
+}
+
+/// Hands `request` to the daemon's main loop and waits for its outcome.
+pub async fn submit(calls: &mpsc::Sender<Call>, request: Request) -> Result<Value, RpcError> {
+    let (reply, result) = oneshot::channel();
+    let shutting_down = || RpcError::new(FAILED, "daemon is shutting down");
+    calls
+        .send(Call { request, reply })
+        .await
+        .map_err(|_| shutting_down())?;
+    result.await.map_err(|_| shutting_down())?
+}
+
+fn params_of<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+/// Sends one request, with `token` if given, to the daemon listening on `control` and returns
+/// its result.
+pub async fn call(
+    control: &ControlAddr,
+    token: Option<&str>,
+    method: &str,
+    params: Value,
+) -> anyhow::Result<Value> {
+    let mut request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    if let Some(token) = token {
+        request["token"] = token.into();
+    }
+    let response = match control {
+        ControlAddr::Tcp(addr) => exchange(TcpStream::connect(addr).await, &request).await,
+        ControlAddr::Unix(path) => exchange(UnixStream::connect(path).await, &request).await,
+    }
+    .with_context(|| format!("talk to daemon on {control}"))?;
+    if let Some(error) = response.get("error") {
+        let message = error["message"].as_str().unwrap_or("unknown error");
+        anyhow::bail!("daemon refused `{method}`: {message}");
+    }
+    Ok(response.get("result").cloned().unwrap_or(Value::Null))
+}
+
+async fn exchange(
+    stream: std::io::Result<impl AsyncRead + AsyncWrite + Unpin>,
+    request: &Value,
+) -> anyhow::Result<Value> {
+    let mut stream = BufReader::new(stream?);
+    let mut line = request.to_string();
+    line.push('\n');
+    stream.get_mut().write_all(line.as_bytes()).await?;
+    line.clear();
+    if stream.read_line(&mut line).await? == 0 {
+        anyhow::bail!("daemon hung up without answering");
+    }
+    serde_json::from_str(&line).context("parse daemon response")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(token: Option<&str>) -> ControlOptions {
+        ControlOptions {
+            token: token.map(str::to_owned),
+            root: PathBuf::from("/"),
+        }
+    }
+
+    async fn respond_to(line: &str, options: &ControlOptions) -> Result<Option<Value>, Value> {
+        let (calls, _) = mpsc::channel(1);
+        respond(line, &calls, options, &ClientConfig::default()).await
+    }
+
+    #[tokio::test]
+    async fn refuses_requests_without_the_token() {
+        let options = options(Some("secret"));
+        let request = r#"{"jsonrpc": "2.0", "id": 7, "method": "alt_speed"}"#;
+        let refused = respond_to(request, &options).await.unwrap_err();
+        assert_eq!(refused["id"], 7);
+        assert_eq!(refused["error"]["code"], UNAUTHORIZED);
+
+        let request = r#"{"jsonrpc": "2.0", "id": 7, "method": "alt_speed", "token": "secret"}"#;
+        let answered = respond_to(request, &options).await.unwrap().unwrap();
+        assert_eq!(answered["result"]["enabled"], false);
+    }
+
+    #[tokio::test]
+    async fn hangs_up_on_http() {
+        // what a web page posting to the control port sends first
+        let refused = respond_to("POST / HTTP/1.1\r\n", &options(None)).await;
+        assert_eq!(refused.unwrap_err()["error"]["code"], PARSE_ERROR);
+    }
+
+    #[test]
+    fn confines_paths_to_the_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        assert_eq!(confine(&root, Path::new("a/b")).unwrap(), root.join("a/b"));
+        assert!(confine(&root, Path::new("a/../../etc")).is_err());
+        assert!(confine(&root, Path::new("/etc/passwd")).is_err());
+        std::os::unix::fs::symlink("/", root.join("out")).unwrap();
+        assert!(confine(&root, Path::new("out/etc")).is_err());
+    }
+}
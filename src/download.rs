@@ -0,0 +1,505 @@
+use std::collections::{HashSet, VecDeque};
+use std::time::Duration;
+
+use anyhow::Context;
+use bytes::Bytes;
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use tokio::time::Instant;
+
+use crate::bitfield::Bitfield;
+use crate::geometry::PieceGeometry;
+use crate::message::{Message, MessageTag};
+use crate::session_stats::PeerMeter;
+use crate::speed::SpeedLimits;
+use crate::timeout::{self, TimeoutError, Timeouts};
+use crate::wire::{Capabilities, Piece, Request};
+
+/// Largest block we request; peers may refuse anything bigger.
+pub const BLOCK_MAX: usize = 1 << 14;
+/// The piece was finished by another peer while this one was still fetching it. Its
+/// outstanding requests have been cancelled.
+#[derive(Debug, thiserror::Error)]
+#[error("piece {piece} was finished by another peer")]
+pub struct Superseded {
+    pub piece: usize,
+}
+
+/// The peer stopped answering our requests while fetching the piece, so it is snubbing us.
+/// Its outstanding requests have been cancelled.
+#[derive(Debug, thiserror::Error)]
+#[error("peer stopped answering requests for piece {piece}")]
+pub struct Snubbed {
+    pub piece: usize,
+}
+
+/// Fewest block requests kept outstanding with a peer, however slow.
+pub const MIN_IN_FLIGHT: usize = 2;
+/// Most block requests kept outstanding with a peer, however fast.
+pub const MAX_IN_FLIGHT: usize = 256;
+/// Most of a peer's requests we hold on to until we get round to them; more are dropped.
+const MAX_PEER_REQUESTS: usize = 256;
+/// How much transfer time a peer's outstanding requests should cover, like libtorrent's
+/// request queue time.
+const QUEUE_TIME: Duration = Duration::from_secs(3);
+/// Weight given to the newest sample in the rate and round-trip averages.
+const BUDGET_ALPHA: f64 = 0.2;
+/// Blocks received before the measured rate, rather than slow start, sets the depth.
+const WARMUP_BLOCKS: u32 = 8;
+/// Request timeouts in a row after which a peer counts as snubbing us.
+const SNUB_AFTER: u32 = 2;
+/// Extended messages held for whoever waits on them; older ones are dropped past this.
+const MAX_HELD_EXTENDED: usize = 16;
+
+pub async fn recv<S>(
+    peer: &mut S,
+    state: &PeerState,
+    timeouts: &Timeouts,
+) -> anyhow::Result<Message>
+where
+    S: Stream<Item = std::io::Result<Message>> + Unpin,
+{
+    let msg = timeout::timeout(timeouts.block, TimeoutError::Block, peer.next()).await?;
+    accept(msg, state)
+}
+
+/// Waits however long it takes for the peer's next message, for when we have no requests
+/// out, and applies it to `state`.
+pub async fn idle<S>(peer: &mut S, state: &mut PeerState) -> anyhow::Result<()>
+where
+    S: Stream<Item = std::io::Result<Message>> + Unpin,
+{
+    let msg = accept(peer.next().await, state)?;
+    apply(&msg, state)
+}
+
+/// Updates `state` for a message that isn't the one we were waiting for, so control messages
+/// may arrive interleaved with anything else. The peer's piece announcement, extended
+/// messages and requests are held for whoever asks for them later; blocks are ignored.
+pub fn apply(msg: &Message, state: &mut PeerState) -> anyhow::Result<()> {
+    match msg.tag {
+        MessageTag::Bitfield | MessageTag::HaveAll | MessageTag::HaveNone => {
+            if is_announcement(msg, state) && state.announcement.is_none() {
+                state.announcement = Some(msg.clone());
+            }
+        }
+        MessageTag::Extended => {
+            if state.extended.len() == MAX_HELD_EXTENDED {
+                state.extended.pop_front();
+            }
+            state.extended.push_back(msg.payload.clone());
+        }
+        MessageTag::Have => state.announced.push(piece_index(&msg.payload)?),
+        MessageTag::Choke => {
+            state.choked = true;
+            state.budget.pause();
+        }
+        MessageTag::Unchoke => state.choked = false,
+        MessageTag::Interested => state.peer_interested = true,
+        MessageTag::NotInterested => state.peer_interested = false,
+        MessageTag::Request => {
+            let request = Request::from_bytes(&msg.payload).context("parse request message")?;
+            if state.peer_requests.len() < MAX_PEER_REQUESTS {
+                state.peer_requests.push_back(request);
+            }
+        }
+        MessageTag::Cancel => {
+            let cancelled = Request::from_bytes(&msg.payload).context("parse cancel message")?;
+            state.peer_requests.retain(|&request| request != cancelled);
+        }
+        MessageTag::AllowedFast if state.negotiated.fast => {
+            state.allowed_fast.insert(piece_index(&msg.payload)?);
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn accept(msg: Option<std::io::Result<Message>>, state: &PeerState) -> anyhow::Result<Message> {
+    let msg = msg
+        .context("peer closed the connection")?
+        .context("peer message was invalid")?;
+    tracing::trace!(tag = ?msg.tag, len = msg.payload.len(), "received message");
+    if state.strict {
+        anyhow::ensure!(
+            state.negotiated.allows(msg.tag),
+            "peer sent {:?} without negotiating the extension",
+            msg.tag
+        );
+    }
+    Ok(msg)
+}
+
+fn piece_index(payload: &[u8]) -> anyhow::Result<u32> {
+    let index: [u8; 4] = payload
+        .try_into()
+        .context("piece index payload must be 4 bytes")?;
+    Ok(u32::from_be_bytes(index))
+}
+
+/// What we know about a connected peer's willingness to serve us.
+#[derive(Debug)]
+pub struct PeerState {
+    /// Extensions both sides advertised.
+    pub negotiated: Capabilities,
+    pub strict: bool,
+    pub choked: bool,
+    pub allowed_fast: HashSet<u32>,
+    pub budget: RequestBudget,
+    /// The peer's bitfield, `have all` or `have none`, until the caller parses it once the
+    /// piece count is known, which for magnet links is after the metadata exchange.
+    pub announcement: Option<Message>,
+    /// Pieces the peer announced with `have` that the caller hasn't taken yet.
+    pub announced: Vec<u32>,
+    /// Extended messages, id byte first, that arrived while we waited on something else.
+    pub extended: VecDeque<Bytes>,
+    /// Where received blocks are counted, if anywhere.
+    pub meter: Option<PeerMeter>,
+    /// Rate limits received blocks are held to, if any.
+    pub limits: Option<SpeedLimits>,
+    /// Requests that have timed out since the peer last delivered a block.
+    pub request_timeouts: u32,
+    /// Whether the peer has let [`SNUB_AFTER`] requests in a row time out. Only one request
+    /// at a time is sent to it until it delivers again.
+    pub snubbed: bool,
+    /// Whether the peer wants to download from us.
+    pub peer_interested: bool,
+    /// Whether we refuse the peer's requests, as we do until it gets an upload slot.
+    pub choking: bool,
+    /// Blocks the peer asked us for that haven't been answered yet, oldest first.
+    pub peer_requests: VecDeque<Request>,
+}
+
+/// How many block requests to keep outstanding with one peer. Enough requests are queued to
+/// cover [`QUEUE_TIME`] (or one round trip, if longer) at the peer's measured rate, so fast
+/// peers get deep pipelines and slow ones aren't handed blocks they will sit on.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestBudget {
+    /// Bytes per second, averaged over block arrivals.
+    rate: f64,
+    rtt: Option<Duration>,
+    last_block: Option<Instant>,
+    blocks: u32,
+}
+
+impl Default for RequestBudget {
+    fn default() -> Self {
+        Self {
+            rate: 0.0,
+            rtt: None,
+            last_block: None,
+            blocks: 0,
+        }
+    }
+}
+
+impl RequestBudget {
+    /// Requests to keep outstanding when asking for blocks of `block_size` bytes.
+    pub fn depth(&self, block_size: usize) -> usize {
+        if self.blocks < WARMUP_BLOCKS {
+            // slow start: one more request for every block that arrives
+            return MIN_IN_FLIGHT + self.blocks as usize;
+        }
+        let window = self.rtt.map_or(QUEUE_TIME, |rtt| rtt.max(QUEUE_TIME));
+        let depth = (self.rate * window.as_secs_f64() / block_size as f64).ceil() as usize;
+        depth.clamp(MIN_IN_FLIGHT, MAX_IN_FLIGHT)
+    }
+
+    /// Folds in a block of `bytes` answering a request sent at `sent`.
+    pub fn block_received(&mut self, bytes: usize, sent: Instant) {
+        let now = Instant::now();
+        let rtt = now.duration_since(sent);
+        self.rtt = Some(match self.rtt {
+            Some(avg) => avg.mul_f64(1.0 - BUDGET_ALPHA) + rtt.mul_f64(BUDGET_ALPHA),
+            None => rtt,
+        });
+        // with a full pipeline, the gap between arrivals is what the peer's bandwidth allows
+        let gap = self.last_block.map_or(rtt, |last| now.duration_since(last));
+        let sample = bytes as f64 / gap.as_secs_f64().max(1e-3);
+        self.rate = if self.blocks == 0 {
+            sample
+        } else {
+            BUDGET_ALPHA * sample + (1.0 - BUDGET_ALPHA) * self.rate
+        };
+        self.last_block = Some(now);
+        self.blocks = self.blocks.saturating_add(1);
+    }
+
+    /// Forgets the arrival clock, e.g. after being choked, so idle time isn't counted as a
+    /// slow transfer.
+    pub fn pause(&mut self) {
+        self.last_block = None;
+    }
+}
+
+impl PeerState {
+    pub fn new(negotiated: Capabilities, strict: bool) -> Self {
+        Self {
+            negotiated,
+            strict,
+            choked: true,
+            allowed_fast: HashSet::new(),
+            budget: RequestBudget::default(),
+            announcement: None,
+            announced: Vec::new(),
+            extended: VecDeque::new(),
+            meter: None,
+            limits: None,
+            request_timeouts: 0,
+            snubbed: false,
+            peer_interested: false,
+            choking: true,
+            peer_requests: VecDeque::new(),
+        }
+    }
+
+    fn can_request(&self, piece: u32) -> bool {
+        !self.choked || self.allowed_fast.contains(&piece)
+    }
+}
+
+/// Whether `msg` announces the peer's pieces. Only a peer's first message may, and it need
+/// not: a peer with nothing to offer can skip it and send `have`s later.
+pub fn is_announcement(msg: &Message, state: &PeerState) -> bool {
+    match msg.tag {
+        MessageTag::Bitfield => true,
+        MessageTag::HaveAll | MessageTag::HaveNone => state.negotiated.fast,
+        _ => false,
+    }
+}
+
+/// Interprets a peer's piece announcement once the number of pieces is known. Peers that
+/// negotiated the Fast extension may send `have all`/`have none` in place of a bitfield.
+pub fn parse_availability(
+    msg: Message,
+    state: &PeerState,
+    npieces: usize,
+) -> anyhow::Result<Bitfield> {
+    let fast = state.negotiated.fast;
+    match msg.tag {
+        MessageTag::Bitfield => {
+            Bitfield::from_payload(msg.payload.to_vec(), npieces).context("parse peer bitfield")
+        }
+        MessageTag::HaveAll if fast => Ok(Bitfield::full(npieces)),
+        MessageTag::HaveNone if fast => Ok(Bitfield::new(npieces)),
+        tag => anyhow::bail!("expected peer to announce its pieces, got {tag:?}"),
+    }
+}
+
+/// Downloads every block of `piece` from a peer we have already declared interest in.
+///
+/// As many block requests as the peer's [`RequestBudget`] allows are kept outstanding at once
+/// and blocks are placed by offset as they arrive, so a slow round trip isn't paid once per
+/// block. A peer may answer with blocks of a different size than requested: whatever part of
+/// a request a block leaves uncovered is requested again. Blocks are requested while
+/// unchoked, or while choked if the peer has marked the piece as allowed-fast. Requests the
+/// peer rejects (or drops by choking us, without the Fast extension) go back on the queue.
+///
+/// Messages other than blocks and rejections may arrive in between and go through [`apply`].
+/// `superseded` is asked after every message whether the piece is still wanted. Once it
+/// isn't, every outstanding request is cancelled and [`Superseded`] returned.
+///
+/// Requests unanswered for [`Timeouts::request`] are cancelled and sent again. When that
+/// makes the peer [snubbed](PeerState::snubbed), [`Snubbed`] is returned so the piece can go
+/// to another peer; an already snubbed peer keeps going, one request at a time.
+#[tracing::instrument(skip(peer, state, geometry, timeouts, superseded))]
+pub async fn fetch_piece<S>(
+    peer: &mut S,
+    state: &mut PeerState,
+    geometry: &PieceGeometry,
+    piece: usize,
+    timeouts: &Timeouts,
+    superseded: impl Fn() -> bool,
+) -> anyhow::Result<Vec<u8>>
+where
+    S: Stream<Item = std::io::Result<Message>> + Sink<Message, Error = std::io::Error> + Unpin,
+{
+    let piece_size = geometry.piece_len(piece);
+    let nblock = geometry.block_count(piece);
+    let mut pending: VecDeque<Request> = (0..nblock)
+        .map(|block| {
+            let block_size = geometry.block_len(piece, block);
+            let begin = geometry.block_offset(block);
+            Request::new(piece as u32, begin as u32, block_size as u32)
+        })
+        .collect();
+    let block_size = geometry.block_len(piece, 0);
+    let mut in_flight: Vec<(Request, Instant)> = Vec::new();
+    let max_rejects = 3 * nblock;
+    let mut rejects = 0;
+
+    let fast = state.negotiated.fast;
+    let mut all_blocks = vec![0; piece_size];
+    while !pending.is_empty() || !in_flight.is_empty() {
+        let depth = if state.snubbed {
+            1
+        } else {
+            state.budget.depth(block_size)
+        };
+        while in_flight.len() < depth {
+            let Some(&request) = pending.front() else {
+                break;
+            };
+            if !state.can_request(request.index) {
+                break;
+            }
+            pending.pop_front();
+            peer.send(Message {
+                tag: MessageTag::Request,
+                payload: request.to_bytes().to_vec().into(),
+            })
+            .await
+            .with_context(|| format!("send request message for offset {}", request.begin))?;
+            in_flight.push((request, Instant::now()));
+        }
+
+        let timeout_at = in_flight
+            .iter()
+            .map(|&(_, sent)| sent + timeouts.request)
+            .min();
+        let msg = tokio::select! {
+            msg = recv(peer, state, timeouts) => Some(msg?),
+            _ = tokio::time::sleep_until(timeout_at.unwrap_or_else(Instant::now)),
+                if timeout_at.is_some() => None,
+        };
+        let Some(msg) = msg else {
+            let expired: Vec<Request> = in_flight
+                .iter()
+                .filter(|&&(_, sent)| sent.elapsed() >= timeouts.request)
+                .map(|&(r, _)| r)
+                .collect();
+            cancel(peer, expired.iter().copied()).await?;
+            in_flight.retain(|(r, _)| !expired.contains(r));
+            tracing::debug!(requests = expired.len(), "requests timed out, re-queueing");
+            for request in expired.into_iter().rev() {
+                pending.push_front(request);
+            }
+            state.request_timeouts += 1;
+            if superseded() {
+                cancel(peer, in_flight.iter().map(|&(r, _)| r)).await?;
+                return Err(Superseded { piece }.into());
+            }
+            if state.request_timeouts >= SNUB_AFTER && !state.snubbed {
+                state.snubbed = true;
+                tracing::info!("peer is snubbing us");
+                cancel(peer, in_flight.iter().map(|&(r, _)| r)).await?;
+                return Err(Snubbed { piece }.into());
+            }
+            continue;
+        };
+        if superseded() {
+            cancel(peer, in_flight.iter().map(|&(r, _)| r)).await?;
+            return Err(Superseded { piece }.into());
+        }
+        match msg.tag {
+            MessageTag::Piece => {
+                let block = Piece::from_bytes(&msg.payload).context("parse piece message")?;
+                let begin = block.begin as usize;
+                let end = begin + block.block.len();
+                let answered: Vec<(Request, Instant)> = in_flight
+                    .iter()
+                    .copied()
+                    .filter(|(r, _)| r.index == block.index && overlaps(r, begin, end))
+                    .collect();
+                if answered.is_empty() {
+                    // a late answer to a request we already gave up on
+                    continue;
+                }
+                anyhow::ensure!(
+                    end <= piece_size,
+                    "peer sent block past the end of piece {piece}"
+                );
+                all_blocks[begin..end].copy_from_slice(block.block);
+                if let Some(meter) = &state.meter {
+                    meter.block_received(end - begin);
+                }
+                state.request_timeouts = 0;
+                if state.snubbed {
+                    state.snubbed = false;
+                    tracing::info!("peer is no longer snubbing us");
+                }
+                in_flight.retain(|entry| !answered.contains(entry));
+                let sent = answered.iter().map(|&(_, sent)| sent).min();
+                state
+                    .budget
+                    .block_received(end - begin, sent.expect("answered is not empty"));
+                if let Some(limits) = &state.limits {
+                    // reading nothing more until then makes the peer slow down too, and
+                    // after timing the block so the wait doesn't count as latency
+                    limits.consume(end - begin).await;
+                }
+                for (request, _) in answered {
+                    for rest in uncovered(request, begin, end) {
+                        tracing::debug!(begin = rest.begin, len = rest.length, "short block");
+                        pending.push_front(rest);
+                    }
+                }
+            }
+            MessageTag::RejectRequest if fast => {
+                let rejected = Request::from_bytes(&msg.payload).context("parse reject message")?;
+                if let Some(pos) = in_flight.iter().position(|&(r, _)| r == rejected) {
+                    in_flight.swap_remove(pos);
+                    rejects += 1;
+                    anyhow::ensure!(
+                        rejects <= max_rejects,
+                        "peer keeps rejecting requests for piece {piece}"
+                    );
+                    tracing::debug!(begin = rejected.begin, "request rejected, re-queueing");
+                    pending.push_back(rejected);
+                }
+            }
+            MessageTag::Choke if !fast => {
+                apply(&msg, state)?;
+                // without the Fast extension a choke silently drops our requests
+                tracing::debug!(requests = in_flight.len(), "choked, re-queueing requests");
+                pending.extend(in_flight.drain(..).map(|(r, _)| r));
+            }
+            _ => apply(&msg, state)?,
+        }
+    }
+    Ok(all_blocks)
+}
+
+/// Tells the peer we no longer want `requests`. Blocks it already sent on their way are
+/// ignored when they arrive.
+async fn cancel<S>(peer: &mut S, requests: impl Iterator<Item = Request>) -> anyhow::Result<()>
+where
+    S: Sink<Message, Error = std::io::Error> + Unpin,
+{
+    for request in requests {
+        tracing::debug!(
+            index = request.index,
+            begin = request.begin,
+            "cancelling request"
+        );
+        peer.feed(Message {
+            tag: MessageTag::Cancel,
+            payload: request.to_bytes().to_vec().into(),
+        })
+        .await
+        .context("send cancel message")?;
+    }
+    peer.flush().await.context("send cancel message")
+}
+
+fn overlaps(request: &Request, begin: usize, end: usize) -> bool {
+    let start = request.begin as usize;
+    start < end && begin < start + request.length as usize
+}
+
+/// The parts of `request` that a block covering `begin..end` left unfilled.
+fn uncovered(request: Request, begin: usize, end: usize) -> Vec<Request> {
+    let start = request.begin as usize;
+    let stop = start + request.length as usize;
+    let mut rest = Vec::new();
+    if start < begin {
+        rest.push(Request::new(
+            request.index,
+            start as u32,
+            (begin - start) as u32,
+        ));
+    }
+    if end < stop {
+        rest.push(Request::new(request.index, end as u32, (stop - end) as u32));
+    }
+    rest
+}
@@ -0,0 +1,212 @@
+//! The extension protocol (BEP 10) and metadata exchange (BEP 9).
+
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use bytes::Bytes;
+use futures_util::{Sink, SinkExt, Stream};
+use serde::{Deserialize, Serialize};
+
+use crate::bencode;
+use crate::download::{self, PeerState};
+use crate::hash::TorrentVersion;
+use crate::message::{Message, MessageTag};
+use crate::timeout::Timeouts;
+
+/// Extended message id reserved for the extension handshake.
+pub const HANDSHAKE_ID: u8 = 0;
+/// The id we ask peers to use when sending us `ut_metadata` messages.
+pub const UT_METADATA_ID: u8 = 16;
+/// The id we ask peers to use when sending us `ut_holepunch` messages.
+pub const UT_HOLEPUNCH_ID: u8 = 17;
+/// Metadata is exchanged in pieces of this size.
+const METADATA_PIECE_LEN: usize = 1 << 14;
+/// Largest info dictionary we will fetch from a peer.
+const MAX_METADATA: usize = 10 << 20;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ExtensionHandshake {
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtensionHandshake {
+    pub fn ut_metadata(&self) -> Option<u8> {
+        self.m.get("ut_metadata").copied()
+    }
+
+    pub fn ut_holepunch(&self) -> Option<u8> {
+        self.m.get("ut_holepunch").copied()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MetadataMessage {
+    msg_type: u8,
+    piece: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+const METADATA_REQUEST: u8 = 0;
+const METADATA_DATA: u8 = 1;
+const METADATA_REJECT: u8 = 2;
+
+pub async fn send_extended<S>(peer: &mut S, id: u8, body: &[u8]) -> anyhow::Result<()>
+where
+    S: Sink<Message, Error = std::io::Error> + Unpin,
+{
+    let mut payload = Vec::with_capacity(1 + body.len());
+    payload.push(id);
+    payload.extend_from_slice(body);
+    peer.send(Message {
+        tag: MessageTag::Extended,
+        payload: payload.into(),
+    })
+    .await
+    .context("send extended message")
+}
+
+/// Waits for an extended message with the given id, or takes it from those that arrived
+/// earlier. Anything else that arrives meanwhile is applied to `state`.
+async fn recv_extended<S>(
+    peer: &mut S,
+    state: &mut PeerState,
+    id: u8,
+    timeouts: &Timeouts,
+) -> anyhow::Result<Bytes>
+where
+    S: Stream<Item = std::io::Result<Message>> + Unpin,
+{
+    if let Some(held) = state
+        .extended
+        .iter()
+        .position(|body| body.first() == Some(&id))
+    {
+        let body = state.extended.remove(held).expect("position is in range");
+        return Ok(body.slice(1..));
+    }
+    loop {
+        let msg = download::recv(peer, state, timeouts).await?;
+        if msg.tag == MessageTag::Extended && msg.payload.first() == Some(&id) {
+            return Ok(msg.payload.slice(1..));
+        }
+        download::apply(&msg, state)?;
+    }
+}
+
+/// Sends our extension handshake, advertising `ut_metadata` and `ut_holepunch` support. The
+/// peer's arrives as an extended message with [`HANDSHAKE_ID`] whenever it sends it.
+pub async fn send_handshake<S>(peer: &mut S) -> anyhow::Result<()>
+where
+    S: Sink<Message, Error = std::io::Error> + Unpin,
+{
+    let ours = ExtensionHandshake {
+        m: BTreeMap::from([
+            ("ut_metadata".to_string(), UT_METADATA_ID),
+            ("ut_holepunch".to_string(), UT_HOLEPUNCH_ID),
+        ]),
+        metadata_size: None,
+    };
+    let body = serde_bencode::to_bytes(&ours).context("encode extension handshake")?;
+    send_extended(peer, HANDSHAKE_ID, &body).await
+}
+
+/// Exchanges extension handshakes.
+pub async fn handshake<S>(
+    peer: &mut S,
+    state: &mut PeerState,
+    timeouts: &Timeouts,
+) -> anyhow::Result<ExtensionHandshake>
+where
+    S: Stream<Item = std::io::Result<Message>> + Sink<Message, Error = std::io::Error> + Unpin,
+{
+    send_handshake(peer).await?;
+    let body = recv_extended(peer, state, HANDSHAKE_ID, timeouts).await?;
+    serde_bencode::from_bytes(&body).context("parse extension handshake")
+}
+
+/// Fetches the info dictionary for `info_hash` over `ut_metadata`, a piece at a time, and
+/// verifies its hash. Fails, so the peer can be dropped, if it sends pieces of the wrong size,
+/// more than [`MAX_METADATA`] bytes, or metadata that doesn't match.
+pub async fn fetch_metadata<S>(
+    peer: &mut S,
+    state: &mut PeerState,
+    theirs: &ExtensionHandshake,
+    info_hash: [u8; 20],
+    timeouts: &Timeouts,
+) -> anyhow::Result<Vec<u8>>
+where
+    S: Stream<Item = std::io::Result<Message>> + Sink<Message, Error = std::io::Error> + Unpin,
+{
+    let id = theirs
+        .ut_metadata()
+        .context("peer does not support ut_metadata")?;
+    // the handshake's size is optional; failing that the first piece tells us
+    let mut total_size = theirs.metadata_size.map(check_metadata_size).transpose()?;
+    let mut metadata = Vec::new();
+    for piece in 0.. {
+        let request = MetadataMessage {
+            msg_type: METADATA_REQUEST,
+            piece,
+            total_size: None,
+        };
+        let body = serde_bencode::to_bytes(&request).context("encode metadata request")?;
+        send_extended(peer, id, &body).await?;
+
+        let body = recv_extended(peer, state, UT_METADATA_ID, timeouts).await?;
+        let header_len = bencode::value_len(&body).context("malformed metadata message")?;
+        let header: MetadataMessage =
+            serde_bencode::from_bytes(&body[..header_len]).context("parse metadata message")?;
+        match header.msg_type {
+            METADATA_DATA => {}
+            METADATA_REJECT => {
+                anyhow::bail!("peer rejected the request for metadata piece {piece}")
+            }
+            other => anyhow::bail!("unexpected metadata message type {other}"),
+        }
+        anyhow::ensure!(
+            header.piece == piece,
+            "peer sent metadata piece {} when asked for {piece}",
+            header.piece
+        );
+        let size = header
+            .total_size
+            .context("metadata message has no total_size")?;
+        let total = match total_size {
+            Some(total) => total,
+            None => *total_size.insert(check_metadata_size(size)?),
+        };
+        anyhow::ensure!(
+            size == total,
+            "peer said the metadata is {total} bytes, then {size}"
+        );
+        let data = &body[header_len..];
+        let expected = (total - metadata.len()).min(METADATA_PIECE_LEN);
+        anyhow::ensure!(
+            data.len() == expected,
+            "metadata piece {piece} is {} bytes, expected {expected}",
+            data.len()
+        );
+        metadata.extend_from_slice(data);
+        if metadata.len() == total {
+            break;
+        }
+    }
+    anyhow::ensure!(
+        TorrentVersion::V1.verify(&metadata, &info_hash),
+        "metadata does not match the info hash"
+    );
+    Ok(metadata)
+}
+
+fn check_metadata_size(size: usize) -> anyhow::Result<usize> {
+    anyhow::ensure!(size > 0, "peer advertised empty metadata");
+    anyhow::ensure!(
+        size <= MAX_METADATA,
+        "metadata of {size} bytes is larger than the {MAX_METADATA} we accept"
+    );
+    Ok(size)
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use crate::geometry::PieceGeometry;
+
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "piece {piece} of {} failed hash verification {failures} times, giving up; \
+     suspected peers: {suspects:?}",
+    .files.join(", ")
+)]
+pub struct TooManyHashFailures {
+    pub piece: usize,
+    /// The files the piece holds part of.
+    pub files: Vec<String>,
+    pub failures: u32,
+    /// Peers that contributed to failed pieces, most failures first.
+    pub suspects: Vec<(SocketAddr, u32)>,
+}
+
+/// A piece that did not match its hash. The data is discarded and the piece fetched again
+/// from a different peer.
+#[derive(Debug, thiserror::Error)]
+#[error("piece {piece} from {peer} failed hash verification")]
+pub struct HashMismatch {
+    pub piece: usize,
+    pub peer: SocketAddr,
+}
+
+/// Counts hash verification failures so poisoned pieces aren't re-downloaded forever. Shared
+/// by every peer task of a download.
+#[derive(Debug)]
+pub struct HashFailures {
+    limit: u32,
+    /// The torrent's files laid end to end, with their lengths, to tell which a piece is of.
+    files: Vec<(String, u64)>,
+    geometry: PieceGeometry,
+    counts: Mutex<Counts>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    by_piece: HashMap<usize, u32>,
+    by_peer: HashMap<SocketAddr, u32>,
+}
+
+impl HashFailures {
+    /// Gives up on a piece after `limit` failures, naming the ones of `files` it overlaps.
+    pub fn new(limit: u32, files: &[(String, u64)], geometry: PieceGeometry) -> Self {
+        Self {
+            limit,
+            files: files.to_vec(),
+            geometry,
+            counts: Mutex::default(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Counts> {
+        self.counts
+            .lock()
+            .expect("hash failure counts lock poisoned")
+    }
+
+    /// Records that `piece`, downloaded from `peer`, did not match its hash. Fails once the
+    /// piece has failed more than the configured limit.
+    pub fn record(&self, piece: usize, peer: SocketAddr) -> Result<(), TooManyHashFailures> {
+        let mut counts = self.lock();
+        *counts.by_peer.entry(peer).or_default() += 1;
+        let failures = counts.by_piece.entry(piece).or_default();
+        *failures += 1;
+        let failures = *failures;
+        drop(counts);
+        if failures <= self.limit {
+            return Ok(());
+        }
+        Err(TooManyHashFailures {
+            piece,
+            files: self.files_of(piece),
+            failures,
+            suspects: self.suspects(),
+        })
+    }
+
+    /// The files `piece` holds part of.
+    fn files_of(&self, piece: usize) -> Vec<String> {
+        let start = self.geometry.piece_offset(piece) as u64;
+        let end = start + self.geometry.piece_len(piece) as u64;
+        let mut offset = 0;
+        self.files
+            .iter()
+            .filter_map(|(path, length)| {
+                let file = offset..offset + length;
+                offset = file.end;
+                (!file.is_empty() && file.start < end && start < file.end).then(|| path.clone())
+            })
+            .collect()
+    }
+
+    pub fn suspects(&self) -> Vec<(SocketAddr, u32)> {
+        let counts = self.lock();
+        let mut suspects: Vec<_> = counts.by_peer.iter().map(|(&p, &n)| (p, n)).collect();
+        suspects.sort_by_key(|&(_, failures)| std::cmp::Reverse(failures));
+        suspects
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:6881".parse().unwrap()
+    }
+
+    #[test]
+    fn names_the_files_a_failing_piece_holds() {
+        let files = [
+            ("t/a".to_string(), 10),
+            ("t/empty".to_string(), 0),
+            ("t/b".to_string(), 20),
+            ("t/c".to_string(), 2),
+        ];
+        let failures = HashFailures::new(0, &files, PieceGeometry::new(32, 16));
+        let first = failures.record(0, peer()).unwrap_err();
+        assert_eq!(first.files, ["t/a", "t/b"]);
+        let last = failures.record(1, peer()).unwrap_err();
+        assert_eq!(last.files, ["t/b", "t/c"]);
+    }
+
+    #[test]
+    fn gives_up_past_the_limit() {
+        let files = [("t".to_string(), 32)];
+        let failures = HashFailures::new(2, &files, PieceGeometry::new(32, 16));
+        assert!(failures.record(1, peer()).is_ok());
+        assert!(failures.record(1, peer()).is_ok());
+        let error = failures.record(1, peer()).unwrap_err();
+        assert_eq!(error.failures, 3);
+        assert_eq!(error.suspects, [(peer(), 3)]);
+    }
+}
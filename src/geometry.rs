@@ -0,0 +1,144 @@
+use bittorrent_starter_rust::Torrent;
+
+use crate::download::BLOCK_MAX;
+
+/// How a torrent's bytes split into pieces, and each piece into the blocks we request.
+///
+/// Only the last piece, and the last block of each piece, can be short.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PieceGeometry {
+    length: usize,
+    piece_length: usize,
+    block_size: usize,
+}
+
+impl PieceGeometry {
+    pub fn new(length: usize, piece_length: usize) -> Self {
+        assert!(piece_length > 0, "piece length must be positive");
+        Self {
+            length,
+            piece_length,
+            block_size: BLOCK_MAX,
+        }
+    }
+
+    /// The same torrent requested in blocks of `block_size` bytes, at most [`BLOCK_MAX`].
+    pub fn with_block_size(self, block_size: usize) -> Self {
+        assert!(
+            (1..=BLOCK_MAX).contains(&block_size),
+            "block size must be between 1 and {BLOCK_MAX}"
+        );
+        Self { block_size, ..self }
+    }
+
+    pub fn of(t: &Torrent) -> Self {
+        Self::new(t.length(), t.info.plength)
+    }
+
+    /// Total bytes in the torrent's contents.
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.length.div_ceil(self.piece_length)
+    }
+
+    /// Where `piece` starts within the torrent's contents.
+    pub fn piece_offset(&self, piece: usize) -> usize {
+        piece * self.piece_length
+    }
+
+    /// The piece containing byte `offset` of the torrent's contents.
+    pub fn piece_at(&self, offset: usize) -> usize {
+        offset / self.piece_length
+    }
+
+    pub fn piece_len(&self, piece: usize) -> usize {
+        assert!(
+            piece < self.piece_count(),
+            "piece {piece} out of range for {} pieces",
+            self.piece_count()
+        );
+        (self.length - self.piece_offset(piece)).min(self.piece_length)
+    }
+
+    pub fn block_count(&self, piece: usize) -> usize {
+        self.piece_len(piece).div_ceil(self.block_size)
+    }
+
+    /// Where `block` starts within its piece.
+    pub fn block_offset(&self, block: usize) -> usize {
+        block * self.block_size
+    }
+
+    pub fn block_len(&self, piece: usize, block: usize) -> usize {
+        let piece_len = self.piece_len(piece);
+        let begin = self.block_offset(block);
+        assert!(
+            begin < piece_len,
+            "block {block} out of range for piece {piece}"
+        );
+        (piece_len - begin).min(self.block_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KIB: usize = 1 << 10;
+
+    #[test]
+    fn evenly_divided_torrent() {
+        let geometry = PieceGeometry::new(4 * 32 * KIB, 32 * KIB);
+        assert_eq!(geometry.piece_count(), 4);
+        assert_eq!(geometry.piece_offset(3), 3 * 32 * KIB);
+        assert_eq!(geometry.piece_len(3), 32 * KIB);
+        assert_eq!(geometry.block_count(3), 2);
+        assert_eq!(geometry.block_len(3, 1), BLOCK_MAX);
+        assert_eq!(geometry.piece_at(32 * KIB - 1), 0);
+        assert_eq!(geometry.piece_at(32 * KIB), 1);
+    }
+
+    #[test]
+    fn short_last_piece() {
+        let geometry = PieceGeometry::new(2 * 32 * KIB + 100, 32 * KIB);
+        assert_eq!(geometry.piece_count(), 3);
+        assert_eq!(geometry.piece_len(1), 32 * KIB);
+        assert_eq!(geometry.piece_len(2), 100);
+        assert_eq!(geometry.block_count(2), 1);
+        assert_eq!(geometry.block_len(2, 0), 100);
+    }
+
+    #[test]
+    fn short_last_block() {
+        let geometry = PieceGeometry::new(3 * 20 * KIB, 20 * KIB);
+        assert_eq!(geometry.block_count(0), 2);
+        assert_eq!(geometry.block_offset(1), BLOCK_MAX);
+        assert_eq!(geometry.block_len(0, 0), BLOCK_MAX);
+        assert_eq!(geometry.block_len(0, 1), 4 * KIB);
+    }
+
+    #[test]
+    fn single_block_pieces() {
+        let geometry = PieceGeometry::new(10 * KIB + 1, 8 * KIB);
+        assert_eq!(geometry.piece_count(), 2);
+        assert_eq!(geometry.block_count(0), 1);
+        assert_eq!(geometry.block_len(0, 0), 8 * KIB);
+        assert_eq!(geometry.block_len(1, 0), 2 * KIB + 1);
+    }
+
+    #[test]
+    fn smaller_blocks() {
+        let geometry = PieceGeometry::new(32 * KIB, 32 * KIB).with_block_size(10 * KIB);
+        assert_eq!(geometry.block_count(0), 4);
+        assert_eq!(geometry.block_len(0, 3), 2 * KIB);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn block_past_the_piece() {
+        PieceGeometry::new(100, 64).block_len(1, 1);
+    }
+}
@@ -0,0 +1,114 @@
+use std::sync::{Arc, Mutex};
+
+use futures_util::{Sink, SinkExt};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+
+use crate::message::{Message, MessageTag};
+
+/// Fans verified piece indices out to every connected peer so each can be sent a `have`.
+#[derive(Debug, Clone)]
+pub struct HaveBroadcast {
+    tx: broadcast::Sender<u32>,
+    /// Every piece verified so far, for peers that fell too far behind to be told one by one.
+    verified: Arc<Mutex<Vec<u32>>>,
+}
+
+impl HaveBroadcast {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(256);
+        Self {
+            tx,
+            verified: Arc::default(),
+        }
+    }
+
+    /// Registers a peer connection; it will see every piece verified from now on.
+    pub fn subscribe(&self) -> HaveSubscriber {
+        HaveSubscriber {
+            rx: self.tx.subscribe(),
+            verified: self.verified.clone(),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn piece_verified(&self, index: u32) {
+        self.verified
+            .lock()
+            .expect("have broadcast lock poisoned")
+            .push(index);
+        // no subscribers just means no peers are connected right now
+        let _ = self.tx.send(index);
+    }
+}
+
+impl Default for HaveBroadcast {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One peer's view of a [`HaveBroadcast`]: the pieces it hasn't been told about yet.
+#[derive(Debug)]
+pub struct HaveSubscriber {
+    rx: broadcast::Receiver<u32>,
+    verified: Arc<Mutex<Vec<u32>>>,
+    pending: Vec<u32>,
+}
+
+impl HaveSubscriber {
+    /// Waits until a piece has been verified that the peer hasn't been told about. Cancel
+    /// safe, so it can sit in a `select!` with the peer's other work.
+    pub async fn ready(&mut self) {
+        loop {
+            if !self.pending.is_empty() {
+                return;
+            }
+            match self.rx.recv().await {
+                Ok(index) => self.pending.push(index),
+                Err(RecvError::Lagged(_)) => self.catch_up(),
+                // the download is over; nothing more will be verified
+                Err(RecvError::Closed) => std::future::pending().await,
+            }
+        }
+    }
+
+    /// Sends a `have` for every piece verified since the last flush.
+    pub async fn flush<S>(&mut self, peer: &mut S) -> anyhow::Result<()>
+    where
+        S: Sink<Message, Error = std::io::Error> + Unpin,
+    {
+        loop {
+            match self.rx.try_recv() {
+                Ok(index) => self.pending.push(index),
+                Err(TryRecvError::Lagged(_)) => self.catch_up(),
+                Err(_) => break,
+            }
+        }
+        for index in self.pending.drain(..) {
+            peer.feed(have_message(index)).await?;
+        }
+        peer.flush().await?;
+        Ok(())
+    }
+
+    /// After missing some of the broadcast, queues every piece verified so far instead. A
+    /// peer told twice about a piece just ignores the second `have`.
+    fn catch_up(&mut self) {
+        // subscribe again first, so a piece verified meanwhile is in one or the other
+        self.rx = self.rx.resubscribe();
+        let verified = self.verified.lock().expect("have broadcast lock poisoned");
+        tracing::debug!(
+            pieces = verified.len(),
+            "fell behind on haves, resending them all"
+        );
+        self.pending.clone_from(&verified);
+    }
+}
+
+pub fn have_message(index: u32) -> Message {
+    Message {
+        tag: MessageTag::Have,
+        payload: index.to_be_bytes().to_vec().into(),
+    }
+}
@@ -0,0 +1,282 @@
+//! Holepunching (BEP 55): two peers that can't reach each other, typically both behind NATs,
+//! are introduced by a peer connected to both and dial each other over uTP at the same time,
+//! so each side's outgoing packets open its NAT to the other's.
+//!
+//! Every download keeps a [`Relay`] of its connected peers. Peers can ask it to introduce
+//! them to one another, it asks them to introduce us to peers we failed to dial, and the
+//! connections that come of their introductions join the download.
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Mutex, MutexGuard};
+
+use anyhow::Context;
+use futures_util::Sink;
+use tokio::sync::mpsc;
+
+use crate::config::ClientConfig;
+use crate::extension::{self, ExtensionHandshake};
+use crate::message::Message;
+use crate::mse::PeerStream;
+use crate::peer::{self, PeerConnection};
+use crate::timeout::TimeoutError;
+use crate::wire::{Holepunch, HolepunchError, HolepunchKind};
+
+/// Relays asked at once to introduce us to a peer we couldn't dial.
+const RENDEZVOUS_RELAYS: usize = 3;
+/// Holepunch messages queued for one peer before further ones are dropped.
+const OUTBOX: usize = 16;
+/// Holepunched connections waiting to be taken up by the download.
+const PUNCHED_BACKLOG: usize = 8;
+
+struct Member {
+    outbox: mpsc::Sender<Holepunch>,
+    /// Whether its extension handshake advertised `ut_holepunch`.
+    supports: bool,
+}
+
+#[derive(Default)]
+struct Inner {
+    members: HashMap<SocketAddr, Member>,
+    /// Peers we have asked to be introduced to, so each is only asked about once.
+    asked: HashSet<SocketAddr>,
+}
+
+/// The connected peers of one download, for introducing them to each other and to us.
+pub struct Relay {
+    inner: Mutex<Inner>,
+    punched: mpsc::Sender<PeerConnection>,
+    arrived: Mutex<mpsc::Receiver<PeerConnection>>,
+}
+
+impl Relay {
+    pub fn new() -> Self {
+        let (punched, arrived) = mpsc::channel(PUNCHED_BACKLOG);
+        Self {
+            inner: Mutex::new(Inner::default()),
+            punched,
+            arrived: Mutex::new(arrived),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().expect("relay lock poisoned")
+    }
+
+    /// Adds a connected peer, until the returned [`RelayMember`] is dropped.
+    pub fn join(&self, addr: SocketAddr) -> RelayMember<'_> {
+        let (outbox, inbox) = mpsc::channel(OUTBOX);
+        let member = Member {
+            outbox,
+            supports: false,
+        };
+        self.lock().members.insert(addr, member);
+        RelayMember {
+            relay: self,
+            addr,
+            id: None,
+            inbox,
+        }
+    }
+
+    /// A connection an introduction brought us, if one is waiting.
+    pub fn take_punched(&self) -> Option<PeerConnection> {
+        self.arrived
+            .lock()
+            .expect("relay lock poisoned")
+            .try_recv()
+            .ok()
+    }
+
+    /// Asks connected peers to introduce us to `target`, which we couldn't dial. Needs our
+    /// uTP endpoint, to dial back from the port they will tell `target` about.
+    pub fn unreachable(&self, target: SocketAddr, config: &ClientConfig) {
+        if config.utp.is_none() {
+            return;
+        }
+        let mut inner = self.lock();
+        if !inner.asked.insert(target) {
+            return;
+        }
+        let rendezvous = Holepunch {
+            kind: HolepunchKind::Rendezvous,
+            addr: target,
+        };
+        let asked = inner
+            .members
+            .iter()
+            .filter(|&(&addr, member)| member.supports && addr != target)
+            .take(RENDEZVOUS_RELAYS)
+            .filter(|(_, member)| member.outbox.try_send(rendezvous).is_ok())
+            .count();
+        if asked > 0 {
+            tracing::debug!(peer = %target, relays = asked, "asking to be introduced");
+        }
+    }
+
+    /// Tells `from` and `target` to dial each other.
+    fn introduce(&self, from: SocketAddr, target: SocketAddr) -> Result<(), HolepunchError> {
+        if target.ip().is_unspecified() || target.port() == 0 {
+            return Err(HolepunchError::NoSuchPeer);
+        }
+        let inner = self.lock();
+        let to = inner
+            .members
+            .get(&target)
+            .ok_or(HolepunchError::NotConnected)?;
+        if !to.supports {
+            return Err(HolepunchError::NoSupport);
+        }
+        let connect = |addr| Holepunch {
+            kind: HolepunchKind::Connect,
+            addr,
+        };
+        // a full outbox drops the introduction, as a lost message would
+        let _ = to.outbox.try_send(connect(from));
+        if let Some(from) = inner.members.get(&from) {
+            let _ = from.outbox.try_send(connect(target));
+        }
+        tracing::debug!(%from, %target, "introduced peers");
+        Ok(())
+    }
+
+    /// Dials `target` from our uTP endpoint, as it dials us, and queues the connection for
+    /// [`Self::take_punched`].
+    fn punch(&self, target: SocketAddr, info_hash: [u8; 20], config: &ClientConfig) {
+        let Some(endpoint) = config.utp.clone() else {
+            tracing::debug!(peer = %target, "not listening on uTP, can't holepunch");
+            return;
+        };
+        let Some(slot) = config.connections.try_acquire() else {
+            tracing::debug!(peer = %target, "no connection slot to holepunch with");
+            return;
+        };
+        let (punched, config) = (self.punched.clone(), config.clone());
+        tokio::spawn(async move {
+            let conn = async {
+                let mut stream = endpoint
+                    .connect(target)
+                    .await
+                    .context("holepunch to peer")?;
+                let handshake = peer::handshake(&mut stream, info_hash, &config).await?;
+                let stream = PeerStream::plaintext(Box::new(stream));
+                PeerConnection::from_stream(target, stream, handshake, slot, &config).await
+            };
+            match conn.await {
+                Ok(conn) => {
+                    tracing::info!(peer = %target, "holepunched connection");
+                    if punched.try_send(conn).is_err() {
+                        tracing::debug!(peer = %target, "dropped holepunched connection");
+                    }
+                }
+                Err(e) => tracing::debug!(peer = %target, error = %e, "holepunch failed"),
+            }
+        });
+    }
+}
+
+impl Default for Relay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One peer's place in a [`Relay`], for as long as it is connected.
+pub struct RelayMember<'r> {
+    relay: &'r Relay,
+    addr: SocketAddr,
+    /// The peer's id for `ut_holepunch`, once its extension handshake arrives.
+    id: Option<u8>,
+    inbox: mpsc::Receiver<Holepunch>,
+}
+
+impl RelayMember<'_> {
+    /// The next message the relay has for the peer.
+    pub async fn next(&mut self) -> Option<Holepunch> {
+        self.inbox.recv().await
+    }
+
+    pub async fn send<S>(&self, peer: &mut S, msg: Holepunch) -> anyhow::Result<()>
+    where
+        S: Sink<Message, Error = std::io::Error> + Unpin,
+    {
+        match self.id {
+            Some(id) => extension::send_extended(peer, id, &msg.to_bytes()).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Acts on the peer's extension handshake and holepunch messages among those `conn` is
+    /// holding, leaving the rest held.
+    pub async fn handle(
+        &mut self,
+        conn: &mut PeerConnection,
+        info_hash: [u8; 20],
+        config: &ClientConfig,
+    ) -> anyhow::Result<()> {
+        for body in std::mem::take(&mut conn.state.extended) {
+            match body.first() {
+                Some(&extension::HANDSHAKE_ID) => {
+                    let theirs: ExtensionHandshake = serde_bencode::from_bytes(&body[1..])
+                        .context("parse extension handshake")?;
+                    self.id = theirs.ut_holepunch();
+                    if let Some(member) = self.relay.lock().members.get_mut(&self.addr) {
+                        member.supports = self.id.is_some();
+                    }
+                }
+                Some(&extension::UT_HOLEPUNCH_ID) => {
+                    let msg = Holepunch::from_bytes(&body[1..])?;
+                    self.on_message(conn, msg, info_hash, config).await?;
+                }
+                _ => conn.state.extended.push_back(body),
+            }
+        }
+        Ok(())
+    }
+
+    async fn on_message(
+        &self,
+        conn: &mut PeerConnection,
+        msg: Holepunch,
+        info_hash: [u8; 20],
+        config: &ClientConfig,
+    ) -> anyhow::Result<()> {
+        match msg.kind {
+            HolepunchKind::Rendezvous => {
+                if let Err(error) = self.relay.introduce(self.addr, msg.addr) {
+                    let reply = Holepunch {
+                        kind: HolepunchKind::Error(error),
+                        addr: msg.addr,
+                    };
+                    self.send(&mut conn.frames, reply).await?;
+                }
+            }
+            HolepunchKind::Connect => self.relay.punch(msg.addr, info_hash, config),
+            HolepunchKind::Error(error) => {
+                let (relay, peer) = (self.addr, msg.addr);
+                tracing::debug!(%relay, %peer, %error, "introduction failed");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RelayMember<'_> {
+    fn drop(&mut self) {
+        self.relay.lock().members.remove(&self.addr);
+    }
+}
+
+/// Whether `error` means the peer couldn't be reached at all, as opposed to misbehaving once
+/// it was.
+pub fn is_unreachable(error: &anyhow::Error) -> bool {
+    if let Some(TimeoutError::Connect(_)) = error.downcast_ref::<TimeoutError>() {
+        return true;
+    }
+    error.downcast_ref::<std::io::Error>().is_some_and(|e| {
+        matches!(
+            e.kind(),
+            std::io::ErrorKind::ConnectionRefused | std::io::ErrorKind::TimedOut
+        )
+    })
+}
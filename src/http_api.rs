@@ -0,0 +1,234 @@
+//! The daemon's operations as an HTTP API, for dashboards and scripts:
+//!
+//! - `GET /torrents` lists every torrent, `POST /torrents` with `{source, output, ...}` adds
+//!   one, taking the same options as the control socket's `add`
+//! - `GET /torrents/{id}` and `GET /torrents/{id}/peers` show one torrent and its peers
+//! - `POST /torrents/{id}/pause` and `POST /torrents/{id}/resume`
+//! - `DELETE /torrents/{id}` removes one, leaving its files on disk
+//! - `GET /alt-speed` shows whether the alternative speed limits are in force, `PUT /alt-speed`
+//!   with `{enabled}` switches them
+//! - `GET /maintenance` reports how the maintenance jobs have been doing, `POST
+//!   /maintenance/{job}` runs one now
+//! - `GET /events`, optionally `?id={id}`, upgrades to a WebSocket streaming torrents' events
+//!   as they happen, one JSON text message each
+//!
+//! Bodies are JSON, as are the `{"error": ...}` bodies of failed requests. Requests go through
+//! the same main loop as the control socket's.
+//!
+//! With a token set, every request must carry it as `Authorization: Bearer {token}`. The
+//! `.torrent` files and outputs of added torrents must lie below the API's root directory.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, Query, State};
+use axum::http::{StatusCode, header};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+
+use crate::config::ClientConfig;
+use crate::daemon::{self, AddParams, AltSpeedParams, Call, HttpOptions, Request, RpcError};
+use crate::maintenance::Job;
+use crate::session::TorrentEvent;
+
+#[derive(Clone)]
+struct Api {
+    options: Arc<HttpOptions>,
+    root: Arc<PathBuf>,
+    calls: mpsc::Sender<Call>,
+    events: broadcast::Sender<TorrentEvent>,
+    config: ClientConfig,
+}
+
+#[derive(Deserialize)]
+struct EventFilter {
+    id: Option<String>,
+}
+
+/// Serves the API on `listener` in the background, handing requests to the daemon's main
+/// loop through `calls` and streaming `events` to subscribers. `root` must already be
+/// canonical.
+pub fn spawn(
+    listener: TcpListener,
+    options: HttpOptions,
+    root: PathBuf,
+    calls: mpsc::Sender<Call>,
+    events: broadcast::Sender<TorrentEvent>,
+    config: ClientConfig,
+) -> JoinHandle<()> {
+    let api = Api {
+        options: Arc::new(options),
+        root: Arc::new(root),
+        calls,
+        events,
+        config,
+    };
+    let router = Router::new()
+        .route("/torrents", get(list).post(add))
+        .route("/torrents/:id", get(torrent).delete(remove))
+        .route("/torrents/:id/peers", get(peers))
+        .route("/torrents/:id/pause", post(pause))
+        .route("/torrents/:id/resume", post(resume))
+        .route("/alt-speed", get(alt_speed).put(set_alt_speed))
+        .route("/maintenance", get(maintenance))
+        .route("/maintenance/:job", post(run_maintenance))
+        .route("/events", get(subscribe))
+        .layer(middleware::from_fn_with_state(api.clone(), authorize))
+        .with_state(api);
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, router).await {
+            tracing::warn!(error = %e, "HTTP API stopped");
+        }
+    })
+}
+
+impl IntoResponse for RpcError {
+    fn into_response(self) -> Response {
+        let status = match self.code {
+            daemon::NO_SUCH_TORRENT => StatusCode::NOT_FOUND,
+            daemon::FAILED => StatusCode::UNPROCESSABLE_ENTITY,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        (status, Json(json!({ "error": self.message }))).into_response()
+    }
+}
+
+/// Turns away requests without the bearer token, if there is one.
+async fn authorize(
+    State(api): State<Api>,
+    request: axum::extract::Request,
+    next: Next,
+) -> Response {
+    let Some(token) = &api.options.token else {
+        return next.run(request).await;
+    };
+    let given = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if given.is_some_and(|given| daemon::same_token(given.as_bytes(), token.as_bytes())) {
+        return next.run(request).await;
+    }
+    let body = Json(json!({ "error": "missing or wrong bearer token" }));
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Bearer")],
+        body,
+    )
+        .into_response()
+}
+
+async fn list(State(api): State<Api>) -> Result<Json<Value>, RpcError> {
+    daemon::submit(&api.calls, Request::Status).await.map(Json)
+}
+
+async fn add(
+    State(api): State<Api>,
+    Json(AddParams { source, params }): Json<AddParams>,
+) -> Result<(StatusCode, Json<Value>), RpcError> {
+    let request = daemon::add_request(&api.root, source, params, &api.config).await?;
+    let id = daemon::submit(&api.calls, request).await?;
+    Ok((StatusCode::CREATED, Json(id)))
+}
+
+async fn torrent(State(api): State<Api>, Path(id): Path<String>) -> Result<Json<Value>, RpcError> {
+    daemon::submit(&api.calls, Request::Torrent(id))
+        .await
+        .map(Json)
+}
+
+async fn peers(State(api): State<Api>, Path(id): Path<String>) -> Result<Json<Value>, RpcError> {
+    daemon::submit(&api.calls, Request::Peers(id))
+        .await
+        .map(Json)
+}
+
+async fn pause(State(api): State<Api>, Path(id): Path<String>) -> Result<StatusCode, RpcError> {
+    daemon::submit(&api.calls, Request::Pause(id)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn resume(State(api): State<Api>, Path(id): Path<String>) -> Result<StatusCode, RpcError> {
+    daemon::submit(&api.calls, Request::Resume(id)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn remove(State(api): State<Api>, Path(id): Path<String>) -> Result<StatusCode, RpcError> {
+    daemon::submit(&api.calls, Request::Remove(id)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn alt_speed(State(api): State<Api>) -> Json<Value> {
+    Json(daemon::alt_speed(&api.config, AltSpeedParams::default()))
+}
+
+async fn set_alt_speed(State(api): State<Api>, Json(params): Json<AltSpeedParams>) -> Json<Value> {
+    Json(daemon::alt_speed(&api.config, params))
+}
+
+async fn maintenance(State(api): State<Api>) -> Result<Json<Value>, RpcError> {
+    daemon::submit(&api.calls, Request::Maintenance(None))
+        .await
+        .map(Json)
+}
+
+async fn run_maintenance(
+    State(api): State<Api>,
+    Path(job): Path<Job>,
+) -> Result<Json<Value>, RpcError> {
+    daemon::submit(&api.calls, Request::Maintenance(Some(job)))
+        .await
+        .map(Json)
+}
+
+async fn subscribe(
+    State(api): State<Api>,
+    Query(EventFilter { id }): Query<EventFilter>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let events = api.events.subscribe();
+    upgrade.on_upgrade(move |socket| stream_events(socket, events, id))
+}
+
+/// Sends `events`, or only those of torrent `id`, until the client goes away.
+async fn stream_events(
+    mut socket: WebSocket,
+    mut events: broadcast::Receiver<TorrentEvent>,
+    id: Option<String>,
+) {
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                // nothing to answer; pings are handled for us
+                Some(Ok(_)) => continue,
+            },
+        };
+        let event = match event {
+            Ok(event) => event,
+            // a slow client misses events rather than holding the others up
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                tracing::debug!(missed, "event subscriber fell behind");
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        if id.as_ref().is_some_and(|id| *id != event.id) {
+            continue;
+        }
+        let text = serde_json::to_string(&event).expect("events serialize to JSON");
+        if socket.send(Message::Text(text)).await.is_err() {
+            return;
+        }
+    }
+}
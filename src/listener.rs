@@ -0,0 +1,332 @@
+//! Accepting connections from peers that found us through a tracker, over TCP and uTP.
+//!
+//! The listen port may be open to the whole internet, so accepts are paced, handshakes in
+//! progress capped and each IP address held to a few connections, and a flood of bogus
+//! connections runs into those limits before it runs us out of file descriptors.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpSocket};
+use tokio::sync::{OwnedSemaphorePermit, mpsc};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::config::ClientConfig;
+use crate::mse::PeerStream;
+use crate::peer::{self, ConnectionLimit, PeerConnection};
+use crate::transport::PeerIo;
+use crate::utp::Endpoint;
+
+/// Ports tried in turn when none is configured.
+pub const DEFAULT_PORTS: RangeInclusive<u16> = 6881..=6889;
+/// Inbound connections queued per torrent before further ones are turned away.
+const INBOUND_BACKLOG: usize = 16;
+/// Connections the kernel queues for us to accept.
+const LISTEN_BACKLOG: u32 = 1024;
+
+/// The torrents we accept connections for, each with a queue its download takes peers from.
+#[derive(Debug, Clone, Default)]
+pub struct Torrents {
+    inner: Arc<Mutex<HashMap<[u8; 20], mpsc::Sender<PeerConnection>>>>,
+}
+
+impl Torrents {
+    /// Starts accepting peers for `info_hash`. They stop being accepted once the receiver
+    /// is dropped.
+    pub fn register(&self, info_hash: [u8; 20]) -> mpsc::Receiver<PeerConnection> {
+        let (tx, rx) = mpsc::channel(INBOUND_BACKLOG);
+        self.lock().insert(info_hash, tx);
+        rx
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<[u8; 20], mpsc::Sender<PeerConnection>>> {
+        self.inner.lock().expect("torrent registry lock poisoned")
+    }
+
+    fn knows(&self, info_hash: &[u8; 20]) -> bool {
+        self.lock().get(info_hash).is_some_and(|tx| !tx.is_closed())
+    }
+
+    fn sender(&self, info_hash: &[u8; 20]) -> Option<mpsc::Sender<PeerConnection>> {
+        self.lock().get(info_hash).cloned()
+    }
+}
+
+/// Limits on inbound connections, shared by the TCP and uTP listeners. Clones share the same
+/// state.
+#[derive(Debug, Clone)]
+pub struct AcceptLimits {
+    /// Time between accepts once a burst of a second's worth has been used up.
+    interval: Duration,
+    burst: Duration,
+    /// When the next accept is due, were they spaced out evenly.
+    due: Arc<Mutex<Instant>>,
+    handshakes: ConnectionLimit,
+    per_ip: usize,
+    /// Open inbound connections by address, handshaking or established.
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl AcceptLimits {
+    /// At most `per_second` accepts a second, `handshakes` inbound handshakes in progress
+    /// and `per_ip` connections from one address.
+    pub fn new(per_second: u32, handshakes: usize, per_ip: usize) -> Self {
+        let interval = Duration::from_secs(1) / per_second.max(1);
+        Self {
+            interval,
+            burst: interval * (per_second.max(1) - 1),
+            due: Arc::new(Mutex::new(Instant::now())),
+            handshakes: ConnectionLimit::new(handshakes),
+            per_ip,
+            open: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Waits until another connection may be accepted. Until then the kernel queues them,
+    /// without them costing us a descriptor.
+    pub async fn pace(&self) {
+        let wait = {
+            let mut due = self.due.lock().expect("accept pacing lock poisoned");
+            let now = Instant::now();
+            let start = (*due).max(now);
+            *due = start + self.interval;
+            start
+                .checked_sub(self.burst)
+                .map_or(Duration::ZERO, |allowed| {
+                    allowed.saturating_duration_since(now)
+                })
+        };
+        tokio::time::sleep(wait).await;
+    }
+
+    /// A share of `ip`'s connections, if it has any left. It is given back when dropped.
+    fn admit_ip(&self, ip: IpAddr) -> Option<IpSlot> {
+        let mut open = self.open.lock().expect("per-IP connection lock poisoned");
+        let count = open.entry(ip).or_default();
+        if *count >= self.per_ip {
+            return None;
+        }
+        *count += 1;
+        Some(IpSlot {
+            ip,
+            open: Arc::clone(&self.open),
+        })
+    }
+}
+
+impl Default for AcceptLimits {
+    fn default() -> Self {
+        Self::new(20, 32, 4)
+    }
+}
+
+#[derive(Debug)]
+struct IpSlot {
+    ip: IpAddr,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().expect("per-IP connection lock poisoned");
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// An inbound stream holding its address's [`IpSlot`] for as long as it is open.
+struct Counted {
+    io: Box<dyn PeerIo>,
+    _slot: IpSlot,
+}
+
+impl AsyncRead for Counted {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Counted {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+/// Binds the first free port of `ports` on the configured local address.
+pub async fn bind(ports: RangeInclusive<u16>, config: &ClientConfig) -> io::Result<TcpListener> {
+    let ip = local_ip(config);
+    let mut last_err = None;
+    for port in ports {
+        match listen(SocketAddr::new(ip, port), config) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no ports to try")))
+}
+
+/// Binds a uTP endpoint on `port`, which should be the TCP listener's.
+pub async fn bind_utp(port: u16, config: &ClientConfig) -> io::Result<Endpoint> {
+    Endpoint::bind(SocketAddr::new(local_ip(config), port)).await
+}
+
+fn local_ip(config: &ClientConfig) -> IpAddr {
+    config.net.bind.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+}
+
+fn listen(addr: SocketAddr, config: &ClientConfig) -> io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    // as `TcpListener::bind` does on Unix, so a restart can rebind straight away
+    socket.set_reuseaddr(true)?;
+    // accepted connections inherit the buffer sizes
+    config.net.tune(&socket)?;
+    socket.bind(addr)?;
+    socket.listen(LISTEN_BACKLOG)
+}
+
+/// Accepts connections on `listener` in the background, handing each peer that completes
+/// the handshake for a registered torrent to that torrent's queue.
+pub fn spawn(listener: TcpListener, torrents: Torrents, config: ClientConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            config.accept.pace().await;
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::warn!(error = %e, "accepting peer connection failed");
+                    continue;
+                }
+            };
+            if let Err(e) = stream.set_nodelay(config.net.nodelay) {
+                tracing::debug!(peer = %addr, error = %e, "setting up inbound peer failed");
+                continue;
+            }
+            admit_in_background(Box::new(stream), addr, &torrents, &config);
+        }
+    })
+}
+
+/// Like [`spawn`], for the uTP connections peers open to `endpoint`.
+pub fn spawn_utp(endpoint: Endpoint, torrents: Torrents, config: ClientConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            config.accept.pace().await;
+            let Some((stream, addr)) = endpoint.accept().await else {
+                return;
+            };
+            admit_in_background(Box::new(stream), addr, &torrents, &config);
+        }
+    })
+}
+
+fn admit_in_background(
+    stream: Box<dyn PeerIo>,
+    addr: SocketAddr,
+    torrents: &Torrents,
+    config: &ClientConfig,
+) {
+    let Some(ip_slot) = config.accept.admit_ip(addr.ip()) else {
+        tracing::debug!(peer = %addr, "turned away inbound peer: too many from its address");
+        return;
+    };
+    let Some(handshake) = config.accept.handshakes.try_acquire() else {
+        tracing::debug!(peer = %addr, "turned away inbound peer: too many handshakes");
+        return;
+    };
+    let Some(slot) = config.connections.try_acquire() else {
+        tracing::debug!(peer = %addr, "turned away inbound peer: too many connections");
+        return;
+    };
+    let stream = Box::new(Counted {
+        io: stream,
+        _slot: ip_slot,
+    });
+    let torrents = torrents.clone();
+    let config = config.clone();
+    tokio::spawn(async move {
+        if let Err(e) = admit(stream, addr, slot, handshake, &torrents, &config).await {
+            tracing::debug!(peer = %addr, error = %e, "rejected inbound peer");
+        }
+    });
+}
+
+async fn admit(
+    mut stream: Box<dyn PeerIo>,
+    addr: SocketAddr,
+    slot: OwnedSemaphorePermit,
+    handshaking: OwnedSemaphorePermit,
+    torrents: &Torrents,
+    config: &ClientConfig,
+) -> anyhow::Result<()> {
+    let handshake =
+        peer::accept_handshake(&mut stream, |hash| torrents.knows(hash), config).await?;
+    drop(handshaking);
+    let tx = torrents
+        .sender(&handshake.info_hash)
+        .ok_or_else(|| anyhow::anyhow!("torrent is no longer active"))?;
+    let stream = PeerStream::plaintext(stream);
+    let conn = PeerConnection::from_stream(addr, stream, handshake, slot, config).await?;
+    tx.try_send(conn)
+        .map_err(|_| anyhow::anyhow!("too many inbound peers waiting"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caps_connections_per_address() {
+        let limits = AcceptLimits::new(20, 32, 2);
+        let (ip, other) = ([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+        let first = limits.admit_ip(ip).unwrap();
+        let _second = limits.admit_ip(ip).unwrap();
+        assert!(limits.admit_ip(ip).is_none());
+        assert!(limits.admit_ip(other).is_some());
+        drop(first);
+        assert!(limits.admit_ip(ip).is_some());
+    }
+
+    #[tokio::test]
+    async fn paces_accepts_after_a_burst() {
+        let limits = AcceptLimits::new(10, 32, 4);
+        let start = Instant::now();
+        for _ in 0..10 {
+            limits.pace().await;
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+        limits.pace().await;
+        assert!(start.elapsed() >= Duration::from_millis(90));
+    }
+}
@@ -0,0 +1,162 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use anyhow::Context;
+
+/// The parts of a `magnet:` URI we use: the v1 info hash, display name, trackers and file
+/// selection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+    /// BEP 53 `so=`: the indices of the files to download, or `None` for all of them.
+    pub select_only: Option<Vec<RangeInclusive<usize>>>,
+}
+
+impl Magnet {
+    /// Whether the link asks for file `index`, in the order the info dictionary lists them.
+    pub fn selects(&self, index: usize) -> bool {
+        self.select_only
+            .as_ref()
+            .is_none_or(|ranges| ranges.iter().any(|range| range.contains(&index)))
+    }
+}
+
+impl FromStr for Magnet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = reqwest::Url::parse(s).context("parse magnet link")?;
+        anyhow::ensure!(url.scheme() == "magnet", "not a magnet link");
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut select_only: Option<Vec<_>> = None;
+        for (key, value) in url.query_pairs() {
+            match &*key {
+                "xt" => {
+                    let Some(hash) = value.strip_prefix("urn:btih:") else {
+                        continue;
+                    };
+                    let hash = hex::decode(hash).context("info hash must be 40 hex digits")?;
+                    info_hash = Some(
+                        <[u8; 20]>::try_from(hash)
+                            .map_err(|_| anyhow::anyhow!("info hash must be 20 bytes"))?,
+                    );
+                }
+                "dn" => name = Some(value.into_owned()),
+                "tr" => trackers.push(value.into_owned()),
+                "so" => select_only
+                    .get_or_insert_with(Vec::new)
+                    .extend(parse_select_only(&value)?),
+                _ => {}
+            }
+        }
+        Ok(Self {
+            info_hash: info_hash.context("magnet link has no btih info hash")?,
+            name,
+            trackers,
+            select_only,
+        })
+    }
+}
+
+impl fmt::Display for Magnet {
+    /// Writes the link back out, with just the parts we keep.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "magnet:?xt=urn:btih:{}", hex::encode(self.info_hash))?;
+        let mut params: Vec<_> = self.name.iter().map(|name| ("dn", name.clone())).collect();
+        params.extend(self.trackers.iter().map(|tracker| ("tr", tracker.clone())));
+        if let Some(ranges) = &self.select_only {
+            let ranges: Vec<_> = ranges
+                .iter()
+                .map(|range| match (range.start(), range.end()) {
+                    (first, last) if first == last => first.to_string(),
+                    (first, last) => format!("{first}-{last}"),
+                })
+                .collect();
+            params.push(("so", ranges.join(",")));
+        }
+        if !params.is_empty() {
+            let params = serde_urlencoded::to_string(params).map_err(|_| fmt::Error)?;
+            write!(f, "&{params}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a BEP 53 file list such as `0,2,4,6-8`.
+fn parse_select_only(value: &str) -> anyhow::Result<Vec<RangeInclusive<usize>>> {
+    value
+        .split(',')
+        .map(|part| {
+            let (first, last) = part.split_once('-').unwrap_or((part, part));
+            let (first, last) = (first.parse()?, last.parse()?);
+            anyhow::ensure!(first <= last, "file range {part} is backwards");
+            Ok(first..=last)
+        })
+        .collect::<anyhow::Result<_>>()
+        .with_context(|| format!("parse select-only files {value:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f";
+
+    #[test]
+    fn parses_links() {
+        let link =
+            format!("magnet:?xt=urn:btih:{HASH}&dn=sample.torrent&tr=http%3A%2F%2Ft%2Fannounce");
+        let magnet: Magnet = link.parse().unwrap();
+        assert_eq!(hex::encode(magnet.info_hash), HASH);
+        assert_eq!(magnet.name.as_deref(), Some("sample.torrent"));
+        assert_eq!(magnet.trackers, ["http://t/announce"]);
+        assert!(magnet.select_only.is_none());
+        assert!(magnet.selects(12));
+        assert_eq!(magnet.to_string(), link);
+    }
+
+    #[test]
+    fn selects_only_the_listed_files() {
+        let link = format!("magnet:?xt=urn:btih:{HASH}&so=0,2,4-6&so=9");
+        let magnet: Magnet = link.parse().unwrap();
+        assert_eq!(magnet.select_only, Some(vec![0..=0, 2..=2, 4..=6, 9..=9]));
+        let selected: Vec<_> = (0..10).filter(|&index| magnet.selects(index)).collect();
+        assert_eq!(selected, [0, 2, 4, 5, 6, 9]);
+        assert_eq!(
+            magnet.to_string(),
+            format!("magnet:?xt=urn:btih:{HASH}&so=0%2C2%2C4-6%2C9")
+        );
+        assert_eq!(magnet.to_string().parse::<Magnet>().unwrap(), magnet);
+    }
+
+    #[test]
+    fn rejects_bad_file_selections() {
+        for so in ["3-1", "a", "1-", ""] {
+            let link = format!("magnet:?xt=urn:btih:{HASH}&so={so}");
+            let error = link.parse::<Magnet>().unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("parse select-only files {so:?}"),
+                "so={so}"
+            );
+        }
+    }
+
+    #[test]
+    fn needs_a_v1_info_hash() {
+        let error = "magnet:?dn=x".parse::<Magnet>().unwrap_err();
+        assert_eq!(error.to_string(), "magnet link has no btih info hash");
+        assert!("magnet:?xt=urn:btih:abcd".parse::<Magnet>().is_err());
+        assert!(
+            "http://example.com/?xt=urn:btih:00"
+                .parse::<Magnet>()
+                .is_err()
+        );
+    }
+}
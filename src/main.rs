@@ -1,241 +1,1774 @@
-#![feature(addr_parse_ascii)]
-
-use std::net::SocketAddrV4;
+use std::future::Future;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime};
 
 use anyhow::Context;
-use clap::Parser;
-use futures_util::{SinkExt, StreamExt};
-use sha1::{Digest, Sha1};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-
-use bittorrent_starter_rust::{
-    Args, Commands, decode_bencoded, Handshake, Message, MessageFramer, MessageTag, Piece,
-    Request, Torrent, TrackerRequest, TrackerResponse, urlencode,
+use futures_util::stream::FuturesUnordered;
+use futures_util::{Sink, StreamExt};
+use tokio::sync::watch;
+
+use bittorrent_starter_rust::{Torrent, TrackerResponse};
+
+use crate::bitfield::Bitfield;
+use crate::cli::{
+    Capability, Commands, ConfigCommand, DaemonCommand, OutputFormat, StatsCommand, Switch,
 };
+use crate::config::ClientConfig;
+use crate::create::TorrentBuilder;
+use crate::daemon::{ControlAddr, ControlOptions, HttpOptions};
+use crate::download::{Snubbed, Superseded};
+use crate::extension::ExtensionHandshake;
+use crate::failures::{HashFailures, HashMismatch, TooManyHashFailures};
+use crate::geometry::PieceGeometry;
+use crate::hash::TorrentVersion;
+use crate::have::HaveBroadcast;
+use crate::holepunch::Relay;
+use crate::listener::{AcceptLimits, Torrents};
+use crate::magnet::Magnet;
+use crate::metadata_cache::MetadataCache;
+use crate::net::NetConfig;
+use crate::peer::{ConnectionLimit, PeerConnection};
+use crate::peer_id::PeerId;
+use crate::peer_source::{PeerSources, StaticPeers, TrackerSource};
+use crate::picker::{FilePriorities, Priority};
+use crate::resume::{ResumeData, ResumeStore};
+use crate::retry::PeerBook;
+use crate::scheduler::PieceScheduler;
+use crate::session::AddTorrentParams;
+use crate::session_stats::SessionStats;
+use crate::session_store::SessionStore;
+use crate::sink::{Delivery, DiskWriter, PieceForwarder, VerifiedPiece};
+use crate::speed::SpeedLimits;
+use crate::stats::TransferRecord;
+use crate::timeout::Timeouts;
+use crate::top::TopOptions;
+use crate::tracker::{AnnounceParams, Announcer, Event, Tiers};
+use crate::transport::TransportPolicy;
+use crate::upload::{UploadSource, Uploader};
+use crate::wire::Capabilities;
+use crate::wire_trace::TraceFile;
+
+mod bencode;
+mod bitfield;
+mod cli;
+mod config;
+mod config_file;
+mod conformance;
+mod create;
+mod daemon;
+mod download;
+mod extension;
+mod failures;
+mod geometry;
+mod hash;
+mod have;
+mod holepunch;
+#[cfg(feature = "http-api")]
+mod http_api;
+mod listener;
+mod logging;
+mod magnet;
+mod maintenance;
+mod message;
+mod metadata_cache;
+mod metainfo;
+mod mse;
+mod net;
+mod peer;
+mod peer_id;
+mod peer_source;
+mod picker;
+mod portmap;
+mod proxy;
+mod resolve;
+mod resume;
+mod retry;
+mod scheduler;
+mod session;
+mod session_stats;
+mod session_store;
+mod sink;
+mod speed;
+mod stats;
+mod storage;
+mod stream;
+mod supervisor;
+#[cfg(test)]
+mod testsupport;
+mod timeout;
+mod top;
+mod tracker;
+mod transport;
+mod upload;
+mod utp;
+mod v2;
+mod verify;
+mod webseed;
+mod wire;
+mod wire_trace;
 
-const BLOCK_MAX: usize = 1 << 14;
+/// Whether to print exactly what the codecrafters stage tests expect rather than the richer
+/// default output.
+const CODECRAFTERS: bool = cfg!(feature = "codecrafters");
+
+/// In sequential mode, how many pieces past the first missing one may be in flight, per peer
+/// the download may use.
+const READAHEAD_PER_PEER: usize = 2;
+/// How often a download looks for more peers and checks whether it has stalled, even while
+/// no peer task finishes.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Ctrl-C arrived before a command finished. Whatever it was doing has already been wound
+/// down by then.
+#[derive(Debug, thiserror::Error)]
+#[error("interrupted")]
+struct Interrupted;
 
 // Usage: your_bittorrent.sh decode "<encoded_value>"
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+async fn main() -> anyhow::Result<ExitCode> {
+    match run().await {
+        Ok(()) => Ok(ExitCode::SUCCESS),
+        // as a shell reports a process killed by SIGINT
+        Err(e) if e.is::<Interrupted>() => Ok(ExitCode::from(130)),
+        Err(e) => Err(e),
+    }
+}
+
+async fn run() -> anyhow::Result<()> {
+    let (args, layers) = config_file::parse()?;
+    logging::init(args.verbose, args.log_json);
+    let mut capabilities = Capabilities::default();
+    for disabled in &args.disabled {
+        match disabled {
+            Capability::Dht => capabilities.dht = false,
+            Capability::Fast => capabilities.fast = false,
+            Capability::Extensions => capabilities.extensions = false,
+        }
+    }
+    let mut config = ClientConfig {
+        peer_id: args.peer_id.unwrap_or_else(PeerId::generate),
+        timeouts: Timeouts {
+            connect: Duration::from_secs(args.connect_timeout),
+            handshake: Duration::from_secs(args.handshake_timeout),
+            announce: Duration::from_secs(args.announce_timeout),
+            block: Duration::from_secs(args.block_timeout),
+            request: Duration::from_secs(args.request_timeout),
+            piece: Duration::from_secs(args.piece_timeout),
+        },
+        net: NetConfig {
+            bind: args.bind,
+            interface: args.interface,
+            proxy: args.proxy,
+            nodelay: !args.no_nodelay,
+            send_buffer: args.send_buffer,
+            recv_buffer: args.recv_buffer,
+        },
+        max_hash_failures: args.max_hash_failures,
+        max_tracker_response: args.max_tracker_response,
+        numwant: args.numwant,
+        connections: ConnectionLimit::new(args.max_peers as usize),
+        half_open: ConnectionLimit::new(args.max_half_open as usize),
+        accept: AcceptLimits::new(
+            args.max_accepts_per_sec,
+            args.max_inbound_handshakes as usize,
+            args.max_connections_per_ip as usize,
+        ),
+        max_connections_per_torrent: args.max_connections_per_torrent as usize,
+        upload_slots: ConnectionLimit::new(args.max_upload_slots as usize),
+        capabilities,
+        strict: args.strict,
+        block_size: args.block_size as usize,
+        encryption: args.encryption,
+        transport: args.transport,
+        transport_policy: TransportPolicy {
+            private: args.private_peers,
+            public: args.public_peers,
+        },
+        fsync: args.fsync,
+        mmap: args.mmap,
+        speed: SpeedLimits::new(
+            args.download_limit.map(|kib| u64::from(kib) << 10),
+            args.alt_download_limit.map(|kib| u64::from(kib) << 10),
+            args.alt_speed_window,
+            args.alt_speed,
+        ),
+        sequential: args.sequential,
+        // the stage tests run in a sandbox that shouldn't be written to behind their back
+        metadata_cache: match args.metadata_cache {
+            _ if args.no_metadata_cache => None,
+            Some(dir) => Some(MetadataCache::new(dir)),
+            None if CODECRAFTERS => None,
+            None => MetadataCache::default_dir().map(MetadataCache::new),
+        },
+        wire_trace: args
+            .trace_wire
+            .as_deref()
+            .map(TraceFile::create)
+            .transpose()
+            .context("create wire trace file")?,
+        ..Default::default()
+    };
+    if let Some(key) = args.tracker_key {
+        config.tracker_key = key;
+    }
+    let inbound = if args.listen {
+        let ports = args
+            .port
+            .map_or(listener::DEFAULT_PORTS, |port| port..=port);
+        let listener = listener::bind(ports, &config)
+            .await
+            .context("listen for peers")?;
+        config.port = listener.local_addr().context("listen for peers")?.port();
+        tracing::info!(port = config.port, "accepting peer connections");
+        match listener::bind_utp(config.port, &config).await {
+            Ok(endpoint) => config.utp = Some(endpoint),
+            // TCP peers can still reach us; only uTP and holepunching are lost
+            Err(e) => tracing::warn!(port = config.port, error = %e, "can't listen for uTP peers"),
+        }
+        if args.map_port {
+            portmap::spawn(config.port, config.utp.is_some());
+        }
+        let torrents = Torrents::default();
+        listener::spawn(listener, torrents.clone(), config.clone());
+        if let Some(endpoint) = config.utp.clone() {
+            listener::spawn_utp(endpoint, torrents.clone(), config.clone());
+        }
+        Some(torrents)
+    } else {
+        None
+    };
+    let announcer = Announcer::new(&config)?;
+    let stats = SessionStats::new();
+    let stats_interval = args.stats_interval.map(Duration::from_secs);
     match args.commands {
-        Commands::Decode { value } => {
-            let v = decode_bencoded(&value).0;
-            println!("{v}");
+        Commands::Decode { value, file } => {
+            if let Some(value) = value {
+                let (v, _) = bencode::decode_bytes(value.as_bytes())?;
+                println!("{v}");
+                return Ok(());
+            }
+            let input = match file {
+                Some(path) => {
+                    std::fs::read(&path).with_context(|| format!("read {}", path.display()))?
+                }
+                None => {
+                    let mut input = Vec::new();
+                    std::io::stdin()
+                        .read_to_end(&mut input)
+                        .context("read stdin")?;
+                    input
+                }
+            };
+            // the input may hold several values back to back, e.g. a captured peer stream
+            let input = input.trim_ascii_end();
+            let mut rest = input;
+            while !rest.is_empty() {
+                let decoded_len = input.len() - rest.len();
+                let (v, remaining) =
+                    bencode::decode_bytes(rest).map_err(|e| e.shifted(decoded_len))?;
+                println!("{v}");
+                rest = remaining;
+            }
+        }
+        Commands::Encode { value } => {
+            let v: serde_json::Value = serde_json::from_str(&value).context("parse JSON value")?;
+            let encoded = bencode::encode_bencoded(&v)?;
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(&encoded).context("write encoded value")?;
+            stdout.write_all(b"\n").context("write encoded value")?;
+        }
+        Commands::Info { torrent, format } => {
+            let f = metainfo::load(&torrent, &config).await?;
+            let v2 = v2::parse(&f)?;
+            // a v2-only torrent has no `pieces`, so the library can't read it
+            if let Some(v2) = v2.as_ref().filter(|v2| !v2.hybrid) {
+                match format {
+                    OutputFormat::Plain => print_v2_info(v2),
+                    OutputFormat::Json => print_json(&v2_info_json(v2))?,
+                }
+                return Ok(());
+            }
+            let t: Torrent = serde_bencode::from_bytes(&f).context("parse torrent file")?;
+            match format {
+                OutputFormat::Plain => {
+                    print_info(&t);
+                    if let Some(v2) = v2.as_ref().filter(|_| !CODECRAFTERS) {
+                        println!("Meta Version: hybrid");
+                        println!("Info Hash v2: {}", hex::encode(v2.info_hash));
+                        print_v2_files(v2);
+                    }
+                }
+                OutputFormat::Json => {
+                    let mut json = info_json(&t, &f);
+                    if let Some(v2) = &v2 {
+                        json["meta_version"] = "hybrid".into();
+                        json["info_hash_v2"] = hex::encode(v2.info_hash).into();
+                        json["files"] = v2_info_json(v2)["files"].take();
+                    }
+                    print_json(&json)?;
+                }
+            }
         }
-        Commands::Info { torrent } => {
-            let f = std::fs::read(torrent).context("read torrent file")?;
+        Commands::Peers {
+            torrent,
+            probe,
+            format,
+        } => {
+            let f = metainfo::load(&torrent, &config).await?;
             let t: Torrent = serde_bencode::from_bytes(&f).context("parse torrent file")?;
-            // eprintln!("{t:?}");
-            println!("Tracker URL: {}", t.announce);
 
-            let length = t.length();
-            println!("Length: {}", length);
+            let mut tiers = Tiers::new(metainfo::tiers(&f, &t));
+            let response = announcer.announce_torrent(&t, &mut tiers, None).await?;
+            if !probe {
+                match format {
+                    OutputFormat::Plain => {
+                        for peer in response.peers.0 {
+                            println!("{}:{}", peer.ip(), peer.port());
+                        }
+                    }
+                    OutputFormat::Json => {
+                        let peers: Vec<_> = response
+                            .peers
+                            .0
+                            .iter()
+                            .map(|peer| serde_json::json!({ "addr": peer.to_string() }))
+                            .collect();
+                        print_json(&serde_json::Value::Array(peers))?;
+                    }
+                }
+                return Ok(());
+            }
 
             let info_hash = t.info_hash();
-            println!("Info Hash: {}", hex::encode(info_hash));
-            println!("Piece Length: {}", t.info.plength);
-            println!("Piece Hashes:");
-            for hash in t.info.pieces.0 {
-                println!("{}", hex::encode(hash));
+            let peers: Vec<_> = response
+                .peers
+                .0
+                .iter()
+                .map(|&p| SocketAddr::from(p))
+                .collect();
+            let probes = peers.iter().map(|&peer| {
+                let config = &config;
+                async move {
+                    let _slot = config.connections.acquire().await;
+                    peer::connect(peer, info_hash, config).await
+                }
+            });
+            let results = futures_util::future::join_all(probes).await;
+            let mut report = Vec::new();
+            for (peer, result) in peers.iter().zip(results) {
+                let (client, error) = match result {
+                    Ok((_, handshake)) => (
+                        peer_id::client(&handshake.peer_id).map(|c| c.to_string()),
+                        None,
+                    ),
+                    Err(e) => (None, Some(format!("{e:#}"))),
+                };
+                match format {
+                    OutputFormat::Plain => match &error {
+                        None => {
+                            let client = client.as_deref().unwrap_or("unknown client");
+                            println!("{peer} reachable, {client}");
+                        }
+                        Some(e) => println!("{peer} unreachable: {e}"),
+                    },
+                    OutputFormat::Json => report.push(serde_json::json!({
+                        "addr": peer.to_string(),
+                        "reachable": error.is_none(),
+                        "client": client,
+                        "error": error,
+                    })),
+                }
+            }
+            if format == OutputFormat::Json {
+                print_json(&serde_json::Value::Array(report))?;
             }
         }
-        Commands::Peers { torrent } => {
-            let f = std::fs::read(torrent).context("read torrent file")?;
+        Commands::Handshake { torrent, peer } => {
+            let f = metainfo::load(&torrent, &config).await?;
             let t: Torrent = serde_bencode::from_bytes(&f).context("parse torrent file")?;
 
-            let length = t.length();
             let info_hash = t.info_hash();
-            let request = TrackerRequest {
-                peer_id: "00112233445566778899".to_string(),
-                port: 6881,
-                uploaded: 0,
-                downloaded: 0,
-                left: length,
-                compact: 1,
-            };
-
-            let url_params =
-                serde_urlencoded::to_string(&request).context("url-encode tracker parameters")?;
 
-            let tracker_url = format!(
-                "{}?{}&info_hash={}",
-                t.announce,
-                url_params,
-                &urlencode(&info_hash)
-            );
-
-            let response = reqwest::get(tracker_url)
+            let endpoints: Vec<_> = tokio::net::lookup_host(&peer)
                 .await
-                .context("query tracker")?
-                .bytes()
-                .await
-                .context("fetch tracker response")?;
-            let response: TrackerResponse =
-                serde_bencode::from_bytes(&response).context("parse tracker response")?;
-            for peer in response.peers.0 {
-                println!("{}:{}", peer.ip(), peer.port());
-            }
+                .context("resolve peer addr")?
+                .collect();
+            let (_, handshake, _) = peer::connect_any(&endpoints, info_hash, &config).await?;
+            println!("Peer ID: {}", hex::encode(handshake.peer_id));
         }
-        Commands::Handshake { torrent, peer } => {
-            let f = std::fs::read(torrent).context("read torrent file")?;
+        Commands::Announce {
+            torrent,
+            tracker,
+            event,
+            uploaded,
+            downloaded,
+            left,
+        } => {
+            let f = metainfo::load(&torrent, &config).await?;
+            let t: Torrent = serde_bencode::from_bytes(&f).context("parse torrent file")?;
+            let params = AnnounceParams {
+                uploaded,
+                downloaded,
+                left: left.unwrap_or_else(|| t.length()),
+                event,
+            };
+            let tracker = tracker.as_deref().unwrap_or(&t.announce);
+            let response = announcer
+                .announce_raw(tracker, t.info_hash(), &params)
+                .await?;
+            let (value, _) = bencode::decode_bytes(&response).context("decode tracker response")?;
+            println!("{value}");
+        }
+        Commands::Conformance { torrent, peer } => {
+            let f = metainfo::load(&torrent, &config).await?;
             let t: Torrent = serde_bencode::from_bytes(&f).context("parse torrent file")?;
 
-            let info_hash = t.info_hash();
-
-            let peer = peer.parse::<SocketAddrV4>().context("parse peer addr")?;
-            let mut peer = tokio::net::TcpStream::connect(peer)
+            let peer = tokio::net::lookup_host(&peer)
                 .await
-                .context("connect to peer")?;
-            let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
-            {
-                let handshake_bytes =
-                    &mut handshake as *mut Handshake as *mut [u8; std::mem::size_of::<Handshake>()];
-                // Safety: Handshake is a POD(Plain of Data) with repr(C)
-                // which means any byte pattern is valid
-                let handshake_bytes: &mut [u8; std::mem::size_of::<Handshake>()] =
-                    unsafe { &mut *handshake_bytes };
-                peer.write_all(handshake_bytes)
-                    .await
-                    .context("write handshake")?;
-                peer.read_exact(handshake_bytes)
-                    .await
-                    .context("read handshake")?;
+                .context("resolve peer addr")?
+                .next()
+                .context("peer addr did not resolve")?;
+            let checks = conformance::run(&t, peer, &config).await;
+            let failed = checks
+                .iter()
+                .filter(|c| matches!(c.outcome, conformance::Outcome::Fail(_)))
+                .count();
+            for check in &checks {
+                println!("{check}");
+            }
+            println!("{} checks, {failed} failed", checks.len());
+            if failed > 0 {
+                std::process::exit(1);
+            }
+        }
+        Commands::Verify { torrent, path, v2 } => {
+            let f = metainfo::load(&torrent, &config).await?;
+            let v2_info = v2::parse(&f)?;
+            let report = match (v2_info, v2) {
+                (Some(info), _) if v2 || !info.hybrid => verify::verify_v2(&info, &path)?,
+                (None, true) => anyhow::bail!("torrent has no v2 metadata"),
+                _ => {
+                    let t: Torrent = serde_bencode::from_bytes(&f).context("parse torrent file")?;
+                    verify::verify_v1(&t, &f, &path)?
+                }
+            };
+            for bad in &report.bad {
+                match &bad.file {
+                    Some(file) => println!("{file}: piece {} bad", bad.index),
+                    None => println!("piece {} bad", bad.index),
+                }
+            }
+            let good = report.pieces - report.bad.len();
+            println!("{good}/{} pieces ok", report.pieces);
+            if !report.bad.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Commands::Recheck {
+            torrent,
+            path,
+            resume_dir,
+        } => {
+            let f = metainfo::load(&torrent, &config).await?;
+            let t: Torrent = serde_bencode::from_bytes(&f)
+                .context("parse torrent file; recheck needs v1 piece hashes")?;
+            let resume = resume_dir
+                .or_else(ResumeStore::default_dir)
+                .map(ResumeStore::new)
+                .context("no resume directory; pass --resume-dir")?;
+            let have = verify::recheck_v1(&t, &f, &path)?;
+            let written = resume.store(&ResumeData::new(t.info_hash(), &path, &have))?;
+            println!(
+                "{}/{} pieces, {:.1}% complete",
+                have.count(),
+                have.len(),
+                100.0 * have.count() as f64 / have.len().max(1) as f64
+            );
+            println!("resume file written to {}", written.display());
+        }
+        Commands::CreateTorrent {
+            path,
+            output,
+            announce,
+            tiers,
+            comment,
+            private,
+            piece_length,
+            meta_version,
+        } => {
+            let mut builder = TorrentBuilder::from_path(&path)?
+                .announce(announce)
+                .private(private)
+                .piece_length(piece_length)
+                .meta_version(meta_version);
+            for tier in &tiers {
+                builder = builder.tier(tier.split(',').map(str::to_string).collect());
+            }
+            if let Some(comment) = comment {
+                builder = builder.comment(comment);
+            }
+            let created = builder.build()?;
+            std::fs::write(&output, created.to_bytes()?).context("write torrent file")?;
+            if let Some(info_hash) = created.info_hash()? {
+                println!("Info Hash: {}", hex::encode(info_hash));
+            }
+            if let Some(info_hash) = created.info_hash_v2()? {
+                println!("Info Hash v2: {}", hex::encode(info_hash));
             }
-            assert_eq!(handshake.length, 19);
-            assert_eq!(&handshake.bittorrent, b"BitTorrent protocol");
-            println!("Peer ID: {}", hex::encode(&handshake.peer_id));
         }
         Commands::DownloadPiece {
             output,
             torrent,
             piece,
+            peers,
         } => {
-            let f = std::fs::read(torrent).context("read torrent file")?;
+            let f = metainfo::load(&torrent, &config).await?;
             let t: Torrent = serde_bencode::from_bytes(&f).context("parse torrent file")?;
-            let length = t.length();
-            let info_hash = t.info_hash();
-
             assert!(piece < t.info.pieces.0.len());
 
-            let request = TrackerRequest {
-                peer_id: "00112233445566778899".to_string(),
-                port: 6881,
-                uploaded: 0,
-                downloaded: 0,
-                left: length,
-                compact: 1,
+            let geometry = PieceGeometry::of(&t);
+            let web_seeds = webseed::web_seeds(&f, &t);
+            let files = metainfo::files(&f, &t);
+            let download = async {
+                let tiers = Tiers::new(metainfo::tiers(&f, &t));
+                let mut sources = peer_sources(&peers, &announcer, &t, tiers);
+                match download_from_swarm(&t, &files, piece, &mut sources, &config).await {
+                    Ok(all_blocks) => Ok(all_blocks),
+                    Err(e) if !web_seeds.is_empty() => {
+                        tracing::warn!(error = %e, "peers failed, trying web seeds");
+                        webseed::fetch_piece(&web_seeds, &t, piece, &config).await
+                    }
+                    Err(e) => Err(e),
+                }
             };
+            let all_blocks =
+                until_interrupted(download, &t.announce, t.info_hash(), &announcer).await?;
 
-            let url_params =
-                serde_urlencoded::to_string(&request).context("url-encode tracker parameters")?;
-
-            let tracker_url = format!(
-                "{}?{}&info_hash={}",
-                t.announce,
-                url_params,
-                &urlencode(&info_hash)
+            let file = tokio::fs::File::create(&output)
+                .await
+                .context("create output file")?;
+            let mut forwarder = PieceForwarder::new(
+                Box::pin(sink::file_sink(file, t.info.plength, piece)),
+                Delivery::InOrder,
+                piece,
             );
-
-            let response = reqwest::get(tracker_url)
+            forwarder
+                .piece_verified(VerifiedPiece {
+                    index: piece,
+                    data: all_blocks,
+                })
                 .await
-                .context("query tracker")?
-                .bytes()
+                .context("write out downloaded piece")?;
+            forwarder.close().await.context("close output file")?;
+            if CODECRAFTERS {
+                println!("piece {:?} downloaded to {:?}.", piece, output);
+            } else {
+                println!(
+                    "Piece {piece} ({} bytes) downloaded to {}.",
+                    geometry.piece_len(piece),
+                    output.display()
+                );
+            }
+        }
+        Commands::Download {
+            output,
+            torrent,
+            peers,
+            files,
+        } => {
+            let f = metainfo::load(&torrent, &config).await?;
+            let t: Torrent = serde_bencode::from_bytes(&f).context("parse torrent file")?;
+            // exactly as the file has it: re-encoding drops keys `Torrent` doesn't model
+            if let Some(info) = v2::info_bytes(&f) {
+                let info_hash = TorrentVersion::V1.digest(info);
+                let info_hash = info_hash.try_into().expect("SHA-1 digests are 20 bytes");
+                remember_metadata(info_hash, info, &config);
+            }
+            let priorities = if files.is_empty() {
+                None
+            } else {
+                let indices = file_indices(&f, &t, &files)?;
+                Some(selected_files(&f, &t, |index| indices.contains(&index)))
+            };
+
+            let started = SystemTime::now();
+            let download = download_torrent(
+                &f,
+                &t,
+                &peers,
+                &[],
+                priorities.as_deref(),
+                &output,
+                inbound.as_ref(),
+                &announcer,
+                &stats,
+                &config,
+            );
+            let download = reporting_stats(download, &stats, stats_interval);
+            until_interrupted(download, &t.announce, t.info_hash(), &announcer).await?;
+            record_transfer(&t, started, &stats, &config);
+            print_downloaded(&t, &torrent.display().to_string(), &output);
+        }
+        Commands::Stream {
+            output,
+            torrent,
+            addr,
+            peers,
+        } => {
+            let f = metainfo::load(&torrent, &config).await?;
+            let t: Torrent = serde_bencode::from_bytes(&f).context("parse torrent file")?;
+            let path = config.transport_policy.path(metainfo::is_private(&f));
+            let listener = tokio::net::TcpListener::bind(addr)
                 .await
-                .context("fetch tracker response")?;
-            let tracker_info: TrackerResponse =
-                serde_bencode::from_bytes(&response).context("parse tracker response")?;
+                .context("bind stream server")?;
+            let npieces = t.info.pieces.0.len();
+            let (written, progress) = watch::channel(Bitfield::new(npieces));
+            let served = stream::Served {
+                path: output.clone(),
+                geometry: PieceGeometry::of(&t),
+                written: progress,
+            };
+            let addr = listener.local_addr().context("bind stream server")?;
+            println!("Streaming {} at http://{addr}/", t.info.name);
+            let server = stream::spawn(listener, served);
+            // a player reads from the start, so fetch pieces in the order it will want them
+            let config = ClientConfig {
+                sequential: true,
+                ..path.apply(&config)?
+            };
+
+            let started = SystemTime::now();
+            let download = async {
+                let tiers = Tiers::new(metainfo::tiers(&f, &t));
+                let mut sources = peer_sources(&peers, &announcer, &t, tiers);
+                if let Some(inbound) = inbound.as_ref().filter(|_| path.accepts_inbound()) {
+                    sources.set_inbound(inbound.register(t.info_hash()));
+                }
+                let files = metainfo::files(&f, &t);
+                let download = download_pieces(
+                    None,
+                    &mut sources,
+                    &t,
+                    &files,
+                    0..npieces,
+                    None,
+                    &output,
+                    Some(written),
+                    &stats,
+                    &config,
+                );
+                reporting_stats(download, &stats, stats_interval).await
+            };
+            until_interrupted(download, &t.announce, t.info_hash(), &announcer).await?;
+            record_transfer(&t, started, &stats, &config);
+            print_downloaded(&t, &torrent.display().to_string(), &output);
+            eprintln!("Still serving; press Ctrl-C to stop.");
+            tokio::select! {
+                _ = server => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        }
+        Commands::MagnetParse { link } => {
+            let magnet: Magnet = link.parse()?;
+            if CODECRAFTERS {
+                if let Some(tracker) = magnet.trackers.first() {
+                    println!("Tracker URL: {tracker}");
+                }
+            } else {
+                if let Some(name) = &magnet.name {
+                    println!("Name: {name}");
+                }
+                for tracker in &magnet.trackers {
+                    println!("Tracker URL: {tracker}");
+                }
+                if let Some(ranges) = &magnet.select_only {
+                    let ranges: Vec<_> = ranges
+                        .iter()
+                        .map(|range| {
+                            if range.start() == range.end() {
+                                range.start().to_string()
+                            } else {
+                                format!("{}-{}", range.start(), range.end())
+                            }
+                        })
+                        .collect();
+                    println!("Select Only: {}", ranges.join(","));
+                }
+            }
+            println!("Info Hash: {}", hex::encode(magnet.info_hash));
+        }
+        Commands::MagnetHandshake { link } => {
+            let magnet: Magnet = link.parse()?;
+            let (conn, theirs, _) = magnet_connect(&magnet, &config, &announcer).await?;
+            println!("Peer ID: {}", hex::encode(conn.handshake.peer_id));
+            if let Some(id) = theirs.ut_metadata() {
+                println!("Peer Metadata Extension ID: {id}");
+            }
+        }
+        Commands::MagnetInfo { link } => {
+            let magnet: Magnet = link.parse()?;
+            let (_, t) = match cached_torrent(&magnet, &config)? {
+                Some(cached) => cached,
+                None => {
+                    let (mut conn, theirs, _) =
+                        magnet_connect(&magnet, &config, &announcer).await?;
+                    magnet_torrent(&magnet, &mut conn, &theirs, &config).await?
+                }
+            };
+            print_info(&t);
+        }
+        Commands::MagnetDownloadPiece {
+            output,
+            link,
+            piece,
+        } => {
+            let magnet: Magnet = link.parse()?;
+            let download = async {
+                let (mut conn, theirs, peers) =
+                    magnet_connect(&magnet, &config, &announcer).await?;
+                let (metainfo, t) = magnet_torrent(&magnet, &mut conn, &theirs, &config).await?;
+                anyhow::ensure!(
+                    piece < t.info.pieces.0.len(),
+                    "torrent only has {} pieces",
+                    t.info.pieces.0.len()
+                );
+                let path = config
+                    .transport_policy
+                    .path(metainfo::is_private(&metainfo));
+                let conn = path.keeps_configured().then_some(conn);
+                let config = path.apply(&config)?;
+                let mut sources = PeerSources::new();
+                sources.add(StaticPeers(peers));
+                if let Some(inbound) = inbound.as_ref().filter(|_| path.accepts_inbound()) {
+                    sources.set_inbound(inbound.register(magnet.info_hash));
+                }
+                let pieces = piece..piece + 1;
+                let files = metainfo::files(&metainfo, &t);
+                let download = download_pieces(
+                    conn,
+                    &mut sources,
+                    &t,
+                    &files,
+                    pieces,
+                    None,
+                    &output,
+                    None,
+                    &stats,
+                    &config,
+                );
+                reporting_stats(download, &stats, stats_interval).await?;
+                anyhow::Ok(t)
+            };
+            let tracker = magnet.trackers.first().map_or("", String::as_str);
+            let t = until_interrupted(download, tracker, magnet.info_hash, &announcer).await?;
+            if CODECRAFTERS {
+                println!("Piece {piece} downloaded to {}.", output.display());
+            } else {
+                println!(
+                    "Piece {piece} ({} bytes) downloaded to {}.",
+                    PieceGeometry::of(&t).piece_len(piece),
+                    output.display()
+                );
+            }
+        }
+        Commands::ResolveMagnets {
+            output,
+            links,
+            jobs,
+        } => {
+            resolve::resolve_all(&links, &output, jobs, &config, &announcer).await?;
+        }
+        Commands::MagnetDownload { output, link } => {
+            let magnet: Magnet = link.parse()?;
+            let started = SystemTime::now();
+            let download = download_magnet(
+                &magnet,
+                &[],
+                &output,
+                inbound.as_ref(),
+                &announcer,
+                &stats,
+                &config,
+            );
+            let download = reporting_stats(download, &stats, stats_interval);
+            let tracker = magnet.trackers.first().map_or("", String::as_str);
+            let t = until_interrupted(download, tracker, magnet.info_hash, &announcer).await?;
+            record_transfer(&t, started, &stats, &config);
+            print_downloaded(&t, &link, &output);
+        }
+        Commands::Stats {
+            command: StatsCommand::Export { format, since },
+        } => {
+            let store = config
+                .stats
+                .as_ref()
+                .context("no statistics store: neither XDG_DATA_HOME nor HOME is set")?;
+            let records = store.load_since(since)?;
+            print!("{}", stats::export(&stats::totals(&records), format)?);
+        }
+        Commands::Config {
+            command: ConfigCommand::Show,
+        } => print!("{}", config_file::show(&layers)),
+        Commands::Daemon {
+            control,
+            control_token,
+            command,
+        } => {
+            let control = control
+                .or_else(ControlAddr::default_socket)
+                .context("no control socket given and no runtime or home directory for one")?;
+            let (method, params) = match command {
+                DaemonCommand::Run {
+                    http,
+                    http_token,
+                    root,
+                    session_dir,
+                    ephemeral,
+                } => {
+                    let root = match root {
+                        Some(root) => root,
+                        None => std::env::current_dir().context("find working directory")?,
+                    };
+                    let http = http.map(|addr| HttpOptions {
+                        addr,
+                        token: http_token,
+                    });
+                    let options = ControlOptions {
+                        token: control_token,
+                        root,
+                    };
+                    let store = if ephemeral {
+                        None
+                    } else {
+                        session_dir
+                            .or_else(SessionStore::default_dir)
+                            .map(SessionStore::new)
+                    };
+                    let inbound = inbound.as_ref();
+                    return daemon::run(
+                        &control, options, http, store, &config, &announcer, inbound,
+                    )
+                    .await;
+                }
+                DaemonCommand::Add {
+                    output,
+                    source,
+                    category,
+                    paused,
+                    trackers,
+                    skip_verify,
+                } => {
+                    // the daemon resolves paths against its own working directory
+                    let output = std::path::absolute(&output).context("resolve output path")?;
+                    let source = if source.starts_with("magnet:") || source.contains("://") {
+                        source
+                    } else {
+                        let path = std::path::absolute(&source).context("resolve torrent path")?;
+                        path.display().to_string()
+                    };
+                    let params = AddTorrentParams {
+                        category,
+                        paused,
+                        trackers,
+                        skip_verify,
+                        ..AddTorrentParams::new(output)
+                    };
+                    let mut params = serde_json::to_value(params)?;
+                    params["source"] = source.into();
+                    ("add", params)
+                }
+                DaemonCommand::Pause { id } => ("pause", serde_json::json!({ "id": id })),
+                DaemonCommand::Resume { id } => ("resume", serde_json::json!({ "id": id })),
+                DaemonCommand::Remove { id } => ("remove", serde_json::json!({ "id": id })),
+                DaemonCommand::Status => ("status", serde_json::json!({})),
+                DaemonCommand::Maintenance { run } => {
+                    ("maintenance", serde_json::json!({ "run": run }))
+                }
+                DaemonCommand::Top {
+                    interval,
+                    once,
+                    limit,
+                } => {
+                    let options = TopOptions {
+                        interval: Duration::from_secs(interval),
+                        once,
+                        limit,
+                    };
+                    return top::run(&control, control_token.as_deref(), &options).await;
+                }
+                DaemonCommand::AltSpeed { set } => {
+                    let enabled = set.map(|set| set == Switch::On);
+                    ("alt_speed", serde_json::json!({ "enabled": enabled }))
+                }
+            };
+            let token = control_token.as_deref();
+            print_json(&daemon::call(&control, token, method, params).await?)?;
+        }
+    }
 
-            let peer = &tracker_info.peers.0[0];
+    Ok(())
+}
 
-            let mut peer = tokio::net::TcpStream::connect(peer)
-                .await
-                .context("connect to peer")?;
-            let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
-            {
-                let handshake_bytes = handshake.as_bytes_mut();
-                peer.write_all(handshake_bytes)
-                    .await
-                    .context("write handshake")?;
-                peer.read_exact(handshake_bytes)
-                    .await
-                    .context("read handshake")?;
+/// Prints a torrent's metainfo the way the `info` stage expects.
+fn print_info(t: &Torrent) {
+    println!("Tracker URL: {}", t.announce);
+    if !CODECRAFTERS {
+        println!("Name: {}", t.info.name);
+    }
+
+    let length = t.length();
+    println!("Length: {}", length);
+
+    let info_hash = t.info_hash();
+    println!("Info Hash: {}", hex::encode(info_hash));
+    println!("Piece Length: {}", t.info.plength);
+    if !CODECRAFTERS {
+        println!("Pieces: {}", t.info.pieces.0.len());
+    }
+    println!("Piece Hashes:");
+    for hash in &t.info.pieces.0 {
+        println!("{}", hex::encode(hash));
+    }
+}
+
+/// Everything `info` prints, plus the file list and tracker tiers, as one JSON object.
+fn info_json(t: &Torrent, metainfo: &[u8]) -> serde_json::Value {
+    let files: Vec<_> = metainfo::files(metainfo, t)
+        .into_iter()
+        .map(|(path, length)| serde_json::json!({ "path": path, "length": length }))
+        .collect();
+    serde_json::json!({
+        "name": t.info.name,
+        "announce": t.announce,
+        "tiers": metainfo::tiers(metainfo, t),
+        "length": t.length(),
+        "info_hash": hex::encode(t.info_hash()),
+        "piece_length": t.info.plength,
+        "piece_hashes": t.info.pieces.0.iter().map(hex::encode).collect::<Vec<_>>(),
+        "files": files,
+    })
+}
+
+fn print_v2_info(v2: &v2::V2Info) {
+    if let Some(announce) = &v2.announce {
+        println!("Tracker URL: {announce}");
+    }
+    println!("Name: {}", v2.name);
+    println!("Length: {}", v2.length());
+    println!("Meta Version: v2");
+    println!("Info Hash v2: {}", hex::encode(v2.info_hash));
+    println!("Piece Length: {}", v2.piece_length);
+    print_v2_files(v2);
+}
+
+fn print_v2_files(v2: &v2::V2Info) {
+    println!("Files:");
+    for file in &v2.files {
+        let root = file
+            .pieces_root
+            .map(hex::encode)
+            .unwrap_or_else(|| "-".to_string());
+        println!("{} {} {root}", file.length, file.path.join("/"));
+    }
+}
+
+fn v2_info_json(v2: &v2::V2Info) -> serde_json::Value {
+    let files: Vec<_> = v2
+        .files
+        .iter()
+        .map(|file| {
+            serde_json::json!({
+                "path": file.path.join("/"),
+                "length": file.length,
+                "pieces_root": file.pieces_root.map(hex::encode),
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "name": v2.name,
+        "announce": v2.announce,
+        "length": v2.length(),
+        "meta_version": "v2",
+        "info_hash_v2": hex::encode(v2.info_hash),
+        "piece_length": v2.piece_length,
+        "files": files,
+    })
+}
+
+fn print_json(value: &serde_json::Value) -> anyhow::Result<()> {
+    let json = serde_json::to_string_pretty(value).context("encode output as JSON")?;
+    println!("{json}");
+    Ok(())
+}
+
+fn print_downloaded(t: &Torrent, source: &str, output: &Path) {
+    if CODECRAFTERS {
+        println!("Downloaded {source} to {}.", output.display());
+    } else {
+        println!(
+            "Downloaded {} ({} bytes in {} pieces) to {}.",
+            t.info.name,
+            t.length(),
+            t.info.pieces.0.len(),
+            output.display()
+        );
+    }
+}
+
+fn tracker_peers(tracker_info: &TrackerResponse) -> anyhow::Result<Vec<SocketAddr>> {
+    let peers: Vec<_> = tracker_info
+        .peers
+        .0
+        .iter()
+        .map(|&peer| SocketAddr::from(peer))
+        .collect();
+    anyhow::ensure!(!peers.is_empty(), "tracker returned no peers");
+    Ok(peers)
+}
+
+/// Runs `work` to completion unless Ctrl-C arrives first. Then `work` is dropped, closing its
+/// peer connections and leaving pieces already verified to the disk writer, and the tracker
+/// is told we're leaving before [`Interrupted`] is returned.
+async fn until_interrupted<T>(
+    work: impl Future<Output = anyhow::Result<T>>,
+    tracker: &str,
+    info_hash: [u8; 20],
+    announcer: &Announcer,
+) -> anyhow::Result<T> {
+    tokio::select! {
+        result = work => return result,
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    tracing::warn!("interrupted, announcing stop to tracker");
+    if let Err(e) = announcer
+        .announce(tracker, info_hash, 0, Some(Event::Stopped))
+        .await
+    {
+        tracing::warn!(error = %e, "stopped announce failed");
+    }
+    Err(Interrupted.into())
+}
+
+/// Runs `work`, printing a line of transfer statistics to stderr every `interval` until it
+/// finishes.
+async fn reporting_stats<T>(
+    work: impl Future<Output = T>,
+    stats: &SessionStats,
+    interval: Option<Duration>,
+) -> T {
+    let Some(interval) = interval else {
+        return work.await;
+    };
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    tokio::pin!(work);
+    loop {
+        tokio::select! {
+            result = &mut work => return result,
+            _ = ticker.tick() => eprintln!("{}", stats.snapshot()),
+        }
+    }
+}
+
+/// Piece priorities that download only the files `wanted` accepts, by their index in the
+/// torrent. Pieces they share with other files are still downloaded whole.
+fn selected_files(metainfo: &[u8], t: &Torrent, wanted: impl Fn(usize) -> bool) -> Vec<Priority> {
+    let files: Vec<_> = (0..metainfo::files(metainfo, t).len())
+        .map(|index| {
+            if wanted(index) {
+                Priority::Normal
+            } else {
+                Priority::Skip
             }
-            println!("Peer ID: {}", hex::encode(&handshake.peer_id));
+        })
+        .collect();
+    file_priorities(metainfo, t, &files)
+}
 
-            let mut peer = tokio_util::codec::Framed::new(peer, MessageFramer);
-            let bitfield = peer
-                .next()
-                .await
-                .expect("peer always sends a bitfields")
-                .context("peer message was invalid")?;
-            assert_eq!(bitfield.tag, MessageTag::Bitfield);
-            eprintln!("{:?}", bitfield.tag);
-
-            peer.send(Message {
-                tag: MessageTag::Interested,
-                payload: Vec::new(),
+/// The priority of each piece of `t` when its files, in the order the info dictionary lists
+/// them, have `files`' priorities. Files past the end of `files` are at normal priority.
+fn file_priorities(metainfo: &[u8], t: &Torrent, files: &[Priority]) -> Vec<Priority> {
+    let lengths = metainfo::files(metainfo, t);
+    let mut priorities = FilePriorities::new(
+        lengths.iter().map(|&(_, length)| length as usize),
+        PieceGeometry::of(t),
+    );
+    for (index, &priority) in files.iter().enumerate().take(lengths.len()) {
+        priorities.set_file_priority(index, priority);
+    }
+    priorities.piece_priorities()
+}
+
+/// The indices of the files at `paths` within the torrent, given with or without the
+/// torrent's name in front.
+fn file_indices(metainfo: &[u8], t: &Torrent, paths: &[String]) -> anyhow::Result<Vec<usize>> {
+    let files = metainfo::files(metainfo, t);
+    let root = format!("{}/", t.info.name);
+    paths
+        .iter()
+        .map(|path| {
+            files
+                .iter()
+                .position(|(name, _)| {
+                    name == path || name.strip_prefix(&root) == Some(path.as_str())
+                })
+                .with_context(|| format!("torrent has no file {path}"))
+        })
+        .collect()
+}
+
+/// Peers given on the command line if there are any, otherwise the torrent's trackers.
+fn peer_sources<'a>(
+    peers: &[SocketAddr],
+    announcer: &'a Announcer,
+    t: &'a Torrent,
+    tiers: Tiers,
+) -> PeerSources<'a> {
+    let mut sources = PeerSources::new();
+    if peers.is_empty() {
+        sources.add(TrackerSource::new(announcer, t, tiers));
+    } else {
+        sources.add(StaticPeers(peers.to_vec()));
+    }
+    sources
+}
+
+/// Downloads `t` into `output` from `peers`, or from its trackers and `trackers` if none are
+/// given, and from any peers that connect to us. Only the pieces `priorities` wants are
+/// fetched. Peers are reached the way [`ClientConfig::transport_policy`] says for `t`.
+#[allow(clippy::too_many_arguments)]
+async fn download_torrent(
+    metainfo: &[u8],
+    t: &Torrent,
+    peers: &[SocketAddr],
+    trackers: &[String],
+    priorities: Option<&[Priority]>,
+    output: &Path,
+    inbound: Option<&Torrents>,
+    announcer: &Announcer,
+    stats: &SessionStats,
+    config: &ClientConfig,
+) -> anyhow::Result<()> {
+    let private = metainfo::is_private(metainfo);
+    let path = config.transport_policy.path(private);
+    tracing::debug!(private, ?path, "peer connection policy");
+    let config = &path.apply(config)?;
+    let mut tiers = metainfo::tiers(metainfo, t);
+    if !trackers.is_empty() {
+        tiers.push(trackers.to_vec());
+    }
+    let mut sources = peer_sources(peers, announcer, t, Tiers::new(tiers));
+    if let Some(inbound) = inbound.filter(|_| path.accepts_inbound()) {
+        sources.set_inbound(inbound.register(t.info_hash()));
+    }
+    let pieces = 0..t.info.pieces.0.len();
+    download_pieces(
+        None,
+        &mut sources,
+        t,
+        &metainfo::files(metainfo, t),
+        pieces,
+        priorities,
+        output,
+        None,
+        stats,
+        config,
+    )
+    .await
+}
+
+/// Fetches a magnet link's metadata, unless it is cached, then downloads the files the link
+/// selects into `output`, at the priorities `files` gives them, if any. The metadata is
+/// fetched as configured; the rest of the download goes the way
+/// [`ClientConfig::transport_policy`] says once the metadata tells whether the torrent is
+/// private.
+#[allow(clippy::too_many_arguments)]
+async fn download_magnet(
+    magnet: &Magnet,
+    files: &[Priority],
+    output: &Path,
+    inbound: Option<&Torrents>,
+    announcer: &Announcer,
+    stats: &SessionStats,
+    config: &ClientConfig,
+) -> anyhow::Result<Torrent> {
+    let (mut conn, theirs, peers) = magnet_connect(magnet, config, announcer).await?;
+    let (metainfo, t) = magnet_torrent(magnet, &mut conn, &theirs, config).await?;
+    let private = metainfo::is_private(&metainfo);
+    let path = config.transport_policy.path(private);
+    tracing::debug!(private, ?path, "peer connection policy");
+    // the metadata connection was made before we knew which way to go
+    let conn = path.keeps_configured().then_some(conn);
+    let config = &path.apply(config)?;
+    let layout = metainfo::files(&metainfo, &t);
+    let count = layout.len();
+    anyhow::ensure!(
+        files.len() <= count,
+        "{} file priorities given for {count} files",
+        files.len()
+    );
+    let priorities = (magnet.select_only.is_some() || !files.is_empty()).then(|| {
+        let files: Vec<_> = (0..count)
+            .map(|index| {
+                if magnet.selects(index) {
+                    files.get(index).copied().unwrap_or_default()
+                } else {
+                    Priority::Skip
+                }
             })
-            .await
-            .context("send interested message")?;
+            .collect();
+        file_priorities(&metainfo, &t, &files)
+    });
+    let mut sources = PeerSources::new();
+    sources.add(StaticPeers(peers));
+    if let Some(inbound) = inbound.filter(|_| path.accepts_inbound()) {
+        sources.set_inbound(inbound.register(magnet.info_hash));
+    }
+    let pieces = 0..t.info.pieces.0.len();
+    download_pieces(
+        conn,
+        &mut sources,
+        &t,
+        &layout,
+        pieces,
+        priorities.as_deref(),
+        output,
+        None,
+        stats,
+        config,
+    )
+    .await?;
+    Ok(t)
+}
 
-            let unchoke = peer
-                .next()
-                .await
-                .expect("peer always sends an unchoke")
-                .context("peer message was invalid")?;
-            assert_eq!(unchoke.tag, MessageTag::Unchoke);
-            assert!(unchoke.payload.is_empty());
+/// Downloads `piece` from the peers `sources` hand out, moving on to the next peer each
+/// time a connection fails.
+async fn download_from_swarm(
+    t: &Torrent,
+    files: &[(String, u64)],
+    piece: usize,
+    sources: &mut PeerSources<'_>,
+    config: &ClientConfig,
+) -> anyhow::Result<Vec<u8>> {
+    let failures = HashFailures::new(config.max_hash_failures, files, PieceGeometry::of(t));
+    let book = PeerBook::new(config.retry);
+    let mut last_exit = None;
+    loop {
+        let peers = sources.poll().await;
+        if peers.is_empty() {
+            break;
+        }
+        for peer_addr in peers {
+            let work = fetch_from_peer(peer_addr, t, piece, &failures, &book, config);
+            match supervisor::supervise(peer_addr, work).await {
+                Ok(all_blocks) => return Ok(all_blocks),
+                Err(exit) if exit.should_redial() => last_exit = Some(exit),
+                Err(exit) => return Err(exit.into()),
+            }
+        }
+    }
+    Err(last_exit.map_or_else(|| anyhow::anyhow!("no peers to download from"), Into::into))
+}
 
-            let piece_hash = t.info.pieces.0[piece];
+/// Downloads `piece` from a single peer and tells it once verified.
+async fn fetch_from_peer(
+    peer_addr: SocketAddr,
+    t: &Torrent,
+    piece: usize,
+    failures: &HashFailures,
+    book: &PeerBook,
+    config: &ClientConfig,
+) -> anyhow::Result<Vec<u8>> {
+    let mut conn = retry::open(peer_addr, t.info_hash(), config, book).await?;
+
+    let haves = HaveBroadcast::new();
+    let mut peer_haves = haves.subscribe();
+    let bitfield = conn.availability(t.info.pieces.0.len())?;
+    anyhow::ensure!(
+        bitfield.has_piece(piece),
+        "peer does not have piece {piece}"
+    );
+    conn.interested().await?;
+
+    let piece_hash = t.info.pieces.0[piece];
+    let all_blocks = conn
+        .download_piece(
+            &PieceGeometry::of(t),
+            piece,
+            &piece_hash,
+            failures,
+            config,
+            || false,
+        )
+        .await?;
+    haves.piece_verified(piece as u32);
+    peer_haves
+        .flush(&mut conn.frames)
+        .await
+        .context("send have messages")?;
 
-            let piece_size = if piece == t.info.pieces.0.len() + 1 {
-                length % t.info.plength
+    Ok(all_blocks)
+}
+
+/// The pieces of `t` that [`ClientConfig::resume`] records as intact in `output`, as it was
+/// when `existing` was read, if it has an up-to-date record of the whole torrent there.
+fn resumed_pieces(
+    t: &Torrent,
+    pieces: &Range<usize>,
+    output: &Path,
+    existing: &std::fs::Metadata,
+    config: &ClientConfig,
+) -> Option<Bitfield> {
+    let npieces = t.info.pieces.0.len();
+    if *pieces != (0..npieces) || existing.len() != t.length() as u64 {
+        return None;
+    }
+    let data = match config.resume.as_ref()?.load(t.info_hash()) {
+        Ok(data) => data?,
+        Err(e) => {
+            tracing::warn!(error = %format!("{e:#}"), "can't read resume data");
+            return None;
+        }
+    };
+    let have = data.pieces_at(output, npieces, existing.modified().ok()?)?;
+    tracing::info!(pieces = have.count(), "taking pieces from resume data");
+    Some(have)
+}
+
+/// The pieces of `t` among `pieces` that lie wholly within the first `stored` bytes of an
+/// output holding them back to back.
+fn stored_pieces(t: &Torrent, pieces: &Range<usize>, stored: u64) -> Bitfield {
+    let geometry = PieceGeometry::of(t);
+    let base = geometry.piece_offset(pieces.start);
+    let mut have = Bitfield::new(geometry.piece_count());
+    pieces
+        .clone()
+        .take_while(|&piece| {
+            (geometry.piece_offset(piece + 1).min(t.length()) - base) as u64 <= stored
+        })
+        .for_each(|piece| have.set_piece(piece));
+    have
+}
+
+/// Downloads `pieces` into `output`. `conn`, if given, is used first. Up to
+/// [`ClientConfig::max_connections_per_torrent`] peers download at once, each taking the
+/// pieces it has from a shared [`PieceScheduler`]; when one fails its pieces go back on the
+/// queue and the next peer from `sources` is dialled. `files` are the torrent's files, to say
+/// which a piece that keeps failing verification belongs to.
+/// Pieces `priorities` skips are left out, and the rest fetched higher priority first. Pieces
+/// already intact in `output`, left by an interrupted run, are kept rather than fetched again;
+/// with [`ClientConfig::skip_verify`] every piece it holds is taken to be.
+/// Pieces are marked in `written`, if given, once they are in `output`, and from then on are
+/// uploaded to peers that ask for them.
+#[allow(clippy::too_many_arguments)]
+async fn download_pieces(
+    mut conn: Option<PeerConnection>,
+    sources: &mut PeerSources<'_>,
+    t: &Torrent,
+    files: &[(String, u64)],
+    pieces: Range<usize>,
+    priorities: Option<&[Priority]>,
+    output: &Path,
+    written: Option<watch::Sender<Bitfield>>,
+    stats: &SessionStats,
+    config: &ClientConfig,
+) -> anyhow::Result<()> {
+    let geometry = PieceGeometry::of(t);
+    let len =
+        geometry.piece_offset(pieces.end).min(t.length()) - geometry.piece_offset(pieces.start);
+    // what an interrupted download, or another client, left behind
+    let existing = std::fs::metadata(output).ok();
+    let resumed = existing
+        .as_ref()
+        .and_then(|existing| resumed_pieces(t, &pieces, output, existing, config));
+    let stored = existing.map_or(0, |existing| existing.len());
+    let mut storage =
+        storage::create(output, len as u64, config.mmap).context("create output file")?;
+    let (storage, have) = match resumed {
+        Some(have) => (storage, have),
+        None if config.skip_verify => (storage, stored_pieces(t, &pieces, stored)),
+        None => {
+            let (t, pieces) = (t.clone(), pieces.clone());
+            let (storage, have) = tokio::task::spawn_blocking(move || {
+                let have = verify::recheck_stored(&t, &mut storage, pieces, stored);
+                (storage, have)
+            })
+            .await?;
+            (storage, have.context("check existing output")?)
+        }
+    };
+    if have.count() > 0 {
+        tracing::info!(pieces = have.count(), "pieces already in output");
+    }
+    let written =
+        written.unwrap_or_else(|| watch::channel(Bitfield::new(geometry.piece_count())).0);
+    written.send_modify(|written| have.pieces().for_each(|piece| written.set_piece(piece)));
+    let (uploadable, base) = (written.subscribe(), pieces.start);
+    let mut forwarder = PieceForwarder::new(
+        DiskWriter::spawn(
+            storage,
+            t.info.plength,
+            pieces.start,
+            config.fsync,
+            Some(written),
+        ),
+        // the writer places pieces by offset, so only a streaming reader needs them in order
+        if config.sequential {
+            Delivery::InOrder
+        } else {
+            Delivery::AsAvailable
+        },
+        pieces.start,
+    );
+    let failures = HashFailures::new(config.max_hash_failures, files, geometry);
+    let book = PeerBook::new(config.retry);
+    let mut scheduler = PieceScheduler::new(pieces.clone(), config.timeouts.piece).without(&have);
+    forwarder.skip(have.pieces());
+    if let Some(priorities) = priorities {
+        forwarder.skip(pieces.filter(|&piece| priorities[piece] == Priority::Skip));
+        scheduler = scheduler.with_priorities(priorities);
+    }
+    if config.sequential {
+        scheduler = scheduler.sequential(READAHEAD_PER_PEER * config.max_connections_per_torrent);
+    }
+    let forwarder = tokio::sync::Mutex::new(forwarder);
+    let haves = HaveBroadcast::new();
+    let relay = Relay::new();
+
+    if let Some(conn) = &conn {
+        sources.mark_seen(conn.addr);
+    }
+    let mut candidates = Vec::new().into_iter();
+    let mut workers = FuturesUnordered::new();
+    let mut last_exit = None;
+    let mut stalled = false;
+    let result = loop {
+        if scheduler.is_done() {
+            break Ok(());
+        }
+        stats.set_availability(scheduler.availability());
+        if scheduler.is_stalled() != stalled {
+            stalled = !stalled;
+            stats.set_stalled(stalled);
+            if stalled {
+                tracing::warn!("download stalled: no connected peer has a piece we need");
             } else {
-                t.info.plength
+                tracing::info!("download resumed");
+            }
+        }
+        while workers.len() < config.max_connections_per_torrent {
+            let waiting = conn
+                .take()
+                .or_else(|| sources.take_inbound())
+                .or_else(|| relay.take_punched());
+            let (peer_addr, open) = match waiting {
+                Some(conn) => (conn.addr, Some(conn)),
+                None => {
+                    let mut next = candidates.find(|&peer| !book.is_blacklisted(peer));
+                    if next.is_none() {
+                        candidates = sources.poll().await.into_iter();
+                        next = candidates.find(|&peer| !book.is_blacklisted(peer));
+                    }
+                    match next {
+                        Some(peer_addr) => (peer_addr, None),
+                        None => break,
+                    }
+                }
             };
-            let nblock = (piece_size + (BLOCK_MAX + 1)) / BLOCK_MAX;
-            let mut all_blocks: Vec<u8> = Vec::with_capacity(piece_size);
-            for block in 0..nblock {
-                let block_size = if block == nblock - 1 {
-                    piece_size % BLOCK_MAX
-                } else {
-                    BLOCK_MAX
+            let (scheduler, forwarder, failures, book, haves, relay) =
+                (&scheduler, &forwarder, &failures, &book, &haves, &relay);
+            let uploadable = uploadable.clone();
+            let work = async move {
+                let mut conn = match open {
+                    Some(conn) => conn,
+                    None => match retry::open(peer_addr, t.info_hash(), config, book).await {
+                        Ok(conn) => conn,
+                        Err(e) => {
+                            if holepunch::is_unreachable(&e) {
+                                relay.unreachable(peer_addr, config);
+                            }
+                            return Err(e);
+                        }
+                    },
                 };
-                let mut request =
-                    Request::new(piece as u32, (block * BLOCK_MAX) as u32, block_size as u32);
-                let request_bytes = request.as_bytes_mut();
-                peer.send(Message {
-                    tag: MessageTag::Request,
-                    payload: request_bytes.to_vec(),
-                })
-                .await
-                .with_context(|| format!("send request message for block {}", block))?;
-
-                let piece = peer
-                    .next()
+                let source = UploadSource::open(output, geometry, base, uploadable)
                     .await
-                    .expect("peer always sends an piece")
-                    .context("peer message was invalid")?;
-                assert_eq!(piece.tag, MessageTag::Piece);
-                assert!(!piece.payload.is_empty());
-                // trying to cast a thin pointer to a fat pointer
-                let piece = &piece.payload[..] as *const [u8] as *const Piece;
-                let piece = unsafe { &*piece };
-                all_blocks.extend(piece.block());
-            }
-            let mut hasher = Sha1::new();
-            hasher.update(&all_blocks);
-            let hash = hasher.finalize();
-            assert_eq!(piece_hash, hash.as_slice());
-
-            tokio::fs::write(&output, all_blocks)
+                    .context("open output for uploading")?;
+                let uploader = Uploader::new(source, config.upload_slots.clone());
+                download_from_peer(
+                    &mut conn, t, scheduler, forwarder, failures, haves, relay, uploader, stats,
+                    config,
+                )
                 .await
-                .context("write out downloaded piece")?;
-            println!("piece {:?} downloaded to {:?}.", piece, &output);
+            };
+            workers.push(async move { (peer_addr, supervisor::supervise(peer_addr, work).await) });
+        }
+        let finished = tokio::select! {
+            finished = workers.next() => finished,
+            // peers waiting for pieces to be announced keep their slots, so don't wait on them
+            _ = tokio::time::sleep(RECHECK_INTERVAL), if !workers.is_empty() => continue,
+        };
+        let Some((peer_addr, exit)) = finished else {
+            break Err(
+                last_exit.map_or_else(|| anyhow::anyhow!("no peers to download from"), Into::into)
+            );
+        };
+        scheduler.release(peer_addr);
+        stats.peer_disconnected(peer_addr);
+        match exit {
+            // every piece is done
+            Ok(()) => {}
+            Err(exit) if exit.should_redial() => {
+                match exit.error() {
+                    Some(error) => book.record_failure(peer_addr, error),
+                    None => book.record_failure(peer_addr, &anyhow::anyhow!("{exit}")),
+                };
+                last_exit = Some(exit);
+            }
+            Err(exit) => break Err(exit.into()),
+        }
+    };
+    // stop peers still racing for pieces someone else already finished
+    drop(workers);
+    stats.all_disconnected();
+    for (source, stats) in sources.stats() {
+        tracing::debug!(source, ?stats, "peer source statistics");
+    }
+    result?;
+    forwarder
+        .into_inner()
+        .close()
+        .await
+        .context("close output file")
+}
+
+/// Downloads pieces the scheduler hands out from one peer until the download is done. While
+/// it has nothing the peer has to hand out, the peer's `have` messages are still read so it
+/// is offered pieces as soon as it gets them. Between pieces, `uploader` answers what the peer
+/// has asked of us.
+#[allow(clippy::too_many_arguments)]
+async fn download_from_peer<S>(
+    conn: &mut PeerConnection,
+    t: &Torrent,
+    scheduler: &PieceScheduler,
+    forwarder: &tokio::sync::Mutex<PieceForwarder<S>>,
+    failures: &HashFailures,
+    haves: &HaveBroadcast,
+    relay: &Relay,
+    mut uploader: Uploader,
+    stats: &SessionStats,
+    config: &ClientConfig,
+) -> anyhow::Result<()>
+where
+    S: Sink<VerifiedPiece, Error = std::io::Error> + Unpin,
+{
+    let npieces = t.info.pieces.0.len();
+    let bitfield = conn.availability(npieces)?.clone();
+    conn.interested().await?;
+    conn.state.meter = Some(stats.peer_connected(conn.addr));
+    conn.state.limits = Some(config.speed.clone());
+    scheduler.join(conn.addr, bitfield);
+    let mut member = relay.join(conn.addr);
+    if conn.state.negotiated.extensions {
+        extension::send_handshake(&mut conn.frames).await?;
+    }
+
+    let mut peer_haves = haves.subscribe();
+    let geometry = PieceGeometry::of(t);
+    loop {
+        uploader.serve(&mut conn.frames, &mut conn.state).await?;
+        let next = tokio::select! {
+            next = scheduler.next(conn.addr) => Some(next),
+            idle = download::idle(&mut conn.frames, &mut conn.state) => idle.map(|()| None)?,
+            Some(msg) = member.next() => {
+                member.send(&mut conn.frames, msg).await?;
+                continue;
+            }
+            // tell a peer waiting on us about pieces others fetched meanwhile
+            () = peer_haves.ready() => {
+                peer_haves.flush(&mut conn.frames).await.context("send have messages")?;
+                continue;
+            }
+        };
+        record_announced(conn, scheduler, npieces)?;
+        member.handle(conn, t.info_hash(), config).await?;
+        let piece = match next {
+            Some(Some(piece)) => piece,
+            Some(None) => break,
+            None => continue,
+        };
+        let downloaded = conn
+            .download_piece(
+                &geometry,
+                piece,
+                &t.info.pieces.0[piece],
+                failures,
+                config,
+                || scheduler.is_complete(piece),
+            )
+            .await;
+        record_announced(conn, scheduler, npieces)?;
+        let data = match downloaded {
+            Ok(data) => data,
+            // another peer won the race; move on to the next piece
+            Err(e) if e.is::<Superseded>() => continue,
+            // let a peer that answers have it, and keep this one for what nobody else has
+            Err(e) if e.is::<Snubbed>() => {
+                scheduler.snub(conn.addr, piece);
+                continue;
+            }
+            Err(e) => {
+                if e.is::<HashMismatch>() || e.is::<TooManyHashFailures>() {
+                    stats.wasted(geometry.piece_len(piece));
+                }
+                return Err(e);
+            }
+        };
+        scheduler.unsnub(conn.addr);
+        if !scheduler.complete(piece, conn.addr) {
+            continue;
         }
+        stats.piece_completed(piece);
+        haves.piece_verified(piece as u32);
+        peer_haves
+            .flush(&mut conn.frames)
+            .await
+            .context("send have messages")?;
+        forwarder
+            .lock()
+            .await
+            .piece_verified(VerifiedPiece { index: piece, data })
+            .await
+            .context("write out downloaded piece")?;
     }
+    Ok(())
+}
 
+/// Tells the scheduler about the pieces `conn`'s peer has announced since it joined.
+fn record_announced(
+    conn: &mut PeerConnection,
+    scheduler: &PieceScheduler,
+    npieces: usize,
+) -> anyhow::Result<()> {
+    for piece in conn.state.announced.drain(..) {
+        let piece = piece as usize;
+        anyhow::ensure!(
+            piece < npieces,
+            "peer announced piece {piece} of a torrent with {npieces}"
+        );
+        scheduler.have(conn.addr, piece);
+    }
     Ok(())
 }
+
+async fn magnet_connect(
+    magnet: &Magnet,
+    config: &ClientConfig,
+    announcer: &Announcer,
+) -> anyhow::Result<(PeerConnection, ExtensionHandshake, Vec<SocketAddr>)> {
+    let mut tiers = Tiers::new(magnet.trackers.iter().map(|t| vec![t.clone()]).collect());
+    // the length is unknown until we have the metadata, but trackers want `left` > 0
+    let tracker_info = announcer
+        .announce_tiers(&mut tiers, magnet.info_hash, 999, Some(Event::Started))
+        .await?;
+    let peers = tracker_peers(&tracker_info)?;
+    let book = PeerBook::new(config.retry);
+    let mut conn = None;
+    for &peer_addr in &peers {
+        match retry::open(peer_addr, magnet.info_hash, config, &book).await {
+            Ok(opened) => {
+                conn = Some(opened);
+                break;
+            }
+            Err(e) => tracing::warn!(peer = %peer_addr, error = %e, "moving on to next peer"),
+        }
+    }
+    let mut conn = conn.context("no peer from the tracker was reachable")?;
+    anyhow::ensure!(
+        conn.state.negotiated.extensions,
+        "peer does not support the extension protocol"
+    );
+    let theirs = extension::handshake(&mut conn.frames, &mut conn.state, &config.timeouts).await?;
+    Ok((conn, theirs, peers))
+}
+
+/// The metainfo and torrent for a magnet link whose metadata we have seen before, if any.
+fn cached_torrent(
+    magnet: &Magnet,
+    config: &ClientConfig,
+) -> anyhow::Result<Option<(Vec<u8>, Torrent)>> {
+    let Some(cache) = &config.metadata_cache else {
+        return Ok(None);
+    };
+    cache
+        .load(magnet.info_hash)
+        .map(|info| torrent_from_info(magnet, &info))
+        .transpose()
+}
+
+/// Remembers a torrent's info dictionary so magnet links for it skip the metadata exchange.
+fn remember_metadata(info_hash: [u8; 20], info: &[u8], config: &ClientConfig) {
+    let Some(cache) = &config.metadata_cache else {
+        return;
+    };
+    if let Err(e) = cache.store(info_hash, info) {
+        tracing::warn!(error = %e, "could not cache torrent metadata");
+    }
+}
+
+/// Adds a finished download to the statistics store, if there is one, with the bytes `stats`
+/// counted each way. Pieces a file selection left out, or the output already held, aren't
+/// counted as downloaded.
+fn record_transfer(t: &Torrent, started: SystemTime, stats: &SessionStats, config: &ClientConfig) {
+    let Some(store) = &config.stats else {
+        return;
+    };
+    let transferred = stats.snapshot();
+    let record = TransferRecord {
+        info_hash: hex::encode(t.info_hash()),
+        name: t.info.name.clone(),
+        started: stats::unix_time(started),
+        finished: stats::unix_time(SystemTime::now()),
+        downloaded: transferred.downloaded,
+        uploaded: transferred.uploaded,
+    };
+    if let Err(e) = store.record(&record) {
+        tracing::warn!(error = %e, "could not record transfer statistics");
+    }
+}
+
+/// Fetches a magnet link's info dictionary, unless it is cached, and wraps it up as metainfo
+/// and a torrent.
+async fn magnet_torrent(
+    magnet: &Magnet,
+    conn: &mut PeerConnection,
+    theirs: &ExtensionHandshake,
+    config: &ClientConfig,
+) -> anyhow::Result<(Vec<u8>, Torrent)> {
+    if let Some(cached) = cached_torrent(magnet, config)? {
+        return Ok(cached);
+    }
+    let info = extension::fetch_metadata(
+        &mut conn.frames,
+        &mut conn.state,
+        theirs,
+        magnet.info_hash,
+        &config.timeouts,
+    )
+    .await?;
+    remember_metadata(magnet.info_hash, &info, config);
+    torrent_from_info(magnet, &info)
+}
+
+fn torrent_from_info(magnet: &Magnet, info: &[u8]) -> anyhow::Result<(Vec<u8>, Torrent)> {
+    let announce = magnet.trackers.first().map_or("", String::as_str);
+    let mut metainfo = format!("d8:announce{}:{announce}4:info", announce.len()).into_bytes();
+    metainfo.extend_from_slice(info);
+    metainfo.push(b'e');
+    let t = serde_bencode::from_bytes(&metainfo).context("parse torrent metadata")?;
+    Ok((metainfo, t))
+}
@@ -0,0 +1,229 @@
+//! Upkeep the daemon does while it runs: saving its torrents, trimming the metadata cache and
+//! scrubbing finished downloads for damage. Each [`Job`] comes due on its own period, jittered
+//! so that jobs, and daemons sharing a disk, don't fall into step, and can be run on demand
+//! too. Runs, failures and timings are kept for each job.
+//!
+//! Re-announcing isn't a job: each download's tracker source already announces again on the
+//! interval its tracker asks for.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::time::Instant;
+
+use crate::stats;
+
+/// How far each period is stretched or shortened at random, as a fraction of it.
+const JITTER: f64 = 0.1;
+
+/// Upkeep done now and then.
+#[derive(
+    clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Job {
+    /// Writes the torrents to the session store, which also happens whenever they are added,
+    /// paused, resumed or removed, to keep their progress and catch ones that have finished.
+    Save,
+    /// Drops cached metadata that hasn't been used in a while.
+    TrimCache,
+    /// Rechecks finished downloads against their piece hashes.
+    Scrub,
+}
+
+impl Job {
+    const ALL: [Job; 3] = [Job::Save, Job::TrimCache, Job::Scrub];
+
+    fn period(self) -> Duration {
+        match self {
+            Job::Save => Duration::from_secs(60),
+            Job::TrimCache => Duration::from_secs(6 * 60 * 60),
+            Job::Scrub => Duration::from_secs(24 * 60 * 60),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Job::Save => "save",
+            Job::TrimCache => "trim_cache",
+            Job::Scrub => "scrub",
+        }
+    }
+}
+
+/// What is known about one job's runs.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct JobMetrics {
+    pub runs: u64,
+    pub failures: u64,
+    /// Unix time the last run started.
+    pub last_run: Option<u64>,
+    /// Seconds the last finished run took.
+    pub last_duration: Option<f64>,
+    pub last_error: Option<String>,
+    pub running: bool,
+}
+
+/// When each [`Job`] is next due, and how its runs went.
+pub struct Maintenance {
+    due: BTreeMap<Job, Instant>,
+    metrics: Arc<Mutex<BTreeMap<Job, JobMetrics>>>,
+}
+
+impl Default for Maintenance {
+    /// Every job first comes due a jittered period from now.
+    fn default() -> Self {
+        let now = Instant::now();
+        Self {
+            due: Job::ALL
+                .into_iter()
+                .map(|job| (job, now + jittered(job.period())))
+                .collect(),
+            metrics: Arc::default(),
+        }
+    }
+}
+
+impl Maintenance {
+    /// Waits for the next job to come due, and schedules its next run. Nothing changes if
+    /// this is dropped before it finishes, so it can be selected against other work.
+    pub async fn next(&mut self) -> Job {
+        let (&job, &at) = self
+            .due
+            .iter()
+            .min_by_key(|&(_, at)| at)
+            .expect("every job is scheduled");
+        tokio::time::sleep_until(at).await;
+        self.due
+            .insert(job, Instant::now() + jittered(job.period()));
+        job
+    }
+
+    /// Makes `job` due now. Takes effect the next time [`Maintenance::next`] is called.
+    pub fn trigger(&mut self, job: Job) {
+        tracing::info!(job = job.name(), "maintenance job triggered");
+        self.due.insert(job, Instant::now());
+    }
+
+    /// Starts timing a run of `job`, or `None` if the last one hasn't finished.
+    pub fn start(&self, job: Job) -> Option<JobRun> {
+        let mut metrics = self.metrics.lock().expect("maintenance lock poisoned");
+        let entry = metrics.entry(job).or_default();
+        if entry.running {
+            return None;
+        }
+        entry.running = true;
+        entry.last_run = Some(stats::unix_time(SystemTime::now()));
+        Some(JobRun {
+            job,
+            started: Instant::now(),
+            metrics: self.metrics.clone(),
+        })
+    }
+
+    /// Every job's metrics, and the seconds until it is next due.
+    pub fn report(&self) -> Value {
+        let metrics = self.metrics.lock().expect("maintenance lock poisoned");
+        let now = Instant::now();
+        let jobs = self.due.iter().map(|(&job, &at)| {
+            let mut report = json!(metrics.get(&job).cloned().unwrap_or_default());
+            report["job"] = job.name().into();
+            report["next_in"] = at.saturating_duration_since(now).as_secs_f64().into();
+            report
+        });
+        Value::Array(jobs.collect())
+    }
+}
+
+/// One run of a job, recorded in its metrics once it finishes.
+pub struct JobRun {
+    job: Job,
+    started: Instant,
+    metrics: Arc<Mutex<BTreeMap<Job, JobMetrics>>>,
+}
+
+impl JobRun {
+    pub fn finish(self, result: &anyhow::Result<()>) {
+        let took = self.started.elapsed();
+        let mut metrics = self.metrics.lock().expect("maintenance lock poisoned");
+        let entry = metrics.entry(self.job).or_default();
+        entry.runs += 1;
+        entry.last_duration = Some(took.as_secs_f64());
+        match result {
+            Ok(()) => {
+                tracing::debug!(job = self.job.name(), ?took, "maintenance job done");
+                entry.last_error = None;
+            }
+            Err(e) => {
+                let error = format!("{e:#}");
+                tracing::warn!(job = self.job.name(), %error, "maintenance job failed");
+                entry.failures += 1;
+                entry.last_error = Some(error);
+            }
+        }
+    }
+}
+
+impl Drop for JobRun {
+    /// Lets the job run again, even if this run was dropped before it finished.
+    fn drop(&mut self) {
+        let mut metrics = self.metrics.lock().expect("maintenance lock poisoned");
+        metrics.entry(self.job).or_default().running = false;
+    }
+}
+
+fn jittered(period: Duration) -> Duration {
+    period.mul_f64(1.0 + rand::thread_rng().gen_range(-JITTER..=JITTER))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jitters_within_bounds() {
+        let period = Duration::from_secs(100);
+        for _ in 0..100 {
+            let jittered = jittered(period);
+            assert!((Duration::from_secs(90)..=Duration::from_secs(110)).contains(&jittered));
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_triggered_jobs_first() {
+        let mut maintenance = Maintenance::default();
+        maintenance.trigger(Job::Scrub);
+        let job = tokio::time::timeout(Duration::from_secs(1), maintenance.next())
+            .await
+            .unwrap();
+        assert_eq!(job, Job::Scrub);
+        // rescheduled a period out, not left due
+        assert!(maintenance.due[&Job::Scrub] > Instant::now() + Duration::from_secs(60 * 60));
+    }
+
+    #[test]
+    fn keeps_metrics_per_job() {
+        let maintenance = Maintenance::default();
+        let run = maintenance.start(Job::Save).unwrap();
+        assert!(maintenance.start(Job::Save).is_none(), "still running");
+        run.finish(&Ok(()));
+        let run = maintenance.start(Job::Save).unwrap();
+        run.finish(&Err(anyhow::anyhow!("disk full")));
+
+        let report = maintenance.report();
+        let save = report
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|job| job["job"] == "save")
+            .unwrap();
+        assert_eq!(save["runs"], 2);
+        assert_eq!(save["failures"], 1);
+        assert_eq!(save["last_error"], "disk full");
+        assert_eq!(save["running"], false);
+    }
+}
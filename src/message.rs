@@ -0,0 +1,398 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::wire_trace::{Direction, WireTrace};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum MessageTag {
+    Choke = 0,
+    Unchoke = 1,
+    Interested = 2,
+    NotInterested = 3,
+    Have = 4,
+    Bitfield = 5,
+    Request = 6,
+    Piece = 7,
+    Cancel = 8,
+    Port = 9,
+    // Fast extension (BEP 6)
+    SuggestPiece = 13,
+    HaveAll = 14,
+    HaveNone = 15,
+    RejectRequest = 16,
+    AllowedFast = 17,
+    Extended = 20,
+}
+
+impl TryFrom<u8> for MessageTag {
+    type Error = u8;
+
+    fn try_from(tag: u8) -> Result<Self, Self::Error> {
+        Ok(match tag {
+            0 => MessageTag::Choke,
+            1 => MessageTag::Unchoke,
+            2 => MessageTag::Interested,
+            3 => MessageTag::NotInterested,
+            4 => MessageTag::Have,
+            5 => MessageTag::Bitfield,
+            6 => MessageTag::Request,
+            7 => MessageTag::Piece,
+            8 => MessageTag::Cancel,
+            9 => MessageTag::Port,
+            13 => MessageTag::SuggestPiece,
+            14 => MessageTag::HaveAll,
+            15 => MessageTag::HaveNone,
+            16 => MessageTag::RejectRequest,
+            17 => MessageTag::AllowedFast,
+            20 => MessageTag::Extended,
+            tag => return Err(tag),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub tag: MessageTag,
+    /// A view into the buffer the frame was read from, so blocks aren't copied on receipt.
+    pub payload: Bytes,
+}
+
+impl Message {
+    pub fn empty(tag: MessageTag) -> Self {
+        Self {
+            tag,
+            payload: Bytes::new(),
+        }
+    }
+}
+
+/// Why a frame from a peer was refused. Decoding returns these wrapped in an
+/// [`InvalidData`](std::io::ErrorKind::InvalidData) I/O error; [`FrameError::find`] gets them
+/// back out so the connection layer can drop the peer for good.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum FrameError {
+    #[error("frame of length {0} is too large")]
+    TooLarge(usize),
+    #[error("{tag:?} message with a {len}-byte payload")]
+    BadPayload { tag: MessageTag, len: usize },
+}
+
+impl FrameError {
+    /// The frame error behind `error`, if the peer sent a malformed frame.
+    pub fn find(error: &anyhow::Error) -> Option<&FrameError> {
+        error.chain().find_map(|e| {
+            e.downcast_ref::<FrameError>().or_else(|| {
+                e.downcast_ref::<std::io::Error>()?
+                    .get_ref()?
+                    .downcast_ref::<FrameError>()
+            })
+        })
+    }
+}
+
+impl From<FrameError> for std::io::Error {
+    fn from(e: FrameError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+impl MessageTag {
+    /// Whether a payload of `len` bytes is the right size for this kind of message.
+    fn accepts_payload(self, len: usize) -> bool {
+        match self {
+            MessageTag::Choke
+            | MessageTag::Unchoke
+            | MessageTag::Interested
+            | MessageTag::NotInterested
+            | MessageTag::HaveAll
+            | MessageTag::HaveNone => len == 0,
+            MessageTag::Have | MessageTag::SuggestPiece | MessageTag::AllowedFast => len == 4,
+            MessageTag::Request | MessageTag::Cancel | MessageTag::RejectRequest => len == 12,
+            MessageTag::Piece => len >= 8,
+            MessageTag::Port => len == 2,
+            MessageTag::Bitfield => true,
+            MessageTag::Extended => len >= 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MessageFramer {
+    trace: Option<WireTrace>,
+}
+
+impl MessageFramer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A framer that records every message it decodes or encodes.
+    pub fn traced(trace: Option<WireTrace>) -> Self {
+        Self { trace }
+    }
+}
+
+/// Largest frame we accept or send. The length prefix is checked against this before any
+/// buffer space is reserved, so a peer can't make us allocate whatever it declares.
+const MAX: usize = 1 << 16;
+
+impl Decoder for MessageFramer {
+    type Item = Message;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            if src.len() < 4 {
+                return Ok(None);
+            }
+            let length =
+                u32::from_be_bytes(src[..4].try_into().expect("slice is 4 bytes")) as usize;
+            if length == 0 {
+                // keep-alive
+                src.advance(4);
+                continue;
+            }
+            if length > MAX {
+                return Err(FrameError::TooLarge(length).into());
+            }
+            if src.len() < 4 + length {
+                src.reserve(4 + length - src.len());
+                return Ok(None);
+            }
+
+            let tag = match MessageTag::try_from(src[4]) {
+                Ok(tag) => tag,
+                Err(tag) => {
+                    // an extension we don't speak; its frame is still length-delimited
+                    tracing::debug!(tag, len = length - 1, "skipping unknown message");
+                    src.advance(4 + length);
+                    continue;
+                }
+            };
+            if !tag.accepts_payload(length - 1) {
+                return Err(FrameError::BadPayload {
+                    tag,
+                    len: length - 1,
+                }
+                .into());
+            }
+            let mut frame = src.split_to(4 + length);
+            frame.advance(5);
+            let message = Message {
+                tag,
+                payload: frame.freeze(),
+            };
+            if let Some(trace) = &self.trace {
+                trace.record(Direction::Received, &message);
+            }
+            return Ok(Some(message));
+        }
+    }
+}
+
+impl Encoder<Message> for MessageFramer {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        if item.payload.len() + 1 > MAX {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("frame of length {} is too large", item.payload.len() + 1),
+            ));
+        }
+        if let Some(trace) = &self.trace {
+            trace.record(Direction::Sent, &item);
+        }
+        dst.reserve(4 + 1 + item.payload.len());
+        dst.put_u32(1 + item.payload.len() as u32);
+        dst.put_u8(item.tag as u8);
+        dst.extend_from_slice(&item.payload);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every message type with a sample payload, and the exact bytes it goes over the wire as.
+    const FIXTURES: &[(MessageTag, &[u8], &[u8])] = &[
+        (MessageTag::Choke, &[], &[0, 0, 0, 1, 0]),
+        (MessageTag::Unchoke, &[], &[0, 0, 0, 1, 1]),
+        (MessageTag::Interested, &[], &[0, 0, 0, 1, 2]),
+        (MessageTag::NotInterested, &[], &[0, 0, 0, 1, 3]),
+        (
+            MessageTag::Have,
+            &[0, 0, 1, 2],
+            &[0, 0, 0, 5, 4, 0, 0, 1, 2],
+        ),
+        // ten pieces, of which 0, 1 and 9 are set; the six spare bits are zero
+        (
+            MessageTag::Bitfield,
+            &[0b1100_0000, 0b0100_0000],
+            &[0, 0, 0, 3, 5, 0b1100_0000, 0b0100_0000],
+        ),
+        (
+            MessageTag::Request,
+            &[0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+            &[0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+        ),
+        (
+            MessageTag::Piece,
+            &[0, 0, 0, 1, 0, 0, 0, 0, b'a', b'b', b'c'],
+            &[0, 0, 0, 12, 7, 0, 0, 0, 1, 0, 0, 0, 0, b'a', b'b', b'c'],
+        ),
+        (
+            MessageTag::Cancel,
+            &[0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+            &[0, 0, 0, 13, 8, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+        ),
+        (
+            MessageTag::Port,
+            &[0x1a, 0xe1],
+            &[0, 0, 0, 3, 9, 0x1a, 0xe1],
+        ),
+        (
+            MessageTag::SuggestPiece,
+            &[0, 0, 0, 3],
+            &[0, 0, 0, 5, 13, 0, 0, 0, 3],
+        ),
+        (MessageTag::HaveAll, &[], &[0, 0, 0, 1, 14]),
+        (MessageTag::HaveNone, &[], &[0, 0, 0, 1, 15]),
+        (
+            MessageTag::RejectRequest,
+            &[0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+            &[0, 0, 0, 13, 16, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+        ),
+        (
+            MessageTag::AllowedFast,
+            &[0, 0, 0, 7],
+            &[0, 0, 0, 5, 17, 0, 0, 0, 7],
+        ),
+        // extension handshake advertising ut_metadata as 1
+        (
+            MessageTag::Extended,
+            b"\0d1:md11:ut_metadatai1eee",
+            b"\0\0\0\x1a\x14\0d1:md11:ut_metadatai1eee",
+        ),
+    ];
+
+    fn frame_error(error: &std::io::Error) -> Option<&FrameError> {
+        error.get_ref()?.downcast_ref::<FrameError>()
+    }
+
+    #[test]
+    fn encodes_fixtures() {
+        for &(tag, payload, wire) in FIXTURES {
+            let mut dst = BytesMut::new();
+            let message = Message {
+                tag,
+                payload: Bytes::from_static(payload),
+            };
+            MessageFramer::new().encode(message, &mut dst).unwrap();
+            assert_eq!(&dst[..], wire, "{tag:?}");
+        }
+    }
+
+    #[test]
+    fn decodes_fixtures() {
+        for &(tag, payload, wire) in FIXTURES {
+            let mut src = BytesMut::from(wire);
+            let message = MessageFramer::new().decode(&mut src).unwrap();
+            assert_eq!(
+                message,
+                Some(Message {
+                    tag,
+                    payload: Bytes::from_static(payload),
+                })
+            );
+            assert!(src.is_empty(), "{tag:?} left bytes behind");
+        }
+    }
+
+    #[test]
+    fn skips_keep_alives() {
+        let mut framer = MessageFramer::new();
+        let mut src = BytesMut::from(&[0, 0, 0, 0][..]);
+        assert_eq!(framer.decode(&mut src).unwrap(), None);
+        assert!(src.is_empty());
+
+        let mut src = BytesMut::from(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1][..]);
+        let message = framer.decode(&mut src).unwrap();
+        assert_eq!(message, Some(Message::empty(MessageTag::Unchoke)));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn waits_for_split_frames() {
+        let (_, payload, wire) = FIXTURES
+            .iter()
+            .find(|(tag, ..)| *tag == MessageTag::Piece)
+            .unwrap();
+        let mut framer = MessageFramer::new();
+        let mut src = BytesMut::new();
+        for &byte in &wire[..wire.len() - 1] {
+            src.put_u8(byte);
+            assert_eq!(framer.decode(&mut src).unwrap(), None);
+        }
+        src.put_u8(wire[wire.len() - 1]);
+        let message = framer.decode(&mut src).unwrap().unwrap();
+        assert_eq!(message.tag, MessageTag::Piece);
+        assert_eq!(&message.payload[..], *payload);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn decodes_back_to_back_frames() {
+        let mut src = BytesMut::new();
+        for (_, _, wire) in FIXTURES {
+            src.extend_from_slice(wire);
+        }
+        let mut framer = MessageFramer::new();
+        for &(tag, ..) in FIXTURES {
+            assert_eq!(framer.decode(&mut src).unwrap().unwrap().tag, tag);
+        }
+        assert_eq!(framer.decode(&mut src).unwrap(), None);
+    }
+
+    #[test]
+    fn refuses_oversized_frames() {
+        let len = (MAX as u32 + 1).to_be_bytes();
+        let mut src = BytesMut::from(&len[..]);
+        let error = MessageFramer::new().decode(&mut src).unwrap_err();
+        assert_eq!(frame_error(&error), Some(&FrameError::TooLarge(MAX + 1)));
+        assert!(
+            src.capacity() < MAX,
+            "reserved space for the declared length"
+        );
+
+        let message = Message {
+            tag: MessageTag::Bitfield,
+            payload: Bytes::from(vec![0; MAX]),
+        };
+        let error = MessageFramer::new()
+            .encode(message, &mut BytesMut::new())
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn refuses_payloads_of_the_wrong_size() {
+        let mut src = BytesMut::from(&[0, 0, 0, 4, 4, 0, 0, 1][..]);
+        let error = MessageFramer::new().decode(&mut src).unwrap_err();
+        let expected = FrameError::BadPayload {
+            tag: MessageTag::Have,
+            len: 3,
+        };
+        assert_eq!(frame_error(&error), Some(&expected));
+    }
+
+    #[test]
+    fn skips_unknown_messages() {
+        let mut src = BytesMut::from(&[0, 0, 0, 3, 42, 1, 2, 0, 0, 0, 1, 2][..]);
+        let message = MessageFramer::new().decode(&mut src).unwrap();
+        assert_eq!(message, Some(Message::empty(MessageTag::Interested)));
+        assert!(src.is_empty());
+    }
+}
@@ -0,0 +1,105 @@
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use crate::hash::TorrentVersion;
+
+/// Info dictionaries we have already fetched or parsed, one `<info hash>.info` file each, so
+/// a magnet link seen before doesn't need the metadata exchange again.
+#[derive(Debug, Clone)]
+pub struct MetadataCache {
+    dir: PathBuf,
+}
+
+impl MetadataCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// `$XDG_CACHE_HOME/bittorrent-starter-rust/metadata`, falling back to `~/.cache`.
+    pub fn default_dir() -> Option<PathBuf> {
+        let base = std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+        Some(base.join("bittorrent-starter-rust").join("metadata"))
+    }
+
+    fn path(&self, info_hash: [u8; 20]) -> PathBuf {
+        self.dir.join(format!("{}.info", hex::encode(info_hash)))
+    }
+
+    /// The cached info dictionary for `info_hash`, if there is one and it still hashes right.
+    pub fn load(&self, info_hash: [u8; 20]) -> Option<Vec<u8>> {
+        let path = self.path(info_hash);
+        let info = std::fs::read(&path).ok()?;
+        if !TorrentVersion::V1.verify(&info, &info_hash) {
+            tracing::warn!(path = %path.display(), "discarding corrupt cached metadata");
+            let _ = std::fs::remove_file(&path);
+            return None;
+        }
+        tracing::debug!(info_hash = %hex::encode(info_hash), "metadata cache hit");
+        // keeps it from being trimmed; access times aren't kept on every filesystem
+        let _ = File::options()
+            .append(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now()));
+        Some(info)
+    }
+
+    /// Stores `info` under `info_hash`. Written to a temporary file first so a crash can't
+    /// leave a truncated entry behind.
+    pub fn store(&self, info_hash: [u8; 20], info: &[u8]) -> io::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.path(info_hash);
+        let partial = path.with_extension("info.part");
+        std::fs::write(&partial, info)?;
+        std::fs::rename(partial, path)
+    }
+
+    /// Removes the entries not loaded or stored for `unused`, returning how many went.
+    pub fn trim(&self, unused: Duration) -> io::Result<usize> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let cutoff = SystemTime::now() - unused;
+        let mut removed = 0;
+        for entry in entries {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_file() && metadata.modified()? < cutoff {
+                std::fs::remove_file(entry.path())?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trims_entries_not_used_lately() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = MetadataCache::new(dir.path().to_owned());
+        let (old, fresh) = (b"d4:name3:olde".to_vec(), b"d4:name5:freshe".to_vec());
+        let hash = |info: &[u8]| TorrentVersion::V1.digest(info).try_into().unwrap();
+        cache.store(hash(&old), &old).unwrap();
+        cache.store(hash(&fresh), &fresh).unwrap();
+        let long_ago = SystemTime::now() - Duration::from_secs(3600);
+        File::options()
+            .append(true)
+            .open(cache.path(hash(&old)))
+            .unwrap()
+            .set_modified(long_ago)
+            .unwrap();
+
+        assert_eq!(cache.trim(Duration::from_secs(60)).unwrap(), 1);
+        assert_eq!(cache.load(hash(&old)), None);
+        assert_eq!(cache.load(hash(&fresh)), Some(fresh));
+    }
+}
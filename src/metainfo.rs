@@ -0,0 +1,107 @@
+//! Loading `.torrent` files, and the metainfo fields the library's `Torrent` doesn't model,
+//! read from the raw bytes.
+
+use std::path::Path;
+
+use anyhow::Context;
+use reqwest::header::CONTENT_TYPE;
+use serde::Deserialize;
+
+use bittorrent_starter_rust::Torrent;
+
+use crate::config::ClientConfig;
+use crate::tracker;
+
+/// Largest `.torrent` file we fetch over HTTP.
+const MAX_METAINFO: usize = 10 << 20;
+/// Content types a server may label a `.torrent` file with. Anything else, typically an HTML
+/// error or login page, is refused.
+const METAINFO_TYPES: [&str; 3] = [
+    "application/x-bittorrent",
+    "application/octet-stream",
+    "binary/octet-stream",
+];
+
+#[derive(Deserialize)]
+struct Extras {
+    #[serde(rename = "announce-list", default)]
+    announce_list: Vec<Vec<String>>,
+    info: InfoExtras,
+}
+
+#[derive(Deserialize)]
+struct InfoExtras {
+    files: Option<Vec<FileExtras>>,
+    private: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct FileExtras {
+    length: u64,
+    path: Vec<String>,
+}
+
+/// Reads a `.torrent` file from `source`: a local path, or an `http://` or `https://` URL to
+/// fetch through the configured proxy.
+pub async fn load(source: &Path, config: &ClientConfig) -> anyhow::Result<Vec<u8>> {
+    let url = source
+        .to_str()
+        .filter(|s| s.starts_with("http://") || s.starts_with("https://"));
+    match url {
+        Some(url) => fetch(url, config)
+            .await
+            .with_context(|| format!("fetch torrent file from {url}")),
+        None => std::fs::read(source).context("read torrent file"),
+    }
+}
+
+async fn fetch(url: &str, config: &ClientConfig) -> anyhow::Result<Vec<u8>> {
+    let client = config.net.http_client().context("build http client")?;
+    let response = client.get(url).send().await?.error_for_status()?;
+    if let Some(content_type) = response.headers().get(CONTENT_TYPE) {
+        let content_type = content_type.to_str().unwrap_or_default();
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+        anyhow::ensure!(
+            METAINFO_TYPES.contains(&mime.to_ascii_lowercase().as_str()),
+            "server sent {mime}, not a torrent file"
+        );
+    }
+    let metainfo = tracker::read_limited(response, MAX_METAINFO).await?;
+    anyhow::ensure!(
+        metainfo.first() == Some(&b'd'),
+        "response is not a bencoded dictionary"
+    );
+    Ok(metainfo)
+}
+
+/// The BEP 12 tracker tiers, or just the `announce` URL for torrents without any.
+pub fn tiers(metainfo: &[u8], t: &Torrent) -> Vec<Vec<String>> {
+    match serde_bencode::from_bytes::<Extras>(metainfo) {
+        Ok(extras) if !extras.announce_list.is_empty() => extras.announce_list,
+        _ => vec![vec![t.announce.clone()]],
+    }
+}
+
+/// Every file in the torrent as a `/`-separated path under the torrent name, with its length.
+pub fn files(metainfo: &[u8], t: &Torrent) -> Vec<(String, u64)> {
+    let files = serde_bencode::from_bytes::<Extras>(metainfo)
+        .ok()
+        .and_then(|extras| extras.info.files);
+    match files {
+        Some(files) => files
+            .into_iter()
+            .map(|file| {
+                (
+                    format!("{}/{}", t.info.name, file.path.join("/")),
+                    file.length,
+                )
+            })
+            .collect(),
+        None => vec![(t.info.name.clone(), t.length() as u64)],
+    }
+}
+
+/// Whether the torrent is private (BEP 27), i.e. its peers should only come from its trackers.
+pub fn is_private(metainfo: &[u8]) -> bool {
+    serde_bencode::from_bytes::<Extras>(metainfo).is_ok_and(|extras| extras.info.private == Some(1))
+}
@@ -0,0 +1,405 @@
+//! Message Stream Encryption (MSE/PE): the obfuscation handshake some peers and networks
+//! insist on before any BitTorrent traffic. We only implement the initiating side.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll, ready};
+
+use anyhow::Context;
+use num_bigint::BigUint;
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::transport::PeerIo;
+
+/// The 768-bit safe prime all MSE implementations share.
+const PRIME: &[u8] = b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74\
+    020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576\
+    625E7EC6F44C42E9A63A36210000000000090563";
+const GENERATOR: u32 = 2;
+const KEY_LEN: usize = 96;
+const MAX_PAD: usize = 512;
+/// Verification constant: eight zero bytes, sent encrypted so each side can find where the
+/// other's random padding ends.
+const VC: [u8; 8] = [0; 8];
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+/// Whether to obfuscate peer connections.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encryption {
+    /// Try MSE first and fall back to a plaintext connection if the peer won't speak it.
+    Prefer,
+    /// Only talk to peers over RC4-encrypted connections.
+    Require,
+    /// Always connect in plaintext.
+    #[default]
+    Disable,
+}
+
+/// RC4 with the first 1024 bytes of keystream discarded, as MSE requires.
+#[derive(Clone)]
+struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut s = [0u8; 256];
+        for (i, b) in s.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        let mut rc4 = Self { s, i: 0, j: 0 };
+        rc4.apply(&mut [0; 1024]);
+        rc4
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k = self.s[self.s[self.i as usize].wrapping_add(self.s[self.j as usize]) as usize];
+            *byte ^= k;
+        }
+    }
+}
+
+/// A peer connection that may be RC4-encrypted in either direction.
+pub struct PeerStream {
+    inner: Box<dyn PeerIo>,
+    read: Option<Rc4>,
+    write: Option<Rc4>,
+    /// Encrypted bytes accepted from the caller but not yet written to the socket.
+    out: Vec<u8>,
+    out_pos: usize,
+}
+
+impl PeerStream {
+    pub fn plaintext(inner: Box<dyn PeerIo>) -> Self {
+        Self {
+            inner,
+            read: None,
+            write: None,
+            out: Vec::new(),
+            out_pos: 0,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.write.is_some()
+    }
+
+    fn poll_drain(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        while self.out_pos < self.out.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.out[self.out_pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.out_pos += n;
+        }
+        self.out.clear();
+        self.out_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(rc4) = &mut this.read {
+            rc4.apply(&mut buf.filled_mut()[filled..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.write.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        // the keystream has moved on once bytes are encrypted, so they must all be sent
+        // before we accept more
+        ready!(this.poll_drain(cx))?;
+        let rc4 = this.write.as_mut().expect("checked above");
+        this.out.extend_from_slice(buf);
+        rc4.apply(&mut this.out);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn to_key_bytes(n: &BigUint) -> [u8; KEY_LEN] {
+    let bytes = n.to_bytes_be();
+    let mut padded = [0; KEY_LEN];
+    padded[KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    padded
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(0..=MAX_PAD);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+/// Runs the initiator side of the MSE handshake for the torrent `info_hash`, after which the
+/// regular BitTorrent handshake goes over the returned stream.
+#[tracing::instrument(skip_all)]
+pub async fn initiate(
+    mut stream: Box<dyn PeerIo>,
+    info_hash: [u8; 20],
+    allow_plaintext: bool,
+) -> anyhow::Result<PeerStream> {
+    let prime = BigUint::parse_bytes(PRIME, 16).expect("MSE prime is valid hex");
+    let private = BigUint::from_bytes_be(&rand::thread_rng().gen::<[u8; 20]>());
+    let public = BigUint::from(GENERATOR).modpow(&private, &prime);
+
+    let mut hello = to_key_bytes(&public).to_vec();
+    hello.extend(random_pad());
+    stream
+        .write_all(&hello)
+        .await
+        .context("send MSE public key")?;
+
+    let mut theirs = [0; KEY_LEN];
+    stream
+        .read_exact(&mut theirs)
+        .await
+        .context("read MSE public key")?;
+    let secret = to_key_bytes(&BigUint::from_bytes_be(&theirs).modpow(&private, &prime));
+
+    let mut encrypt = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut decrypt = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+
+    let mut request = hash(&[b"req1", &secret]).to_vec();
+    let req2 = hash(&[b"req2", &info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    request.extend(req2.iter().zip(req3).map(|(a, b)| a ^ b));
+    let mut offer = VC.to_vec();
+    let provide = if allow_plaintext {
+        CRYPTO_RC4 | CRYPTO_PLAINTEXT
+    } else {
+        CRYPTO_RC4
+    };
+    offer.extend(provide.to_be_bytes());
+    // no padding, and no initial payload: the BitTorrent handshake follows separately
+    offer.extend(0u16.to_be_bytes());
+    offer.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut offer);
+    request.extend(offer);
+    stream
+        .write_all(&request)
+        .await
+        .context("send MSE crypto offer")?;
+
+    // their padding ends where the encrypted verification constant starts
+    let mut expected = VC;
+    decrypt.clone().apply(&mut expected);
+    let mut window = Vec::with_capacity(MAX_PAD + VC.len());
+    while !window.ends_with(&expected) {
+        anyhow::ensure!(
+            window.len() < MAX_PAD + VC.len(),
+            "peer did not answer the MSE handshake"
+        );
+        window.push(stream.read_u8().await.context("read MSE padding")?);
+    }
+    let mut vc = VC;
+    decrypt.apply(&mut vc);
+
+    let mut answer = [0; 6];
+    stream
+        .read_exact(&mut answer)
+        .await
+        .context("read MSE crypto select")?;
+    decrypt.apply(&mut answer);
+    let select = u32::from_be_bytes(answer[..4].try_into().expect("slice is 4 bytes"));
+    let pad_len = u16::from_be_bytes([answer[4], answer[5]]) as usize;
+    anyhow::ensure!(
+        pad_len <= MAX_PAD,
+        "peer sent {pad_len} bytes of MSE padding"
+    );
+    let mut pad = vec![0; pad_len];
+    stream
+        .read_exact(&mut pad)
+        .await
+        .context("read MSE padding")?;
+    decrypt.apply(&mut pad);
+
+    let mut peer = PeerStream::plaintext(stream);
+    match select {
+        CRYPTO_RC4 => {
+            peer.read = Some(decrypt);
+            peer.write = Some(encrypt);
+        }
+        CRYPTO_PLAINTEXT if allow_plaintext => {}
+        select => anyhow::bail!("peer selected unsupported MSE crypto method {select:#x}"),
+    }
+    tracing::debug!(encrypted = peer.is_encrypted(), "MSE handshake complete");
+    Ok(peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::DuplexStream;
+
+    const INFO_HASH: [u8; 20] = [7; 20];
+
+    /// The receiving side of the handshake, selecting `select`. Hands back the stream and the
+    /// ciphers for what follows.
+    async fn respond(mut stream: DuplexStream, select: u32) -> (DuplexStream, Rc4, Rc4) {
+        let prime = BigUint::parse_bytes(PRIME, 16).unwrap();
+        let private = BigUint::from_bytes_be(&rand::thread_rng().gen::<[u8; 20]>());
+        let public = BigUint::from(GENERATOR).modpow(&private, &prime);
+        let mut theirs = [0; KEY_LEN];
+        stream.read_exact(&mut theirs).await.unwrap();
+        let mut hello = to_key_bytes(&public).to_vec();
+        hello.extend(random_pad());
+        stream.write_all(&hello).await.unwrap();
+        let secret = to_key_bytes(&BigUint::from_bytes_be(&theirs).modpow(&private, &prime));
+
+        // their padding ends where the request hash starts
+        let req1 = hash(&[b"req1", &secret]);
+        let mut window = Vec::new();
+        while !window.ends_with(&req1) {
+            window.push(stream.read_u8().await.unwrap());
+        }
+        let mut skey = [0; 20];
+        stream.read_exact(&mut skey).await.unwrap();
+        let req3 = hash(&[b"req3", &secret]);
+        let req2: Vec<_> = skey.iter().zip(req3).map(|(a, b)| a ^ b).collect();
+        assert_eq!(req2, hash(&[b"req2", &INFO_HASH]));
+
+        let mut decrypt = Rc4::new(&hash(&[b"keyA", &secret, &INFO_HASH]));
+        let mut encrypt = Rc4::new(&hash(&[b"keyB", &secret, &INFO_HASH]));
+        let mut offer = [0; 16];
+        stream.read_exact(&mut offer).await.unwrap();
+        decrypt.apply(&mut offer);
+        assert_eq!(offer[..8], VC);
+        let provide = u32::from_be_bytes(offer[8..12].try_into().unwrap());
+        assert_eq!(provide & CRYPTO_RC4, CRYPTO_RC4);
+        assert_eq!(offer[12..], [0, 0, 0, 0], "no padding or initial payload");
+
+        let mut answer = VC.to_vec();
+        answer.extend(select.to_be_bytes());
+        answer.extend(3u16.to_be_bytes());
+        answer.extend([1, 2, 3]);
+        encrypt.apply(&mut answer);
+        stream.write_all(&answer).await.unwrap();
+        (stream, decrypt, encrypt)
+    }
+
+    #[tokio::test]
+    async fn negotiates_rc4() {
+        let (ours, theirs) = tokio::io::duplex(4096);
+        let responder = tokio::spawn(respond(theirs, CRYPTO_RC4));
+        let mut peer = initiate(Box::new(ours), INFO_HASH, true).await.unwrap();
+        let (mut theirs, mut decrypt, mut encrypt) = responder.await.unwrap();
+        assert!(peer.is_encrypted());
+
+        peer.write_all(b"hello").await.unwrap();
+        peer.flush().await.unwrap();
+        let mut received = [0; 5];
+        theirs.read_exact(&mut received).await.unwrap();
+        assert_ne!(&received, b"hello");
+        decrypt.apply(&mut received);
+        assert_eq!(&received, b"hello");
+
+        let mut reply = *b"world";
+        encrypt.apply(&mut reply);
+        theirs.write_all(&reply).await.unwrap();
+        let mut received = [0; 5];
+        peer.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"world");
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_plaintext_if_allowed() {
+        let (ours, theirs) = tokio::io::duplex(4096);
+        let responder = tokio::spawn(respond(theirs, CRYPTO_PLAINTEXT));
+        let mut peer = initiate(Box::new(ours), INFO_HASH, true).await.unwrap();
+        let (mut theirs, _, _) = responder.await.unwrap();
+        assert!(!peer.is_encrypted());
+
+        peer.write_all(b"hello").await.unwrap();
+        let mut received = [0; 5];
+        theirs.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"hello");
+    }
+
+    #[tokio::test]
+    async fn refuses_plaintext_when_encryption_is_required() {
+        let (ours, theirs) = tokio::io::duplex(4096);
+        let responder = tokio::spawn(respond(theirs, CRYPTO_PLAINTEXT));
+        let error = initiate(Box::new(ours), INFO_HASH, false)
+            .await
+            .err()
+            .expect("plaintext was selected");
+        assert_eq!(
+            error.to_string(),
+            "peer selected unsupported MSE crypto method 0x1"
+        );
+        responder.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_peers_that_do_not_answer() {
+        let (ours, mut theirs) = tokio::io::duplex(4096);
+        let responder = tokio::spawn(async move {
+            let mut key = [0; KEY_LEN];
+            theirs.read_exact(&mut key).await.unwrap();
+            // a key, then more than the most padding allowed without a verification constant
+            theirs
+                .write_all(&[1; KEY_LEN + MAX_PAD + 64])
+                .await
+                .unwrap();
+            theirs
+        });
+        let error = initiate(Box::new(ours), INFO_HASH, true)
+            .await
+            .err()
+            .expect("no verification constant was sent");
+        assert_eq!(error.to_string(), "peer did not answer the MSE handshake");
+        drop(responder.await.unwrap());
+    }
+}
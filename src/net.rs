@@ -0,0 +1,183 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::Context;
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+
+use crate::proxy;
+
+/// Where a torrent's traffic leaves the machine, and how its TCP sockets are set up.
+#[derive(Debug, Clone)]
+pub struct NetConfig {
+    pub bind: Option<IpAddr>,
+    pub interface: Option<String>,
+    /// SOCKS5 or HTTP proxy to send tracker requests and peer connections through.
+    pub proxy: Option<reqwest::Url>,
+    /// Set `TCP_NODELAY`, so short messages like requests aren't held back to be coalesced.
+    pub nodelay: bool,
+    /// Socket buffer sizes in bytes, where not left to the OS.
+    pub send_buffer: Option<u32>,
+    pub recv_buffer: Option<u32>,
+}
+
+impl Default for NetConfig {
+    fn default() -> Self {
+        Self {
+            bind: None,
+            interface: None,
+            proxy: None,
+            nodelay: true,
+            send_buffer: None,
+            recv_buffer: None,
+        }
+    }
+}
+
+impl NetConfig {
+    /// Applies the configured buffer sizes to a socket before it connects or listens.
+    pub fn tune(&self, socket: &TcpSocket) -> io::Result<()> {
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+
+    /// Connects to `addr`, through the proxy if one is configured.
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let Some(proxy) = &self.proxy else {
+            return self.connect_direct(addr).await;
+        };
+        let host = proxy.host_str().unwrap_or_default();
+        let port = proxy.port_or_known_default().unwrap_or(1080);
+        let proxy_addr = tokio::net::lookup_host((host, port))
+            .await?
+            .next()
+            .ok_or_else(|| io::Error::other(format!("proxy host {host} did not resolve")))?;
+        let mut stream = self.connect_direct(proxy_addr).await?;
+        proxy::handshake(&mut stream, proxy, addr).await?;
+        Ok(stream)
+    }
+
+    async fn connect_direct(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        if let Some(interface) = &self.interface {
+            #[cfg(target_os = "linux")]
+            socket.bind_device(Some(interface.as_bytes()))?;
+            #[cfg(not(target_os = "linux"))]
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("binding to interface {interface} is only supported on Linux"),
+            ));
+        }
+        if let Some(ip) = self.bind {
+            socket.bind(SocketAddr::new(ip, 0))?;
+        }
+        self.tune(&socket)?;
+        let stream = socket.connect(addr).await?;
+        stream.set_nodelay(self.nodelay)?;
+        Ok(stream)
+    }
+
+    /// A UDP socket for talking to `peer`, bound like our TCP connections.
+    pub async fn bind_udp(&self, peer: SocketAddr) -> io::Result<UdpSocket> {
+        let ip = self.bind.unwrap_or(if peer.is_ipv4() {
+            Ipv4Addr::UNSPECIFIED.into()
+        } else {
+            Ipv6Addr::UNSPECIFIED.into()
+        });
+        let socket = UdpSocket::bind(SocketAddr::new(ip, 0)).await?;
+        if let Some(interface) = &self.interface {
+            #[cfg(target_os = "linux")]
+            socket.bind_device(Some(interface.as_bytes()))?;
+            #[cfg(not(target_os = "linux"))]
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("binding to interface {interface} is only supported on Linux"),
+            ));
+        }
+        Ok(socket)
+    }
+
+    /// A client for trackers and web seeds, leaving through the same address, interface and
+    /// proxy as peer connections.
+    pub fn http_client(&self) -> anyhow::Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder().local_address(self.bind);
+        if let (Some(interface), None) = (&self.interface, self.bind) {
+            // the client can only be bound to an address, so use the interface's own
+            #[cfg(target_os = "linux")]
+            {
+                let address = interface_address(interface)
+                    .with_context(|| format!("find the address of interface {interface}"))?;
+                builder = builder.local_address(address);
+            }
+            #[cfg(not(target_os = "linux"))]
+            anyhow::bail!("binding to interface {interface} is only supported on Linux");
+        }
+        if let Some(proxy) = &self.proxy {
+            let mut proxy = proxy.clone();
+            if proxy.scheme() == "socks5" {
+                // let the proxy resolve tracker hostnames so lookups don't leak around it
+                proxy
+                    .set_scheme("socks5h")
+                    .expect("socks5h is a valid scheme");
+            }
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// An address `interface` has, IPv4 if it has one since that's how most trackers are reached.
+#[cfg(target_os = "linux")]
+fn interface_address(interface: &str) -> io::Result<IpAddr> {
+    let mut list = std::ptr::null_mut();
+    // SAFETY: on success `list` points to a linked list we own until `freeifaddrs`
+    if unsafe { libc::getifaddrs(&mut list) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut addresses = Vec::new();
+    let mut next = list;
+    while !next.is_null() {
+        // SAFETY: every node, and the name and address it points to, live until the list is
+        // freed; the address is as long as its family says
+        let entry = unsafe { &*next };
+        next = entry.ifa_next;
+        let name = unsafe { std::ffi::CStr::from_ptr(entry.ifa_name) };
+        if entry.ifa_addr.is_null() || name.to_bytes() != interface.as_bytes() {
+            continue;
+        }
+        match i32::from(unsafe { (*entry.ifa_addr).sa_family }) {
+            libc::AF_INET => {
+                let addr = unsafe { &*entry.ifa_addr.cast::<libc::sockaddr_in>() };
+                addresses.push(IpAddr::from(Ipv4Addr::from(u32::from_be(
+                    addr.sin_addr.s_addr,
+                ))));
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*entry.ifa_addr.cast::<libc::sockaddr_in6>() };
+                addresses.push(IpAddr::from(Ipv6Addr::from(addr.sin6_addr.s6_addr)));
+            }
+            _ => {}
+        }
+    }
+    // SAFETY: nothing borrowed from the list outlives this
+    unsafe { libc::freeifaddrs(list) };
+    addresses
+        .iter()
+        .find(|address| address.is_ipv4())
+        .or(addresses.first())
+        .copied()
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("interface {interface} has no address"),
+            )
+        })
+}
@@ -0,0 +1,367 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use futures_util::SinkExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+use tokio_util::codec::Framed;
+
+use crate::bitfield::Bitfield;
+use crate::config::ClientConfig;
+use crate::download::{self, PeerState};
+use crate::failures::{HashFailures, HashMismatch};
+use crate::geometry::PieceGeometry;
+use crate::hash::TorrentVersion;
+use crate::message::{Message, MessageFramer, MessageTag};
+use crate::mse::{self, Encryption, PeerStream};
+use crate::timeout::{self, TimeoutError};
+use crate::transport::PeerIo;
+use crate::wire::Handshake;
+
+async fn dial(addr: SocketAddr, config: &ClientConfig) -> anyhow::Result<Box<dyn PeerIo>> {
+    let _connecting = config.half_open.acquire().await;
+    let stream = timeout::timeout(config.timeouts.connect, TimeoutError::Connect, async {
+        config.transport.connect(addr, &config.net).await
+    })
+    .await?
+    .context("connect to peer")?;
+    tracing::debug!(transport = ?config.transport, "connection established");
+    Ok(stream)
+}
+
+/// Dials `addr` and, if configured, negotiates Message Stream Encryption over it.
+async fn open_stream(
+    addr: SocketAddr,
+    info_hash: [u8; 20],
+    config: &ClientConfig,
+) -> anyhow::Result<PeerStream> {
+    let stream = dial(addr, config).await?;
+    let handshake = config.timeouts.handshake;
+    match config.encryption {
+        Encryption::Disable => Ok(PeerStream::plaintext(stream)),
+        Encryption::Require => {
+            timeout::timeout(handshake, TimeoutError::Handshake, async {
+                mse::initiate(stream, info_hash, false).await
+            })
+            .await?
+        }
+        Encryption::Prefer => {
+            let mse = timeout::timeout(handshake, TimeoutError::Handshake, async {
+                mse::initiate(stream, info_hash, true).await
+            })
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|r| r);
+            match mse {
+                Ok(stream) => Ok(stream),
+                Err(e) => {
+                    // a peer that doesn't speak MSE hangs up on it, so start afresh
+                    tracing::debug!(error = %e, "MSE handshake failed, retrying in plaintext");
+                    Ok(PeerStream::plaintext(dial(addr, config).await?))
+                }
+            }
+        }
+    }
+}
+
+/// Connects to `addr` and exchanges handshakes, returning the stream and the peer's handshake.
+#[tracing::instrument(skip_all, fields(peer = %addr))]
+pub async fn connect(
+    addr: SocketAddr,
+    info_hash: [u8; 20],
+    config: &ClientConfig,
+) -> anyhow::Result<(PeerStream, Handshake)> {
+    let mut peer = open_stream(addr, info_hash, config).await?;
+    let handshake = handshake(&mut peer, info_hash, config).await?;
+    Ok((peer, handshake))
+}
+
+/// Exchanges BitTorrent handshakes over an already connected stream of any transport.
+pub async fn handshake<S>(
+    peer: &mut S,
+    info_hash: [u8; 20],
+    config: &ClientConfig,
+) -> anyhow::Result<Handshake>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let timeouts = &config.timeouts;
+    let mut handshake = Handshake::new(info_hash, config.peer_id.0);
+    handshake.reserved = config.capabilities.to_reserved();
+    let mut handshake_bytes = handshake.to_bytes();
+    timeout::timeout(timeouts.handshake, TimeoutError::Handshake, async {
+        peer.write_all(&handshake_bytes)
+            .await
+            .context("write handshake")?;
+        // an encrypted stream buffers what it has encrypted until flushed
+        peer.flush().await.context("write handshake")?;
+        peer.read_exact(&mut handshake_bytes)
+            .await
+            .context("read handshake")?;
+        anyhow::Ok(())
+    })
+    .await??;
+    let handshake = Handshake::from_bytes(&handshake_bytes).context("parse peer handshake")?;
+    tracing::debug!(
+        capabilities = ?handshake.capabilities(),
+        "handshake complete"
+    );
+    Ok(handshake)
+}
+
+/// Answers the handshake of a peer that connected to us. The peer speaks first; its info
+/// hash must be one `known` accepts, and we reply for that torrent.
+pub async fn accept_handshake<S>(
+    peer: &mut S,
+    known: impl Fn(&[u8; 20]) -> bool,
+    config: &ClientConfig,
+) -> anyhow::Result<Handshake>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut theirs = [0; Handshake::LEN];
+    let handshake = timeout::timeout(config.timeouts.handshake, TimeoutError::Handshake, async {
+        peer.read_exact(&mut theirs)
+            .await
+            .context("read handshake")?;
+        let theirs = Handshake::from_bytes(&theirs).context("parse peer handshake")?;
+        anyhow::ensure!(
+            known(&theirs.info_hash),
+            "peer wants unknown torrent {}",
+            hex::encode(theirs.info_hash)
+        );
+        let mut ours = Handshake::new(theirs.info_hash, config.peer_id.0);
+        ours.reserved = config.capabilities.to_reserved();
+        peer.write_all(&ours.to_bytes())
+            .await
+            .context("write handshake")?;
+        peer.flush().await.context("write handshake")?;
+        anyhow::Ok(theirs)
+    })
+    .await??;
+    tracing::debug!(
+        capabilities = ?handshake.capabilities(),
+        "inbound handshake complete"
+    );
+    Ok(handshake)
+}
+
+/// Delay between starting successive connection attempts in [`connect_any`].
+const CONNECT_STAGGER: Duration = Duration::from_millis(250);
+/// How long a new peer gets to announce its pieces before we take it to have none.
+const ANNOUNCEMENT_WAIT: Duration = Duration::from_secs(5);
+
+/// Races connection attempts to every known endpoint of a single peer, starting them
+/// [`CONNECT_STAGGER`] apart, and keeps the first one that completes the handshake.
+/// The remaining attempts are cancelled.
+pub async fn connect_any(
+    endpoints: &[SocketAddr],
+    info_hash: [u8; 20],
+    config: &ClientConfig,
+) -> anyhow::Result<(PeerStream, Handshake, SocketAddr)> {
+    anyhow::ensure!(!endpoints.is_empty(), "peer has no known endpoints");
+
+    let mut attempts = JoinSet::new();
+    for (i, addr) in interleave_families(endpoints).into_iter().enumerate() {
+        let config = config.clone();
+        attempts.spawn(async move {
+            tokio::time::sleep(CONNECT_STAGGER * i as u32).await;
+            connect(addr, info_hash, &config)
+                .await
+                .with_context(|| format!("connect to {addr}"))
+                .map(|(stream, handshake)| (stream, handshake, addr))
+        });
+    }
+
+    let mut last_err = None;
+    while let Some(attempt) = attempts.join_next().await {
+        match attempt.context("connection attempt panicked")? {
+            // dropping the JoinSet aborts the attempts still in flight
+            Ok(connected) => return Ok(connected),
+            Err(e) => {
+                tracing::debug!(error = %e, "connection attempt failed");
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.expect("at least one attempt was made"))
+}
+
+/// Alternates IPv6 and IPv4 endpoints so a broken address family doesn't delay the other.
+fn interleave_families(endpoints: &[SocketAddr]) -> Vec<SocketAddr> {
+    let (mut v6, mut v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        endpoints.iter().copied().partition(|a| a.is_ipv6());
+    v6.reverse();
+    v4.reverse();
+    let mut ordered = Vec::with_capacity(endpoints.len());
+    while !v6.is_empty() || !v4.is_empty() {
+        ordered.extend(v6.pop());
+        ordered.extend(v4.pop());
+    }
+    ordered
+}
+
+/// Caps how many peer connections may be open, or still connecting, at once across every
+/// torrent, so a large swarm can't exhaust our file descriptors. Clones share the same slots.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimit {
+    slots: Arc<Semaphore>,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(max)),
+        }
+    }
+
+    /// Waits for a free slot. It is given back when the permit is dropped.
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        Arc::clone(&self.slots)
+            .acquire_owned()
+            .await
+            .expect("connection limit is never closed")
+    }
+
+    /// A free slot, if there is one right now.
+    pub fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        Arc::clone(&self.slots).try_acquire_owned().ok()
+    }
+}
+
+/// An established connection to a peer together with the state of our exchange with it.
+pub struct PeerConnection {
+    pub addr: SocketAddr,
+    pub handshake: Handshake,
+    pub frames: Framed<PeerStream, MessageFramer>,
+    pub state: PeerState,
+    bitfield: Option<Bitfield>,
+    /// Held for as long as the connection is open.
+    _slot: OwnedSemaphorePermit,
+}
+
+impl PeerConnection {
+    /// Connects, handshakes and reads the peer's piece announcement, if it makes one.
+    pub async fn open(
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        config: &ClientConfig,
+    ) -> anyhow::Result<Self> {
+        let slot = config.connections.acquire().await;
+        let (stream, handshake) = connect(addr, info_hash, config).await?;
+        Self::from_stream(addr, stream, handshake, slot, config).await
+    }
+
+    /// Sets up the exchange over a stream that has already completed the handshake, however
+    /// it was connected. `slot` is its share of [`ClientConfig::connections`].
+    pub async fn from_stream(
+        addr: SocketAddr,
+        stream: PeerStream,
+        handshake: Handshake,
+        slot: OwnedSemaphorePermit,
+        config: &ClientConfig,
+    ) -> anyhow::Result<Self> {
+        tracing::info!(
+            peer = %addr,
+            peer_id = %hex::encode(handshake.peer_id),
+            "connected"
+        );
+        let negotiated = config.capabilities.intersect(handshake.capabilities());
+        let mut state = PeerState::new(negotiated, config.strict);
+        let trace = config.wire_trace.as_ref().map(|file| file.peer(addr));
+        let mut frames = Framed::new(stream, MessageFramer::traced(trace));
+        if negotiated.fast {
+            // the Fast extension requires announcing our pieces, and we have none yet
+            frames
+                .send(Message::empty(MessageTag::HaveNone))
+                .await
+                .context("send have none message")?;
+        }
+        // peers with nothing to offer may not announce anything
+        if let Ok(first) = tokio::time::timeout(
+            ANNOUNCEMENT_WAIT,
+            download::recv(&mut frames, &state, &config.timeouts),
+        )
+        .await
+        {
+            download::apply(&first?, &mut state)?;
+        }
+        Ok(Self {
+            addr,
+            handshake,
+            frames,
+            state,
+            bitfield: None,
+            _slot: slot,
+        })
+    }
+
+    /// The pieces the peer has, given the number of pieces in the torrent: those it
+    /// announced, if it did, and those of any `have`s that came before we asked.
+    pub fn availability(&mut self, npieces: usize) -> anyhow::Result<&Bitfield> {
+        if self.bitfield.is_none() {
+            let mut bitfield = match self.state.announcement.take() {
+                Some(announcement) => {
+                    download::parse_availability(announcement, &self.state, npieces)?
+                }
+                None => Bitfield::new(npieces),
+            };
+            for piece in self.state.announced.drain(..) {
+                let piece = piece as usize;
+                anyhow::ensure!(
+                    piece < npieces,
+                    "peer announced piece {piece} of a torrent with {npieces}"
+                );
+                bitfield.set_piece(piece);
+            }
+            self.bitfield = Some(bitfield);
+        }
+        Ok(self
+            .bitfield
+            .as_ref()
+            .expect("availability was just worked out"))
+    }
+
+    pub async fn interested(&mut self) -> anyhow::Result<()> {
+        self.frames
+            .send(Message::empty(MessageTag::Interested))
+            .await
+            .context("send interested message")
+    }
+
+    /// Downloads and verifies `piece`. A piece that fails verification is counted against
+    /// this peer and returned as a [`HashMismatch`], so the caller can fetch it from another.
+    /// The download is cancelled once `superseded` says another peer has finished it.
+    pub async fn download_piece(
+        &mut self,
+        geometry: &PieceGeometry,
+        piece: usize,
+        hash: &[u8],
+        failures: &HashFailures,
+        config: &ClientConfig,
+        superseded: impl Fn() -> bool,
+    ) -> anyhow::Result<Vec<u8>> {
+        let data = download::fetch_piece(
+            &mut self.frames,
+            &mut self.state,
+            &geometry.with_block_size(config.block_size),
+            piece,
+            &config.timeouts,
+            superseded,
+        )
+        .await?;
+        let (data, ok) = TorrentVersion::V1.verify_blocking(data, hash).await;
+        if ok {
+            return Ok(data);
+        }
+        failures.record(piece, self.addr)?;
+        Err(HashMismatch {
+            piece,
+            peer: self.addr,
+        }
+        .into())
+    }
+}
@@ -0,0 +1,146 @@
+use std::fmt;
+use std::str::FromStr;
+
+use rand::Rng;
+use rand::distributions::Alphanumeric;
+
+/// Azureus-style client prefix: `RS` for this client, version 0.1.0.0.
+const PREFIX: &[u8; 8] = b"-RS0100-";
+
+/// The 20-byte id we present to trackers and peers. Always printable ASCII so it survives
+/// being sent as a tracker query parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerId(pub [u8; 20]);
+
+impl PeerId {
+    /// A fresh id: the client prefix followed by random alphanumerics.
+    pub fn generate() -> Self {
+        let mut id = [0; 20];
+        id[..PREFIX.len()].copy_from_slice(PREFIX);
+        let mut rng = rand::thread_rng();
+        for byte in &mut id[PREFIX.len()..] {
+            *byte = rng.sample(Alphanumeric);
+        }
+        Self(id)
+    }
+
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.0).expect("peer ids are ASCII")
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("peer id must be exactly 20 printable ASCII characters")]
+pub struct InvalidPeerId;
+
+impl FromStr for PeerId {
+    type Err = InvalidPeerId;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let id: [u8; 20] = s.as_bytes().try_into().map_err(|_| InvalidPeerId)?;
+        if !id.iter().all(|b| b.is_ascii_graphic()) {
+            return Err(InvalidPeerId);
+        }
+        Ok(Self(id))
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The client software a peer id claims, decoded from the usual peer id conventions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub name: String,
+    pub version: String,
+}
+
+impl fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.name, self.version)
+    }
+}
+
+/// Decodes an Azureus-style (`-UT3550-`), Mainline-style (`M7-4-2--`) or Shadow-style
+/// (`S58B-----`) peer id. Returns `None` for ids following none of them.
+pub fn client(id: &[u8; 20]) -> Option<ClientInfo> {
+    if id[0] == b'-' && id[7] == b'-' && id[1..7].iter().all(u8::is_ascii_alphanumeric) {
+        let code = std::str::from_utf8(&id[1..3]).ok()?;
+        let name = azureus_client(code).map_or_else(|| format!("unknown ({code})"), String::from);
+        let version = id[3..7]
+            .iter()
+            .map(|&b| char::from(b).to_string())
+            .collect::<Vec<_>>()
+            .join(".");
+        return Some(ClientInfo { name, version });
+    }
+    if id[0] == b'M' {
+        let prefix = std::str::from_utf8(&id[1..8]).ok()?;
+        let version: Vec<_> = prefix.trim_end_matches('-').split('-').collect();
+        if version
+            .iter()
+            .all(|v| !v.is_empty() && v.bytes().all(|b| b.is_ascii_digit()))
+        {
+            return Some(ClientInfo {
+                name: "Mainline".to_string(),
+                version: version.join("."),
+            });
+        }
+    }
+    let name = shadow_client(id[0])?;
+    let version: String = id[1..6]
+        .iter()
+        .take_while(|&&b| b != b'-')
+        .map(|&b| shadow_digit(b).map(|d| d.to_string()))
+        .collect::<Option<Vec<_>>>()?
+        .join(".");
+    (!version.is_empty()).then(|| ClientInfo {
+        name: name.to_string(),
+        version,
+    })
+}
+
+fn azureus_client(code: &str) -> Option<&'static str> {
+    Some(match code {
+        "AZ" => "Vuze",
+        "BC" => "BitComet",
+        "BT" => "BitTorrent",
+        "DE" => "Deluge",
+        "KT" => "KTorrent",
+        "LT" => "libtorrent (rakshasa)",
+        "lt" => "libtorrent",
+        "qB" => "qBittorrent",
+        "RS" => "bittorrent-starter-rust",
+        "TR" => "Transmission",
+        "UT" => "\u{b5}Torrent",
+        "WW" => "WebTorrent",
+        _ => return None,
+    })
+}
+
+fn shadow_client(code: u8) -> Option<&'static str> {
+    Some(match code {
+        b'A' => "ABC",
+        b'O' => "Osprey Permaseed",
+        b'Q' => "BTQueue",
+        b'R' => "Tribler",
+        b'S' => "Shadow's client",
+        b'T' => "BitTornado",
+        b'U' => "UPnP NAT Bit Torrent",
+        _ => return None,
+    })
+}
+
+/// Shadow-style ids encode each version number as one base-64 character.
+fn shadow_digit(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'A'..=b'Z' => Some(b - b'A' + 10),
+        b'a'..=b'z' => Some(b - b'a' + 36),
+        b'.' => Some(62),
+        _ => None,
+    }
+}
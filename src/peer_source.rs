@@ -0,0 +1,179 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures_util::future::BoxFuture;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use bittorrent_starter_rust::Torrent;
+
+use crate::peer::PeerConnection;
+use crate::tracker::{Announcer, Event, Tiers};
+
+/// Somewhere candidate peers come from: a tracker, a fixed list, or any discovery mechanism
+/// an embedder plugs in.
+pub trait PeerSource: Send {
+    /// Short name for logs and statistics.
+    fn name(&self) -> &str;
+
+    /// Shortest gap allowed between two polls of this source.
+    fn min_interval(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    /// Asks the source for peers. Returning peers already handed out is fine; they are
+    /// filtered before being dialled.
+    fn peers(&mut self) -> BoxFuture<'_, anyhow::Result<Vec<SocketAddr>>>;
+}
+
+/// Peers from announcing a torrent to its trackers. The first announce is sent as `started`.
+pub struct TrackerSource<'a> {
+    announcer: &'a Announcer,
+    torrent: &'a Torrent,
+    tiers: Tiers,
+    event: Option<Event>,
+}
+
+impl<'a> TrackerSource<'a> {
+    pub fn new(announcer: &'a Announcer, torrent: &'a Torrent, tiers: Tiers) -> Self {
+        Self {
+            announcer,
+            torrent,
+            tiers,
+            event: Some(Event::Started),
+        }
+    }
+}
+
+impl PeerSource for TrackerSource<'_> {
+    fn name(&self) -> &str {
+        "tracker"
+    }
+
+    fn min_interval(&self) -> Duration {
+        let tracker = self.tiers.current().unwrap_or(&self.torrent.announce);
+        self.announcer
+            .min_interval(tracker)
+            .unwrap_or(Duration::from_secs(30))
+    }
+
+    fn peers(&mut self) -> BoxFuture<'_, anyhow::Result<Vec<SocketAddr>>> {
+        Box::pin(async move {
+            let response = self
+                .announcer
+                .announce_torrent(self.torrent, &mut self.tiers, self.event.take())
+                .await?;
+            Ok(response.peers.0.into_iter().map(SocketAddr::from).collect())
+        })
+    }
+}
+
+/// A fixed list of peers, such as those a tracker returned before the torrent was known.
+pub struct StaticPeers(pub Vec<SocketAddr>);
+
+impl PeerSource for StaticPeers {
+    fn name(&self) -> &str {
+        "static"
+    }
+
+    fn peers(&mut self) -> BoxFuture<'_, anyhow::Result<Vec<SocketAddr>>> {
+        let peers = self.0.clone();
+        Box::pin(async move { Ok(peers) })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SourceStats {
+    pub polls: u32,
+    pub failures: u32,
+    /// Peers returned across all polls, duplicates included.
+    pub returned: usize,
+    /// Peers this source was the first to hand out.
+    pub new: usize,
+}
+
+struct Slot<'a> {
+    source: Box<dyn PeerSource + 'a>,
+    stats: SourceStats,
+    last_poll: Option<Instant>,
+}
+
+/// Every peer source for a download, polled for fresh candidates whenever the connection
+/// manager runs out of peers to dial, plus any peers that connected to us.
+#[derive(Default)]
+pub struct PeerSources<'a> {
+    slots: Vec<Slot<'a>>,
+    seen: HashSet<SocketAddr>,
+    inbound: Option<mpsc::Receiver<PeerConnection>>,
+}
+
+impl<'a> PeerSources<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, source: impl PeerSource + 'a) -> &mut Self {
+        self.slots.push(Slot {
+            source: Box::new(source),
+            stats: SourceStats::default(),
+            last_poll: None,
+        });
+        self
+    }
+
+    /// Takes peers that connected to us from `inbound`, ahead of any we would dial.
+    pub fn set_inbound(&mut self, inbound: mpsc::Receiver<PeerConnection>) {
+        self.inbound = Some(inbound);
+    }
+
+    /// A peer that connected to us and is waiting to be used, if there is one.
+    pub fn take_inbound(&mut self) -> Option<PeerConnection> {
+        let conn = self.inbound.as_mut()?.try_recv().ok()?;
+        self.seen.insert(conn.addr);
+        Some(conn)
+    }
+
+    /// Records a peer that was found some other way, so sources don't hand it out again.
+    pub fn mark_seen(&mut self, peer: SocketAddr) {
+        self.seen.insert(peer);
+    }
+
+    /// Polls every source whose interval has passed and returns the peers none of them has
+    /// handed out before. A source that fails is logged and skipped.
+    pub async fn poll(&mut self) -> Vec<SocketAddr> {
+        let mut fresh = Vec::new();
+        for slot in &mut self.slots {
+            let due = slot
+                .last_poll
+                .is_none_or(|last| last.elapsed() >= slot.source.min_interval());
+            if !due {
+                continue;
+            }
+            slot.last_poll = Some(Instant::now());
+            slot.stats.polls += 1;
+            match slot.source.peers().await {
+                Ok(peers) => {
+                    slot.stats.returned += peers.len();
+                    for peer in peers {
+                        if self.seen.insert(peer) {
+                            slot.stats.new += 1;
+                            fresh.push(peer);
+                        }
+                    }
+                }
+                Err(e) => {
+                    slot.stats.failures += 1;
+                    tracing::warn!(source = slot.source.name(), error = %e, "peer source failed");
+                }
+            }
+        }
+        fresh
+    }
+
+    pub fn stats(&self) -> impl Iterator<Item = (&str, SourceStats)> {
+        self.slots
+            .iter()
+            .map(|slot| (slot.source.name(), slot.stats))
+    }
+}
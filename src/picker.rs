@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::geometry::PieceGeometry;
+
+/// How much we want a file, or a piece inherited from the files it overlaps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Not wanted at all.
+    Skip,
+    #[default]
+    Normal,
+    High,
+}
+
+/// Works out each piece's priority from the priorities of the files laid end to end in the
+/// torrent. A piece spanning several files takes the highest of their priorities, so a piece
+/// is only skipped when every byte of it belongs to skipped files.
+pub fn piece_priorities(files: &[(usize, Priority)], geometry: &PieceGeometry) -> Vec<Priority> {
+    let mut priorities = vec![Priority::Skip; geometry.piece_count()];
+    let mut offset = 0;
+    for &(length, priority) in files {
+        if length > 0 {
+            let first = geometry.piece_at(offset);
+            let last = geometry.piece_at(offset + length - 1);
+            for piece in &mut priorities[first..=last] {
+                *piece = (*piece).max(priority);
+            }
+        }
+        offset += length;
+    }
+    priorities
+}
+
+/// The priority of each file in a torrent, in the order the files are laid end to end, from
+/// which the piece priorities follow.
+#[derive(Debug, Clone)]
+pub struct FilePriorities {
+    files: Vec<(usize, Priority)>,
+    geometry: PieceGeometry,
+}
+
+impl FilePriorities {
+    /// Files of the given lengths, all at normal priority.
+    pub fn new(lengths: impl IntoIterator<Item = usize>, geometry: PieceGeometry) -> Self {
+        Self {
+            files: lengths
+                .into_iter()
+                .map(|length| (length, Priority::default()))
+                .collect(),
+            geometry,
+        }
+    }
+
+    pub fn set_file_priority(&mut self, index: usize, priority: Priority) {
+        assert!(
+            index < self.files.len(),
+            "file {index} out of range for {} files",
+            self.files.len()
+        );
+        self.files[index].1 = priority;
+    }
+
+    pub fn piece_priorities(&self) -> Vec<Priority> {
+        piece_priorities(&self.files, &self.geometry)
+    }
+}
+
+/// How many connected peers have each piece of a download, for telling a slow swarm from one
+/// missing pieces altogether.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Availability {
+    /// Peers with each piece, by piece index.
+    peers: BTreeMap<usize, usize>,
+}
+
+impl Availability {
+    /// Builds the view from `(piece, peers with it)` pairs.
+    pub fn new(peers: impl IntoIterator<Item = (usize, usize)>) -> Self {
+        Self {
+            peers: peers.into_iter().collect(),
+        }
+    }
+
+    /// Complete copies of the pieces the connected peers hold between them: how many have
+    /// the rarest piece, plus the fraction of pieces more common than that.
+    pub fn distributed_copies(&self) -> f64 {
+        let Some(&rarest) = self.peers.values().min() else {
+            return 0.0;
+        };
+        let above = self.peers.values().filter(|&&n| n > rarest).count();
+        rarest as f64 + above as f64 / self.peers.len() as f64
+    }
+
+    /// How many pieces are held by each number of peers.
+    pub fn histogram(&self) -> BTreeMap<usize, usize> {
+        let mut histogram = BTreeMap::new();
+        for &n in self.peers.values() {
+            *histogram.entry(n).or_default() += 1;
+        }
+        histogram
+    }
+}
+
+impl fmt::Display for Availability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "availability {:.2}, pieces by peer count:",
+            self.distributed_copies()
+        )?;
+        for (peers, pieces) in self.histogram() {
+            write!(f, " {peers}: {pieces}")?;
+        }
+        Ok(())
+    }
+}
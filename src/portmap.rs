@@ -0,0 +1,392 @@
+//! Asking the home router to forward our listen port, so peers outside can connect to us:
+//! NAT-PMP (RFC 6886) where the router speaks it, UPnP IGD otherwise. Mappings are leased and
+//! renewed at half their lifetime while we run; once we stop, the router drops them when the
+//! lease runs out.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+
+use anyhow::Context;
+use reqwest::Url;
+use reqwest::header::CONTENT_TYPE;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+const NATPMP_PORT: u16 = 5351;
+/// Lease asked for on each mapping.
+const LEASE: Duration = Duration::from_secs(3600);
+/// NAT-PMP requests are resent after 250 ms, then twice as long each time; we give up after
+/// fewer tries than RFC 6886 does, since UPnP is still left to try.
+const NATPMP_FIRST_WAIT: Duration = Duration::from_millis(250);
+const NATPMP_TRIES: u32 = 4;
+const SSDP_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+/// How long routers get to answer a UPnP search.
+const SSDP_WAIT: Duration = Duration::from_secs(2);
+/// For each request to a UPnP router's HTTP server.
+const UPNP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Wait before trying again when the router wouldn't map the port.
+const RETRY: Duration = Duration::from_secs(300);
+/// Shortest wait between renewals, whatever lease the router grants.
+const MIN_RENEW: Duration = Duration::from_secs(60);
+/// The UPnP services that can forward ports, most capable first.
+const WAN_SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    fn natpmp_opcode(self) -> u8 {
+        match self {
+            Protocol::Udp => 1,
+            Protocol::Tcp => 2,
+        }
+    }
+
+    fn upnp_name(self) -> &'static str {
+        match self {
+            Protocol::Tcp => "TCP",
+            Protocol::Udp => "UDP",
+        }
+    }
+}
+
+/// A port the router forwards to us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub external_port: u16,
+    pub lifetime: Duration,
+}
+
+/// Keeps `port` forwarded to us in the background, for TCP and, if `utp`, for UDP too.
+pub fn spawn(port: u16, utp: bool) -> JoinHandle<()> {
+    let protocols = if utp {
+        &[Protocol::Tcp, Protocol::Udp][..]
+    } else {
+        &[Protocol::Tcp][..]
+    };
+    tokio::spawn(async move {
+        loop {
+            let mut renew = LEASE / 2;
+            for &protocol in protocols {
+                match map(port, protocol).await {
+                    Ok(mapping) => {
+                        tracing::info!(
+                            port,
+                            external_port = mapping.external_port,
+                            protocol = protocol.upnp_name(),
+                            "router forwards listen port"
+                        );
+                        renew = renew.min(mapping.lifetime / 2);
+                    }
+                    Err(e) => {
+                        tracing::warn!(
+                            port,
+                            protocol = protocol.upnp_name(),
+                            error = %format!("{e:#}"),
+                            "port mapping failed"
+                        );
+                        renew = renew.min(RETRY);
+                    }
+                }
+            }
+            tokio::time::sleep(renew.max(MIN_RENEW)).await;
+        }
+    })
+}
+
+/// Forwards `port` over NAT-PMP, or over UPnP if that doesn't work.
+pub async fn map(port: u16, protocol: Protocol) -> anyhow::Result<Mapping> {
+    let natpmp = match default_gateway() {
+        Ok(gateway) => {
+            let gateway = SocketAddr::from((gateway, NATPMP_PORT));
+            match natpmp_map(gateway, port, protocol).await {
+                Ok(mapping) => return Ok(mapping),
+                Err(e) => e,
+            }
+        }
+        Err(e) => e,
+    };
+    tracing::debug!(error = %format!("{natpmp:#}"), "no NAT-PMP, trying UPnP");
+    upnp_map(port, protocol)
+        .await
+        .context("router offers neither NAT-PMP nor UPnP port mapping")
+}
+
+/// The IPv4 default gateway, where NAT-PMP requests go.
+#[cfg(target_os = "linux")]
+fn default_gateway() -> anyhow::Result<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route").context("read routing table")?;
+    parse_default_gateway(&routes).context("no IPv4 default route")
+}
+
+#[cfg(not(target_os = "linux"))]
+fn default_gateway() -> anyhow::Result<Ipv4Addr> {
+    anyhow::bail!("finding the default gateway is only supported on Linux")
+}
+
+/// The gateway of the default route in `/proc/net/route`, which prints addresses as the hex
+/// of their in-memory value.
+fn parse_default_gateway(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines().skip(1).find_map(|route| {
+        let fields: Vec<_> = route.split_whitespace().collect();
+        if fields.get(1) != Some(&"00000000") {
+            return None;
+        }
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        (gateway != 0).then(|| Ipv4Addr::from(gateway.to_ne_bytes()))
+    })
+}
+
+async fn natpmp_map(gateway: SocketAddr, port: u16, protocol: Protocol) -> anyhow::Result<Mapping> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect(gateway).await?;
+    let request = natpmp_request(port, protocol, LEASE);
+    let mut response = [0; 16];
+    let mut wait = NATPMP_FIRST_WAIT;
+    for _ in 0..NATPMP_TRIES {
+        socket.send(&request).await?;
+        match tokio::time::timeout(wait, socket.recv(&mut response)).await {
+            Ok(received) => {
+                let received = received.with_context(|| format!("ask {gateway} over NAT-PMP"))?;
+                return parse_natpmp_response(&response[..received], port, protocol);
+            }
+            Err(_) => wait *= 2,
+        }
+    }
+    anyhow::bail!("no NAT-PMP answer from {gateway}")
+}
+
+/// A request to forward the same external `port` to ours for `lease`.
+fn natpmp_request(port: u16, protocol: Protocol, lease: Duration) -> [u8; 12] {
+    let mut request = [0; 12];
+    request[1] = protocol.natpmp_opcode();
+    request[4..6].copy_from_slice(&port.to_be_bytes());
+    request[6..8].copy_from_slice(&port.to_be_bytes());
+    let lease = u32::try_from(lease.as_secs()).unwrap_or(u32::MAX);
+    request[8..12].copy_from_slice(&lease.to_be_bytes());
+    request
+}
+
+fn parse_natpmp_response(
+    response: &[u8],
+    port: u16,
+    protocol: Protocol,
+) -> anyhow::Result<Mapping> {
+    anyhow::ensure!(response.len() >= 16, "short NAT-PMP response");
+    anyhow::ensure!(
+        response[0] == 0 && response[1] == 128 + protocol.natpmp_opcode(),
+        "not an answer to our NAT-PMP request"
+    );
+    let result = u16::from_be_bytes([response[2], response[3]]);
+    let reason = match result {
+        0 => None,
+        1 => Some("unsupported version"),
+        2 => Some("not authorized"),
+        3 => Some("network failure"),
+        4 => Some("out of resources"),
+        5 => Some("unsupported opcode"),
+        _ => Some("unknown error"),
+    };
+    if let Some(reason) = reason {
+        anyhow::bail!("router refused the NAT-PMP mapping: {reason} ({result})");
+    }
+    let internal = u16::from_be_bytes([response[8], response[9]]);
+    anyhow::ensure!(internal == port, "NAT-PMP answer is for port {internal}");
+    Ok(Mapping {
+        external_port: u16::from_be_bytes([response[10], response[11]]),
+        lifetime: Duration::from_secs(u32::from_be_bytes(response[12..16].try_into()?).into()),
+    })
+}
+
+async fn upnp_map(port: u16, protocol: Protocol) -> anyhow::Result<Mapping> {
+    let location = ssdp_search().await?;
+    // routers are on the local network, never behind the configured proxy
+    let client = reqwest::Client::builder()
+        .no_proxy()
+        .timeout(UPNP_TIMEOUT)
+        .build()?;
+    let description = client
+        .get(location.clone())
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .with_context(|| format!("fetch router description from {location}"))?
+        .text()
+        .await?;
+    let (service, control) = wan_service(&description, &location)
+        .context("router description has no WAN connection service")?;
+    let client_ip = local_ip_towards(&control).await?;
+    let body = add_port_mapping(service, port, protocol, client_ip);
+    client
+        .post(control.clone())
+        .header("SOAPAction", format!("\"{service}#AddPortMapping\""))
+        .header(CONTENT_TYPE, "text/xml; charset=\"utf-8\"")
+        .body(body)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .with_context(|| format!("add port mapping at {control}"))?;
+    Ok(Mapping {
+        external_port: port,
+        lifetime: LEASE,
+    })
+}
+
+/// Finds an Internet gateway device on the local network, returning the URL of its
+/// description.
+async fn ssdp_search() -> anyhow::Result<Url> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {SSDP_ADDR}\r\nMAN: \"ssdp:discover\"\r\nMX: {}\r\n\
+         ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n",
+        SSDP_WAIT.as_secs()
+    );
+    socket.send_to(search.as_bytes(), SSDP_ADDR).await?;
+    let mut response = vec![0; 2048];
+    let deadline = tokio::time::Instant::now() + SSDP_WAIT;
+    loop {
+        let received = tokio::time::timeout_at(deadline, socket.recv(&mut response))
+            .await
+            .context("no UPnP gateway answered")??;
+        if let Some(location) = ssdp_location(&String::from_utf8_lossy(&response[..received])) {
+            return Ok(location);
+        }
+    }
+}
+
+/// The `LOCATION` header of a search response.
+fn ssdp_location(response: &str) -> Option<Url> {
+    response.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("location")
+            .then(|| value.trim().parse().ok())?
+    })
+}
+
+/// The first of [`WAN_SERVICES`] a device `description` offers, with its control URL
+/// resolved against `base`.
+fn wan_service(description: &str, base: &Url) -> Option<(&'static str, Url)> {
+    let services: Vec<_> = description
+        .split("<service>")
+        .skip(1)
+        .map(|service| service.split("</service>").next().unwrap_or(service))
+        .collect();
+    WAN_SERVICES.iter().find_map(|&wanted| {
+        let service = services
+            .iter()
+            .find(|service| element(service, "serviceType") == Some(wanted))?;
+        Some((wanted, base.join(element(service, "controlURL")?).ok()?))
+    })
+}
+
+/// The text of the first `<name>` element in `xml`.
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{name}>"))? + name.len() + 2;
+    let len = xml[start..].find(&format!("</{name}>"))?;
+    Some(xml[start..start + len].trim())
+}
+
+/// Our address on the network `url`'s host is on, for the router to forward to.
+async fn local_ip_towards(url: &Url) -> anyhow::Result<IpAddr> {
+    let host = url.host_str().context("router URL has no host")?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    // no packet is sent; connecting only picks the route
+    socket.connect((host, port)).await?;
+    Ok(socket.local_addr()?.ip())
+}
+
+fn add_port_mapping(service: &str, port: u16, protocol: Protocol, client: IpAddr) -> String {
+    format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body>\
+         <u:AddPortMapping xmlns:u=\"{service}\">\
+         <NewRemoteHost></NewRemoteHost>\
+         <NewExternalPort>{port}</NewExternalPort>\
+         <NewProtocol>{}</NewProtocol>\
+         <NewInternalPort>{port}</NewInternalPort>\
+         <NewInternalClient>{client}</NewInternalClient>\
+         <NewEnabled>1</NewEnabled>\
+         <NewPortMappingDescription>bittorrent-starter-rust</NewPortMappingDescription>\
+         <NewLeaseDuration>{}</NewLeaseDuration>\
+         </u:AddPortMapping></s:Body></s:Envelope>",
+        protocol.upnp_name(),
+        LEASE.as_secs()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_default_gateway() {
+        let routes = "Iface\tDestination\tGateway \tFlags\n\
+                      eth0\t0000A8C0\t00000000\t0001\n\
+                      eth0\t00000000\t0101A8C0\t0003\n";
+        let gateway = u32::from_str_radix("0101A8C0", 16).unwrap().to_ne_bytes();
+        assert_eq!(parse_default_gateway(routes), Some(Ipv4Addr::from(gateway)));
+        assert_eq!(parse_default_gateway("Iface\tDestination\tGateway\n"), None);
+    }
+
+    #[tokio::test]
+    async fn maps_over_natpmp() {
+        let gateway = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = gateway.local_addr().unwrap();
+        let router = tokio::spawn(async move {
+            let mut request = [0; 12];
+            let (len, from) = gateway.recv_from(&mut request).await.unwrap();
+            assert_eq!(request[..len], natpmp_request(6881, Protocol::Tcp, LEASE));
+            let mut response = [0; 16];
+            response[1] = 130;
+            response[8..10].copy_from_slice(&6881u16.to_be_bytes());
+            response[10..12].copy_from_slice(&40000u16.to_be_bytes());
+            response[12..16].copy_from_slice(&7200u32.to_be_bytes());
+            gateway.send_to(&response, from).await.unwrap();
+        });
+        let mapping = natpmp_map(addr, 6881, Protocol::Tcp).await.unwrap();
+        router.await.unwrap();
+        assert_eq!(
+            mapping,
+            Mapping {
+                external_port: 40000,
+                lifetime: Duration::from_secs(7200),
+            }
+        );
+    }
+
+    #[test]
+    fn reports_natpmp_refusals() {
+        let mut response = [0; 16];
+        response[1] = 129;
+        response[3] = 2;
+        let error = parse_natpmp_response(&response, 6881, Protocol::Udp).unwrap_err();
+        assert!(error.to_string().contains("not authorized"));
+    }
+
+    #[test]
+    fn finds_the_wan_connection_service() {
+        let response = "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\n\
+                        Location: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+        let location = ssdp_location(response).unwrap();
+        assert_eq!(location.as_str(), "http://192.168.1.1:5000/rootDesc.xml");
+        let description = "<root><device><serviceList>\
+            <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+            <controlURL>/ctl/L3F</controlURL></service>\
+            <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+            <controlURL>/ctl/IPConn</controlURL></service>\
+            </serviceList></device></root>";
+        let (service, control) = wan_service(description, &location).unwrap();
+        assert_eq!(service, WAN_SERVICES[1]);
+        assert_eq!(control.as_str(), "http://192.168.1.1:5000/ctl/IPConn");
+    }
+}
@@ -0,0 +1,183 @@
+//! Tunnelling peer connections through a SOCKS5 or HTTP `CONNECT` proxy.
+
+use std::io;
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTH: u8 = 0x00;
+const SOCKS_USER_PASS: u8 = 0x02;
+const SOCKS_NO_ACCEPTABLE: u8 = 0xff;
+const SOCKS_CONNECT: u8 = 0x01;
+const SOCKS_IPV4: u8 = 0x01;
+const SOCKS_DOMAIN: u8 = 0x03;
+const SOCKS_IPV6: u8 = 0x04;
+/// Longest HTTP proxy response header we'll read before giving up on the proxy.
+const MAX_HTTP_HEADER: usize = 8 << 10;
+
+fn proxy_error(message: impl Into<String>) -> io::Error {
+    io::Error::other(message.into())
+}
+
+/// Asks the proxy at the other end of `stream` to connect it onwards to `target`.
+pub async fn handshake<S>(
+    stream: &mut S,
+    proxy: &reqwest::Url,
+    target: SocketAddr,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let credentials = (!proxy.username().is_empty())
+        .then(|| (proxy.username(), proxy.password().unwrap_or_default()));
+    match proxy.scheme() {
+        "socks5" | "socks5h" => socks5(stream, credentials, target).await,
+        "http" => http_connect(stream, credentials, target).await,
+        scheme => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("unsupported proxy scheme {scheme}"),
+        )),
+    }
+}
+
+async fn socks5<S>(
+    stream: &mut S,
+    credentials: Option<(&str, &str)>,
+    target: SocketAddr,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let methods: &[u8] = if credentials.is_some() {
+        &[SOCKS_NO_AUTH, SOCKS_USER_PASS]
+    } else {
+        &[SOCKS_NO_AUTH]
+    };
+    let mut greeting = vec![SOCKS_VERSION, methods.len() as u8];
+    greeting.extend_from_slice(methods);
+    stream.write_all(&greeting).await?;
+
+    let mut choice = [0; 2];
+    stream.read_exact(&mut choice).await?;
+    match choice {
+        [SOCKS_VERSION, SOCKS_NO_AUTH] => {}
+        [SOCKS_VERSION, SOCKS_USER_PASS] => {
+            let (user, pass) = credentials.ok_or_else(|| proxy_error("proxy wants a password"))?;
+            let mut auth = vec![1, user.len() as u8];
+            auth.extend_from_slice(user.as_bytes());
+            auth.push(pass.len() as u8);
+            auth.extend_from_slice(pass.as_bytes());
+            stream.write_all(&auth).await?;
+            let mut status = [0; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0 {
+                return Err(proxy_error("proxy rejected our credentials"));
+            }
+        }
+        [SOCKS_VERSION, SOCKS_NO_ACCEPTABLE] => {
+            return Err(proxy_error("proxy accepts none of our auth methods"));
+        }
+        _ => return Err(proxy_error("proxy is not speaking SOCKS5")),
+    }
+
+    let mut request = vec![SOCKS_VERSION, SOCKS_CONNECT, 0];
+    match target {
+        SocketAddr::V4(v4) => {
+            request.push(SOCKS_IPV4);
+            request.extend_from_slice(&v4.ip().octets());
+        }
+        SocketAddr::V6(v6) => {
+            request.push(SOCKS_IPV6);
+            request.extend_from_slice(&v6.ip().octets());
+        }
+    }
+    request.extend_from_slice(&target.port().to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(proxy_error(format!(
+            "proxy could not connect to {target}: {}",
+            socks_reply(reply[1])
+        )));
+    }
+    // the address the proxy bound for us, which we don't need
+    let bound_len = match reply[3] {
+        SOCKS_IPV4 => 4,
+        SOCKS_IPV6 => 16,
+        SOCKS_DOMAIN => stream.read_u8().await? as usize,
+        _ => return Err(proxy_error("proxy sent an unknown address type")),
+    };
+    let mut bound = vec![0; bound_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+fn socks_reply(code: u8) -> &'static str {
+    match code {
+        0x01 => "general failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+async fn http_connect<S>(
+    stream: &mut S,
+    credentials: Option<(&str, &str)>,
+    target: SocketAddr,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+    if let Some((user, pass)) = credentials {
+        let token = base64(format!("{user}:{pass}").as_bytes());
+        request.push_str(&format!("Proxy-Authorization: Basic {token}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // read byte by byte so nothing past the header, which belongs to the peer, is consumed
+    let mut header = Vec::new();
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() >= MAX_HTTP_HEADER {
+            return Err(proxy_error("proxy response header too long"));
+        }
+        header.push(stream.read_u8().await?);
+    }
+    let status_line = header.split(|&b| b == b'\r').next().unwrap_or_default();
+    let status_line = String::from_utf8_lossy(status_line);
+    match status_line.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(proxy_error(format!(
+            "proxy refused to connect to {target}: {status_line}"
+        ))),
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
@@ -0,0 +1,143 @@
+//! Fetching the metadata of many magnet links at once, for cataloging and archiving: each is
+//! written out as a `.torrent` file, and what it holds is summed up without downloading any of
+//! its files. Peers come from the links' trackers; this client has no DHT, so a link without
+//! trackers only resolves if its metadata is cached.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use futures_util::StreamExt;
+use serde_json::{Value, json};
+
+use crate::config::ClientConfig;
+use crate::magnet::Magnet;
+use crate::metainfo;
+use crate::tracker::{Announcer, Event};
+
+/// Resolves every magnet link in `list`, one per line with blank lines and `#` comments
+/// skipped, `jobs` at a time. Writes `<info hash>.torrent` files into `out_dir` and prints a
+/// JSON line for each link as it finishes, failing at the end if any link did.
+pub async fn resolve_all(
+    list: &Path,
+    out_dir: &Path,
+    jobs: usize,
+    config: &ClientConfig,
+    announcer: &Announcer,
+) -> anyhow::Result<()> {
+    let list = std::fs::read_to_string(list)
+        .with_context(|| format!("read magnet links from {}", list.display()))?;
+    let links: Vec<_> = list
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+    std::fs::create_dir_all(out_dir)
+        .with_context(|| format!("create output directory {}", out_dir.display()))?;
+    let mut results = futures_util::stream::iter(&links)
+        .map(|link| async move {
+            match resolve(link, out_dir, config, announcer).await {
+                Ok(summary) => summary,
+                Err(e) => json!({ "link": link, "error": format!("{e:#}") }),
+            }
+        })
+        .buffer_unordered(jobs.max(1));
+    let mut failed = 0;
+    while let Some(summary) = results.next().await {
+        failed += usize::from(summary.get("error").is_some());
+        println!("{summary}");
+    }
+    anyhow::ensure!(
+        failed == 0,
+        "{failed} of {} magnet links could not be resolved",
+        links.len()
+    );
+    Ok(())
+}
+
+async fn resolve(
+    link: &str,
+    out_dir: &Path,
+    config: &ClientConfig,
+    announcer: &Announcer,
+) -> anyhow::Result<Value> {
+    let magnet: Magnet = link.parse()?;
+    let (metainfo, t) = match crate::cached_torrent(&magnet, config)? {
+        Some(cached) => cached,
+        None => {
+            anyhow::ensure!(
+                !magnet.trackers.is_empty(),
+                "no trackers to find peers with, and no DHT"
+            );
+            let (mut conn, theirs, _) = crate::magnet_connect(&magnet, config, announcer).await?;
+            let fetched = crate::magnet_torrent(&magnet, &mut conn, &theirs, config).await;
+            // we announced ourselves to get peers, but won't be downloading
+            let tracker = &magnet.trackers[0];
+            if let Err(e) = announcer
+                .announce(tracker, magnet.info_hash, 0, Some(Event::Stopped))
+                .await
+            {
+                tracing::debug!(%tracker, error = %e, "stopped announce failed");
+            }
+            fetched?
+        }
+    };
+    let path = torrent_path(out_dir, magnet.info_hash);
+    std::fs::write(&path, &metainfo).with_context(|| format!("write {}", path.display()))?;
+    let files: Vec<_> = metainfo::files(&metainfo, &t)
+        .into_iter()
+        .map(|(path, length)| json!({ "path": path, "length": length }))
+        .collect();
+    Ok(json!({
+        "link": link,
+        "info_hash": hex::encode(magnet.info_hash),
+        "name": t.info.name,
+        "length": t.length(),
+        "files": files,
+        "torrent": path.display().to_string(),
+    }))
+}
+
+fn torrent_path(out_dir: &Path, info_hash: [u8; 20]) -> PathBuf {
+    out_dir.join(format!("{}.torrent", hex::encode(info_hash)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::hash::TorrentVersion;
+    use crate::metadata_cache::MetadataCache;
+
+    #[tokio::test]
+    async fn resolves_cached_links_and_reports_bad_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let info = b"d6:lengthi3e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let info_hash: [u8; 20] = TorrentVersion::V1.digest(info).try_into().unwrap();
+        let cache = MetadataCache::new(dir.path().join("cache"));
+        cache.store(info_hash, info).unwrap();
+        let config = ClientConfig {
+            metadata_cache: Some(cache),
+            ..ClientConfig::default()
+        };
+        let announcer = Announcer::new(&config).unwrap();
+        let list = dir.path().join("links");
+        let link = format!("magnet:?xt=urn:btih:{}", hex::encode(info_hash));
+        std::fs::write(&list, format!("# to archive\n{link}\n\nnot a magnet\n")).unwrap();
+        let out = dir.path().join("out");
+
+        let error = resolve_all(&list, &out, 2, &config, &announcer)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "1 of 2 magnet links could not be resolved"
+        );
+
+        let summary = resolve(&link, &out, &config, &announcer).await.unwrap();
+        assert_eq!(summary["name"], "a");
+        assert_eq!(summary["length"], 3);
+        assert_eq!(summary["files"][0]["path"], "a");
+        let written = std::fs::read(torrent_path(&out, info_hash)).unwrap();
+        assert!(written.ends_with(&[info.as_slice(), b"e"].concat()));
+    }
+}
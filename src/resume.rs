@@ -0,0 +1,147 @@
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::bitfield::Bitfield;
+
+/// Resume data older than this much before the data's last change is taken to be stale, to
+/// allow for file times coarser than a second.
+const MTIME_SLACK: u64 = 1;
+
+/// What a torrent's data on disk is known to hold, as a bencoded dictionary.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResumeData {
+    #[serde(rename = "info hash")]
+    pub info_hash: String,
+    /// The file, or the directory holding a multi-file torrent's files.
+    pub path: PathBuf,
+    #[serde(rename = "piece count")]
+    pub piece_count: usize,
+    /// Verified pieces, as a wire-format bitfield.
+    pub pieces: ByteBuf,
+    /// Unix time, in seconds, the pieces were checked.
+    pub checked: u64,
+}
+
+impl ResumeData {
+    pub fn new(info_hash: [u8; 20], path: &Path, have: &Bitfield) -> Self {
+        Self {
+            info_hash: hex::encode(info_hash),
+            path: std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf()),
+            piece_count: have.len(),
+            pieces: ByteBuf::from(have.as_bytes().to_vec()),
+            checked: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+        }
+    }
+
+    /// The pieces recorded as intact, if the record is of the data at `path`, last changed at
+    /// `modified`, and of a torrent of `npieces` pieces.
+    pub fn pieces_at(&self, path: &Path, npieces: usize, modified: SystemTime) -> Option<Bitfield> {
+        let path = std::path::absolute(path).ok()?;
+        let modified = modified.duration_since(UNIX_EPOCH).ok()?.as_secs();
+        if path != self.path || self.piece_count != npieces || self.checked + MTIME_SLACK < modified
+        {
+            return None;
+        }
+        Bitfield::from_payload(self.pieces.to_vec(), npieces).ok()
+    }
+}
+
+/// Resume files, one `<info hash>.resume` each, recording which pieces of a torrent are
+/// already on disk.
+#[derive(Debug, Clone)]
+pub struct ResumeStore {
+    dir: PathBuf,
+}
+
+impl ResumeStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// `$XDG_DATA_HOME/bittorrent-starter-rust/resume`, falling back to `~/.local/share`.
+    pub fn default_dir() -> Option<PathBuf> {
+        let base = std::env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share"))
+            })?;
+        Some(base.join("bittorrent-starter-rust").join("resume"))
+    }
+
+    /// The resume data for the torrent with `info_hash`, if there is any.
+    pub fn load(&self, info_hash: [u8; 20]) -> anyhow::Result<Option<ResumeData>> {
+        let path = self.dir.join(format!("{}.resume", hex::encode(info_hash)));
+        let encoded = match std::fs::read(&path) {
+            Ok(encoded) => encoded,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+        };
+        let data = serde_bencode::from_bytes(&encoded)
+            .with_context(|| format!("parse {}", path.display()))?;
+        Ok(Some(data))
+    }
+
+    /// Replaces the resume file for `data`'s torrent, returning where it was written.
+    /// Written to a temporary file first so a crash can't leave a truncated one behind.
+    pub fn store(&self, data: &ResumeData) -> anyhow::Result<PathBuf> {
+        let encoded = serde_bencode::to_bytes(data).context("encode resume data")?;
+        let path = self.dir.join(format!("{}.resume", data.info_hash));
+        let partial = path.with_extension("resume.part");
+        std::fs::create_dir_all(&self.dir)
+            .and_then(|()| std::fs::write(&partial, encoded))
+            .and_then(|()| std::fs::rename(&partial, &path))
+            .with_context(|| format!("write {}", path.display()))?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn reads_back_the_pieces_of_unchanged_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ResumeStore::new(dir.path().join("resume"));
+        let data = dir.path().join("sample.bin");
+        std::fs::write(&data, b"data").unwrap();
+        let modified = std::fs::metadata(&data).unwrap().modified().unwrap();
+        let mut have = Bitfield::new(10);
+        have.set_piece(0);
+        have.set_piece(9);
+        store
+            .store(&ResumeData::new([7; 20], &data, &have))
+            .unwrap();
+
+        let loaded = store.load([7; 20]).unwrap().unwrap();
+        let pieces = loaded.pieces_at(&data, 10, modified).unwrap();
+        assert_eq!(pieces.pieces().collect::<Vec<_>>(), [0, 9]);
+        assert!(store.load([8; 20]).unwrap().is_none());
+    }
+
+    #[test]
+    fn ignores_records_of_other_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("sample.bin");
+        let record = ResumeData::new([7; 20], &data, &Bitfield::full(10));
+        let now = SystemTime::now();
+        assert!(record.pieces_at(&data, 10, now).is_some());
+        assert!(
+            record
+                .pieces_at(&dir.path().join("other.bin"), 10, now)
+                .is_none()
+        );
+        assert!(record.pieces_at(&data, 11, now).is_none());
+        // changed since it was checked
+        let later = now + Duration::from_secs(60);
+        assert!(record.pieces_at(&data, 10, later).is_none());
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::config::ClientConfig;
+use crate::message::FrameError;
+use crate::peer::PeerConnection;
+use crate::timeout::TimeoutError;
+
+/// How hard to try a peer before benching it.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Failures in a row after which a peer is blacklisted.
+    pub attempts: u32,
+    /// Wait before the first retry; doubled for each one after.
+    pub backoff: Duration,
+    /// How long a blacklisted peer is skipped for.
+    pub blacklist: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff: Duration::from_millis(500),
+            blacklist: Duration::from_secs(300),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PeerRecord {
+    failures: u32,
+    banned_until: Option<Instant>,
+}
+
+/// Per-peer failure counters and a temporary blacklist of peers that keep failing. Shared by
+/// every peer task of a download.
+#[derive(Debug)]
+pub struct PeerBook {
+    policy: RetryPolicy,
+    peers: Mutex<HashMap<SocketAddr, PeerRecord>>,
+}
+
+impl PeerBook {
+    pub fn new(policy: RetryPolicy) -> Self {
+        Self {
+            policy,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<SocketAddr, PeerRecord>> {
+        self.peers.lock().expect("peer book lock poisoned")
+    }
+
+    pub fn is_blacklisted(&self, peer: SocketAddr) -> bool {
+        self.lock()
+            .get(&peer)
+            .and_then(|record| record.banned_until)
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Records why dialling or talking to `peer` failed and returns how long to wait before
+    /// retrying, or `None` once the peer has been blacklisted. A peer that broke the wire
+    /// protocol is blacklisted straight away, since retrying won't fix that, while a timeout
+    /// that isn't the peer's fault doesn't count against it.
+    pub fn record_failure(&self, peer: SocketAddr, error: &anyhow::Error) -> Option<Duration> {
+        if FrameError::find(error).is_some() {
+            self.ban(peer);
+            return None;
+        }
+        let timeout = error.chain().find_map(|e| e.downcast_ref::<TimeoutError>());
+        if timeout.is_some_and(|timeout| !timeout.is_peer_fault()) {
+            return Some(self.policy.backoff);
+        }
+        let mut peers = self.lock();
+        let record = peers.entry(peer).or_default();
+        record.failures += 1;
+        if record.failures >= self.policy.attempts {
+            record.failures = 0;
+            record.banned_until = Some(Instant::now() + self.policy.blacklist);
+            tracing::info!(%peer, duration = ?self.policy.blacklist, "blacklisting peer");
+            return None;
+        }
+        Some(self.policy.backoff * 2u32.pow(record.failures - 1))
+    }
+
+    /// Blacklists `peer` straight away, for misbehaviour that retrying won't fix.
+    pub fn ban(&self, peer: SocketAddr) {
+        let mut peers = self.lock();
+        let record = peers.entry(peer).or_default();
+        record.failures = 0;
+        record.banned_until = Some(Instant::now() + self.policy.blacklist);
+        tracing::info!(%peer, duration = ?self.policy.blacklist, "banning peer");
+    }
+
+    pub fn record_success(&self, peer: SocketAddr) {
+        self.lock().remove(&peer);
+    }
+}
+
+/// Opens a connection to `peer`, retrying with exponential backoff until it succeeds or the
+/// peer is blacklisted.
+pub async fn open(
+    peer: SocketAddr,
+    info_hash: [u8; 20],
+    config: &ClientConfig,
+    book: &PeerBook,
+) -> anyhow::Result<PeerConnection> {
+    anyhow::ensure!(!book.is_blacklisted(peer), "peer {peer} is blacklisted");
+    loop {
+        match PeerConnection::open(peer, info_hash, config).await {
+            Ok(conn) => {
+                book.record_success(peer);
+                return Ok(conn);
+            }
+            Err(e) => {
+                let Some(backoff) = book.record_failure(peer, &e) else {
+                    return Err(e.context(format!("giving up on peer {peer}")));
+                };
+                tracing::debug!(%peer, error = %e, ?backoff, "connect failed, retrying");
+                tokio::time::sleep(backoff).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timeout::TimeoutError;
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:6881".parse().unwrap()
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 2,
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[test]
+    fn backs_off_then_blacklists_peers_that_time_out() {
+        let book = PeerBook::new(policy());
+        let timeout = anyhow::Error::new(TimeoutError::Connect(Duration::from_secs(5)));
+        assert_eq!(
+            book.record_failure(peer(), &timeout),
+            Some(policy().backoff)
+        );
+        assert!(!book.is_blacklisted(peer()));
+        assert_eq!(book.record_failure(peer(), &timeout), None);
+        assert!(book.is_blacklisted(peer()));
+    }
+
+    #[test]
+    fn blacklists_protocol_violations_at_once() {
+        let book = PeerBook::new(policy());
+        let violation = anyhow::Error::new(FrameError::TooLarge(1 << 30));
+        assert_eq!(book.record_failure(peer(), &violation), None);
+        assert!(book.is_blacklisted(peer()));
+    }
+
+    #[test]
+    fn tracker_timeouts_do_not_count_against_the_peer() {
+        let book = PeerBook::new(policy());
+        let timeout = anyhow::Error::new(TimeoutError::Announce(Duration::from_secs(15)));
+        for _ in 0..3 {
+            assert!(book.record_failure(peer(), &timeout).is_some());
+        }
+        assert!(!book.is_blacklisted(peer()));
+    }
+}
@@ -0,0 +1,322 @@
+//! Hands the pieces of a download out to concurrently running peer tasks.
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::bitfield::Bitfield;
+use crate::picker::{Availability, Priority};
+
+/// A piece handed to a peer, and when it may be handed to another peer instead.
+#[derive(Debug, Clone, Copy)]
+struct Assignment {
+    peer: SocketAddr,
+    deadline: Instant,
+}
+
+#[derive(Debug)]
+struct State {
+    queue: VecDeque<usize>,
+    in_flight: HashMap<usize, Vec<Assignment>>,
+    done: HashSet<usize>,
+    total: usize,
+    /// What each connected peer has, from its bitfield and the `have` messages since.
+    peers: HashMap<SocketAddr, Bitfield>,
+    /// Peers that stopped answering requests. They are handed whatever the others are least
+    /// likely to want next, never take pieces over, and give up theirs to the next idle peer.
+    snubbed: HashSet<SocketAddr>,
+}
+
+impl State {
+    /// The lowest piece nobody has finished yet.
+    fn first_missing(&self) -> Option<usize> {
+        self.queue
+            .iter()
+            .chain(self.in_flight.keys())
+            .copied()
+            .min()
+    }
+
+    /// Whether `peer` has announced `piece`.
+    fn has(&self, peer: SocketAddr, piece: usize) -> bool {
+        self.peers
+            .get(&peer)
+            .is_some_and(|pieces| pieces.has_piece(piece))
+    }
+}
+
+/// A shared queue of pieces that idle peer tasks take work from. Pieces are only handed to
+/// peers that have announced them. A piece not finished by its deadline is handed to the
+/// next idle peer that has it, while the first keeps going; the piece counts as done for
+/// whichever finishes first. Pieces held by [snubbed](Self::snub) peers are past their
+/// deadline from the start.
+#[derive(Debug)]
+pub struct PieceScheduler {
+    state: Mutex<State>,
+    changed: Notify,
+    timeout: Duration,
+    /// In sequential mode, how far past the first missing piece pieces may be handed out.
+    readahead: Option<usize>,
+}
+
+impl PieceScheduler {
+    pub fn new(pieces: impl IntoIterator<Item = usize>, timeout: Duration) -> Self {
+        let queue: VecDeque<usize> = pieces.into_iter().collect();
+        Self {
+            state: Mutex::new(State {
+                total: queue.len(),
+                queue,
+                in_flight: HashMap::new(),
+                done: HashSet::new(),
+                peers: HashMap::new(),
+                snubbed: HashSet::new(),
+            }),
+            changed: Notify::new(),
+            timeout,
+            readahead: None,
+        }
+    }
+
+    /// Hands pieces out in order, never more than `readahead` past the first one still
+    /// missing, so the start of the data is complete as early as possible.
+    pub fn sequential(self, readahead: usize) -> Self {
+        Self {
+            readahead: Some(readahead.max(1)),
+            ..self
+        }
+    }
+
+    /// Leaves out the pieces `priorities` skips and hands out higher priority pieces first,
+    /// in index order among equals.
+    pub fn with_priorities(self, priorities: &[Priority]) -> Self {
+        {
+            let mut state = self.lock();
+            let mut queue: Vec<usize> = state
+                .queue
+                .drain(..)
+                .filter(|&piece| priorities[piece] > Priority::Skip)
+                .collect();
+            queue.sort_by_key(|&piece| Reverse(priorities[piece]));
+            state.total = queue.len();
+            state.queue = queue.into();
+        }
+        self
+    }
+
+    /// Leaves out the pieces in `have`, such as ones already intact on disk.
+    pub fn without(self, have: &Bitfield) -> Self {
+        {
+            let mut state = self.lock();
+            state.queue.retain(|&piece| !have.has_piece(piece));
+            state.total = state.queue.len();
+        }
+        self
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("piece scheduler lock poisoned")
+    }
+
+    /// Pieces at or past this index aren't handed out yet.
+    fn limit(&self, state: &State) -> usize {
+        match (self.readahead, state.first_missing()) {
+            (Some(readahead), Some(first)) => first + readahead,
+            _ => usize::MAX,
+        }
+    }
+
+    /// Adds a connected peer, which has the pieces in `pieces`.
+    pub fn join(&self, peer: SocketAddr, pieces: Bitfield) {
+        self.lock().peers.insert(peer, pieces);
+        self.changed.notify_waiters();
+    }
+
+    /// Records that `peer` announced `piece` after joining.
+    pub fn have(&self, peer: SocketAddr, piece: usize) {
+        if let Some(pieces) = self.lock().peers.get_mut(&peer) {
+            pieces.set_piece(piece);
+        }
+        self.changed.notify_waiters();
+    }
+
+    /// Whether the download can't make progress because no connected peer has any piece
+    /// that could be handed out. It picks up again once a peer announces one.
+    pub fn is_stalled(&self) -> bool {
+        let state = self.lock();
+        let limit = self.limit(&state);
+        !state.peers.is_empty()
+            && state.done.len() < state.total
+            && state.in_flight.is_empty()
+            && !state.queue.iter().any(|&piece| {
+                piece < limit && state.peers.values().any(|pieces| pieces.has_piece(piece))
+            })
+    }
+
+    /// How many connected peers have each piece of the download, done or not.
+    pub fn availability(&self) -> Availability {
+        let state = self.lock();
+        let pieces = state
+            .queue
+            .iter()
+            .chain(state.in_flight.keys())
+            .chain(&state.done);
+        Availability::new(pieces.map(|&piece| {
+            let peers = state.peers.values();
+            (
+                piece,
+                peers.filter(|pieces| pieces.has_piece(piece)).count(),
+            )
+        }))
+    }
+
+    /// Whether some peer has already finished `piece`.
+    pub fn is_complete(&self, piece: usize) -> bool {
+        self.lock().done.contains(&piece)
+    }
+
+    pub fn is_done(&self) -> bool {
+        let state = self.lock();
+        state.done.len() == state.total
+    }
+
+    /// The next piece for `peer`, which must have [joined](Self::join). Waits while the only
+    /// pieces it has are in flight with other peers or past the readahead window, and while
+    /// it has none of the pieces still missing; `None` once the download is done.
+    pub async fn next(&self, peer: SocketAddr) -> Option<usize> {
+        loop {
+            let changed = self.changed.notified();
+            let wake = {
+                let mut state = self.lock();
+                if state.done.len() == state.total {
+                    return None;
+                }
+                let now = Instant::now();
+                let limit = self.limit(&state);
+                let snubbed = state.snubbed.contains(&peer);
+                let available = |piece: usize| piece < limit && state.has(peer, piece);
+                let mut queue = state.queue.iter();
+                let pos = if snubbed {
+                    queue.rposition(|&piece| available(piece))
+                } else {
+                    queue.position(|&piece| available(piece))
+                };
+                if let Some(pos) = pos {
+                    let piece = state.queue.remove(pos).expect("position is in range");
+                    self.assign(&mut state, piece, peer, now);
+                    return Some(piece);
+                }
+                // steal the piece whose every holder is furthest past its deadline
+                let overdue = state
+                    .in_flight
+                    .iter()
+                    .filter(|(&piece, holders)| {
+                        !snubbed && state.has(peer, piece) && holders.iter().all(|a| a.peer != peer)
+                    })
+                    .map(|(&piece, holders)| (piece, latest_deadline(holders)))
+                    .min_by_key(|&(_, deadline)| deadline);
+                match overdue {
+                    Some((piece, deadline)) if deadline <= now => {
+                        tracing::debug!(piece, %peer, "re-assigning timed out piece");
+                        self.assign(&mut state, piece, peer, now);
+                        return Some(piece);
+                    }
+                    Some((_, deadline)) => Some(deadline),
+                    // pieces come into the window as earlier ones finish, and the peer may
+                    // announce more
+                    None => None,
+                }
+            };
+            match wake {
+                Some(wake) => tokio::select! {
+                    _ = changed => {}
+                    _ = tokio::time::sleep_until(wake) => {}
+                },
+                None => changed.await,
+            }
+        }
+    }
+
+    fn assign(&self, state: &mut State, piece: usize, peer: SocketAddr, now: Instant) {
+        let deadline = if state.snubbed.contains(&peer) {
+            // up for grabs straight away
+            self.changed.notify_waiters();
+            now
+        } else {
+            now + self.timeout
+        };
+        state
+            .in_flight
+            .entry(piece)
+            .or_default()
+            .push(Assignment { peer, deadline });
+    }
+
+    /// Records that `peer` stopped answering requests for `piece`, which goes back to the
+    /// front of the queue unless another peer is fetching it too.
+    pub fn snub(&self, peer: SocketAddr, piece: usize) {
+        let mut state = self.lock();
+        state.snubbed.insert(peer);
+        if let Some(holders) = state.in_flight.get_mut(&piece) {
+            holders.retain(|a| a.peer != peer);
+            if holders.is_empty() {
+                state.in_flight.remove(&piece);
+                state.queue.push_front(piece);
+            }
+        }
+        drop(state);
+        self.changed.notify_waiters();
+    }
+
+    /// Records that `peer` delivered a piece, so it is no longer snubbing us.
+    pub fn unsnub(&self, peer: SocketAddr) {
+        self.lock().snubbed.remove(&peer);
+    }
+
+    /// Marks `piece` as downloaded by `peer`. Returns `false` if another peer finished it
+    /// first, in which case the data should be thrown away.
+    pub fn complete(&self, piece: usize, peer: SocketAddr) -> bool {
+        let mut state = self.lock();
+        state.in_flight.remove(&piece);
+        let first = state.done.insert(piece);
+        if !first {
+            tracing::debug!(piece, %peer, "piece already finished by another peer");
+        }
+        drop(state);
+        self.changed.notify_waiters();
+        first
+    }
+
+    /// Gives back every piece `peer` was working on and forgets what it has, e.g. because its
+    /// connection failed.
+    pub fn release(&self, peer: SocketAddr) {
+        let mut state = self.lock();
+        state.peers.remove(&peer);
+        state.snubbed.remove(&peer);
+        let mut freed = Vec::new();
+        state.in_flight.retain(|&piece, holders| {
+            holders.retain(|a| a.peer != peer);
+            if holders.is_empty() {
+                freed.push(piece);
+            }
+            !holders.is_empty()
+        });
+        for piece in freed {
+            state.queue.push_front(piece);
+        }
+        drop(state);
+        self.changed.notify_waiters();
+    }
+}
+
+fn latest_deadline(holders: &[Assignment]) -> Instant {
+    holders
+        .iter()
+        .map(|a| a.deadline)
+        .max()
+        .expect("in-flight pieces have a holder")
+}
@@ -0,0 +1,699 @@
+//! Any number of torrents downloading side by side in one process. They share its peer
+//! listener, tracker announcer, connection limits and metadata cache; each is added with its
+//! own [`AddTorrentParams`] and controlled through the [`TorrentHandle`] [`Session::add`]
+//! returns.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use futures_util::StreamExt;
+use futures_util::future::{AbortHandle, Abortable};
+use futures_util::stream::FuturesUnordered;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, watch};
+use tokio::time::Interval;
+
+use bittorrent_starter_rust::Torrent;
+
+use crate::config::ClientConfig;
+use crate::listener::Torrents;
+use crate::magnet::Magnet;
+use crate::maintenance::JobRun;
+use crate::metainfo;
+use crate::picker::Priority;
+use crate::session_stats::{SessionEvent, SessionStats};
+use crate::tracker::{Announcer, Event};
+use crate::verify;
+
+/// Events a subscriber can fall behind by before it misses some.
+const EVENT_BACKLOG: usize = 1024;
+/// How often downloading torrents' rates are sent to event subscribers.
+const RATE_EVENTS: Duration = Duration::from_secs(1);
+
+/// What a torrent is downloaded from: its metainfo, or a magnet link to fetch that with.
+pub enum TorrentSource {
+    Torrent { metainfo: Vec<u8>, torrent: Torrent },
+    Magnet(Magnet),
+}
+
+impl TorrentSource {
+    /// Reads a magnet link, or a `.torrent` file's path or URL.
+    pub async fn load(source: &str, config: &ClientConfig) -> anyhow::Result<Self> {
+        if source.starts_with("magnet:") {
+            return Ok(TorrentSource::Magnet(source.parse()?));
+        }
+        let metainfo = metainfo::load(Path::new(source), config).await?;
+        let torrent = serde_bencode::from_bytes(&metainfo).context("parse torrent file")?;
+        Ok(TorrentSource::Torrent { metainfo, torrent })
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        match self {
+            TorrentSource::Torrent { torrent, .. } => torrent.info_hash(),
+            TorrentSource::Magnet(magnet) => magnet.info_hash,
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            TorrentSource::Torrent { torrent, .. } => Some(&torrent.info.name),
+            TorrentSource::Magnet(magnet) => magnet.name.as_deref(),
+        }
+    }
+
+    fn tracker(&self) -> &str {
+        match self {
+            TorrentSource::Torrent { torrent, .. } => &torrent.announce,
+            TorrentSource::Magnet(magnet) => magnet.trackers.first().map_or("", String::as_str),
+        }
+    }
+}
+
+/// Everything about a torrent that is settled as it is added, so a client doesn't have to
+/// change it afterwards. Only `output` is required.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddTorrentParams {
+    /// Where the torrent is saved.
+    pub output: PathBuf,
+    /// A label for the client's own use.
+    #[serde(default)]
+    pub category: Option<String>,
+    /// Added without starting the download.
+    #[serde(default)]
+    pub paused: bool,
+    /// Fetches pieces in order, as [`ClientConfig::sequential`] does for every torrent.
+    #[serde(default)]
+    pub sequential: bool,
+    /// The priority of each file, in the order the info dictionary lists them. Files past the
+    /// end are downloaded at normal priority.
+    #[serde(default)]
+    pub file_priorities: Vec<Priority>,
+    /// KiB/s this torrent may download at, within the process's own limits.
+    #[serde(default)]
+    pub download_limit: Option<u32>,
+    /// Trackers announced to besides the torrent's own, as a tier of their own.
+    #[serde(default)]
+    pub trackers: Vec<String>,
+    /// Takes the pieces already at `output` to be intact without checking them, and only
+    /// downloads the rest.
+    #[serde(default)]
+    pub skip_verify: bool,
+    /// How many times a piece may fail verification before the torrent gives up, instead of
+    /// [`ClientConfig::max_hash_failures`].
+    #[serde(default)]
+    pub max_hash_failures: Option<u32>,
+}
+
+impl AddTorrentParams {
+    /// Just `output`, everything else left as the session does it.
+    pub fn new(output: PathBuf) -> Self {
+        Self {
+            output,
+            category: None,
+            paused: false,
+            sequential: false,
+            file_priorities: Vec::new(),
+            download_limit: None,
+            trackers: Vec::new(),
+            skip_verify: false,
+            max_hash_failures: None,
+        }
+    }
+
+    /// `config` with this torrent's own settings applied.
+    fn config(&self, config: &ClientConfig) -> ClientConfig {
+        let mut config = config.clone();
+        config.sequential |= self.sequential;
+        config.skip_verify |= self.skip_verify;
+        if let Some(kib) = self.download_limit {
+            config.speed = config.speed.within(u64::from(kib) << 10);
+        }
+        if let Some(limit) = self.max_hash_failures {
+            config.max_hash_failures = limit;
+        }
+        config
+    }
+}
+
+/// Where a torrent of a [`Session`] is at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentState {
+    Downloading,
+    Paused,
+    Done,
+    Failed(String),
+}
+
+/// A [`SessionEvent`] from one of a session's torrents.
+#[derive(Debug, Clone, Serialize)]
+pub struct TorrentEvent {
+    pub id: String,
+    #[serde(flatten)]
+    pub event: SessionEvent,
+}
+
+/// Controls one torrent of a [`Session`]. Clones control the same torrent.
+#[derive(Clone)]
+pub struct TorrentHandle {
+    id: String,
+    source: Arc<TorrentSource>,
+    params: Arc<AddTorrentParams>,
+    stats: SessionStats,
+    /// Whether the download is being driven; false while paused.
+    running: Arc<watch::Sender<bool>>,
+    abort: AbortHandle,
+    /// Set once the download stops by itself.
+    outcome: watch::Receiver<Option<Result<(), String>>>,
+}
+
+impl TorrentHandle {
+    /// The torrent's hex info hash.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn source(&self) -> &TorrentSource {
+        &self.source
+    }
+
+    pub fn output(&self) -> &Path {
+        &self.params.output
+    }
+
+    /// What it was added with.
+    pub fn params(&self) -> &AddTorrentParams {
+        &self.params
+    }
+
+    pub fn stats(&self) -> &SessionStats {
+        &self.stats
+    }
+
+    pub fn state(&self) -> TorrentState {
+        match &*self.outcome.borrow() {
+            Some(Ok(())) => TorrentState::Done,
+            Some(Err(error)) => TorrentState::Failed(error.clone()),
+            None if *self.running.borrow() => TorrentState::Downloading,
+            None => TorrentState::Paused,
+        }
+    }
+
+    /// Stops driving the download. Its connections stay open but go idle.
+    pub fn pause(&self) {
+        self.running.send_replace(false);
+    }
+
+    pub fn resume(&self) {
+        self.running.send_replace(true);
+    }
+}
+
+type Running<'a> = Pin<Box<dyn Future<Output = ()> + 'a>>;
+
+/// The torrents one process is downloading.
+pub struct Session<'a> {
+    config: &'a ClientConfig,
+    announcer: &'a Announcer,
+    inbound: Option<&'a Torrents>,
+    /// Keyed by id.
+    torrents: BTreeMap<String, TorrentHandle>,
+    running: FuturesUnordered<Running<'a>>,
+    /// Every torrent's events, tagged with its id.
+    events: broadcast::Sender<TorrentEvent>,
+    rates: Interval,
+}
+
+impl<'a> Session<'a> {
+    pub fn new(
+        config: &'a ClientConfig,
+        announcer: &'a Announcer,
+        inbound: Option<&'a Torrents>,
+    ) -> Self {
+        Self {
+            config,
+            announcer,
+            inbound,
+            torrents: BTreeMap::new(),
+            running: FuturesUnordered::new(),
+            events: broadcast::channel(EVENT_BACKLOG).0,
+            rates: tokio::time::interval(RATE_EVENTS),
+        }
+    }
+
+    /// Where every torrent's events are sent, for subscribing to them.
+    #[cfg(feature = "http-api")]
+    pub fn events(&self) -> &broadcast::Sender<TorrentEvent> {
+        &self.events
+    }
+
+    /// Starts downloading `source` as `params` say, alongside the other torrents. Each
+    /// torrent can only be added once.
+    pub fn add(
+        &mut self,
+        source: TorrentSource,
+        params: AddTorrentParams,
+    ) -> anyhow::Result<TorrentHandle> {
+        let id = hex::encode(source.info_hash());
+        anyhow::ensure!(!self.torrents.contains_key(&id), "{id} was already added");
+        if let TorrentSource::Torrent { metainfo, torrent } = &source {
+            let files = metainfo::files(metainfo, torrent).len();
+            anyhow::ensure!(
+                params.file_priorities.len() <= files,
+                "{} file priorities given for {files} files",
+                params.file_priorities.len()
+            );
+        }
+        tracing::info!(%id, output = %params.output.display(), "torrent added");
+        let paused = params.paused;
+        Ok(self.start(id, Arc::new(source), Arc::new(params), !paused))
+    }
+
+    /// Starts torrent `id` over from scratch, e.g. once it has failed.
+    pub fn restart(&mut self, id: &str) -> Option<TorrentHandle> {
+        let old = self.torrents.get(id)?;
+        old.abort.abort();
+        let (source, params) = (old.source.clone(), old.params.clone());
+        tracing::info!(%id, "torrent restarted");
+        Some(self.start(id.to_owned(), source, params, true))
+    }
+
+    pub fn get(&self, id: &str) -> Option<&TorrentHandle> {
+        self.torrents.get(id)
+    }
+
+    pub fn torrents(&self) -> impl Iterator<Item = &TorrentHandle> {
+        self.torrents.values()
+    }
+
+    /// Stops torrent `id` and forgets it. Whatever it wrote stays on disk.
+    pub fn remove(&mut self, id: &str) -> Option<TorrentHandle> {
+        let handle = self.torrents.remove(id)?;
+        handle.abort.abort();
+        tracing::info!(%id, "torrent removed");
+        Some(handle)
+    }
+
+    /// Drives every torrent. Never finishes, so select it against whatever feeds the session.
+    pub async fn run(&mut self) {
+        loop {
+            tokio::select! {
+                Some(()) = self.running.next() => {}
+                _ = self.rates.tick() => self.publish_rates(),
+            }
+        }
+    }
+
+    /// Tells the trackers of every unfinished torrent that we're going away.
+    pub async fn stop(&self) {
+        for (id, handle) in &self.torrents {
+            if handle.outcome.borrow().is_some() {
+                continue;
+            }
+            let source = &handle.source;
+            if let Err(e) = self
+                .announcer
+                .announce(
+                    source.tracker(),
+                    source.info_hash(),
+                    0,
+                    Some(Event::Stopped),
+                )
+                .await
+            {
+                tracing::warn!(%id, error = %e, "stopped announce failed");
+            }
+        }
+    }
+
+    /// Rechecks the data of every finished download of a whole `.torrent` file against its
+    /// piece hashes in the background, failing `run` if any has lost pieces since.
+    pub fn scrub(&mut self, run: JobRun) {
+        let finished: Vec<_> = self
+            .torrents
+            .values()
+            .filter(|handle| {
+                handle.state() == TorrentState::Done
+                    && !handle.params.file_priorities.contains(&Priority::Skip)
+            })
+            .map(|handle| {
+                (
+                    handle.id.clone(),
+                    handle.source.clone(),
+                    handle.params.clone(),
+                )
+            })
+            .collect();
+        self.running.push(Box::pin(async move {
+            let result = scrub(finished).await;
+            run.finish(&result);
+        }));
+    }
+
+    fn start(
+        &mut self,
+        id: String,
+        source: Arc<TorrentSource>,
+        params: Arc<AddTorrentParams>,
+        running: bool,
+    ) -> TorrentHandle {
+        let stats = SessionStats::new();
+        forward_events(&id, &stats, &self.events);
+        let (running, resumed) = watch::channel(running);
+        let (abort, registration) = AbortHandle::new_pair();
+        let (finished, outcome) = watch::channel(None);
+        let work = download(
+            source.clone(),
+            params.clone(),
+            stats.clone(),
+            params.config(self.config),
+            self.announcer,
+            self.inbound,
+        );
+        let work = Abortable::new(pausable(work, resumed), registration);
+        let torrent = id.clone();
+        self.running.push(Box::pin(async move {
+            let Ok(result) = work.await else {
+                return;
+            };
+            let outcome = match result {
+                Ok(()) => {
+                    tracing::info!(id = %torrent, "torrent finished");
+                    Ok(())
+                }
+                Err(error) => {
+                    let error = format!("{error:#}");
+                    tracing::warn!(id = %torrent, %error, "torrent failed");
+                    Err(error)
+                }
+            };
+            finished.send_replace(Some(outcome));
+        }));
+        let handle = TorrentHandle {
+            id: id.clone(),
+            source,
+            params,
+            stats,
+            running: Arc::new(running),
+            abort,
+            outcome,
+        };
+        self.torrents.insert(id, handle.clone());
+        handle
+    }
+
+    fn publish_rates(&self) {
+        for handle in self.torrents.values() {
+            if handle.state() == TorrentState::Downloading {
+                handle.stats.publish_rates();
+            }
+        }
+    }
+}
+
+/// Passes `stats`' events on to `events`, tagged with `id`, until the torrent is dropped.
+fn forward_events(id: &str, stats: &SessionStats, events: &broadcast::Sender<TorrentEvent>) {
+    let (id, mut from, events) = (id.to_owned(), stats.subscribe(), events.clone());
+    tokio::spawn(async move {
+        loop {
+            let event = match from.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            let id = id.clone();
+            // nobody listening is fine
+            let _ = events.send(TorrentEvent { id, event });
+        }
+    });
+}
+
+async fn download(
+    source: Arc<TorrentSource>,
+    params: Arc<AddTorrentParams>,
+    stats: SessionStats,
+    config: ClientConfig,
+    announcer: &Announcer,
+    inbound: Option<&Torrents>,
+) -> anyhow::Result<()> {
+    let output = &params.output;
+    let started = SystemTime::now();
+    match &*source {
+        TorrentSource::Torrent { metainfo, torrent } => {
+            let priorities = (!params.file_priorities.is_empty())
+                .then(|| crate::file_priorities(metainfo, torrent, &params.file_priorities));
+            crate::download_torrent(
+                metainfo,
+                torrent,
+                &[],
+                &params.trackers,
+                priorities.as_deref(),
+                output,
+                inbound,
+                announcer,
+                &stats,
+                &config,
+            )
+            .await?;
+            crate::record_transfer(torrent, started, &stats, &config);
+        }
+        TorrentSource::Magnet(magnet) => {
+            let mut magnet = magnet.clone();
+            magnet.trackers.extend(params.trackers.iter().cloned());
+            let t = crate::download_magnet(
+                &magnet,
+                &params.file_priorities,
+                output,
+                inbound,
+                announcer,
+                &stats,
+                &config,
+            )
+            .await?;
+            crate::record_transfer(&t, started, &stats, &config);
+        }
+    }
+    Ok(())
+}
+
+async fn scrub(
+    finished: Vec<(String, Arc<TorrentSource>, Arc<AddTorrentParams>)>,
+) -> anyhow::Result<()> {
+    let mut damaged = 0;
+    for (id, source, params) in finished {
+        let intact = tokio::task::spawn_blocking(move || match &*source {
+            TorrentSource::Torrent { metainfo, torrent } => {
+                let intact = verify::recheck_v1(torrent, metainfo, &params.output)?;
+                anyhow::Ok(Some((intact.count(), intact.len())))
+            }
+            // the metainfo fetched for it isn't kept
+            TorrentSource::Magnet(_) => Ok(None),
+        })
+        .await?;
+        match intact {
+            Ok(None) => {}
+            Ok(Some((intact, pieces))) if intact < pieces => {
+                tracing::warn!(%id, lost = pieces - intact, "finished download lost pieces");
+                damaged += 1;
+            }
+            Ok(Some(_)) => tracing::debug!(%id, "finished download intact"),
+            Err(e) => {
+                tracing::warn!(%id, error = %format!("{e:#}"), "can't scrub finished download");
+                damaged += 1;
+            }
+        }
+    }
+    anyhow::ensure!(
+        damaged == 0,
+        "{damaged} finished downloads damaged or unreadable"
+    );
+    Ok(())
+}
+
+/// Drives `work` only while `running` holds true. A paused download keeps its connections but
+/// stops reading from them.
+async fn pausable<T>(work: impl Future<Output = T>, mut running: watch::Receiver<bool>) -> T {
+    tokio::pin!(work);
+    loop {
+        // the sender only goes away with its torrent, which aborts this first
+        if running.wait_for(|running| *running).await.is_err() {
+            return std::future::pending().await;
+        }
+        tokio::select! {
+            result = &mut work => return result,
+            _ = running.wait_for(|running| !*running) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::maintenance::{Job, Maintenance};
+    use crate::testsupport::{FakePeer, FakeTorrent, FakeTracker};
+
+    /// Keeps the tests from writing to the real stores under `$HOME`.
+    fn config() -> ClientConfig {
+        ClientConfig {
+            metadata_cache: None,
+            stats: None,
+            resume: None,
+            ..ClientConfig::default()
+        }
+    }
+
+    fn fake() -> FakeTorrent {
+        FakeTorrent::new(
+            "a",
+            16 << 10,
+            (0..40_000).map(|i| (i % 251) as u8).collect(),
+        )
+    }
+
+    fn source(fake: &FakeTorrent, announce: &str) -> TorrentSource {
+        let metainfo = fake.metainfo(announce);
+        let torrent = serde_bencode::from_bytes(&metainfo).unwrap();
+        TorrentSource::Torrent { metainfo, torrent }
+    }
+
+    /// Drives `session` until `handle`'s download stops by itself.
+    async fn run_until_stopped(session: &mut Session<'_>, handle: &TorrentHandle) {
+        let mut outcome = handle.outcome.clone();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            tokio::select! {
+                () = session.run() => unreachable!(),
+                stopped = outcome.wait_for(Option::is_some) => drop(stopped.unwrap()),
+            }
+        })
+        .await
+        .expect("download stopped in time");
+    }
+
+    /// Drives `session` until the scrub it was last given has finished.
+    async fn run_until_scrubbed(session: &mut Session<'_>, maintenance: &Maintenance) {
+        let finished = async {
+            // a run can only be started once the last one is over
+            while maintenance.start(Job::Scrub).is_none() {
+                tokio::task::yield_now().await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), async {
+            tokio::select! {
+                () = session.run() => unreachable!(),
+                () = finished => {}
+            }
+        })
+        .await
+        .expect("scrub finished in time");
+    }
+
+    #[tokio::test]
+    async fn downloads_and_scrubs_torrents() {
+        let fake = fake();
+        let peer = FakePeer::start(&fake).await.unwrap();
+        let tracker = FakeTracker::start(vec![peer.addr]).await.unwrap();
+        let config = config();
+        let announcer = Announcer::new(&config).unwrap();
+        let mut session = Session::new(&config, &announcer, None);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("a");
+
+        let handle = session
+            .add(
+                source(&fake, &tracker.url),
+                AddTorrentParams::new(output.clone()),
+            )
+            .unwrap();
+        assert_eq!(handle.state(), TorrentState::Downloading);
+        run_until_stopped(&mut session, &handle).await;
+        assert_eq!(handle.state(), TorrentState::Done);
+        assert!(std::fs::read(&output).unwrap() == fake.data);
+        assert_eq!(handle.stats().snapshot().downloaded, 40_000);
+
+        // intact, then damaged
+        let maintenance = Maintenance::default();
+        session.scrub(maintenance.start(Job::Scrub).unwrap());
+        run_until_scrubbed(&mut session, &maintenance).await;
+        let mut damaged = fake.data.clone();
+        damaged[20_000] ^= 1;
+        std::fs::write(&output, damaged).unwrap();
+        session.scrub(maintenance.start(Job::Scrub).unwrap());
+        run_until_scrubbed(&mut session, &maintenance).await;
+        let report = maintenance.report();
+        let scrub = report
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|job| job["job"] == "scrub")
+            .unwrap();
+        assert_eq!(scrub["runs"], 2);
+        assert_eq!(scrub["failures"], 1);
+        assert_eq!(
+            scrub["last_error"],
+            "1 finished downloads damaged or unreadable"
+        );
+    }
+
+    #[tokio::test]
+    async fn pauses_resumes_and_removes_torrents() {
+        let config = config();
+        let announcer = Announcer::new(&config).unwrap();
+        let mut session = Session::new(&config, &announcer, None);
+        let fake = fake();
+        let params = AddTorrentParams {
+            paused: true,
+            ..AddTorrentParams::new(PathBuf::from("a"))
+        };
+
+        let handle = session
+            .add(source(&fake, "http://t/announce"), params.clone())
+            .unwrap();
+        assert_eq!(handle.id(), hex::encode(fake.info_hash()));
+        assert_eq!(handle.state(), TorrentState::Paused);
+        handle.resume();
+        assert_eq!(handle.state(), TorrentState::Downloading);
+        handle.pause();
+        assert_eq!(handle.state(), TorrentState::Paused);
+
+        let again = session.add(source(&fake, "http://t/announce"), params);
+        assert!(again.is_err(), "each torrent can only be added once");
+        assert!(session.restart(handle.id()).is_some());
+        assert!(session.remove(handle.id()).is_some());
+        assert!(session.get(handle.id()).is_none());
+        assert!(session.restart(handle.id()).is_none());
+    }
+
+    #[tokio::test]
+    async fn checks_file_priorities_against_the_torrent() {
+        let config = config();
+        let announcer = Announcer::new(&config).unwrap();
+        let mut session = Session::new(&config, &announcer, None);
+        let params = AddTorrentParams {
+            file_priorities: vec![Priority::High, Priority::Skip],
+            ..AddTorrentParams::new(PathBuf::from("a"))
+        };
+        let error = session
+            .add(source(&fake(), "http://t/announce"), params)
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "2 file priorities given for 1 files");
+    }
+
+    #[test]
+    fn applies_torrent_settings_over_the_sessions() {
+        let params = AddTorrentParams {
+            sequential: true,
+            max_hash_failures: Some(9),
+            ..AddTorrentParams::new(PathBuf::from("a"))
+        };
+        let config = params.config(&config());
+        assert!(config.sequential);
+        assert!(!config.skip_verify);
+        assert_eq!(config.max_hash_failures, 9);
+    }
+}
@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+
+use crate::picker::Availability;
+
+/// Time constant of the rate averages: a burst's weight in them halves about every 3.5 s.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+/// Events a subscriber can fall behind by before it misses some.
+const EVENT_BACKLOG: usize = 256;
+
+/// Transfer counters for the whole process, shared by every peer task.
+#[derive(Debug, Clone)]
+pub struct SessionStats {
+    inner: Arc<Mutex<Inner>>,
+    events: broadcast::Sender<SessionEvent>,
+}
+
+/// Something that happened in the session, for subscribers following it live.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SessionEvent {
+    PieceCompleted {
+        piece: usize,
+    },
+    PeerConnected {
+        peer: SocketAddr,
+    },
+    PeerDisconnected {
+        peer: SocketAddr,
+    },
+    /// The current rates, in bytes per second, sent by [`SessionStats::publish_rates`].
+    Rates {
+        down_rate: f64,
+        up_rate: f64,
+    },
+}
+
+#[derive(Debug)]
+struct Inner {
+    started: Instant,
+    downloaded: u64,
+    uploaded: u64,
+    down: RateMeter,
+    up: RateMeter,
+    pieces_completed: u32,
+    wasted: u64,
+    stalled: bool,
+    availability: Option<Availability>,
+    peers: HashMap<SocketAddr, PeerMeters>,
+}
+
+/// A transfer rate in bytes per second, as an exponentially weighted moving average over
+/// time. It decays towards zero while nothing is transferred.
+#[derive(Debug, Clone, Copy)]
+pub struct RateMeter {
+    rate: f64,
+    last: Instant,
+}
+
+impl RateMeter {
+    pub fn new() -> Self {
+        Self {
+            rate: 0.0,
+            last: Instant::now(),
+        }
+    }
+
+    fn decayed(&self, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.rate * (-elapsed / RATE_WINDOW.as_secs_f64()).exp()
+    }
+
+    /// Counts `bytes` transferred just now.
+    pub fn record(&mut self, bytes: usize) {
+        let now = Instant::now();
+        self.rate = self.decayed(now) + bytes as f64 / RATE_WINDOW.as_secs_f64();
+        self.last = now;
+    }
+
+    /// Bytes per second.
+    pub fn rate(&self) -> f64 {
+        self.decayed(Instant::now())
+    }
+}
+
+impl Default for RateMeter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PeerMeters {
+    connected: bool,
+    downloaded: u64,
+    down: RateMeter,
+    up: RateMeter,
+    last_active: Instant,
+}
+
+impl PeerMeters {
+    fn new() -> Self {
+        Self {
+            connected: false,
+            downloaded: 0,
+            down: RateMeter::new(),
+            up: RateMeter::new(),
+            last_active: Instant::now(),
+        }
+    }
+}
+
+/// One peer's transfers, as of a [`StatsSnapshot`].
+#[derive(Debug, Clone, Copy)]
+pub struct PeerStats {
+    pub downloaded: u64,
+    /// Bytes per second.
+    pub down_rate: f64,
+    pub up_rate: f64,
+    /// When a block last went either way.
+    pub last_active: Instant,
+}
+
+/// A point-in-time copy of [`SessionStats`].
+#[derive(Debug, Clone)]
+pub struct StatsSnapshot {
+    pub elapsed: Duration,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// Bytes per second, over every peer.
+    pub down_rate: f64,
+    pub up_rate: f64,
+    pub pieces_completed: u32,
+    /// Bytes thrown away because their piece failed verification.
+    pub wasted: u64,
+    /// No connected peer has a piece we still need.
+    pub stalled: bool,
+    /// How many connected peers have each piece of the current download, once one started.
+    pub availability: Option<Availability>,
+    /// Every peer we have connected to, fastest downloads first.
+    pub peers: Vec<(SocketAddr, PeerStats)>,
+}
+
+/// Feeds one peer's block transfers into the [`SessionStats`] it was made from.
+#[derive(Debug, Clone)]
+pub struct PeerMeter {
+    stats: SessionStats,
+    peer: SocketAddr,
+}
+
+impl PeerMeter {
+    pub fn block_received(&self, bytes: usize) {
+        self.stats.downloaded(self.peer, bytes);
+    }
+
+    pub fn block_sent(&self, bytes: usize) {
+        self.stats.uploaded(self.peer, bytes);
+    }
+}
+
+impl SessionStats {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                started: Instant::now(),
+                downloaded: 0,
+                uploaded: 0,
+                down: RateMeter::new(),
+                up: RateMeter::new(),
+                pieces_completed: 0,
+                wasted: 0,
+                stalled: false,
+                availability: None,
+                peers: HashMap::new(),
+            })),
+            events: broadcast::channel(EVENT_BACKLOG).0,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().expect("session stats lock poisoned")
+    }
+
+    /// Starts the rate clock for a newly connected peer, returning the meter its blocks are
+    /// counted through.
+    pub fn peer_connected(&self, peer: SocketAddr) -> PeerMeter {
+        let mut inner = self.lock();
+        let meters = inner.peers.entry(peer).or_insert_with(PeerMeters::new);
+        meters.connected = true;
+        meters.last_active = Instant::now();
+        drop(inner);
+        self.publish(SessionEvent::PeerConnected { peer });
+        PeerMeter {
+            stats: self.clone(),
+            peer,
+        }
+    }
+
+    /// Counts `bytes` received from `peer` and folds them into the rates.
+    pub fn downloaded(&self, peer: SocketAddr, bytes: usize) {
+        let mut inner = self.lock();
+        inner.downloaded += bytes as u64;
+        inner.down.record(bytes);
+        let meters = inner.peers.entry(peer).or_insert_with(PeerMeters::new);
+        meters.downloaded += bytes as u64;
+        meters.down.record(bytes);
+        meters.last_active = Instant::now();
+    }
+
+    /// Counts `bytes` sent to `peer` and folds them into the rates.
+    pub fn uploaded(&self, peer: SocketAddr, bytes: usize) {
+        let mut inner = self.lock();
+        inner.uploaded += bytes as u64;
+        inner.up.record(bytes);
+        let meters = inner.peers.entry(peer).or_insert_with(PeerMeters::new);
+        meters.up.record(bytes);
+        meters.last_active = Instant::now();
+    }
+
+    /// Notes that `peer` is gone, if it had got as far as [`Self::peer_connected`].
+    pub fn peer_disconnected(&self, peer: SocketAddr) {
+        let was_connected = self
+            .lock()
+            .peers
+            .get_mut(&peer)
+            .is_some_and(|meters| std::mem::replace(&mut meters.connected, false));
+        if was_connected {
+            self.publish(SessionEvent::PeerDisconnected { peer });
+        }
+    }
+
+    /// Notes that every peer is gone, once the download is over.
+    pub fn all_disconnected(&self) {
+        let peers: Vec<_> = self
+            .lock()
+            .peers
+            .iter_mut()
+            .filter_map(|(&peer, meters)| {
+                std::mem::replace(&mut meters.connected, false).then_some(peer)
+            })
+            .collect();
+        for peer in peers {
+            self.publish(SessionEvent::PeerDisconnected { peer });
+        }
+    }
+
+    pub fn piece_completed(&self, piece: usize) {
+        self.lock().pieces_completed += 1;
+        self.publish(SessionEvent::PieceCompleted { piece });
+    }
+
+    pub fn wasted(&self, bytes: usize) {
+        self.lock().wasted += bytes as u64;
+    }
+
+    pub fn set_stalled(&self, stalled: bool) {
+        self.lock().stalled = stalled;
+    }
+
+    pub fn set_availability(&self, availability: Availability) {
+        self.lock().availability = Some(availability);
+    }
+
+    /// Follows the session's events from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionEvent> {
+        self.events.subscribe()
+    }
+
+    /// Sends the current rates to subscribers, so they can chart them without polling.
+    pub fn publish_rates(&self) {
+        let (down_rate, up_rate) = {
+            let inner = self.lock();
+            (inner.down.rate(), inner.up.rate())
+        };
+        self.publish(SessionEvent::Rates { down_rate, up_rate });
+    }
+
+    fn publish(&self, event: SessionEvent) {
+        // nobody listening is fine
+        let _ = self.events.send(event);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let inner = self.lock();
+        let mut peers: Vec<_> = inner
+            .peers
+            .iter()
+            .map(|(&peer, meters)| {
+                let stats = PeerStats {
+                    downloaded: meters.downloaded,
+                    down_rate: meters.down.rate(),
+                    up_rate: meters.up.rate(),
+                    last_active: meters.last_active,
+                };
+                (peer, stats)
+            })
+            .collect();
+        peers.sort_by(|a, b| b.1.down_rate.total_cmp(&a.1.down_rate));
+        StatsSnapshot {
+            elapsed: inner.started.elapsed(),
+            downloaded: inner.downloaded,
+            uploaded: inner.uploaded,
+            down_rate: inner.down.rate(),
+            up_rate: inner.up.rate(),
+            pieces_completed: inner.pieces_completed,
+            wasted: inner.wasted,
+            stalled: inner.stalled,
+            availability: inner.availability.clone(),
+            peers,
+        }
+    }
+}
+
+impl Default for SessionStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{:>4}s] down {} B ({:.1} KiB/s), up {} B ({:.1} KiB/s), {} pieces, {} B wasted, \
+             {} peers",
+            self.elapsed.as_secs(),
+            self.downloaded,
+            self.down_rate / 1024.0,
+            self.uploaded,
+            self.up_rate / 1024.0,
+            self.pieces_completed,
+            self.wasted,
+            self.peers.len()
+        )?;
+        if self.stalled {
+            f.write_str(", stalled")?;
+        }
+        if let Some(availability) = &self.availability {
+            write!(f, "\n        {availability}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+//! The daemon's torrents, kept on disk so a restarted daemon picks them back up: a
+//! `torrents.json` list beside a copy of each `.torrent` file added, named by info hash.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::session::{AddTorrentParams, TorrentSource};
+
+const TORRENTS: &str = "torrents.json";
+
+/// One torrent as the store records it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedTorrent {
+    /// The hex info hash.
+    pub id: String,
+    /// The link a magnet was added by. Torrents added from metainfo have it saved instead.
+    pub magnet: Option<String>,
+    /// What it was added with, with `paused` kept up to date.
+    #[serde(flatten)]
+    pub params: AddTorrentParams,
+    /// Unix time, in seconds, it was first added.
+    pub added: u64,
+    /// Bytes downloaded for it, over every run of the daemon.
+    pub downloaded: u64,
+    /// Bytes uploaded for it, likewise.
+    #[serde(default)]
+    pub uploaded: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionStore {
+    dir: PathBuf,
+}
+
+impl SessionStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// `$XDG_DATA_HOME/bittorrent-starter-rust/session`, falling back to `~/.local/share`.
+    pub fn default_dir() -> Option<PathBuf> {
+        let base = std::env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share"))
+            })?;
+        Some(base.join("bittorrent-starter-rust").join("session"))
+    }
+
+    /// Every saved torrent with what it is downloaded from. Torrents whose metainfo or link
+    /// can't be read back are left out, with a warning.
+    pub fn load(&self) -> anyhow::Result<Vec<(SavedTorrent, TorrentSource)>> {
+        let path = self.dir.join(TORRENTS);
+        let saved: Vec<SavedTorrent> = match std::fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .with_context(|| format!("parse {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+        };
+        Ok(saved
+            .into_iter()
+            .filter_map(|saved| match self.source(&saved) {
+                Ok(source) => Some((saved, source)),
+                Err(e) => {
+                    let error = format!("{e:#}");
+                    tracing::warn!(id = %saved.id, %error, "can't restore saved torrent");
+                    None
+                }
+            })
+            .collect())
+    }
+
+    fn source(&self, saved: &SavedTorrent) -> anyhow::Result<TorrentSource> {
+        if let Some(link) = &saved.magnet {
+            return Ok(TorrentSource::Magnet(link.parse()?));
+        }
+        let path = self.metainfo_path(&saved.id);
+        let metainfo = std::fs::read(&path).with_context(|| format!("read {}", path.display()))?;
+        let torrent = serde_bencode::from_bytes(&metainfo).context("parse torrent file")?;
+        Ok(TorrentSource::Torrent { metainfo, torrent })
+    }
+
+    /// Keeps a copy of torrent `id`'s metainfo, for [`Self::load`] to read back.
+    pub fn save_metainfo(&self, id: &str, metainfo: &[u8]) -> anyhow::Result<()> {
+        let path = self.metainfo_path(id);
+        std::fs::create_dir_all(&self.dir)
+            .and_then(|()| std::fs::write(&path, metainfo))
+            .with_context(|| format!("write {}", path.display()))
+    }
+
+    /// Replaces the saved torrents with `torrents`, dropping the metainfo of any others.
+    /// Written to a temporary file first so a crash can't leave a truncated one behind.
+    pub fn save(&self, torrents: &[SavedTorrent]) -> anyhow::Result<()> {
+        let encoded = serde_json::to_vec_pretty(torrents).context("encode saved torrents")?;
+        let path = self.dir.join(TORRENTS);
+        let partial = path.with_extension("json.part");
+        std::fs::create_dir_all(&self.dir)
+            .and_then(|()| std::fs::write(&partial, encoded))
+            .and_then(|()| std::fs::rename(&partial, &path))
+            .with_context(|| format!("write {}", path.display()))?;
+
+        let kept: HashSet<_> = torrents
+            .iter()
+            .map(|saved| self.metainfo_path(&saved.id))
+            .collect();
+        let entries =
+            std::fs::read_dir(&self.dir).with_context(|| format!("read {}", self.dir.display()))?;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "torrent") && !kept.contains(&path) {
+                if let Err(e) = std::fs::remove_file(&path) {
+                    tracing::debug!(path = %path.display(), error = %e, "removing metainfo failed");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn metainfo_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.torrent"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testsupport::FakeTorrent;
+
+    fn saved(id: String, magnet: Option<String>) -> SavedTorrent {
+        SavedTorrent {
+            id,
+            magnet,
+            params: AddTorrentParams::new(PathBuf::from("out")),
+            added: 1,
+            downloaded: 2,
+            uploaded: 3,
+        }
+    }
+
+    #[test]
+    fn saves_torrents_and_reads_them_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SessionStore::new(dir.path().join("session"));
+        assert!(store.load().unwrap().is_empty());
+
+        let fake = FakeTorrent::new("a", 16 << 10, vec![1; 100]);
+        let id = hex::encode(fake.info_hash());
+        store
+            .save_metainfo(&id, &fake.metainfo("http://t/announce"))
+            .unwrap();
+        let link = format!("magnet:?xt=urn:btih:{}", "11".repeat(20));
+        let lost = "22".repeat(20);
+        store
+            .save(&[
+                saved(id.clone(), None),
+                saved("11".repeat(20), Some(link)),
+                // its metainfo was never saved
+                saved(lost, None),
+            ])
+            .unwrap();
+
+        let loaded = store.load().unwrap();
+        assert_eq!(loaded.len(), 2);
+        let (saved, source) = &loaded[0];
+        assert_eq!(saved.id, id);
+        assert_eq!((saved.downloaded, saved.uploaded), (2, 3));
+        assert_eq!(source.name(), Some("a"));
+        assert!(matches!(loaded[1].1, TorrentSource::Magnet(_)));
+
+        // dropping a torrent drops its metainfo
+        store.save(&[]).unwrap();
+        assert!(!store.metainfo_path(&id).exists());
+        assert!(store.load().unwrap().is_empty());
+    }
+}
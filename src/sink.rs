@@ -0,0 +1,232 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use futures_util::{Sink, SinkExt};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio_util::sync::PollSender;
+
+use crate::bitfield::Bitfield;
+use crate::storage::Storage;
+
+/// Verified pieces that may wait for the disk writer before downloads are held up.
+const DISK_QUEUE: usize = 16;
+/// Most pieces the disk writer takes off its queue to write in one go.
+const DISK_BATCH: usize = 8;
+
+/// A piece whose hash has been checked, ready to hand to storage or a custom consumer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedPiece {
+    pub index: usize,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Forward pieces strictly by index, holding back any that are verified early. Whatever
+    /// the sink has received is always a complete prefix of the data.
+    InOrder,
+    /// Forward pieces as soon as they are verified.
+    AsAvailable,
+}
+
+/// Forwards verified pieces to any `Sink`, e.g. a transcoder or an object-store uploader.
+/// Combine sinks with `SinkExt::fanout` to also keep writing to disk.
+pub struct PieceForwarder<S> {
+    sink: S,
+    delivery: Delivery,
+    next: usize,
+    held: BTreeMap<usize, VerifiedPiece>,
+    skipped: BTreeSet<usize>,
+}
+
+impl<S> PieceForwarder<S>
+where
+    S: Sink<VerifiedPiece> + Unpin,
+{
+    /// `first` is the index in-order delivery starts from.
+    pub fn new(sink: S, delivery: Delivery, first: usize) -> Self {
+        Self {
+            sink,
+            delivery,
+            next: first,
+            held: BTreeMap::new(),
+            skipped: BTreeSet::new(),
+        }
+    }
+
+    /// Marks pieces that will never be verified, e.g. because only some files are wanted, so
+    /// in-order delivery doesn't wait for them.
+    pub fn skip(&mut self, pieces: impl IntoIterator<Item = usize>) {
+        self.skipped.extend(pieces);
+    }
+
+    pub async fn piece_verified(&mut self, piece: VerifiedPiece) -> Result<(), S::Error> {
+        if self.delivery == Delivery::AsAvailable {
+            return self.sink.send(piece).await;
+        }
+        self.held.insert(piece.index, piece);
+        loop {
+            if self.skipped.remove(&self.next) {
+                self.next += 1;
+                continue;
+            }
+            let Some(piece) = self.held.remove(&self.next) else {
+                break;
+            };
+            self.sink.feed(piece).await?;
+            self.next += 1;
+        }
+        self.sink.flush().await
+    }
+
+    /// Flushes and closes the sink. Pieces still held back waiting for a gap to fill are
+    /// dropped.
+    pub async fn close(mut self) -> Result<(), S::Error> {
+        if !self.held.is_empty() {
+            tracing::warn!(
+                held = self.held.len(),
+                next = self.next,
+                "closing piece sink with undelivered pieces"
+            );
+        }
+        self.sink.close().await
+    }
+}
+
+/// A sink writing each piece at its offset in `file`, relative to piece `base`.
+pub fn file_sink(
+    file: tokio::fs::File,
+    piece_length: usize,
+    base: usize,
+) -> impl Sink<VerifiedPiece, Error = std::io::Error> {
+    futures_util::sink::unfold(file, move |mut file, piece: VerifiedPiece| async move {
+        let offset = ((piece.index - base) * piece_length) as u64;
+        file.seek(SeekFrom::Start(offset)).await?;
+        file.write_all(&piece.data).await?;
+        file.flush().await?;
+        Ok(file)
+    })
+}
+
+/// When the disk writer asks the OS to make written pieces durable.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fsync {
+    /// Leave it to the OS.
+    #[default]
+    Never,
+    /// Once, when the download finishes.
+    Completion,
+    /// After every batch of pieces.
+    Batch,
+}
+
+/// A sink handing pieces to a dedicated thread that writes them to a [`Storage`], so peer
+/// tasks don't wait on the disk unless its queue is full.
+pub struct DiskWriter {
+    tx: PollSender<VerifiedPiece>,
+    task: JoinHandle<io::Result<()>>,
+}
+
+impl DiskWriter {
+    /// Starts the writer for `storage`, with piece offsets relative to piece `base`. If
+    /// `written` is given, each piece is marked in it once storage has taken its data, so
+    /// readers of the output know which parts are safe to read.
+    pub fn spawn(
+        storage: impl Storage + 'static,
+        piece_length: usize,
+        base: usize,
+        fsync: Fsync,
+        written: Option<watch::Sender<Bitfield>>,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel(DISK_QUEUE);
+        let task = tokio::task::spawn_blocking(move || {
+            write_pieces(rx, storage, piece_length, base, fsync, written)
+        });
+        Self {
+            tx: PollSender::new(tx),
+            task,
+        }
+    }
+
+    /// Waits for the writer task to end and returns why it did.
+    fn poll_stopped(&mut self, cx: &mut Context<'_>) -> Poll<io::Error> {
+        Poll::Ready(match ready!(Pin::new(&mut self.task).poll(cx)) {
+            Ok(Ok(())) => io::Error::other("disk writer stopped"),
+            Ok(Err(e)) => e,
+            Err(e) => io::Error::other(e),
+        })
+    }
+}
+
+impl Sink<VerifiedPiece> for DiskWriter {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match ready!(this.tx.poll_reserve(cx)) {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(_) => this.poll_stopped(cx).map(Err),
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, piece: VerifiedPiece) -> io::Result<()> {
+        self.get_mut()
+            .tx
+            .send_item(piece)
+            .map_err(|_| io::Error::other("disk writer stopped"))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // queued pieces are the writer's to flush
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.tx.close();
+        Poll::Ready(match ready!(Pin::new(&mut this.task).poll(cx)) {
+            Ok(result) => result,
+            Err(e) => Err(io::Error::other(e)),
+        })
+    }
+}
+
+fn write_pieces(
+    mut rx: mpsc::Receiver<VerifiedPiece>,
+    mut storage: impl Storage,
+    piece_length: usize,
+    base: usize,
+    fsync: Fsync,
+    written: Option<watch::Sender<Bitfield>>,
+) -> io::Result<()> {
+    let mut batch = Vec::with_capacity(DISK_BATCH);
+    while let Some(piece) = rx.blocking_recv() {
+        batch.push(piece);
+        while batch.len() < DISK_BATCH {
+            match rx.try_recv() {
+                Ok(piece) => batch.push(piece),
+                Err(_) => break,
+            }
+        }
+        batch.sort_by_key(|piece| piece.index);
+        for piece in batch.drain(..) {
+            let offset = ((piece.index - base) * piece_length) as u64;
+            storage.write_block(offset, &piece.data)?;
+            if let Some(written) = &written {
+                written.send_modify(|written| written.set_piece(piece.index));
+            }
+        }
+        if fsync == Fsync::Batch {
+            storage.flush()?;
+        }
+    }
+    if fsync == Fsync::Completion {
+        storage.flush()?;
+    }
+    Ok(())
+}
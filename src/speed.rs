@@ -0,0 +1,204 @@
+//! Download rate limits shared by every peer connection in the process, with an alternative
+//! ("turtle mode") limit switched on by hand or during a daily time window. A torrent can
+//! have a limit of its own within them.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::time::Instant;
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// A daily stretch of time, in UTC, such as `08:00-20:00`. It may wrap past midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AltWindow {
+    /// Minutes past midnight; the end is exclusive.
+    start: u32,
+    end: u32,
+}
+
+impl AltWindow {
+    fn contains(&self, time: SystemTime) -> bool {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let minute = (since_epoch.as_secs() / 60 % u64::from(MINUTES_PER_DAY)) as u32;
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+impl FromStr for AltWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("expected a window like 08:00-20:00, got `{s}`"))?;
+        Ok(Self {
+            start: minutes(start)?,
+            end: minutes(end)?,
+        })
+    }
+}
+
+impl fmt::Display for AltWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (start, end) = (self.start, self.end);
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            start / 60,
+            start % 60,
+            end / 60,
+            end % 60
+        )
+    }
+}
+
+fn minutes(time: &str) -> Result<u32, String> {
+    let parsed = time.split_once(':').and_then(|(hours, minutes)| {
+        Some((hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?))
+    });
+    match parsed {
+        Some((hours, minutes)) if hours < 24 && minutes < 60 => Ok(hours * 60 + minutes),
+        _ => Err(format!("expected a time like 08:00, got `{time}`")),
+    }
+}
+
+/// The download limits in force, shared by every clone.
+#[derive(Debug, Clone)]
+pub struct SpeedLimits {
+    inner: Arc<Mutex<Inner>>,
+    /// Limits that apply as well, for a torrent limited within the process's limits.
+    within: Option<Arc<Mutex<Inner>>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// Bytes per second, or `None` for unlimited.
+    normal: Option<u64>,
+    alt: Option<u64>,
+    alt_enabled: bool,
+    window: Option<AltWindow>,
+    /// Whether the clock was inside `window` when last looked at, so the schedule only
+    /// switches on its edges and the manual toggle holds in between.
+    in_window: Option<bool>,
+    /// Bytes that may be downloaded without waiting; negative when in debt.
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Inner {
+    fn follow_schedule(&mut self) {
+        let Some(window) = self.window else {
+            return;
+        };
+        let inside = window.contains(SystemTime::now());
+        if self.in_window == Some(inside) {
+            return;
+        }
+        // starting outside the window leaves `--alt-speed` alone
+        if self.in_window.is_some() || inside {
+            tracing::info!(alt = inside, %window, "schedule switched alternative speed limits");
+            self.alt_enabled = inside;
+        }
+        self.in_window = Some(inside);
+    }
+
+    fn limit(&self) -> Option<u64> {
+        if self.alt_enabled {
+            self.alt
+        } else {
+            self.normal
+        }
+    }
+}
+
+impl SpeedLimits {
+    /// `normal` and `alt` are in bytes per second, `None` meaning unlimited. The alternative
+    /// limit applies while `alt_enabled`, which `window` switches on and off.
+    pub fn new(
+        normal: Option<u64>,
+        alt: Option<u64>,
+        window: Option<AltWindow>,
+        alt_enabled: bool,
+    ) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                normal,
+                alt,
+                alt_enabled,
+                window,
+                in_window: None,
+                tokens: 0.0,
+                refilled: Instant::now(),
+            })),
+            within: None,
+        }
+    }
+
+    /// A limit of `limit` bytes per second for one torrent, on top of these.
+    pub fn within(&self, limit: u64) -> Self {
+        Self {
+            within: Some(self.inner.clone()),
+            ..Self::new(Some(limit), None, None, false)
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().expect("speed limits lock poisoned")
+    }
+
+    /// Whether the alternative limit is in force, and the limit in force in bytes per second.
+    pub fn current(&self) -> (bool, Option<u64>) {
+        let mut inner = self.lock();
+        inner.follow_schedule();
+        (inner.alt_enabled, inner.limit())
+    }
+
+    /// Switches the alternative limit on or off until the schedule next changes it.
+    pub fn set_alt_enabled(&self, enabled: bool) {
+        let mut inner = self.lock();
+        inner.follow_schedule();
+        inner.alt_enabled = enabled;
+    }
+
+    /// Waits for as long as downloading `bytes` more puts us over the limit in force. Up to a
+    /// second's worth can build up while idle, so short bursts don't wait.
+    pub async fn consume(&self, bytes: usize) {
+        consume(&self.inner, bytes).await;
+        if let Some(within) = &self.within {
+            consume(within, bytes).await;
+        }
+    }
+}
+
+async fn consume(inner: &Mutex<Inner>, bytes: usize) {
+    let wait = {
+        let mut inner = inner.lock().expect("speed limits lock poisoned");
+        inner.follow_schedule();
+        let Some(rate) = inner.limit() else {
+            return;
+        };
+        let rate = rate.max(1) as f64;
+        let now = Instant::now();
+        let earned = now.duration_since(inner.refilled).as_secs_f64() * rate;
+        inner.tokens = (inner.tokens + earned).min(rate) - bytes as f64;
+        inner.refilled = now;
+        if inner.tokens >= 0.0 {
+            return;
+        }
+        Duration::from_secs_f64(-inner.tokens / rate)
+    };
+    tokio::time::sleep(wait).await;
+}
+
+impl Default for SpeedLimits {
+    fn default() -> Self {
+        Self::new(None, None, None, false)
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// One completed transfer, as appended to the statistics store.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferRecord {
+    pub info_hash: String,
+    pub name: String,
+    /// Unix time, in seconds, the transfer started and finished.
+    pub started: u64,
+    pub finished: u64,
+    pub downloaded: u64,
+    pub uploaded: u64,
+}
+
+/// Per-torrent totals over a set of transfers.
+#[derive(Debug, Clone, Serialize)]
+pub struct TorrentTotals {
+    pub info_hash: String,
+    pub name: String,
+    pub transfers: u32,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// Uploaded over downloaded bytes.
+    pub ratio: f64,
+    /// Seconds spent transferring, summed over every transfer.
+    pub uptime: u64,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+/// Transfer history, one JSON record per line, kept across runs for ratio reporting.
+#[derive(Debug, Clone)]
+pub struct StatsStore {
+    path: PathBuf,
+}
+
+impl StatsStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// `$XDG_DATA_HOME/bittorrent-starter-rust/stats.jsonl`, falling back to `~/.local/share`.
+    pub fn default_path() -> Option<PathBuf> {
+        let base = std::env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share"))
+            })?;
+        Some(base.join("bittorrent-starter-rust").join("stats.jsonl"))
+    }
+
+    pub fn record(&self, record: &TransferRecord) -> anyhow::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir).context("create statistics directory")?;
+        }
+        let mut line = serde_json::to_vec(record).context("encode transfer record")?;
+        line.push(b'\n');
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(&line))
+            .with_context(|| format!("append to {}", self.path.display()))
+    }
+
+    /// Every transfer that finished at or after `since` (Unix seconds). Lines that don't
+    /// parse, e.g. from a write cut short, are skipped.
+    pub fn load_since(&self, since: u64) -> anyhow::Result<Vec<TransferRecord>> {
+        let contents = match std::fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("read {}", self.path.display())),
+        };
+        Ok(contents
+            .lines()
+            .filter_map(|line| serde_json::from_str::<TransferRecord>(line).ok())
+            .filter(|record| record.finished >= since)
+            .collect())
+    }
+}
+
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Folds transfers into per-torrent totals, ordered by info hash.
+pub fn totals(records: &[TransferRecord]) -> Vec<TorrentTotals> {
+    let mut by_torrent = BTreeMap::<&str, TorrentTotals>::new();
+    for record in records {
+        let totals = by_torrent
+            .entry(&record.info_hash)
+            .or_insert_with(|| TorrentTotals {
+                info_hash: record.info_hash.clone(),
+                name: record.name.clone(),
+                transfers: 0,
+                downloaded: 0,
+                uploaded: 0,
+                ratio: 0.0,
+                uptime: 0,
+            });
+        totals.transfers += 1;
+        totals.downloaded += record.downloaded;
+        totals.uploaded += record.uploaded;
+        totals.uptime += record.finished.saturating_sub(record.started);
+    }
+    by_torrent
+        .into_values()
+        .map(|mut totals| {
+            if totals.downloaded > 0 {
+                totals.ratio = totals.uploaded as f64 / totals.downloaded as f64;
+            }
+            totals
+        })
+        .collect()
+}
+
+pub fn export(totals: &[TorrentTotals], format: ExportFormat) -> anyhow::Result<String> {
+    match format {
+        ExportFormat::Json => {
+            serde_json::to_string_pretty(totals).context("encode statistics as JSON")
+        }
+        ExportFormat::Csv => {
+            let mut csv =
+                String::from("info_hash,name,transfers,downloaded,uploaded,ratio,uptime\n");
+            for t in totals {
+                csv.push_str(&format!(
+                    "{},{},{},{},{},{:.3},{}\n",
+                    t.info_hash,
+                    csv_field(&t.name),
+                    t.transfers,
+                    t.downloaded,
+                    t.uploaded,
+                    t.ratio,
+                    t.uptime
+                ));
+            }
+            Ok(csv)
+        }
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Parses `--since`: either Unix seconds, or an age such as `90m`, `12h` or `30d`.
+pub fn parse_since(since: &str) -> Result<u64, String> {
+    if let Ok(secs) = since.parse::<u64>() {
+        return Ok(secs);
+    }
+    let split = since
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("invalid time {since:?}"))?;
+    let (amount, unit) = since.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("invalid time {since:?}"))?;
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "unknown time unit {unit:?}, expected s, m, h, d or w"
+            ));
+        }
+    };
+    let age = Duration::from_secs(amount.saturating_mul(unit));
+    Ok(unix_time(
+        SystemTime::now().checked_sub(age).unwrap_or(UNIX_EPOCH),
+    ))
+}
@@ -0,0 +1,200 @@
+//! Where downloaded data lives, behind a small trait so the disk writer doesn't care whether
+//! it's a file, memory, or some other backend.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+/// Byte-addressed storage for a torrent's contents. Calls may block, so async code should
+/// make them from the blocking thread pool.
+pub trait Storage: Send {
+    /// Fills `buf` with the bytes at `offset`, failing if any of them were never written.
+    fn read_block(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    fn write_block(&mut self, offset: u64, data: &[u8]) -> io::Result<()>;
+
+    /// Makes every write so far durable.
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+impl<S: Storage + ?Sized> Storage for Box<S> {
+    fn read_block(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        (**self).read_block(offset, buf)
+    }
+
+    fn write_block(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        (**self).write_block(offset, data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+}
+
+/// Opens the storage a download of `len` bytes writes to at `path`, keeping what is already
+/// there: memory-mapped if `mmap` is set, which needs the `mmap` feature, and a plain file
+/// otherwise.
+pub fn create(path: &Path, len: u64, mmap: bool) -> io::Result<Box<dyn Storage>> {
+    if mmap {
+        #[cfg(feature = "mmap")]
+        return Ok(Box::new(MmapStorage::create(path, len)?));
+        #[cfg(not(feature = "mmap"))]
+        {
+            let _ = len;
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "built without the mmap feature",
+            ));
+        }
+    }
+    Ok(Box::new(FileStorage::create(path, len)?))
+}
+
+/// A single file holding the torrent's contents back to back.
+#[derive(Debug)]
+pub struct FileStorage {
+    file: File,
+}
+
+impl FileStorage {
+    /// Opens the file at `path`, creating it if need be, and sizes it to `len` bytes. Data
+    /// already in it is kept, so a restarted download can pick up the pieces it holds.
+    pub fn create(path: &Path, len: u64) -> io::Result<Self> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        file.set_len(len)?;
+        Ok(Self { file })
+    }
+}
+
+impl Storage for FileStorage {
+    fn read_block(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(buf)
+    }
+
+    fn write_block(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+/// A file of fixed length mapped into memory, so blocks are copied in and out without a
+/// syscall each. Flushing writes dirty pages back to the file.
+#[cfg(feature = "mmap")]
+#[derive(Debug)]
+pub struct MmapStorage {
+    map: memmap2::MmapMut,
+}
+
+#[cfg(feature = "mmap")]
+impl MmapStorage {
+    /// Opens the file at `path`, creating it if need be, sizes it to `len` bytes, keeping the
+    /// data already in it, and maps it.
+    pub fn create(path: &Path, len: u64) -> io::Result<Self> {
+        if len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot map an empty file",
+            ));
+        }
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        file.set_len(len)?;
+        // SAFETY: the file was just sized by us and nothing else is expected to resize
+        // it while the download runs
+        let map = unsafe { memmap2::MmapMut::map_mut(&file)? };
+        Ok(Self { map })
+    }
+
+    fn range(&self, offset: u64, len: usize) -> io::Result<std::ops::Range<usize>> {
+        usize::try_from(offset)
+            .ok()
+            .and_then(|start| Some(start..start.checked_add(len)?))
+            .filter(|range| range.end <= self.map.len())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{len} bytes at offset {offset} are past the end of the map"),
+                )
+            })
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl Storage for MmapStorage {
+    fn read_block(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let range = self.range(offset, buf.len())?;
+        buf.copy_from_slice(&self.map[range]);
+        Ok(())
+    }
+
+    fn write_block(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let range = self.range(offset, data.len())?;
+        self.map[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.map.flush()
+    }
+}
+
+/// Contents kept in memory. Clones share the same bytes, so a download and a simulated peer
+/// can serve and check the same data.
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    data: Arc<Mutex<Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl MemoryStorage {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            data: Arc::new(Mutex::new(data)),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Storage for MemoryStorage {
+    fn read_block(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let data = self.data.lock().expect("memory storage lock poisoned");
+        let block = usize::try_from(offset)
+            .ok()
+            .and_then(|start| data.get(start..start.checked_add(buf.len())?))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        buf.copy_from_slice(block);
+        Ok(())
+    }
+
+    fn write_block(&mut self, offset: u64, block: &[u8]) -> io::Result<()> {
+        let mut data = self.data.lock().expect("memory storage lock poisoned");
+        let start = usize::try_from(offset).map_err(io::Error::other)?;
+        let end = start + block.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(block);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
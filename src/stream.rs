@@ -0,0 +1,230 @@
+//! A tiny HTTP server exposing a download's output while it is still arriving, so a media
+//! player can start on it straight away. Reads wait for the pieces they cover to be written.
+
+use std::io::{self, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::bitfield::Bitfield;
+use crate::geometry::PieceGeometry;
+
+/// Longest request head we read before giving up on the client.
+const MAX_HEAD: u64 = 8 << 10;
+
+/// The output file being served, how it splits into pieces, and which of those are on disk.
+#[derive(Debug, Clone)]
+pub struct Served {
+    pub path: PathBuf,
+    pub geometry: PieceGeometry,
+    pub written: watch::Receiver<Bitfield>,
+}
+
+/// What a request's `Range` header asks for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RangeRequest {
+    /// No usable range; send the whole file.
+    Whole,
+    /// These bytes, end exclusive.
+    Part(Range<u64>),
+    /// A range lying entirely past the end of the file.
+    Unsatisfiable,
+}
+
+/// Answers GET and HEAD requests on `listener` in the background, one per connection.
+pub fn spawn(listener: TcpListener, served: Served) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::warn!(error = %e, "accepting stream connection failed");
+                    continue;
+                }
+            };
+            let served = served.clone();
+            tokio::spawn(async move {
+                if let Err(e) = respond(stream, &served).await {
+                    tracing::debug!(client = %addr, error = %e, "stream request failed");
+                }
+            });
+        }
+    })
+}
+
+async fn respond(stream: TcpStream, served: &Served) -> io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let (method, range) = read_request(&mut stream).await?;
+    let stream = stream.get_mut();
+    let len = served.geometry.length() as u64;
+    let head_only = match method.as_str() {
+        "GET" => false,
+        "HEAD" => true,
+        _ => {
+            let head = "HTTP/1.1 405 Method Not Allowed\r\nAllow: GET, HEAD\r\n\
+                        Content-Length: 0\r\nConnection: close\r\n\r\n";
+            stream.write_all(head.as_bytes()).await?;
+            return stream.shutdown().await;
+        }
+    };
+    tracing::debug!(%method, ?range, "stream request");
+    let (body, partial) = match parse_range(range.as_deref(), len) {
+        RangeRequest::Whole => (0..len, false),
+        RangeRequest::Part(range) => (range, true),
+        RangeRequest::Unsatisfiable => {
+            let head = format!(
+                "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{len}\r\n\
+                 Content-Length: 0\r\nConnection: close\r\n\r\n"
+            );
+            stream.write_all(head.as_bytes()).await?;
+            return stream.shutdown().await;
+        }
+    };
+    let status = if partial {
+        "206 Partial Content"
+    } else {
+        "200 OK"
+    };
+    let mut head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Accept-Ranges: bytes\r\nConnection: close\r\n",
+        content_type(&served.path),
+        body.end - body.start
+    );
+    if partial {
+        head += &format!(
+            "Content-Range: bytes {}-{}/{len}\r\n",
+            body.start,
+            body.end - 1
+        );
+    }
+    head += "\r\n";
+    stream.write_all(head.as_bytes()).await?;
+    if !head_only {
+        send_body(stream, served, body).await?;
+    }
+    stream.shutdown().await
+}
+
+/// Reads a request head, returning its method and `Range` header.
+async fn read_request(stream: &mut BufReader<TcpStream>) -> io::Result<(String, Option<String>)> {
+    let mut head = stream.take(MAX_HEAD);
+    let mut method = None;
+    let mut range = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if head.read_line(&mut line).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "request head cut short or too long",
+            ));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if method.is_none() {
+            method = line.split_whitespace().next().map(str::to_owned);
+        } else if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("range") {
+                range = Some(value.trim().to_owned());
+            }
+        }
+    }
+    let method = method.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no method"))?;
+    Ok((method, range))
+}
+
+/// Interprets a `Range` header for a file of `len` bytes. Headers we don't understand, and
+/// requests for several ranges, are ignored, as HTTP allows.
+fn parse_range(header: Option<&str>, len: u64) -> RangeRequest {
+    let Some(spec) = header.and_then(|header| header.strip_prefix("bytes=")) else {
+        return RangeRequest::Whole;
+    };
+    if spec.contains(',') {
+        return RangeRequest::Whole;
+    }
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return RangeRequest::Whole;
+    };
+    if first.is_empty() {
+        // a suffix: the final `last` bytes
+        return match last.parse::<u64>() {
+            Ok(0) => RangeRequest::Unsatisfiable,
+            Ok(_) if len == 0 => RangeRequest::Unsatisfiable,
+            Ok(n) => RangeRequest::Part(len.saturating_sub(n)..len),
+            Err(_) => RangeRequest::Whole,
+        };
+    }
+    let Ok(start) = first.parse::<u64>() else {
+        return RangeRequest::Whole;
+    };
+    let end = if last.is_empty() {
+        len
+    } else {
+        match last.parse::<u64>() {
+            Ok(last) if last >= start => last.saturating_add(1).min(len),
+            _ => return RangeRequest::Whole,
+        }
+    };
+    if start >= len {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Part(start..end)
+    }
+}
+
+/// Copies `range` of the output to `stream` a piece at a time, waiting for each piece to be
+/// written first.
+async fn send_body(stream: &mut TcpStream, served: &Served, range: Range<u64>) -> io::Result<()> {
+    if range.is_empty() {
+        return Ok(());
+    }
+    let geometry = &served.geometry;
+    let first = geometry.piece_at(range.start as usize);
+    let last = geometry.piece_at(range.end as usize - 1);
+    let mut written = served.written.clone();
+    // the file only exists once the download has started writing it
+    wait_written(&mut written, first).await?;
+    let mut file = File::open(&served.path).await?;
+    let mut buf = Vec::new();
+    for piece in first..=last {
+        wait_written(&mut written, piece).await?;
+        let start = geometry.piece_offset(piece) as u64;
+        let end = start + geometry.piece_len(piece) as u64;
+        let chunk = range.start.max(start)..range.end.min(end);
+        file.seek(SeekFrom::Start(chunk.start)).await?;
+        buf.resize((chunk.end - chunk.start) as usize, 0);
+        file.read_exact(&mut buf).await?;
+        stream.write_all(&buf).await?;
+    }
+    Ok(())
+}
+
+async fn wait_written(written: &mut watch::Receiver<Bitfield>, piece: usize) -> io::Result<()> {
+    written
+        .wait_for(|written| written.has_piece(piece))
+        .await
+        .map(drop)
+        .map_err(|_| io::Error::other(format!("download stopped before piece {piece}")))
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("mp4" | "m4v") => "video/mp4",
+        Some("mkv") => "video/x-matroska",
+        Some("webm") => "video/webm",
+        Some("mp3") => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
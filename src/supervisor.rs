@@ -0,0 +1,77 @@
+use std::any::Any;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+
+use futures_util::FutureExt;
+use tracing::Instrument;
+
+use crate::failures::TooManyHashFailures;
+
+/// Why a supervised peer connection ended before finishing its work.
+#[derive(Debug, thiserror::Error)]
+pub enum PeerExit {
+    #[error("peer {peer} failed: {error:#}")]
+    Failed {
+        peer: SocketAddr,
+        error: anyhow::Error,
+    },
+    #[error("peer {peer} task panicked: {message}")]
+    Panicked { peer: SocketAddr, message: String },
+}
+
+impl PeerExit {
+    /// Whether the connection manager should carry on with another peer. Giving up on a
+    /// poisoned piece applies to the whole torrent, not just the peer that sent it.
+    pub fn should_redial(&self) -> bool {
+        match self {
+            PeerExit::Failed { error, .. } => !error.is::<TooManyHashFailures>(),
+            PeerExit::Panicked { .. } => true,
+        }
+    }
+
+    /// What the work failed with; `None` if it panicked.
+    pub fn error(&self) -> Option<&anyhow::Error> {
+        match self {
+            PeerExit::Failed { error, .. } => Some(error),
+            PeerExit::Panicked { .. } => None,
+        }
+    }
+}
+
+/// Runs one peer connection's work, turning errors and panics into a [`PeerExit`] instead of
+/// letting them take down the whole command. Anything the work borrowed, such as request
+/// bookkeeping, is released when it returns.
+pub async fn supervise<T>(
+    peer: SocketAddr,
+    work: impl Future<Output = anyhow::Result<T>>,
+) -> Result<T, PeerExit> {
+    let span = tracing::info_span!("peer", %peer);
+    let exit = match AssertUnwindSafe(work.instrument(span)).catch_unwind().await {
+        Ok(Ok(value)) => return Ok(value),
+        Ok(Err(error)) => PeerExit::Failed { peer, error },
+        Err(panic) => PeerExit::Panicked {
+            peer,
+            message: panic_message(&*panic),
+        },
+    };
+    match &exit {
+        PeerExit::Failed { error, .. } => {
+            tracing::warn!(%peer, error = %format!("{error:#}"), "peer connection failed");
+        }
+        PeerExit::Panicked { message, .. } => {
+            tracing::error!(%peer, panic = %message, "peer connection panicked");
+        }
+    }
+    Err(exit)
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(s) = panic.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "non-string panic payload".to_string()
+    }
+}
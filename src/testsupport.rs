@@ -0,0 +1,369 @@
+//! A fake tracker and a fake seeding peer, both on loopback, so whole downloads can be run
+//! against a known torrent without touching the network.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use futures_util::{SinkExt, StreamExt};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio_util::codec::Framed;
+
+use crate::bitfield::Bitfield;
+use crate::message::{Message, MessageFramer, MessageTag};
+use crate::storage::{MemoryStorage, Storage};
+use crate::wire::{Handshake, Piece, Request};
+
+const PEER_ID: [u8; 20] = *b"-FK0001-fakepeer0001";
+
+/// Longest announce request we'll read before answering anyway.
+const MAX_REQUEST: usize = 8 << 10;
+
+/// A single-file torrent whose content lives in memory.
+#[derive(Debug, Clone)]
+pub struct FakeTorrent {
+    pub name: String,
+    pub piece_length: usize,
+    pub data: Vec<u8>,
+}
+
+impl FakeTorrent {
+    pub fn new(name: impl Into<String>, piece_length: usize, data: Vec<u8>) -> Self {
+        Self {
+            name: name.into(),
+            piece_length,
+            data,
+        }
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.data.len().div_ceil(self.piece_length)
+    }
+
+    /// The bencoded info dictionary.
+    pub fn info(&self) -> Vec<u8> {
+        let mut info = format!(
+            "d6:lengthi{}e4:name{}:{}12:piece lengthi{}e6:pieces{}:",
+            self.data.len(),
+            self.name.len(),
+            self.name,
+            self.piece_length,
+            self.piece_count() * 20
+        )
+        .into_bytes();
+        for piece in self.data.chunks(self.piece_length) {
+            info.extend_from_slice(&Sha1::digest(piece));
+        }
+        info.push(b'e');
+        info
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        Sha1::digest(self.info()).into()
+    }
+
+    /// A complete `.torrent` file announcing to `announce`.
+    pub fn metainfo(&self, announce: &str) -> Vec<u8> {
+        let mut metainfo = format!("d8:announce{}:{announce}4:info", announce.len()).into_bytes();
+        metainfo.extend(self.info());
+        metainfo.push(b'e');
+        metainfo
+    }
+}
+
+/// An HTTP tracker that answers every announce with the same compact peer list.
+pub struct FakeTracker {
+    pub url: String,
+    task: JoinHandle<()>,
+}
+
+impl FakeTracker {
+    pub async fn start(peers: Vec<SocketAddrV4>) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let url = format!("http://{}/announce", listener.local_addr()?);
+
+        let mut body = format!("d8:intervali60e5:peers{}:", peers.len() * 6).into_bytes();
+        for peer in &peers {
+            body.extend_from_slice(&peer.ip().octets());
+            body.extend_from_slice(&peer.port().to_be_bytes());
+        }
+        body.push(b'e');
+
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let body = body.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = answer_announce(stream, &body).await {
+                        tracing::debug!("fake tracker: {e}");
+                    }
+                });
+            }
+        });
+        Ok(Self { url, task })
+    }
+}
+
+impl Drop for FakeTracker {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn answer_announce(mut stream: TcpStream, body: &[u8]) -> io::Result<()> {
+    let mut request = Vec::new();
+    while !request.ends_with(b"\r\n\r\n") && request.len() < MAX_REQUEST {
+        request.push(stream.read_u8().await?);
+    }
+    let header = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await
+}
+
+/// A peer that claims every piece of a [`FakeTorrent`] and uploads to anyone who asks.
+pub struct FakePeer {
+    pub addr: SocketAddrV4,
+    task: JoinHandle<()>,
+}
+
+impl FakePeer {
+    /// Serves the torrent's own data.
+    pub async fn start(torrent: &FakeTorrent) -> io::Result<Self> {
+        Self::serve(torrent, MemoryStorage::new(torrent.data.clone())).await
+    }
+
+    /// Serves blocks read from `storage`, which may be shared with a download under test or
+    /// hold corrupted data.
+    pub async fn serve(torrent: &FakeTorrent, storage: MemoryStorage) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let SocketAddr::V4(addr) = listener.local_addr()? else {
+            unreachable!("bound to an IPv4 address");
+        };
+        let torrent = torrent.clone();
+        let info_hash = torrent.info_hash();
+
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let torrent = torrent.clone();
+                let storage = storage.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = seed(stream, &torrent, storage, info_hash).await {
+                        tracing::debug!("fake peer: {e}");
+                    }
+                });
+            }
+        });
+        Ok(Self { addr, task })
+    }
+}
+
+impl Drop for FakePeer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn seed(
+    mut stream: TcpStream,
+    torrent: &FakeTorrent,
+    mut storage: MemoryStorage,
+    info_hash: [u8; 20],
+) -> io::Result<()> {
+    let mut theirs = [0; Handshake::LEN];
+    stream.read_exact(&mut theirs).await?;
+    let theirs = Handshake::from_bytes(&theirs)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if theirs.info_hash != info_hash {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "handshake for another torrent",
+        ));
+    }
+    stream
+        .write_all(&Handshake::new(info_hash, PEER_ID).to_bytes())
+        .await?;
+
+    let mut frames = Framed::new(stream, MessageFramer::new());
+    let have = Bitfield::full(torrent.piece_count());
+    frames
+        .send(Message {
+            tag: MessageTag::Bitfield,
+            payload: have.as_bytes().to_vec().into(),
+        })
+        .await?;
+    while let Some(message) = frames.next().await {
+        let message = message?;
+        match message.tag {
+            MessageTag::Interested => frames.send(Message::empty(MessageTag::Unchoke)).await?,
+            MessageTag::Request => {
+                let request = Request::from_bytes(&message.payload)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                let start =
+                    request.index as u64 * torrent.piece_length as u64 + request.begin as u64;
+                let mut block = vec![0; request.length as usize];
+                if storage.read_block(start, &mut block).is_err() {
+                    frames.send(Message::empty(MessageTag::Choke)).await?;
+                    continue;
+                }
+                let piece = Piece {
+                    index: request.index,
+                    begin: request.begin,
+                    block: &block,
+                };
+                frames
+                    .send(Message {
+                        tag: MessageTag::Piece,
+                        payload: piece.to_bytes().into(),
+                    })
+                    .await?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bittorrent_starter_rust::Torrent;
+
+    use super::*;
+    use crate::config::ClientConfig;
+    use crate::session_stats::SessionStats;
+    use crate::tracker::Announcer;
+
+    #[tokio::test]
+    async fn downloads_from_fake_swarm() {
+        // a short last piece that ends in a short block
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let torrent = FakeTorrent::new("sample.bin", 32 << 10, data.clone());
+        let peer = FakePeer::start(&torrent).await.unwrap();
+        let tracker = FakeTracker::start(vec![peer.addr]).await.unwrap();
+        let metainfo = torrent.metainfo(&tracker.url);
+        let t: Torrent = serde_bencode::from_bytes(&metainfo).unwrap();
+        assert_eq!(t.info_hash(), torrent.info_hash());
+
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("sample.bin");
+        let config = ClientConfig {
+            metadata_cache: None,
+            stats: None,
+            resume: None,
+            ..ClientConfig::default()
+        };
+        let announcer = Announcer::new(&config).unwrap();
+        let stats = SessionStats::new();
+        crate::download_torrent(
+            &metainfo,
+            &t,
+            &[],
+            &[],
+            None,
+            &output,
+            None,
+            &announcer,
+            &stats,
+            &config,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn keeps_pieces_already_in_output() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let torrent = FakeTorrent::new("sample.bin", 32 << 10, data.clone());
+        // fetching the first piece again would fail it at once
+        let mut served = data.clone();
+        served[..10].fill(0);
+        let peer = FakePeer::serve(&torrent, MemoryStorage::new(served))
+            .await
+            .unwrap();
+        let tracker = FakeTracker::start(vec![peer.addr]).await.unwrap();
+        let metainfo = torrent.metainfo(&tracker.url);
+        let t: Torrent = serde_bencode::from_bytes(&metainfo).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("sample.bin");
+        // an interrupted download: the first piece, a torn second one and stale bytes past it
+        let mut partial = data[..(32 << 10) + 100].to_vec();
+        partial.resize(200_000, 0xff);
+        std::fs::write(&output, partial).unwrap();
+        let config = ClientConfig {
+            max_hash_failures: 0,
+            metadata_cache: None,
+            stats: None,
+            resume: None,
+            ..ClientConfig::default()
+        };
+        let announcer = Announcer::new(&config).unwrap();
+        let stats = SessionStats::new();
+        crate::download_torrent(
+            &metainfo,
+            &t,
+            &[],
+            &[],
+            None,
+            &output,
+            None,
+            &announcer,
+            &stats,
+            &config,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn skipping_verification_still_fetches_missing_pieces() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let torrent = FakeTorrent::new("sample.bin", 32 << 10, data.clone());
+        let peer = FakePeer::start(&torrent).await.unwrap();
+        let tracker = FakeTracker::start(vec![peer.addr]).await.unwrap();
+        let metainfo = torrent.metainfo(&tracker.url);
+        let t: Torrent = serde_bencode::from_bytes(&metainfo).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("sample.bin");
+        // taken on trust, so left as it is
+        let first = vec![0xff; 32 << 10];
+        std::fs::write(&output, &first).unwrap();
+        let config = ClientConfig {
+            skip_verify: true,
+            metadata_cache: None,
+            stats: None,
+            resume: None,
+            ..ClientConfig::default()
+        };
+        let announcer = Announcer::new(&config).unwrap();
+        let stats = SessionStats::new();
+        crate::download_torrent(
+            &metainfo,
+            &t,
+            &[],
+            &[],
+            None,
+            &output,
+            None,
+            &announcer,
+            &stats,
+            &config,
+        )
+        .await
+        .unwrap();
+
+        let downloaded = std::fs::read(&output).unwrap();
+        assert_eq!(downloaded[..32 << 10], first);
+        assert_eq!(downloaded[32 << 10..], data[32 << 10..]);
+    }
+}
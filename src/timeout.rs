@@ -0,0 +1,56 @@
+use std::future::Future;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub connect: Duration,
+    pub handshake: Duration,
+    pub announce: Duration,
+    pub block: Duration,
+    /// How long a block request may go unanswered before it is cancelled and sent again.
+    pub request: Duration,
+    /// How long a peer may spend on one piece before it is also handed to another peer.
+    pub piece: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(5),
+            handshake: Duration::from_secs(10),
+            announce: Duration::from_secs(15),
+            block: Duration::from_secs(30),
+            request: Duration::from_secs(20),
+            piece: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TimeoutError {
+    #[error("connect to peer timed out after {0:?}")]
+    Connect(Duration),
+    #[error("peer handshake timed out after {0:?}")]
+    Handshake(Duration),
+    #[error("tracker announce timed out after {0:?}")]
+    Announce(Duration),
+    #[error("peer did not deliver a message within {0:?}")]
+    Block(Duration),
+}
+
+impl TimeoutError {
+    /// Whether the timeout should count against the peer (as opposed to the tracker).
+    pub fn is_peer_fault(&self) -> bool {
+        !matches!(self, TimeoutError::Announce(_))
+    }
+}
+
+pub async fn timeout<F: Future>(
+    duration: Duration,
+    kind: fn(Duration) -> TimeoutError,
+    fut: F,
+) -> Result<F::Output, TimeoutError> {
+    tokio::time::timeout(duration, fut)
+        .await
+        .map_err(|_| kind(duration))
+}
@@ -0,0 +1,233 @@
+//! A `top`-like view of a running daemon: the torrents and peers moving the most data, redrawn
+//! in place every few seconds with nothing but plain text, so it works over SSH in any
+//! terminal, or printed once for cron jobs and scripts.
+
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::daemon::{self, ControlAddr};
+
+/// Longest torrent name shown before it is cut short.
+const NAME_WIDTH: usize = 32;
+
+#[derive(Debug, Clone)]
+pub struct TopOptions {
+    pub interval: Duration,
+    /// Print one snapshot and exit instead of refreshing.
+    pub once: bool,
+    /// Rows shown in each table.
+    pub limit: usize,
+}
+
+/// A torrent as the daemon's `status` lists it.
+#[derive(Debug, Clone, Deserialize)]
+struct TorrentRow {
+    id: String,
+    name: Option<String>,
+    state: String,
+    #[serde(default)]
+    down_rate: f64,
+    #[serde(default)]
+    up_rate: f64,
+    #[serde(default)]
+    peers: usize,
+}
+
+impl TorrentRow {
+    fn label(&self) -> String {
+        let name = self.name.as_deref().unwrap_or(&self.id);
+        if name.chars().count() > NAME_WIDTH {
+            let cut: String = name.chars().take(NAME_WIDTH - 1).collect();
+            format!("{cut}…")
+        } else {
+            name.to_owned()
+        }
+    }
+}
+
+/// A peer as the daemon's `peers` lists it, with the torrent it is connected for.
+#[derive(Debug, Clone, Deserialize)]
+struct PeerRow {
+    addr: String,
+    #[serde(default)]
+    down_rate: f64,
+    #[serde(default)]
+    up_rate: f64,
+    #[serde(skip)]
+    torrent: String,
+}
+
+/// Shows the daemon on `control` until interrupted, or once.
+pub async fn run(
+    control: &ControlAddr,
+    token: Option<&str>,
+    options: &TopOptions,
+) -> anyhow::Result<()> {
+    loop {
+        let (torrents, peers) = fetch(control, token).await?;
+        let view = render(torrents, peers, options.limit);
+        let mut stdout = std::io::stdout().lock();
+        if options.once {
+            stdout.write_all(view.as_bytes())?;
+            return Ok(());
+        }
+        // home the cursor and clear the screen, which every terminal understands
+        write!(stdout, "\x1b[H\x1b[2J{view}")?;
+        stdout.flush()?;
+        drop(stdout);
+        tokio::select! {
+            () = tokio::time::sleep(options.interval) => {}
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        }
+    }
+}
+
+async fn fetch(
+    control: &ControlAddr,
+    token: Option<&str>,
+) -> anyhow::Result<(Vec<TorrentRow>, Vec<PeerRow>)> {
+    let status = daemon::call(control, token, "status", json!({})).await?;
+    let torrents: Vec<TorrentRow> = serde_json::from_value(status)?;
+    let mut peers = Vec::new();
+    for torrent in torrents.iter().filter(|torrent| torrent.peers > 0) {
+        let params = json!({ "id": torrent.id });
+        // the torrent may have been removed since
+        let listed = match daemon::call(control, token, "peers", params).await {
+            Ok(listed) => listed,
+            Err(e) => {
+                tracing::debug!(id = %torrent.id, error = %format!("{e:#}"), "no peers listed");
+                continue;
+            }
+        };
+        let listed: Vec<PeerRow> = serde_json::from_value(listed)?;
+        let label = torrent.label();
+        peers.extend(listed.into_iter().map(|peer| PeerRow {
+            torrent: label.clone(),
+            ..peer
+        }));
+    }
+    Ok((torrents, peers))
+}
+
+/// The totals, then the busiest `limit` torrents and peers, fastest first.
+fn render(mut torrents: Vec<TorrentRow>, mut peers: Vec<PeerRow>, limit: usize) -> String {
+    torrents.sort_by(|a, b| (b.down_rate + b.up_rate).total_cmp(&(a.down_rate + a.up_rate)));
+    peers.sort_by(|a, b| (b.down_rate + b.up_rate).total_cmp(&(a.down_rate + a.up_rate)));
+    let down: f64 = torrents.iter().map(|torrent| torrent.down_rate).sum();
+    let up: f64 = torrents.iter().map(|torrent| torrent.up_rate).sum();
+
+    let mut view = String::new();
+    let _ = writeln!(
+        view,
+        "{} torrents, {} peers, down {}, up {}\n",
+        torrents.len(),
+        peers.len(),
+        kib(down),
+        kib(up)
+    );
+    let _ = writeln!(
+        view,
+        "{:<NAME_WIDTH$}  {:<11}  {:>12}  {:>12}  {:>5}",
+        "TORRENT", "STATE", "DOWN", "UP", "PEERS"
+    );
+    for torrent in torrents.iter().take(limit) {
+        let _ = writeln!(
+            view,
+            "{:<NAME_WIDTH$}  {:<11}  {:>12}  {:>12}  {:>5}",
+            torrent.label(),
+            torrent.state,
+            kib(torrent.down_rate),
+            kib(torrent.up_rate),
+            torrent.peers
+        );
+    }
+    let _ = writeln!(
+        view,
+        "\n{:<21}  {:>12}  {:>12}  TORRENT",
+        "PEER", "DOWN", "UP"
+    );
+    for peer in peers.iter().take(limit) {
+        let _ = writeln!(
+            view,
+            "{:<21}  {:>12}  {:>12}  {}",
+            peer.addr,
+            kib(peer.down_rate),
+            kib(peer.up_rate),
+            peer.torrent
+        );
+    }
+    view
+}
+
+fn kib(rate: f64) -> String {
+    format!("{:.1} KiB/s", rate / 1024.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn torrent(name: &str, down_rate: f64, up_rate: f64) -> TorrentRow {
+        TorrentRow {
+            id: "00".repeat(20),
+            name: Some(name.to_owned()),
+            state: "downloading".to_owned(),
+            down_rate,
+            up_rate,
+            peers: 1,
+        }
+    }
+
+    fn peer(addr: &str, down_rate: f64) -> PeerRow {
+        PeerRow {
+            addr: addr.to_owned(),
+            down_rate,
+            up_rate: 0.0,
+            torrent: "a".to_owned(),
+        }
+    }
+
+    #[test]
+    fn shows_the_busiest_first() {
+        let torrents = vec![
+            torrent("slow", 1024.0, 0.0),
+            torrent("seeding", 0.0, 4096.0),
+            torrent("idle", 0.0, 0.0),
+        ];
+        let peers = vec![peer("10.0.0.1:6881", 10.0), peer("10.0.0.2:6881", 2048.0)];
+        let view = render(torrents, peers, 2);
+        let lines: Vec<_> = view.lines().collect();
+        assert_eq!(
+            lines[0],
+            "3 torrents, 2 peers, down 1.0 KiB/s, up 4.0 KiB/s"
+        );
+        assert!(lines[3].starts_with("seeding "));
+        assert!(lines[4].starts_with("slow "));
+        assert!(!view.contains("idle"));
+        assert!(lines[7].starts_with("10.0.0.2:6881 "));
+        assert!(lines[8].starts_with("10.0.0.1:6881 "));
+    }
+
+    #[test]
+    fn cuts_long_names_short() {
+        let label = torrent(&"x".repeat(40), 0.0, 0.0).label();
+        assert_eq!(label.chars().count(), NAME_WIDTH);
+        assert!(label.ends_with('…'));
+    }
+
+    #[test]
+    fn reads_the_daemon_status() {
+        let status = json!([{
+            "id": "ab", "name": null, "output": "/tmp/x", "category": null,
+            "state": "paused", "error": null, "downloaded": 0, "down_rate": 0.0,
+            "up_rate": 0.0, "pieces_completed": 0, "peers": 0,
+        }]);
+        let torrents: Vec<TorrentRow> = serde_json::from_value(status).unwrap();
+        assert_eq!(torrents[0].label(), "ab");
+        assert_eq!(torrents[0].state, "paused");
+    }
+}
@@ -0,0 +1,370 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Context;
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+use tokio::time::Instant;
+
+use bittorrent_starter_rust::{Torrent, TrackerRequest, TrackerResponse, urlencode};
+
+use crate::config::ClientConfig;
+use crate::timeout::{self, TimeoutError};
+
+/// Minimum gap between the start of two announces to the same tracker host.
+const HOST_SPACING: Duration = Duration::from_millis(200);
+/// Announces allowed in flight to a single tracker host at once.
+const HOST_CONCURRENCY: usize = 2;
+/// Longest a tracker may go without sending any of its response body.
+const BODY_IDLE: Duration = Duration::from_secs(5);
+
+#[derive(Debug, thiserror::Error)]
+pub enum ResponseError {
+    #[error("tracker response exceeds the {limit} byte limit")]
+    TooLarge { limit: usize },
+    #[error("tracker response stalled for {0:?}")]
+    Stalled(Duration),
+    #[error("read tracker response")]
+    Http(#[from] reqwest::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TrackerError {
+    /// The tracker answered with a `failure reason` instead of peers.
+    #[error("tracker {tracker} refused the announce: {reason}")]
+    Failure { tracker: String, reason: String },
+    #[error("torrent has no trackers")]
+    NoTrackers,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl Event {
+    fn as_str(self) -> &'static str {
+        match self {
+            Event::Started => "started",
+            Event::Completed => "completed",
+            Event::Stopped => "stopped",
+        }
+    }
+}
+
+/// The parameters of a single announce. `Default` describes a fresh download of nothing.
+#[derive(Debug, Clone, Default)]
+pub struct AnnounceParams {
+    pub uploaded: usize,
+    pub downloaded: usize,
+    pub left: usize,
+    pub event: Option<Event>,
+}
+
+/// What a tracker asked us to remember between announces.
+#[derive(Debug, Clone, Default)]
+struct TrackerSession {
+    /// Echoed back as `trackerid` on every later announce.
+    tracker_id: Option<String>,
+    min_interval: Option<Duration>,
+}
+
+/// Response fields the library's `TrackerResponse` doesn't model.
+#[derive(Deserialize)]
+struct ResponseExtras {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    #[serde(rename = "warning message")]
+    warning_message: Option<String>,
+    #[serde(rename = "tracker id")]
+    tracker_id: Option<String>,
+    #[serde(rename = "min interval")]
+    min_interval: Option<u64>,
+}
+
+/// A torrent's trackers in BEP 12 tiers, each shuffled once. Announces try the tiers in
+/// order and the trackers within a tier in order; a tracker that answers moves to the front
+/// of its tier, so it is tried first next time.
+#[derive(Debug, Clone)]
+pub struct Tiers {
+    tiers: Vec<Vec<String>>,
+    /// The tracker that last answered.
+    current: Option<String>,
+}
+
+impl Tiers {
+    pub fn new(mut tiers: Vec<Vec<String>>) -> Self {
+        let mut rng = rand::thread_rng();
+        for tier in &mut tiers {
+            tier.shuffle(&mut rng);
+        }
+        tiers.retain(|tier| !tier.is_empty());
+        Self {
+            tiers,
+            current: None,
+        }
+    }
+
+    /// The tracker that last answered, or else the first one that will be tried.
+    pub fn current(&self) -> Option<&str> {
+        self.current
+            .as_deref()
+            .or_else(|| self.tiers.first()?.first().map(String::as_str))
+    }
+
+    fn answered(&mut self, tier: usize, index: usize) {
+        let tracker = self.tiers[tier].remove(index);
+        self.tiers[tier].insert(0, tracker.clone());
+        self.current = Some(tracker);
+    }
+}
+
+/// Announces on behalf of every torrent in the process. Announces are queued per tracker
+/// host so torrents sharing a tracker reuse its connection and don't stampede it.
+pub struct Announcer {
+    client: reqwest::Client,
+    config: ClientConfig,
+    hosts: Mutex<HashMap<String, Arc<HostQueue>>>,
+    /// Keyed by tracker URL.
+    sessions: Mutex<HashMap<String, TrackerSession>>,
+}
+
+struct HostQueue {
+    in_flight: Semaphore,
+    next_start: tokio::sync::Mutex<Instant>,
+}
+
+impl Announcer {
+    pub fn new(config: &ClientConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: config
+                .net
+                .http_client()
+                .context("build tracker http client")?,
+            config: config.clone(),
+            hosts: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn session(&self, tracker: &str) -> TrackerSession {
+        let sessions = self.sessions.lock().expect("tracker session lock poisoned");
+        sessions.get(tracker).cloned().unwrap_or_default()
+    }
+
+    /// The shortest gap `tracker` allows between announces, if it has told us one.
+    pub fn min_interval(&self, tracker: &str) -> Option<Duration> {
+        self.session(tracker).min_interval
+    }
+
+    fn remember(&self, tracker: &str, response: &[u8]) {
+        let Ok(extras) = serde_bencode::from_bytes::<ResponseExtras>(response) else {
+            return;
+        };
+        let mut sessions = self.sessions.lock().expect("tracker session lock poisoned");
+        let session = sessions.entry(tracker.to_string()).or_default();
+        if let Some(id) = extras.tracker_id {
+            session.tracker_id = Some(id);
+        }
+        if let Some(secs) = extras.min_interval {
+            session.min_interval = Some(Duration::from_secs(secs));
+        }
+    }
+
+    fn queue(&self, url: &reqwest::Url) -> Arc<HostQueue> {
+        let host = format!(
+            "{}:{}",
+            url.host_str().unwrap_or_default(),
+            url.port_or_known_default().unwrap_or_default()
+        );
+        let mut hosts = self.hosts.lock().expect("host queue lock poisoned");
+        hosts
+            .entry(host)
+            .or_insert_with(|| {
+                Arc::new(HostQueue {
+                    in_flight: Semaphore::new(HOST_CONCURRENCY),
+                    next_start: tokio::sync::Mutex::new(Instant::now()),
+                })
+            })
+            .clone()
+    }
+
+    pub async fn announce_torrent(
+        &self,
+        t: &Torrent,
+        tiers: &mut Tiers,
+        event: Option<Event>,
+    ) -> anyhow::Result<TrackerResponse> {
+        self.announce_tiers(tiers, t.info_hash(), t.length(), event)
+            .await
+    }
+
+    /// Announces to the first tracker in `tiers` that answers, moving on to the next one
+    /// whenever a tracker can't be reached or refuses. Fails with the last tracker's error.
+    pub async fn announce_tiers(
+        &self,
+        tiers: &mut Tiers,
+        info_hash: [u8; 20],
+        left: usize,
+        event: Option<Event>,
+    ) -> anyhow::Result<TrackerResponse> {
+        let mut last_err = None;
+        for tier in 0..tiers.tiers.len() {
+            for index in 0..tiers.tiers[tier].len() {
+                let tracker = &tiers.tiers[tier][index];
+                match self.announce(tracker, info_hash, left, event).await {
+                    Ok(response) => {
+                        tiers.answered(tier, index);
+                        return Ok(response);
+                    }
+                    Err(e) => {
+                        tracing::warn!(tracker, error = %e, "announce failed, trying next tracker");
+                        last_err = Some(e);
+                    }
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| TrackerError::NoTrackers.into()))
+    }
+
+    pub async fn announce(
+        &self,
+        tracker: &str,
+        info_hash: [u8; 20],
+        left: usize,
+        event: Option<Event>,
+    ) -> anyhow::Result<TrackerResponse> {
+        let params = AnnounceParams {
+            left,
+            event,
+            ..Default::default()
+        };
+        let response = self.announce_raw(tracker, info_hash, &params).await?;
+        check_response(tracker, &response)?;
+        let response: TrackerResponse =
+            serde_bencode::from_bytes(&response).context("parse tracker response")?;
+        tracing::debug!(peers = response.peers.0.len(), "announce complete");
+        Ok(response)
+    }
+
+    /// Sends an announce with exactly `params` and returns the undecoded response body.
+    #[tracing::instrument(skip(self, info_hash))]
+    pub async fn announce_raw(
+        &self,
+        tracker: &str,
+        info_hash: [u8; 20],
+        params: &AnnounceParams,
+    ) -> anyhow::Result<Vec<u8>> {
+        let request = TrackerRequest {
+            peer_id: self.config.peer_id.to_string(),
+            port: self.config.port,
+            uploaded: params.uploaded,
+            downloaded: params.downloaded,
+            left: params.left,
+            compact: 1,
+        };
+
+        let url_params =
+            serde_urlencoded::to_string(&request).context("url-encode tracker parameters")?;
+
+        let mut tracker_url = format!(
+            "{}?{}&info_hash={}",
+            tracker,
+            url_params,
+            urlencode(&info_hash)
+        );
+        if let Some(event) = params.event {
+            tracker_url.push_str("&event=");
+            tracker_url.push_str(event.as_str());
+        }
+        let session = self.session(tracker);
+        let extra = [
+            ("numwant", Some(self.config.numwant.to_string())),
+            ("key", Some(self.config.tracker_key.clone())),
+            ("trackerid", session.tracker_id),
+        ];
+        let extra: Vec<_> = extra
+            .into_iter()
+            .filter_map(|(name, value)| Some((name, value?)))
+            .collect();
+        tracker_url.push('&');
+        tracker_url.push_str(
+            &serde_urlencoded::to_string(extra).context("url-encode tracker parameters")?,
+        );
+        let tracker_url = reqwest::Url::parse(&tracker_url).context("parse tracker url")?;
+
+        let queue = self.queue(&tracker_url);
+        let _permit = queue
+            .in_flight
+            .acquire()
+            .await
+            .expect("host semaphore is never closed");
+        {
+            let mut next_start = queue.next_start.lock().await;
+            tokio::time::sleep_until(*next_start).await;
+            *next_start = Instant::now() + HOST_SPACING;
+        }
+
+        let timeouts = &self.config.timeouts;
+        let response = timeout::timeout(timeouts.announce, TimeoutError::Announce, async {
+            let response = self
+                .client
+                .get(tracker_url)
+                .send()
+                .await
+                .context("query tracker")?;
+            read_limited(response, self.config.max_tracker_response)
+                .await
+                .context("fetch tracker response")
+        })
+        .await??;
+        self.remember(tracker, &response);
+        Ok(response)
+    }
+}
+
+/// Fails with the tracker's `failure reason`, if it gave one, and logs its `warning message`.
+fn check_response(tracker: &str, response: &[u8]) -> Result<(), TrackerError> {
+    let Ok(extras) = serde_bencode::from_bytes::<ResponseExtras>(response) else {
+        return Ok(());
+    };
+    if let Some(warning) = extras.warning_message {
+        tracing::warn!(tracker, %warning, "tracker warning");
+    }
+    match extras.failure_reason {
+        Some(reason) => Err(TrackerError::Failure {
+            tracker: tracker.to_string(),
+            reason,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Reads a response body of at most `limit` bytes. A tracker that trickles its body out is cut
+/// off once it goes quiet for [`BODY_IDLE`], and in any case by the announce timeout.
+pub async fn read_limited(
+    mut response: reqwest::Response,
+    limit: usize,
+) -> Result<Vec<u8>, ResponseError> {
+    if response
+        .content_length()
+        .is_some_and(|len| len > limit as u64)
+    {
+        return Err(ResponseError::TooLarge { limit });
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = tokio::time::timeout(BODY_IDLE, response.chunk())
+        .await
+        .map_err(|_| ResponseError::Stalled(BODY_IDLE))??
+    {
+        if body.len() + chunk.len() > limit {
+            return Err(ResponseError::TooLarge { limit });
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
@@ -0,0 +1,129 @@
+use std::io;
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use tokio::net::TcpStream;
+
+use crate::config::ClientConfig;
+use crate::mse::Encryption;
+use crate::net::NetConfig;
+use crate::utp;
+
+/// A way of reaching a peer: anything that yields a byte stream the wire protocol can run
+/// over.
+pub trait PeerTransport {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    async fn connect(&self, addr: SocketAddr) -> io::Result<Self::Stream>;
+}
+
+pub struct Tcp<'a>(pub &'a NetConfig);
+
+impl PeerTransport for Tcp<'_> {
+    type Stream = TcpStream;
+
+    async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        self.0.connect(addr).await
+    }
+}
+
+/// The micro transport protocol (BEP 29) over UDP.
+pub struct Utp<'a>(pub &'a NetConfig);
+
+impl PeerTransport for Utp<'_> {
+    type Stream = DuplexStream;
+
+    async fn connect(&self, addr: SocketAddr) -> io::Result<DuplexStream> {
+        if self.0.proxy.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "uTP connections can't go through a proxy",
+            ));
+        }
+        utp::connect(addr, self.0).await
+    }
+}
+
+/// A connected peer byte stream, whichever transport it came over.
+pub trait PeerIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> PeerIo for T {}
+
+/// Which transport to reach peers over.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transport {
+    #[default]
+    Tcp,
+    Utp,
+}
+
+impl Transport {
+    pub async fn connect(self, addr: SocketAddr, net: &NetConfig) -> io::Result<Box<dyn PeerIo>> {
+        Ok(match self {
+            Transport::Tcp => Box::new(Tcp(net).connect(addr).await?),
+            Transport::Utp => Box::new(Utp(net).connect(addr).await?),
+        })
+    }
+}
+
+/// How a torrent's peer connections are made.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PeerPath {
+    /// As `--encryption`, `--transport` and `--proxy` say.
+    #[default]
+    Configured,
+    /// Only MSE-encrypted TCP through the proxy, refusing to start without one. Peers that
+    /// connect to us, which would bypass it, are turned away.
+    Secure,
+    /// Plaintext and straight to the peer, skipping any proxy.
+    Fastest,
+}
+
+impl PeerPath {
+    /// `config` with its peer connection settings changed to take this path.
+    pub fn apply(self, config: &ClientConfig) -> anyhow::Result<ClientConfig> {
+        let mut config = config.clone();
+        match self {
+            PeerPath::Configured => {}
+            PeerPath::Secure => {
+                anyhow::ensure!(
+                    config.net.proxy.is_some(),
+                    "the secure peer path needs a proxy to connect through"
+                );
+                config.encryption = Encryption::Require;
+                // uTP can't be proxied, and holepunched connections would go around it
+                config.transport = Transport::Tcp;
+                config.utp = None;
+            }
+            PeerPath::Fastest => {
+                config.encryption = Encryption::Disable;
+                config.net.proxy = None;
+            }
+        }
+        Ok(config)
+    }
+
+    /// Whether peers that connect to us may join the download.
+    pub fn accepts_inbound(self) -> bool {
+        self != PeerPath::Secure
+    }
+
+    /// Whether a connection made as configured, before the torrent's privacy was known, may
+    /// carry on.
+    pub fn keeps_configured(self) -> bool {
+        self != PeerPath::Secure
+    }
+}
+
+/// Which [`PeerPath`] a torrent's peer connections take, by whether it is private (BEP 27).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TransportPolicy {
+    pub private: PeerPath,
+    pub public: PeerPath,
+}
+
+impl TransportPolicy {
+    pub fn path(&self, private: bool) -> PeerPath {
+        if private { self.private } else { self.public }
+    }
+}
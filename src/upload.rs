@@ -0,0 +1,255 @@
+//! Serving what we have downloaded to the peers that ask for it. Only as many peers as
+//! [`ClientConfig::upload_slots`](crate::config::ClientConfig::upload_slots) allows are unchoked
+//! at once, across every torrent; requests from the rest are rejected if the peer speaks the
+//! fast extension, and dropped if not.
+
+use std::io::SeekFrom;
+use std::path::Path;
+
+use anyhow::Context;
+use bytes::Bytes;
+use futures_util::{Sink, SinkExt};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::{OwnedSemaphorePermit, watch};
+
+use crate::bitfield::Bitfield;
+use crate::download::PeerState;
+use crate::geometry::PieceGeometry;
+use crate::message::{Message, MessageTag};
+use crate::peer::ConnectionLimit;
+use crate::wire::{Piece, Request};
+
+/// Longest block a peer may ask for. Clients ask for 16 KiB, but accept requests up to 128.
+const MAX_REQUEST: u32 = 1 << 17;
+
+/// The pieces of a download written out so far, to read blocks back from.
+pub struct UploadSource {
+    file: File,
+    geometry: PieceGeometry,
+    /// The piece the file starts with.
+    base: usize,
+    written: watch::Receiver<Bitfield>,
+}
+
+impl UploadSource {
+    /// Reads pieces back from `output`, which holds the torrent's pieces from `base` on, once
+    /// `written` marks them.
+    pub async fn open(
+        output: &Path,
+        geometry: PieceGeometry,
+        base: usize,
+        written: watch::Receiver<Bitfield>,
+    ) -> std::io::Result<Self> {
+        Ok(Self {
+            file: File::open(output).await?,
+            geometry,
+            base,
+            written,
+        })
+    }
+
+    /// The block `request` asks for, or `None` if its piece isn't written yet. A request for
+    /// something the torrent doesn't have is the peer's fault.
+    async fn read(&mut self, request: Request) -> anyhow::Result<Option<Vec<u8>>> {
+        let piece = request.index as usize;
+        let npieces = self.geometry.piece_count();
+        anyhow::ensure!(
+            piece < npieces,
+            "peer requested piece {piece} of a torrent with {npieces}"
+        );
+        let (begin, length) = (request.begin as usize, request.length as usize);
+        anyhow::ensure!(
+            (1..=MAX_REQUEST).contains(&request.length)
+                && begin + length <= self.geometry.piece_len(piece),
+            "peer requested {length} bytes at {begin} of piece {piece}"
+        );
+        if piece < self.base || !self.written.borrow().has_piece(piece) {
+            return Ok(None);
+        }
+        let offset =
+            self.geometry.piece_offset(piece) - self.geometry.piece_offset(self.base) + begin;
+        let mut block = vec![0; length];
+        self.file.seek(SeekFrom::Start(offset as u64)).await?;
+        self.file
+            .read_exact(&mut block)
+            .await
+            .with_context(|| format!("read block of piece {piece} back"))?;
+        Ok(Some(block))
+    }
+}
+
+/// Uploads to one peer, while it holds one of the shared upload slots.
+pub struct Uploader {
+    source: UploadSource,
+    slots: ConnectionLimit,
+    /// Held while the peer is unchoked.
+    slot: Option<OwnedSemaphorePermit>,
+}
+
+impl Uploader {
+    pub fn new(source: UploadSource, slots: ConnectionLimit) -> Self {
+        Self {
+            source,
+            slots,
+            slot: None,
+        }
+    }
+
+    /// Unchokes the peer if it is interested and a slot is free, or chokes it once it no
+    /// longer is, then answers the requests it has made since last time.
+    pub async fn serve<S>(&mut self, peer: &mut S, state: &mut PeerState) -> anyhow::Result<()>
+    where
+        S: Sink<Message, Error = std::io::Error> + Unpin,
+    {
+        if state.peer_interested && state.choking {
+            if let Some(slot) = self.slots.try_acquire() {
+                self.slot = Some(slot);
+                state.choking = false;
+                peer.send(Message::empty(MessageTag::Unchoke))
+                    .await
+                    .context("send unchoke message")?;
+            }
+        } else if !state.peer_interested && !state.choking {
+            self.slot = None;
+            state.choking = true;
+            peer.send(Message::empty(MessageTag::Choke))
+                .await
+                .context("send choke message")?;
+        }
+        while let Some(request) = state.peer_requests.pop_front() {
+            let block = if state.choking {
+                None
+            } else {
+                self.source.read(request).await?
+            };
+            match block {
+                Some(block) => {
+                    let piece = Piece {
+                        index: request.index,
+                        begin: request.begin,
+                        block: &block,
+                    };
+                    let msg = Message {
+                        tag: MessageTag::Piece,
+                        payload: piece.to_bytes().into(),
+                    };
+                    peer.send(msg).await.context("send piece message")?;
+                    if let Some(meter) = &state.meter {
+                        meter.block_sent(block.len());
+                    }
+                }
+                None if state.negotiated.fast => {
+                    let msg = Message {
+                        tag: MessageTag::RejectRequest,
+                        payload: Bytes::copy_from_slice(&request.to_bytes()),
+                    };
+                    peer.send(msg).await.context("send reject message")?;
+                }
+                None => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::download::apply;
+    use crate::wire::Capabilities;
+
+    async fn uploader(dir: &Path, slots: &ConnectionLimit) -> (Uploader, watch::Sender<Bitfield>) {
+        let output = dir.join("out");
+        std::fs::write(&output, (0..48u8).collect::<Vec<_>>()).unwrap();
+        // pieces 1 and 2 of a torrent with three 16-byte pieces and a short fourth
+        let geometry = PieceGeometry::new(56, 16);
+        let (written, rx) = watch::channel(Bitfield::new(4));
+        let source = UploadSource::open(&output, geometry, 1, rx).await.unwrap();
+        (Uploader::new(source, slots.clone()), written)
+    }
+
+    fn request(index: u32, begin: u32, length: u32) -> Message {
+        Message {
+            tag: MessageTag::Request,
+            payload: Bytes::copy_from_slice(&Request::new(index, begin, length).to_bytes()),
+        }
+    }
+
+    /// What `uploader` sends the peer in one go.
+    async fn serve(uploader: &mut Uploader, state: &mut PeerState) -> anyhow::Result<Vec<Message>> {
+        let mut sent = Vec::new();
+        let mut peer = (&mut sent).sink_map_err(|e| match e {});
+        uploader.serve(&mut peer, state).await?;
+        Ok(sent)
+    }
+
+    #[tokio::test]
+    async fn serves_written_pieces_to_unchoked_peers() {
+        let dir = tempfile::tempdir().unwrap();
+        let slots = ConnectionLimit::new(1);
+        let (mut uploader, written) = uploader(dir.path(), &slots).await;
+        written.send_modify(|written| written.set_piece(2));
+        let mut state = PeerState::new(Capabilities::default(), false);
+
+        // asking before saying it is interested gets nothing
+        apply(&request(2, 0, 4), &mut state).unwrap();
+        let sent = serve(&mut uploader, &mut state).await.unwrap();
+        assert_eq!(sent[0].tag, MessageTag::RejectRequest);
+
+        apply(&Message::empty(MessageTag::Interested), &mut state).unwrap();
+        apply(&request(2, 4, 4), &mut state).unwrap();
+        apply(&request(1, 0, 4), &mut state).unwrap();
+        let sent = serve(&mut uploader, &mut state).await.unwrap();
+        let tags: Vec<_> = sent.iter().map(|msg| msg.tag).collect();
+        assert_eq!(
+            tags,
+            [
+                MessageTag::Unchoke,
+                MessageTag::Piece,
+                MessageTag::RejectRequest
+            ]
+        );
+        // piece 2 is the second in the file
+        assert_eq!(sent[1].payload[8..], [20, 21, 22, 23]);
+        assert!(
+            slots.try_acquire().is_none(),
+            "the peer holds the only slot"
+        );
+
+        apply(&Message::empty(MessageTag::NotInterested), &mut state).unwrap();
+        let sent = serve(&mut uploader, &mut state).await.unwrap();
+        assert_eq!(sent, [Message::empty(MessageTag::Choke)]);
+        assert!(slots.try_acquire().is_some(), "choking gives the slot back");
+    }
+
+    #[tokio::test]
+    async fn keeps_peers_choked_without_a_slot() {
+        let dir = tempfile::tempdir().unwrap();
+        let slots = ConnectionLimit::new(0);
+        let (mut uploader, written) = uploader(dir.path(), &slots).await;
+        written.send_modify(|written| written.set_piece(1));
+        let mut state = PeerState::new(Capabilities::default(), false);
+        state.negotiated.fast = false;
+
+        apply(&Message::empty(MessageTag::Interested), &mut state).unwrap();
+        apply(&request(1, 0, 4), &mut state).unwrap();
+        let sent = serve(&mut uploader, &mut state).await.unwrap();
+        assert!(sent.is_empty(), "{sent:?}");
+        assert!(state.peer_requests.is_empty());
+    }
+
+    #[tokio::test]
+    async fn refuses_requests_outside_the_torrent() {
+        let dir = tempfile::tempdir().unwrap();
+        let slots = ConnectionLimit::new(1);
+        let (mut uploader, _written) = uploader(dir.path(), &slots).await;
+        let mut state = PeerState::new(Capabilities::default(), false);
+
+        apply(&Message::empty(MessageTag::Interested), &mut state).unwrap();
+        apply(&request(3, 4, 16), &mut state).unwrap();
+        let error = serve(&mut uploader, &mut state).await.unwrap_err();
+        assert_eq!(error.to_string(), "peer requested 16 bytes at 4 of piece 3");
+    }
+}
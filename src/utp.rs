@@ -0,0 +1,715 @@
+//! The micro transport protocol (BEP 29): reliable, ordered byte streams over UDP whose
+//! congestion control backs off as soon as it sees queueing delay, so BitTorrent traffic
+//! yields to everything else on the link.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, WriteHalf};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::net::NetConfig;
+
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 20;
+/// Payload bytes per packet, keeping datagrams under common path MTUs.
+const MSS: usize = 1400;
+/// Receive window we advertise, and the size of the buffer the application reads from.
+const RECV_WINDOW: usize = 1 << 20;
+/// Queueing delay LEDBAT aims for; the window shrinks once our packets wait longer.
+const TARGET_DELAY: Duration = Duration::from_millis(100);
+/// Most the congestion window may grow by in one round trip.
+const MAX_WINDOW_GROWTH: f64 = 3000.0;
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const SYN_TIMEOUT: Duration = Duration::from_secs(1);
+const SYN_ATTEMPTS: u32 = 3;
+/// Retransmissions in a row after which the peer is presumed gone.
+const MAX_TIMEOUTS: u32 = 6;
+/// Out-of-order packets we hold on to while waiting for a gap to fill.
+const MAX_REORDER: usize = 256;
+/// Datagrams queued for one connection of an [`Endpoint`] before further ones are dropped.
+const INBOX: usize = 256;
+/// Connections an [`Endpoint`] queues for [`Endpoint::accept`] before turning others away.
+const ACCEPT_BACKLOG: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => PacketType::Data,
+            1 => PacketType::Fin,
+            2 => PacketType::State,
+            3 => PacketType::Reset,
+            4 => PacketType::Syn,
+            other => return Err(other),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    kind: PacketType,
+    connection_id: u16,
+    timestamp: u32,
+    timestamp_diff: u32,
+    wnd_size: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+}
+
+impl Header {
+    fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0] = (self.kind as u8) << 4 | VERSION;
+        // bytes[1]: no extensions
+        bytes[2..4].copy_from_slice(&self.connection_id.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.timestamp.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.timestamp_diff.to_be_bytes());
+        bytes[12..16].copy_from_slice(&self.wnd_size.to_be_bytes());
+        bytes[16..18].copy_from_slice(&self.seq_nr.to_be_bytes());
+        bytes[18..20].copy_from_slice(&self.ack_nr.to_be_bytes());
+        bytes
+    }
+
+    /// Splits a datagram into its header and payload, skipping any extension headers.
+    fn parse(packet: &[u8]) -> Option<(Self, &[u8])> {
+        if packet.len() < HEADER_LEN || packet[0] & 0x0f != VERSION {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_be_bytes([packet[i], packet[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes(packet[i..i + 4].try_into().unwrap());
+        let header = Self {
+            kind: PacketType::try_from(packet[0] >> 4).ok()?,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+        };
+        let mut extension = packet[1];
+        let mut rest = &packet[HEADER_LEN..];
+        while extension != 0 {
+            let (&[next, len], tail) = rest.split_first_chunk::<2>()?;
+            rest = tail.get(len as usize..)?;
+            extension = next;
+        }
+        Some((header, rest))
+    }
+}
+
+fn now_micros() -> u32 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    since_epoch.as_micros() as u32
+}
+
+/// Whether sequence number `a` comes before `b`, allowing for wraparound.
+fn seq_before(a: u16, b: u16) -> bool {
+    a != b && b.wrapping_sub(a) < 0x8000
+}
+
+/// Opens a uTP connection to `addr` from a socket of its own. The protocol runs on a
+/// background task; the returned stream carries the connection's bytes and closing it sends a
+/// FIN.
+pub async fn connect(addr: SocketAddr, net: &NetConfig) -> io::Result<DuplexStream> {
+    let socket = net.bind_udp(addr).await?;
+    socket.connect(addr).await?;
+    establish(Link::Own(socket), addr, rand::random()).await
+}
+
+/// Sends SYNs carrying `recv_id` over `link` until the peer acknowledges one, then hands the
+/// connection to a background task.
+async fn establish(mut link: Link, addr: SocketAddr, recv_id: u16) -> io::Result<DuplexStream> {
+    let syn = Header {
+        kind: PacketType::Syn,
+        connection_id: recv_id,
+        timestamp: now_micros(),
+        timestamp_diff: 0,
+        wnd_size: RECV_WINDOW as u32,
+        seq_nr: 1,
+        ack_nr: 0,
+    };
+    let mut buf = vec![0; 1 << 16];
+    let mut wait = SYN_TIMEOUT;
+    let mut state = None;
+    'attempts: for _ in 0..SYN_ATTEMPTS {
+        link.send(&syn.to_bytes()).await?;
+        let deadline = Instant::now() + wait;
+        while let Ok(received) = tokio::time::timeout_at(deadline, link.recv(&mut buf)).await {
+            let Some((header, _)) = Header::parse(&buf[..received?]) else {
+                continue;
+            };
+            if header.connection_id != recv_id {
+                continue;
+            }
+            match header.kind {
+                PacketType::State => {
+                    state = Some(header);
+                    break 'attempts;
+                }
+                PacketType::Reset => return Err(io::ErrorKind::ConnectionRefused.into()),
+                _ => {}
+            }
+        }
+        wait *= 2;
+    }
+    let state = state.ok_or(io::ErrorKind::TimedOut)?;
+    tracing::debug!(peer = %addr, "utp connection established");
+
+    let conn = Connection::new(
+        link,
+        recv_id.wrapping_add(1),
+        recv_id,
+        2,
+        // the SYN's acknowledgement doesn't consume a sequence number; data starts at its seq_nr
+        state.seq_nr.wrapping_sub(1),
+        state.wnd_size,
+    );
+    Ok(conn.spawn(addr))
+}
+
+/// Where a connection's datagrams go and come from.
+enum Link {
+    /// A socket of the connection's own, connected to the peer.
+    Own(UdpSocket),
+    /// An [`Endpoint`]'s socket, with the peer's datagrams for this connection routed to
+    /// `inbox`.
+    Shared {
+        socket: Arc<UdpSocket>,
+        peer: SocketAddr,
+        inbox: mpsc::Receiver<Vec<u8>>,
+        _route: Route,
+    },
+}
+
+impl Link {
+    async fn send(&self, packet: &[u8]) -> io::Result<()> {
+        match self {
+            Link::Own(socket) => socket.send(packet).await?,
+            Link::Shared { socket, peer, .. } => socket.send_to(packet, peer).await?,
+        };
+        Ok(())
+    }
+
+    async fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Link::Own(socket) => socket.recv(buf).await,
+            Link::Shared { inbox, .. } => {
+                let packet = inbox.recv().await.ok_or(io::ErrorKind::ConnectionAborted)?;
+                let len = packet.len().min(buf.len());
+                buf[..len].copy_from_slice(&packet[..len]);
+                Ok(len)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Routing {
+    /// Each connection's inbox, by peer and the connection id of the packets it receives.
+    routes: HashMap<(SocketAddr, u16), mpsc::Sender<Vec<u8>>>,
+    /// The connection id of our SYNs to each peer we are still dialling.
+    dialing: HashMap<SocketAddr, u16>,
+}
+
+fn lock(routing: &Mutex<Routing>) -> std::sync::MutexGuard<'_, Routing> {
+    routing.lock().expect("utp routing lock poisoned")
+}
+
+/// Removes a connection's route once it is dropped.
+struct Route {
+    routing: Arc<Mutex<Routing>>,
+    key: (SocketAddr, u16),
+}
+
+impl Route {
+    fn add(
+        routing: &Arc<Mutex<Routing>>,
+        key: (SocketAddr, u16),
+    ) -> (Self, mpsc::Receiver<Vec<u8>>) {
+        let (tx, inbox) = mpsc::channel(INBOX);
+        lock(routing).routes.insert(key, tx);
+        let route = Route {
+            routing: routing.clone(),
+            key,
+        };
+        (route, inbox)
+    }
+}
+
+impl Drop for Route {
+    fn drop(&mut self) {
+        lock(&self.routing).routes.remove(&self.key);
+    }
+}
+
+/// One UDP socket, normally on our listening port, carrying any number of uTP connections in
+/// either direction. Dialling from it rather than a fresh socket means peers see us coming
+/// from the port they would connect to, which is what lets holepunching work.
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    socket: Arc<UdpSocket>,
+    routing: Arc<Mutex<Routing>>,
+    accepted: Arc<tokio::sync::Mutex<mpsc::Receiver<(DuplexStream, SocketAddr)>>>,
+}
+
+impl Endpoint {
+    /// Binds `addr` and starts sorting the datagrams that arrive there into connections.
+    pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let routing = Arc::new(Mutex::new(Routing::default()));
+        let (accept, accepted) = mpsc::channel(ACCEPT_BACKLOG);
+        tokio::spawn(demux(socket.clone(), routing.clone(), accept));
+        Ok(Self {
+            socket,
+            routing,
+            accepted: Arc::new(tokio::sync::Mutex::new(accepted)),
+        })
+    }
+
+    /// Opens a uTP connection to `addr` from this endpoint's socket.
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<DuplexStream> {
+        let recv_id: u16 = rand::random();
+        let (route, inbox) = Route::add(&self.routing, (addr, recv_id));
+        lock(&self.routing).dialing.insert(addr, recv_id);
+        let link = Link::Shared {
+            socket: self.socket.clone(),
+            peer: addr,
+            inbox,
+            _route: route,
+        };
+        let established = establish(link, addr, recv_id).await;
+        lock(&self.routing).dialing.remove(&addr);
+        established
+    }
+
+    /// The next connection a peer opened to us.
+    pub async fn accept(&self) -> Option<(DuplexStream, SocketAddr)> {
+        self.accepted.lock().await.recv().await
+    }
+}
+
+/// Routes each datagram arriving on `socket` to its connection, and answers SYNs for new
+/// ones, queueing them on `accept`.
+async fn demux(
+    socket: Arc<UdpSocket>,
+    routing: Arc<Mutex<Routing>>,
+    accept: mpsc::Sender<(DuplexStream, SocketAddr)>,
+) {
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let (received, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                tracing::debug!(error = %e, "utp receive failed");
+                continue;
+            }
+        };
+        let packet = &buf[..received];
+        let Some((header, _)) = Header::parse(packet) else {
+            continue;
+        };
+        // a SYN carries the id the peer receives on; it sends to us on the next one
+        let recv_id = if header.kind == PacketType::Syn {
+            header.connection_id.wrapping_add(1)
+        } else {
+            header.connection_id
+        };
+        let inbox = lock(&routing).routes.get(&(from, recv_id)).cloned();
+        if let Some(inbox) = inbox {
+            // a full inbox is the same as a lost packet; the peer resends it
+            let _ = inbox.try_send(packet.to_vec());
+            continue;
+        }
+        if header.kind != PacketType::Syn {
+            continue;
+        }
+        // when both sides dial each other at once, the SYN with the larger id wins
+        let ours = lock(&routing).dialing.get(&from).copied();
+        if ours.is_some_and(|ours| ours > header.connection_id) {
+            continue;
+        }
+        let Ok(slot) = accept.try_reserve() else {
+            tracing::debug!(peer = %from, "turned away utp connection: backlog full");
+            continue;
+        };
+        let (route, inbox) = Route::add(&routing, (from, recv_id));
+        let link = Link::Shared {
+            socket: socket.clone(),
+            peer: from,
+            inbox,
+            _route: route,
+        };
+        let mut conn = Connection::new(
+            link,
+            header.connection_id,
+            recv_id,
+            rand::random(),
+            header.seq_nr,
+            header.wnd_size,
+        );
+        conn.reply_micros = now_micros().wrapping_sub(header.timestamp);
+        if let Err(e) = conn.send_ack().await {
+            tracing::debug!(peer = %from, error = %e, "answering utp connection failed");
+            continue;
+        }
+        tracing::debug!(peer = %from, "utp connection accepted");
+        slot.send((conn.spawn(from), from));
+    }
+}
+
+struct Sent {
+    seq_nr: u16,
+    packet: Vec<u8>,
+    payload_len: usize,
+    sent_at: Instant,
+    resent: bool,
+}
+
+struct Connection {
+    socket: Link,
+    send_id: u16,
+    recv_id: u16,
+    /// Sequence number of the next data packet we send.
+    seq_nr: u16,
+    /// Last sequence number we have received in order.
+    ack_nr: u16,
+    /// How long the peer's last packet took to reach us, echoed back for its delay estimate.
+    reply_micros: u32,
+    peer_window: usize,
+    /// Congestion window in bytes.
+    cwnd: f64,
+    base_delay: u32,
+    /// Smoothed round trip time and its variance.
+    rtt: Option<(Duration, Duration)>,
+    rto: Duration,
+    timeouts: u32,
+    unacked: VecDeque<Sent>,
+    reorder: HashMap<u16, (PacketType, Vec<u8>)>,
+    local_closed: bool,
+    remote_closed: bool,
+}
+
+impl Connection {
+    fn new(
+        socket: Link,
+        send_id: u16,
+        recv_id: u16,
+        seq_nr: u16,
+        ack_nr: u16,
+        peer_window: u32,
+    ) -> Self {
+        Self {
+            socket,
+            send_id,
+            recv_id,
+            seq_nr,
+            ack_nr,
+            reply_micros: 0,
+            peer_window: peer_window as usize,
+            cwnd: 2.0 * MSS as f64,
+            base_delay: u32::MAX,
+            rtt: None,
+            rto: SYN_TIMEOUT,
+            timeouts: 0,
+            unacked: VecDeque::new(),
+            reorder: HashMap::new(),
+            local_closed: false,
+            remote_closed: false,
+        }
+    }
+
+    /// Runs the connection on a background task, returning the stream that carries its bytes.
+    fn spawn(self, peer: SocketAddr) -> DuplexStream {
+        let (ours, theirs) = tokio::io::duplex(RECV_WINDOW);
+        tokio::spawn(async move {
+            if let Err(e) = self.run(theirs).await {
+                tracing::debug!(%peer, error = %e, "utp connection closed with error");
+            }
+        });
+        ours
+    }
+
+    async fn run(mut self, app: DuplexStream) -> io::Result<()> {
+        let (mut app_read, mut app_write) = tokio::io::split(app);
+        let mut outgoing = vec![0; MSS];
+        let mut incoming = vec![0; 1 << 16];
+        while !(self.remote_closed && self.local_closed && self.unacked.is_empty()) {
+            let in_flight: usize = self.unacked.iter().map(|s| s.payload_len).sum();
+            let window = (self.cwnd as usize).min(self.peer_window);
+            let can_send = !self.local_closed && in_flight + MSS <= window.max(MSS);
+            let retransmit_at = self.unacked.front().map(|sent| sent.sent_at + self.rto);
+            tokio::select! {
+                read = app_read.read(&mut outgoing), if can_send => match read {
+                    Ok(0) | Err(_) => {
+                        self.local_closed = true;
+                        self.send_packet(PacketType::Fin, &[]).await?;
+                    }
+                    Ok(n) => self.send_packet(PacketType::Data, &outgoing[..n]).await?,
+                },
+                received = self.socket.recv(&mut incoming) => {
+                    let n = received?;
+                    if !self.on_packet(&incoming[..n], &mut app_write).await? {
+                        return Ok(());
+                    }
+                }
+                _ = tokio::time::sleep_until(retransmit_at.unwrap_or_else(Instant::now)),
+                    if retransmit_at.is_some() => self.on_timeout().await?,
+            }
+        }
+        Ok(())
+    }
+
+    fn header(&self, kind: PacketType) -> Header {
+        Header {
+            kind,
+            connection_id: self.send_id,
+            timestamp: now_micros(),
+            timestamp_diff: self.reply_micros,
+            wnd_size: RECV_WINDOW as u32,
+            seq_nr: self.seq_nr,
+            ack_nr: self.ack_nr,
+        }
+    }
+
+    /// Sends a data or FIN packet, which takes a sequence number and awaits an ack.
+    async fn send_packet(&mut self, kind: PacketType, payload: &[u8]) -> io::Result<()> {
+        let mut packet = self.header(kind).to_bytes().to_vec();
+        packet.extend_from_slice(payload);
+        self.socket.send(&packet).await?;
+        self.unacked.push_back(Sent {
+            seq_nr: self.seq_nr,
+            packet,
+            payload_len: payload.len(),
+            sent_at: Instant::now(),
+            resent: false,
+        });
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        Ok(())
+    }
+
+    async fn send_ack(&self) -> io::Result<()> {
+        self.socket
+            .send(&self.header(PacketType::State).to_bytes())
+            .await
+    }
+
+    /// Handles one datagram from the peer. Returns `false` once the peer has reset the
+    /// connection.
+    async fn on_packet(
+        &mut self,
+        packet: &[u8],
+        app: &mut WriteHalf<DuplexStream>,
+    ) -> io::Result<bool> {
+        let Some((header, payload)) = Header::parse(packet) else {
+            return Ok(true);
+        };
+        if header.kind == PacketType::Syn {
+            // the peer resends its SYN, which carries the id before ours, if our answer was lost
+            if header.connection_id == self.recv_id.wrapping_sub(1) {
+                self.send_ack().await?;
+            }
+            return Ok(true);
+        }
+        if header.connection_id != self.recv_id {
+            return Ok(true);
+        }
+        self.reply_micros = now_micros().wrapping_sub(header.timestamp);
+        self.peer_window = header.wnd_size as usize;
+        self.on_ack(header);
+
+        match header.kind {
+            PacketType::Reset => return Ok(false),
+            PacketType::Data | PacketType::Fin => {
+                if seq_before(self.ack_nr, header.seq_nr) && self.reorder.len() < MAX_REORDER {
+                    self.reorder
+                        .insert(header.seq_nr, (header.kind, payload.to_vec()));
+                }
+                while let Some((kind, payload)) = self.reorder.remove(&self.ack_nr.wrapping_add(1))
+                {
+                    self.ack_nr = self.ack_nr.wrapping_add(1);
+                    if kind == PacketType::Fin {
+                        self.remote_closed = true;
+                        self.reorder.clear();
+                        app.shutdown().await?;
+                    } else if !self.remote_closed {
+                        app.write_all(&payload).await?;
+                    }
+                }
+                self.send_ack().await?;
+            }
+            PacketType::State | PacketType::Syn => {}
+        }
+        Ok(true)
+    }
+
+    /// Drops every packet the peer has acknowledged and grows or shrinks the congestion
+    /// window LEDBAT-style, by how far the peer's measured delay is from the target.
+    fn on_ack(&mut self, header: Header) {
+        let mut acked = 0;
+        while let Some(sent) = self.unacked.front() {
+            if seq_before(header.ack_nr, sent.seq_nr) {
+                break;
+            }
+            let sent = self.unacked.pop_front().expect("front was just checked");
+            acked += sent.payload_len;
+            if !sent.resent {
+                self.update_rtt(sent.sent_at.elapsed());
+            }
+        }
+        if acked == 0 {
+            return;
+        }
+        self.timeouts = 0;
+
+        let delay = header.timestamp_diff;
+        self.base_delay = self.base_delay.min(delay);
+        let queueing = Duration::from_micros(u64::from(delay - self.base_delay));
+        let off_target =
+            (TARGET_DELAY.as_secs_f64() - queueing.as_secs_f64()) / TARGET_DELAY.as_secs_f64();
+        self.cwnd += MAX_WINDOW_GROWTH * off_target * acked as f64 / self.cwnd;
+        self.cwnd = self.cwnd.max(MSS as f64);
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        let (rtt, var) = match self.rtt {
+            None => (sample, sample / 2),
+            Some((rtt, var)) => {
+                let delta = rtt.abs_diff(sample);
+                (rtt * 7 / 8 + sample / 8, var * 3 / 4 + delta / 4)
+            }
+        };
+        self.rtt = Some((rtt, var));
+        self.rto = (rtt + var * 4).max(MIN_TIMEOUT);
+    }
+
+    async fn on_timeout(&mut self) -> io::Result<()> {
+        self.timeouts += 1;
+        if self.timeouts > MAX_TIMEOUTS {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.cwnd = (self.cwnd / 2.0).max(MSS as f64);
+        self.rto *= 2;
+        if let Some(sent) = self.unacked.front_mut() {
+            sent.sent_at = Instant::now();
+            sent.resent = true;
+            self.socket.send(&sent.packet).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn endpoint() -> (Endpoint, SocketAddr) {
+        let endpoint = Endpoint::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let addr = endpoint.socket.local_addr().unwrap();
+        (endpoint, addr)
+    }
+
+    #[test]
+    fn reads_headers_back_past_extensions() {
+        let header = Header {
+            kind: PacketType::Data,
+            connection_id: 0x1234,
+            timestamp: 1,
+            timestamp_diff: 2,
+            wnd_size: 3,
+            seq_nr: 4,
+            ack_nr: 5,
+        };
+        let mut packet = header.to_bytes().to_vec();
+        // a selective ack extension of 4 bytes, then the payload
+        packet[1] = 1;
+        packet.extend([0, 4, 0xff, 0xff, 0xff, 0xff]);
+        packet.extend(b"payload");
+        let (parsed, payload) = Header::parse(&packet).unwrap();
+        assert_eq!(parsed.kind, PacketType::Data);
+        assert_eq!(parsed.connection_id, 0x1234);
+        assert_eq!((parsed.seq_nr, parsed.ack_nr), (4, 5));
+        assert_eq!(payload, b"payload");
+
+        assert!(Header::parse(&packet[..HEADER_LEN - 1]).is_none());
+        // an extension running past the end of the packet
+        assert!(Header::parse(&packet[..HEADER_LEN + 3]).is_none());
+        packet[0] = (PacketType::Data as u8) << 4 | 2;
+        assert!(Header::parse(&packet).is_none(), "unknown version");
+    }
+
+    #[test]
+    fn orders_sequence_numbers_across_wraparound() {
+        assert!(seq_before(1, 2));
+        assert!(!seq_before(2, 1));
+        assert!(!seq_before(7, 7));
+        assert!(seq_before(u16::MAX, 0));
+        assert!(seq_before(0xfff0, 0x0010));
+        assert!(!seq_before(0x0010, 0xfff0));
+    }
+
+    #[tokio::test]
+    async fn carries_bytes_both_ways() {
+        let (ours, _) = endpoint().await;
+        let (theirs, their_addr) = endpoint().await;
+        let (mut dialled, accepted) = tokio::join!(
+            async { ours.connect(their_addr).await.unwrap() },
+            theirs.accept()
+        );
+        let (mut accepted, from) = accepted.unwrap();
+        assert_eq!(from, ours.socket.local_addr().unwrap());
+
+        // many packets' worth, to be split up and put back in order
+        let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let sending = async {
+            dialled.write_all(&data).await.unwrap();
+            dialled.shutdown().await.unwrap();
+        };
+        let mut received = Vec::new();
+        let receiving = accepted.read_to_end(&mut received);
+        let (_, read) = tokio::time::timeout(Duration::from_secs(10), async {
+            tokio::join!(sending, receiving)
+        })
+        .await
+        .unwrap();
+        read.unwrap();
+        assert!(received == data, "received {} bytes", received.len());
+
+        accepted.write_all(b"thanks").await.unwrap();
+        let mut reply = [0; 6];
+        dialled.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"thanks");
+    }
+
+    #[tokio::test]
+    async fn dials_endpoints_from_a_socket_of_its_own() {
+        let (theirs, their_addr) = endpoint().await;
+        let net = NetConfig::default();
+        let (dialled, accepted) = tokio::join!(connect(their_addr, &net), theirs.accept());
+        let mut dialled = dialled.unwrap();
+        let (mut accepted, _) = accepted.unwrap();
+        dialled.write_all(b"ping").await.unwrap();
+        let mut received = [0; 4];
+        accepted.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+    }
+}
@@ -0,0 +1,425 @@
+//! BitTorrent v2 (BEP 52) metainfo: per-file merkle trees over 16 KiB blocks, the `file
+//! tree` dictionary and the SHA-256 info hash. Hybrid torrents carry these alongside the v1
+//! fields; v2-only torrents can't be read by the library's `Torrent` at all.
+
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use serde::Deserialize;
+use serde_bencode::value::Value;
+
+use crate::bencode;
+use crate::hash::TorrentVersion;
+
+/// Size of a merkle tree leaf.
+pub const BLOCK_LEN: usize = 16 << 10;
+
+pub type Hash = [u8; 32];
+
+pub fn sha256(data: &[u8]) -> Hash {
+    to_hash(TorrentVersion::V2.digest(data))
+}
+
+fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = TorrentVersion::V2.hasher();
+    hasher.update(left);
+    hasher.update(right);
+    to_hash(hasher.finish())
+}
+
+fn to_hash(digest: Vec<u8>) -> Hash {
+    digest.try_into().expect("SHA-256 digests are 32 bytes")
+}
+
+/// Root of a tree over `width` leaves, a power of two, where leaves past the end of `nodes`
+/// are `pad`. `pad` is itself the root of a subtree when `nodes` is a higher layer.
+pub fn merkle_root(nodes: &[Hash], width: usize, pad: Hash) -> Hash {
+    debug_assert!(width.is_power_of_two() && nodes.len() <= width);
+    let mut layer = nodes.to_vec();
+    let mut pad = pad;
+    let mut width = width;
+    while width > 1 {
+        layer = (0..width / 2)
+            .map(|i| {
+                let left = layer.get(2 * i).unwrap_or(&pad);
+                let right = layer.get(2 * i + 1).unwrap_or(&pad);
+                hash_pair(left, right)
+            })
+            .take(layer.len().div_ceil(2))
+            .collect();
+        pad = hash_pair(&pad, &pad);
+        width /= 2;
+    }
+    layer.first().copied().unwrap_or(pad)
+}
+
+/// Root of a subtree of `width` all-zero leaves, i.e. the padding beyond the end of a file.
+pub fn zero_root(width: usize) -> Hash {
+    merkle_root(&[], width, [0; 32])
+}
+
+/// Leaf hashes of `data`, one per 16 KiB block; the last block may be shorter.
+pub fn block_hashes(data: &[u8]) -> Vec<Hash> {
+    data.chunks(BLOCK_LEN).map(sha256).collect()
+}
+
+/// The piece-layer node for one piece of a file: the root of its blocks, padded with zero
+/// leaves when the piece is the short last one.
+pub fn piece_root(data: &[u8], piece_length: usize) -> Hash {
+    merkle_root(&block_hashes(data), piece_length / BLOCK_LEN, [0; 32])
+}
+
+/// Checks a run of nodes from one layer of a tree against the tree's `root`, as in a BEP 52
+/// `hashes` message. The run starts at node `index`, is padded with `pad` to a power of two it
+/// is aligned to, and `proof` holds the uncle hashes from just above the run to the root.
+pub fn verify_range(root: &Hash, nodes: &[Hash], index: usize, proof: &[Hash], pad: Hash) -> bool {
+    if nodes.is_empty() {
+        return false;
+    }
+    let width = nodes.len().next_power_of_two();
+    if !index.is_multiple_of(width) {
+        return false;
+    }
+    let mut node = merkle_root(nodes, width, pad);
+    let mut position = index / width;
+    for uncle in proof {
+        node = if position.is_multiple_of(2) {
+            hash_pair(&node, uncle)
+        } else {
+            hash_pair(uncle, &node)
+        };
+        position /= 2;
+    }
+    position == 0 && node == *root
+}
+
+/// A file's merkle tree in the form metainfo needs: its root, plus the layer whose nodes each
+/// cover one piece, which is only stored for files longer than a piece.
+pub struct FileHashes {
+    pub root: Hash,
+    pub piece_layer: Vec<Hash>,
+}
+
+impl FileHashes {
+    /// Builds the tree from the SHA-256 of each 16 KiB block of a non-empty file.
+    pub fn from_blocks(blocks: &[Hash], piece_length: usize) -> Self {
+        let per_piece = piece_length / BLOCK_LEN;
+        if blocks.len() <= per_piece {
+            let width = blocks.len().next_power_of_two();
+            return Self {
+                root: merkle_root(blocks, width, [0; 32]),
+                piece_layer: Vec::new(),
+            };
+        }
+        let piece_layer: Vec<Hash> = blocks
+            .chunks(per_piece)
+            .map(|piece| merkle_root(piece, per_piece, [0; 32]))
+            .collect();
+        let width = piece_layer.len().next_power_of_two();
+        Self {
+            root: merkle_root(&piece_layer, width, zero_root(per_piece)),
+            piece_layer,
+        }
+    }
+}
+
+/// The bencoded `info` dictionary exactly as it appears in `metainfo`, which is what both info
+/// hashes are taken over.
+pub fn info_bytes(metainfo: &[u8]) -> Option<&[u8]> {
+    let mut at = 1;
+    if metainfo.first() != Some(&b'd') {
+        return None;
+    }
+    while *metainfo.get(at)? != b'e' {
+        let key_len = bencode::value_len(&metainfo[at..])?;
+        let key = &metainfo[at..at + key_len];
+        at += key_len;
+        let value_len = bencode::value_len(&metainfo[at..])?;
+        if key == b"4:info" {
+            return Some(&metainfo[at..at + value_len]);
+        }
+        at += value_len;
+    }
+    None
+}
+
+#[derive(Debug, Clone)]
+pub struct V2File {
+    /// Path components below the torrent name.
+    pub path: Vec<String>,
+    pub length: u64,
+    /// Absent for empty files, which have no blocks to hash.
+    pub pieces_root: Option<Hash>,
+}
+
+/// The v2 view of a torrent.
+#[derive(Debug, Clone)]
+pub struct V2Info {
+    pub announce: Option<String>,
+    pub name: String,
+    pub piece_length: u64,
+    pub files: Vec<V2File>,
+    pub info_hash: Hash,
+    /// Whether the torrent also carries v1 `pieces`, i.e. is a hybrid.
+    pub hybrid: bool,
+    /// `piece layers`, keyed by pieces root: the concatenated piece-layer hashes of each file
+    /// longer than a piece.
+    pub piece_layers: BTreeMap<Hash, Vec<u8>>,
+}
+
+impl V2Info {
+    pub fn length(&self) -> u64 {
+        self.files.iter().map(|file| file.length).sum()
+    }
+
+    /// The piece-layer hashes of `file`, checked against its pieces root. Empty for files no
+    /// longer than a piece, whose root covers their blocks directly.
+    pub fn piece_layer(&self, file: &V2File) -> anyhow::Result<Vec<Hash>> {
+        let Some(root) = file.pieces_root else {
+            return Ok(Vec::new());
+        };
+        if file.length <= self.piece_length {
+            return Ok(Vec::new());
+        }
+        let path = file.path.join("/");
+        let layer = self
+            .piece_layers
+            .get(&root)
+            .with_context(|| format!("no piece layer for {path}"))?;
+        let pieces = file.length.div_ceil(self.piece_length) as usize;
+        anyhow::ensure!(
+            layer.len() == pieces * 32,
+            "piece layer for {path} has {} bytes, expected {}",
+            layer.len(),
+            pieces * 32
+        );
+        let layer: Vec<Hash> = layer
+            .chunks_exact(32)
+            .map(|hash| hash.try_into().expect("chunk is 32 bytes"))
+            .collect();
+        let pad = zero_root(self.piece_length as usize / BLOCK_LEN);
+        anyhow::ensure!(
+            verify_range(&root, &layer, 0, &[], pad),
+            "piece layer for {path} does not match its pieces root"
+        );
+        Ok(layer)
+    }
+}
+
+#[derive(Deserialize)]
+struct RawMeta {
+    announce: Option<String>,
+    info: RawInfo,
+    #[serde(rename = "piece layers", default)]
+    piece_layers: BTreeMap<serde_bytes::ByteBuf, serde_bytes::ByteBuf>,
+}
+
+#[derive(Deserialize)]
+struct RawInfo {
+    #[serde(rename = "meta version")]
+    meta_version: Option<i64>,
+    name: String,
+    #[serde(rename = "piece length")]
+    piece_length: u64,
+    #[serde(rename = "file tree")]
+    file_tree: Option<Value>,
+    pieces: Option<serde_bytes::ByteBuf>,
+}
+
+/// Reads the v2 fields of `metainfo`, or `None` for a v1-only torrent.
+pub fn parse(metainfo: &[u8]) -> anyhow::Result<Option<V2Info>> {
+    let raw: RawMeta = serde_bencode::from_bytes(metainfo).context("parse torrent file")?;
+    if raw.info.meta_version != Some(2) {
+        return Ok(None);
+    }
+    anyhow::ensure!(
+        raw.info.piece_length.is_power_of_two() && raw.info.piece_length >= BLOCK_LEN as u64,
+        "v2 piece length must be a power of two of at least 16 KiB"
+    );
+    let tree = raw.info.file_tree.context("v2 torrent has no file tree")?;
+    let mut files = Vec::new();
+    walk_file_tree(&tree, &mut Vec::new(), &mut files)?;
+    anyhow::ensure!(!files.is_empty(), "v2 file tree is empty");
+    let info = info_bytes(metainfo).context("locate info dictionary")?;
+    let piece_layers = raw
+        .piece_layers
+        .into_iter()
+        .filter_map(|(root, layer)| Some((root.as_slice().try_into().ok()?, layer.into_vec())))
+        .collect();
+    Ok(Some(V2Info {
+        announce: raw.announce,
+        name: raw.info.name,
+        piece_length: raw.info.piece_length,
+        files,
+        info_hash: sha256(info),
+        hybrid: raw.info.pieces.is_some(),
+        piece_layers,
+    }))
+}
+
+fn walk_file_tree(
+    node: &Value,
+    path: &mut Vec<String>,
+    files: &mut Vec<V2File>,
+) -> anyhow::Result<()> {
+    let Value::Dict(entries) = node else {
+        anyhow::bail!("file tree node is not a dictionary");
+    };
+    // sort so files come out in the order the torrent lists them
+    let mut entries: Vec<_> = entries.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    for (name, child) in entries {
+        if name.is_empty() {
+            files.push(file_entry(child, path)?);
+            continue;
+        }
+        path.push(String::from_utf8_lossy(name).into_owned());
+        walk_file_tree(child, path, files)?;
+        path.pop();
+    }
+    Ok(())
+}
+
+fn file_entry(node: &Value, path: &[String]) -> anyhow::Result<V2File> {
+    let Value::Dict(fields) = node else {
+        anyhow::bail!("file entry {} is not a dictionary", path.join("/"));
+    };
+    let Some(Value::Int(length)) = fields.get(b"length".as_slice()) else {
+        anyhow::bail!("file entry {} has no length", path.join("/"));
+    };
+    let pieces_root = match fields.get(b"pieces root".as_slice()) {
+        Some(Value::Bytes(root)) => Some(
+            root.as_slice()
+                .try_into()
+                .with_context(|| format!("pieces root of {} is not 32 bytes", path.join("/")))?,
+        ),
+        _ => None,
+    };
+    anyhow::ensure!(
+        *length == 0 || pieces_root.is_some(),
+        "file entry {} has no pieces root",
+        path.join("/")
+    );
+    Ok(V2File {
+        path: path.to_vec(),
+        length: u64::try_from(*length).context("negative file length")?,
+        pieces_root,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(byte: u8) -> Hash {
+        sha256(&[byte])
+    }
+
+    /// A v2-only metainfo with one 40000-byte file `a` in 16 KiB pieces, and its piece layer.
+    fn metainfo(layer: &[Hash]) -> (Vec<u8>, Hash) {
+        let root = merkle_root(layer, layer.len().next_power_of_two(), zero_root(1));
+        let mut info = b"d9:file treed1:ad0:d6:lengthi40000e11:pieces root32:".to_vec();
+        info.extend(root);
+        info.extend(b"eee12:meta versioni2e4:name1:t12:piece lengthi16384ee");
+        let mut metainfo = b"d4:info".to_vec();
+        metainfo.extend(&info);
+        metainfo.extend(b"12:piece layersd32:");
+        metainfo.extend(root);
+        metainfo.extend(format!("{}:", layer.len() * 32).as_bytes());
+        metainfo.extend(layer.concat());
+        metainfo.extend(b"ee");
+        (metainfo, root)
+    }
+
+    #[test]
+    fn pads_trees_to_their_width() {
+        let (a, b, c) = (leaf(1), leaf(2), leaf(3));
+        assert_eq!(merkle_root(&[a], 1, [0; 32]), a);
+        assert_eq!(merkle_root(&[a, b], 2, [0; 32]), hash_pair(&a, &b));
+        let zero = [0; 32];
+        assert_eq!(
+            merkle_root(&[a, b, c], 4, zero),
+            hash_pair(&hash_pair(&a, &b), &hash_pair(&c, &zero))
+        );
+        assert_eq!(zero_root(2), hash_pair(&zero, &zero));
+    }
+
+    #[test]
+    fn verifies_ranges_against_the_root() {
+        let leaves: Vec<_> = (0..4).map(leaf).collect();
+        let root = merkle_root(&leaves, 4, [0; 32]);
+        let left = hash_pair(&leaves[0], &leaves[1]);
+        assert!(verify_range(&root, &leaves[2..], 2, &[left], [0; 32]));
+        assert!(verify_range(&root, &leaves, 0, &[], [0; 32]));
+        // the run must sit where its index says, aligned to its width
+        assert!(!verify_range(&root, &leaves[2..], 0, &[left], [0; 32]));
+        assert!(!verify_range(&root, &leaves[1..3], 1, &[left], [0; 32]));
+        assert!(!verify_range(
+            &root,
+            &[leaves[3], leaves[2]],
+            2,
+            &[left],
+            [0; 32]
+        ));
+        assert!(!verify_range(&root, &[], 0, &[], [0; 32]));
+    }
+
+    #[test]
+    fn builds_piece_layers_for_files_longer_than_a_piece() {
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        let blocks = block_hashes(&data);
+        assert_eq!(blocks.len(), 7);
+
+        let small = FileHashes::from_blocks(&blocks, 8 * BLOCK_LEN);
+        assert!(small.piece_layer.is_empty());
+        assert_eq!(small.root, merkle_root(&blocks, 8, [0; 32]));
+
+        let hashes = FileHashes::from_blocks(&blocks, 2 * BLOCK_LEN);
+        assert_eq!(hashes.piece_layer.len(), 4);
+        assert_eq!(
+            hashes.piece_layer[3],
+            piece_root(&data[6 * BLOCK_LEN..], 2 * BLOCK_LEN)
+        );
+        assert!(verify_range(
+            &hashes.root,
+            &hashes.piece_layer,
+            0,
+            &[],
+            zero_root(2)
+        ));
+    }
+
+    #[test]
+    fn parses_v2_metainfo() {
+        let layer = [leaf(1), leaf(2), leaf(3)];
+        let (metainfo, root) = metainfo(&layer);
+        let info = parse(&metainfo).unwrap().expect("a v2 torrent");
+        assert_eq!(info.name, "t");
+        assert!(!info.hybrid);
+        assert_eq!(info.length(), 40000);
+        assert_eq!(info.files[0].path, ["a"]);
+        assert_eq!(info.files[0].pieces_root, Some(root));
+        assert_eq!(info.info_hash, sha256(info_bytes(&metainfo).unwrap()));
+        assert_eq!(info.piece_layer(&info.files[0]).unwrap(), layer);
+    }
+
+    #[test]
+    fn rejects_piece_layers_that_do_not_match() {
+        let (mut metainfo, _) = metainfo(&[leaf(1), leaf(2), leaf(3)]);
+        let last = metainfo.len() - 3;
+        metainfo[last] ^= 1;
+        let info = parse(&metainfo).unwrap().unwrap();
+        let error = info.piece_layer(&info.files[0]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "piece layer for a does not match its pieces root"
+        );
+    }
+
+    #[test]
+    fn leaves_v1_torrents_alone() {
+        let metainfo =
+            b"d4:infod6:lengthi3e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        assert!(parse(metainfo).unwrap().is_none());
+    }
+}
@@ -0,0 +1,203 @@
+//! Checking data already on disk against a torrent's piece hashes.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, mpsc};
+
+use anyhow::Context;
+
+use bittorrent_starter_rust::Torrent;
+
+use crate::bitfield::Bitfield;
+use crate::geometry::PieceGeometry;
+use crate::hash::TorrentVersion;
+use crate::metainfo;
+use crate::storage::Storage;
+use crate::v2::{self, FileHashes, V2Info};
+
+/// A piece that is missing or doesn't match its hash.
+#[derive(Debug)]
+pub struct BadPiece {
+    /// The file the piece belongs to, for v2 where pieces never span files.
+    pub file: Option<String>,
+    pub index: usize,
+}
+
+/// Result of checking every piece of a torrent.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub pieces: usize,
+    pub bad: Vec<BadPiece>,
+}
+
+/// Checks the v1 pieces of `t` against `path`: the file itself for a single-file torrent,
+/// or the directory holding its files. BEP 47 padding files are read as zeros.
+/// Missing files count as bad pieces rather than an error.
+pub fn verify_v1(t: &Torrent, metainfo: &[u8], path: &Path) -> anyhow::Result<Report> {
+    let have = recheck_v1(t, metainfo, path)?;
+    Ok(Report {
+        pieces: have.len(),
+        bad: (0..have.len())
+            .filter(|&index| !have.has_piece(index))
+            .map(|index| BadPiece { file: None, index })
+            .collect(),
+    })
+}
+
+/// Which v1 pieces of `t` are present and intact under `path`, laid out as for
+/// [`verify_v1`]. Pieces are read in order and hashed on one thread per core.
+pub fn recheck_v1(t: &Torrent, metainfo: &[u8], path: &Path) -> anyhow::Result<Bitfield> {
+    let mut data = v1_data(t, metainfo, path)?;
+    let hashes = &t.info.pieces.0;
+    let threads = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    let have = Mutex::new(Bitfield::new(hashes.len()));
+    let (tx, rx) = mpsc::sync_channel::<(usize, Vec<u8>)>(2 * threads);
+    let rx = Mutex::new(rx);
+    std::thread::scope(|scope| {
+        // dropped when reading stops, which lets the hashing threads finish
+        let tx = tx;
+        for _ in 0..threads {
+            scope.spawn(|| {
+                loop {
+                    // released before hashing, so the other threads can take pieces meanwhile
+                    let next = rx.lock().expect("piece queue lock poisoned").recv();
+                    let Ok((index, piece)) = next else {
+                        break;
+                    };
+                    if TorrentVersion::V1.verify(&piece, &hashes[index]) {
+                        have.lock()
+                            .expect("bitfield lock poisoned")
+                            .set_piece(index);
+                    }
+                }
+            });
+        }
+        let mut remaining = t.length() as u64;
+        for index in 0..hashes.len() {
+            let len = remaining.min(t.info.plength as u64) as usize;
+            remaining -= len as u64;
+            let mut piece = vec![0; len];
+            match data.read_exact(&mut piece) {
+                Ok(()) => tx
+                    .send((index, piece))
+                    .expect("hashing threads run until the queue closes"),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+                Err(e) => return Err(e).context("read torrent data"),
+            }
+        }
+        Ok(())
+    })?;
+    Ok(have.into_inner().expect("bitfield lock poisoned"))
+}
+
+/// Which of `pieces` of `t` are intact in `storage`, which holds them back to back from piece
+/// `pieces.start`. Only the first `stored` bytes are read; pieces past them are missing.
+pub fn recheck_stored(
+    t: &Torrent,
+    storage: &mut impl Storage,
+    pieces: Range<usize>,
+    stored: u64,
+) -> io::Result<Bitfield> {
+    let geometry = PieceGeometry::of(t);
+    let base = geometry.piece_offset(pieces.start);
+    let mut have = Bitfield::new(geometry.piece_count());
+    let mut piece = vec![0; t.info.plength];
+    for index in pieces {
+        let (offset, len) = (
+            geometry.piece_offset(index) - base,
+            geometry.piece_len(index),
+        );
+        if (offset + len) as u64 > stored {
+            break;
+        }
+        storage.read_block(offset as u64, &mut piece[..len])?;
+        if TorrentVersion::V1.verify(&piece[..len], &t.info.pieces.0[index]) {
+            have.set_piece(index);
+        }
+    }
+    Ok(have)
+}
+
+/// The torrent's data as one stream, with missing files and padding read as zeros.
+fn v1_data(t: &Torrent, metainfo: &[u8], path: &Path) -> anyhow::Result<Box<dyn Read>> {
+    let files = metainfo::files(metainfo, t);
+    let single = files.len() == 1 && files[0].0 == t.info.name;
+    let mut data: Box<dyn Read> = Box::new(io::empty());
+    for (name, length) in files {
+        let relative = name
+            .strip_prefix(&format!("{}/", t.info.name))
+            .unwrap_or(&name)
+            .to_string();
+        let file = local_path(path, &relative, single);
+        let part: Box<dyn Read> = match File::open(&file) {
+            _ if relative.starts_with(".pad/") => Box::new(io::repeat(0).take(length)),
+            Ok(f) => Box::new(f.take(length)),
+            // stand in for a missing file so the pieces after it still line up
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Box::new(io::repeat(0).take(length)),
+            Err(e) => return Err(e).with_context(|| format!("open {}", file.display())),
+        };
+        data = Box::new(data.chain(part));
+    }
+    Ok(data)
+}
+
+/// Checks every file of `info` against its v2 merkle tree. Each file's piece layer is first
+/// checked against its pieces root, then each piece against the layer.
+pub fn verify_v2(info: &V2Info, path: &Path) -> anyhow::Result<Report> {
+    let single = info.files.len() == 1 && info.files[0].path == [info.name.as_str()];
+    let piece_length = info.piece_length as usize;
+    let mut report = Report::default();
+    for file in &info.files {
+        let Some(root) = file.pieces_root else {
+            continue;
+        };
+        let name = file.path.join("/");
+        let layer = info.piece_layer(file)?;
+        let local = local_path(path, &name, single);
+        let mut data = match File::open(&local) {
+            Ok(f) => Some(f.take(file.length)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).with_context(|| format!("open {}", local.display())),
+        };
+        let pieces = file.length.div_ceil(info.piece_length) as usize;
+        let mut remaining = file.length;
+        let mut buf = vec![0; piece_length];
+        for index in 0..pieces {
+            let len = remaining.min(info.piece_length) as usize;
+            remaining -= len as u64;
+            report.pieces += 1;
+            let piece = match data.as_mut().map(|f| f.read_exact(&mut buf[..len])) {
+                Some(Ok(())) => Some(&buf[..len]),
+                Some(Err(e)) if e.kind() != io::ErrorKind::UnexpectedEof => {
+                    return Err(e).with_context(|| format!("read {}", local.display()));
+                }
+                _ => None,
+            };
+            let ok = piece.is_some_and(|piece| match layer.get(index) {
+                Some(expected) => v2::piece_root(piece, piece_length) == *expected,
+                // a file of one piece or less has no layer; its root covers the blocks
+                None => {
+                    FileHashes::from_blocks(&v2::block_hashes(piece), piece_length).root == root
+                }
+            });
+            if !ok {
+                report.bad.push(BadPiece {
+                    file: Some(name.clone()),
+                    index,
+                });
+            }
+        }
+    }
+    Ok(report)
+}
+
+fn local_path(path: &Path, relative: &str, single: bool) -> PathBuf {
+    if single {
+        path.to_path_buf()
+    } else {
+        path.join(relative)
+    }
+}
@@ -0,0 +1,137 @@
+use anyhow::Context;
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+use bittorrent_starter_rust::Torrent;
+
+use crate::config::ClientConfig;
+use crate::geometry::PieceGeometry;
+use crate::hash::TorrentVersion;
+use crate::timeout::{self, TimeoutError};
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UrlList {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct Extras {
+    #[serde(rename = "url-list")]
+    url_list: Option<UrlList>,
+    info: InfoExtras,
+}
+
+#[derive(Deserialize)]
+struct InfoExtras {
+    length: Option<u64>,
+}
+
+/// Reads the BEP 19 `url-list` from raw metainfo. Only single-file torrents are supported.
+pub fn web_seeds(metainfo: &[u8], t: &Torrent) -> Vec<reqwest::Url> {
+    let Ok(extras) = serde_bencode::from_bytes::<Extras>(metainfo) else {
+        return Vec::new();
+    };
+    if extras.info.length.is_none() {
+        tracing::debug!("ignoring web seeds of multi-file torrent");
+        return Vec::new();
+    }
+    let urls = match extras.url_list {
+        None => return Vec::new(),
+        Some(UrlList::One(url)) => vec![url],
+        Some(UrlList::Many(urls)) => urls,
+    };
+    urls.into_iter()
+        .filter_map(|url| {
+            // a url ending in a slash names the directory holding the file
+            let url = if url.ends_with('/') {
+                format!("{url}{}", t.info.name)
+            } else {
+                url
+            };
+            reqwest::Url::parse(&url).ok()
+        })
+        .collect()
+}
+
+/// Downloads and verifies `piece` from the first web seed that serves it correctly.
+pub async fn fetch_piece(
+    seeds: &[reqwest::Url],
+    t: &Torrent,
+    piece: usize,
+    config: &ClientConfig,
+) -> anyhow::Result<Vec<u8>> {
+    let geometry = PieceGeometry::of(t);
+    let client = config
+        .net
+        .http_client()
+        .context("build web seed http client")?;
+    let offset = geometry.piece_offset(piece) as u64;
+    let mut last_err = anyhow::anyhow!("torrent has no web seeds");
+    for seed in seeds {
+        let data = timeout::timeout(
+            config.timeouts.block,
+            TimeoutError::Block,
+            fetch_range(&client, seed, offset, geometry.piece_len(piece)),
+        )
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|r| r);
+        match data {
+            Ok(data) => {
+                let expected = &t.info.pieces.0[piece];
+                let (data, ok) = TorrentVersion::V1.verify_blocking(data, expected).await;
+                if ok {
+                    return Ok(data);
+                }
+                last_err = anyhow::anyhow!("web seed {seed} served corrupt data");
+            }
+            Err(e) => last_err = e.context(format!("fetch piece {piece} from {seed}")),
+        }
+        tracing::warn!(%seed, error = %last_err, "web seed failed");
+    }
+    Err(last_err)
+}
+
+async fn fetch_range(
+    client: &reqwest::Client,
+    url: &reqwest::Url,
+    offset: u64,
+    len: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let end = offset + len as u64 - 1;
+    let mut response = client
+        .get(url.clone())
+        .header(reqwest::header::RANGE, format!("bytes={offset}-{end}"))
+        .send()
+        .await
+        .context("request range")?
+        .error_for_status()?;
+
+    // servers that ignore Range send the whole file; skip ahead to the part we want
+    let mut skip = match response.status() {
+        StatusCode::PARTIAL_CONTENT => 0,
+        _ => offset as usize,
+    };
+    let mut data = Vec::with_capacity(len);
+    while data.len() < len {
+        let Some(chunk) = response.chunk().await.context("read response body")? else {
+            break;
+        };
+        let chunk = if skip >= chunk.len() {
+            skip -= chunk.len();
+            continue;
+        } else {
+            &chunk[std::mem::take(&mut skip)..]
+        };
+        let take = chunk.len().min(len - data.len());
+        data.extend_from_slice(&chunk[..take]);
+    }
+    anyhow::ensure!(
+        data.len() == len,
+        "web seed sent {} of {len} bytes",
+        data.len()
+    );
+    Ok(data)
+}
@@ -0,0 +1,341 @@
+//! Byte-level encoding of the fixed-layout peer wire structures.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::message::MessageTag;
+
+const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+
+#[derive(Debug, thiserror::Error)]
+pub enum WireError {
+    #[error("{what} needs {expected} bytes, got {actual}")]
+    Length {
+        what: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("handshake is not for the BitTorrent protocol")]
+    Protocol,
+    #[error("unknown {what} {value}")]
+    Unknown { what: &'static str, value: u32 },
+}
+
+fn check_len(what: &'static str, bytes: &[u8], expected: usize) -> Result<(), WireError> {
+    if bytes.len() < expected {
+        return Err(WireError::Length {
+            what,
+            expected,
+            actual: bytes.len(),
+        });
+    }
+    Ok(())
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(bytes[at..at + 4].try_into().expect("slice is 4 bytes"))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub reserved: [u8; 8],
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    pub const LEN: usize = 1 + PROTOCOL.len() + 8 + 20 + 20;
+
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        Self {
+            reserved: [0; 8],
+            info_hash,
+            peer_id,
+        }
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::from_reserved(&self.reserved)
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0; Self::LEN];
+        bytes[0] = PROTOCOL.len() as u8;
+        bytes[1..20].copy_from_slice(PROTOCOL);
+        bytes[20..28].copy_from_slice(&self.reserved);
+        bytes[28..48].copy_from_slice(&self.info_hash);
+        bytes[48..68].copy_from_slice(&self.peer_id);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WireError> {
+        if bytes.len() != Self::LEN {
+            return Err(WireError::Length {
+                what: "handshake",
+                expected: Self::LEN,
+                actual: bytes.len(),
+            });
+        }
+        if bytes[0] as usize != PROTOCOL.len() || &bytes[1..20] != PROTOCOL {
+            return Err(WireError::Protocol);
+        }
+        Ok(Self {
+            reserved: bytes[20..28].try_into().expect("slice is 8 bytes"),
+            info_hash: bytes[28..48].try_into().expect("slice is 20 bytes"),
+            peer_id: bytes[48..68].try_into().expect("slice is 20 bytes"),
+        })
+    }
+}
+
+/// Protocol extensions advertised through the handshake's reserved bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// BEP 5
+    pub dht: bool,
+    /// BEP 6
+    pub fast: bool,
+    /// BEP 10
+    pub extensions: bool,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            dht: false,
+            fast: true,
+            // needed to fetch metadata for magnet links
+            extensions: true,
+        }
+    }
+}
+
+impl Capabilities {
+    const DHT: (usize, u8) = (7, 0x01);
+    const FAST: (usize, u8) = (7, 0x04);
+    const EXTENSIONS: (usize, u8) = (5, 0x10);
+
+    pub fn to_reserved(self) -> [u8; 8] {
+        let mut reserved = [0; 8];
+        for ((byte, bit), enabled) in [
+            (Self::DHT, self.dht),
+            (Self::FAST, self.fast),
+            (Self::EXTENSIONS, self.extensions),
+        ] {
+            if enabled {
+                reserved[byte] |= bit;
+            }
+        }
+        reserved
+    }
+
+    pub fn from_reserved(reserved: &[u8; 8]) -> Self {
+        let has = |(byte, bit): (usize, u8)| reserved[byte] & bit != 0;
+        Self {
+            dht: has(Self::DHT),
+            fast: has(Self::FAST),
+            extensions: has(Self::EXTENSIONS),
+        }
+    }
+
+    /// The capabilities both sides advertised, i.e. the ones actually in effect.
+    pub fn intersect(self, other: Self) -> Self {
+        Self {
+            dht: self.dht && other.dht,
+            fast: self.fast && other.fast,
+            extensions: self.extensions && other.extensions,
+        }
+    }
+
+    /// Whether a message with this tag is legal given these negotiated capabilities.
+    pub fn allows(self, tag: MessageTag) -> bool {
+        match tag {
+            MessageTag::Port => self.dht,
+            MessageTag::SuggestPiece
+            | MessageTag::HaveAll
+            | MessageTag::HaveNone
+            | MessageTag::RejectRequest
+            | MessageTag::AllowedFast => self.fast,
+            MessageTag::Extended => self.extensions,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Request {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+impl Request {
+    pub const LEN: usize = 12;
+
+    pub fn new(index: u32, begin: u32, length: u32) -> Self {
+        Self {
+            index,
+            begin,
+            length,
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::LEN] {
+        let mut bytes = [0; Self::LEN];
+        bytes[0..4].copy_from_slice(&self.index.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.begin.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.length.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WireError> {
+        check_len("request", bytes, Self::LEN)?;
+        Ok(Self {
+            index: u32_at(bytes, 0),
+            begin: u32_at(bytes, 4),
+            length: u32_at(bytes, 8),
+        })
+    }
+}
+
+/// A `piece` message payload, borrowing the block from the received frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Piece<'a> {
+    pub index: u32,
+    pub begin: u32,
+    pub block: &'a [u8],
+}
+
+impl<'a> Piece<'a> {
+    pub const HEADER_LEN: usize = 8;
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::HEADER_LEN + self.block.len());
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes.extend_from_slice(&self.begin.to_be_bytes());
+        bytes.extend_from_slice(self.block);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<Self, WireError> {
+        check_len("piece", bytes, Self::HEADER_LEN)?;
+        Ok(Self {
+            index: u32_at(bytes, 0),
+            begin: u32_at(bytes, 4),
+            block: &bytes[Self::HEADER_LEN..],
+        })
+    }
+}
+
+/// A `ut_holepunch` extended message body (BEP 55).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Holepunch {
+    pub kind: HolepunchKind,
+    /// The peer to be introduced to, or being introduced.
+    pub addr: SocketAddr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HolepunchKind {
+    /// Asks the receiver to introduce us to `addr`.
+    Rendezvous,
+    /// Tells the receiver to dial `addr`, which is dialling it too.
+    Connect,
+    /// Why the receiver couldn't introduce us to `addr`.
+    Error(HolepunchError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum HolepunchError {
+    #[error("not a valid peer address")]
+    NoSuchPeer = 1,
+    #[error("the relay is not connected to that peer")]
+    NotConnected = 2,
+    #[error("that peer does not support holepunching")]
+    NoSupport = 3,
+    #[error("that address is the relay's own")]
+    NoSelf = 4,
+}
+
+impl Holepunch {
+    const RENDEZVOUS: u8 = 0;
+    const CONNECT: u8 = 1;
+    const ERROR: u8 = 2;
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(24);
+        bytes.push(match self.kind {
+            HolepunchKind::Rendezvous => Self::RENDEZVOUS,
+            HolepunchKind::Connect => Self::CONNECT,
+            HolepunchKind::Error(_) => Self::ERROR,
+        });
+        match self.addr.ip() {
+            IpAddr::V4(ip) => {
+                bytes.push(0);
+                bytes.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&ip.octets());
+            }
+        }
+        bytes.extend_from_slice(&self.addr.port().to_be_bytes());
+        // only error messages carry a code
+        if let HolepunchKind::Error(error) = self.kind {
+            bytes.extend_from_slice(&(error as u32).to_be_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WireError> {
+        check_len("holepunch message", bytes, 2)?;
+        let addr_len = match bytes[1] {
+            0 => 4,
+            1 => 16,
+            other => {
+                return Err(WireError::Unknown {
+                    what: "holepunch address type",
+                    value: other.into(),
+                });
+            }
+        };
+        let port_at = 2 + addr_len;
+        check_len("holepunch message", bytes, port_at + 2)?;
+        let ip: IpAddr = if addr_len == 4 {
+            Ipv4Addr::from(<[u8; 4]>::try_from(&bytes[2..port_at]).expect("slice is 4 bytes"))
+                .into()
+        } else {
+            Ipv6Addr::from(<[u8; 16]>::try_from(&bytes[2..port_at]).expect("slice is 16 bytes"))
+                .into()
+        };
+        let port = u16::from_be_bytes([bytes[port_at], bytes[port_at + 1]]);
+        let kind = match bytes[0] {
+            Self::RENDEZVOUS => HolepunchKind::Rendezvous,
+            Self::CONNECT => HolepunchKind::Connect,
+            Self::ERROR => {
+                check_len("holepunch error", bytes, port_at + 6)?;
+                let code = u32_at(bytes, port_at + 2);
+                HolepunchKind::Error(match code {
+                    1 => HolepunchError::NoSuchPeer,
+                    2 => HolepunchError::NotConnected,
+                    3 => HolepunchError::NoSupport,
+                    4 => HolepunchError::NoSelf,
+                    other => {
+                        return Err(WireError::Unknown {
+                            what: "holepunch error code",
+                            value: other,
+                        });
+                    }
+                })
+            }
+            other => {
+                return Err(WireError::Unknown {
+                    what: "holepunch message type",
+                    value: other.into(),
+                });
+            }
+        };
+        Ok(Self {
+            kind,
+            addr: SocketAddr::new(ip, port),
+        })
+    }
+}
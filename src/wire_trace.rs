@@ -0,0 +1,105 @@
+//! `--trace-wire`: a JSON-lines record of every message exchanged with every peer.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::message::{Message, MessageTag};
+
+/// The file trace records are appended to, shared by every connection.
+#[derive(Debug, Clone)]
+pub struct TraceFile {
+    out: Arc<Mutex<File>>,
+}
+
+impl TraceFile {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            out: Arc::new(Mutex::new(File::create(path)?)),
+        })
+    }
+
+    /// A tracer for the connection to `peer`.
+    pub fn peer(&self, peer: SocketAddr) -> WireTrace {
+        WireTrace {
+            file: self.clone(),
+            peer,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// Records the messages of one peer connection. Keep-alives aren't recorded.
+#[derive(Debug, Clone)]
+pub struct WireTrace {
+    file: TraceFile,
+    peer: SocketAddr,
+}
+
+impl WireTrace {
+    pub fn record(&self, direction: Direction, message: &Message) {
+        let ts = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        let record = serde_json::json!({
+            "ts": ts,
+            "peer": self.peer.to_string(),
+            "dir": match direction {
+                Direction::Sent => "send",
+                Direction::Received => "recv",
+            },
+            "tag": format!("{:?}", message.tag),
+            "len": 1 + message.payload.len(),
+            "fields": fields(message),
+        });
+        let mut line = record.to_string();
+        line.push('\n');
+        // one write per line so records from different connections don't interleave
+        let mut out = self.file.out.lock().expect("wire trace lock poisoned");
+        if let Err(e) = out.write_all(line.as_bytes()) {
+            tracing::warn!(error = %e, "writing wire trace failed");
+        }
+    }
+}
+
+/// The payload fields worth seeing at a glance; block data and bitfields are only sized.
+fn fields(message: &Message) -> serde_json::Value {
+    let payload = &message.payload;
+    let u32_at = |at: usize| {
+        payload
+            .get(at..at + 4)
+            .map(|b| u32::from_be_bytes(b.try_into().expect("slice is 4 bytes")))
+    };
+    match message.tag {
+        MessageTag::Have | MessageTag::SuggestPiece | MessageTag::AllowedFast => {
+            serde_json::json!({ "index": u32_at(0) })
+        }
+        MessageTag::Request | MessageTag::Cancel | MessageTag::RejectRequest => {
+            serde_json::json!({ "index": u32_at(0), "begin": u32_at(4), "length": u32_at(8) })
+        }
+        MessageTag::Piece => serde_json::json!({
+            "index": u32_at(0),
+            "begin": u32_at(4),
+            "block": payload.len().saturating_sub(8),
+        }),
+        MessageTag::Bitfield => serde_json::json!({ "bytes": payload.len() }),
+        MessageTag::Port => serde_json::json!({
+            "port": payload.get(..2).map(|b| u16::from_be_bytes([b[0], b[1]])),
+        }),
+        MessageTag::Extended => serde_json::json!({ "id": payload.first() }),
+        MessageTag::Choke
+        | MessageTag::Unchoke
+        | MessageTag::Interested
+        | MessageTag::NotInterested
+        | MessageTag::HaveAll
+        | MessageTag::HaveNone => serde_json::json!({}),
+    }
+}